@@ -0,0 +1,210 @@
+//The treexml loader and writer the streaming ones replaced, kept for `lyrics bench` to time
+//them against
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use treexml::{Document, Element};
+
+use database::metadata::*;
+use database::storage::migrate::CURRENT_VERSION;
+use database::DatabaseError;
+
+fn parse_num(attr: String, val: &str, tag: &str) -> Result<u8, DatabaseError> {
+    val.parse::<u8>()
+        .map_err(|_| DatabaseError::InvalidAttribute((attr, tag.to_owned())))
+}
+
+//Load the whole document with treexml before converting it
+pub fn load_tree(path: &str) -> Result<Vec<Artist>, DatabaseError> {
+    let mut entries = Vec::new();
+
+    //Open file for reading
+    let path = Path::new(path);
+    let file = File::open(path)?;
+    let mut buf_reader = BufReader::new(file);
+    let mut data = String::new();
+    buf_reader.read_to_string(&mut data)?;
+
+    let doc = Document::parse(data.as_bytes()).map_err(|e| DatabaseError::Xml(e.to_string()))?;
+    if let Some(root) = doc.root {
+        if root.name != "database" {
+            return Err(DatabaseError::InvalidTag(root.name));
+        }
+        for artist_tag in root.children {
+            //Only the artists are compared with the streaming loader
+            if artist_tag.name == "setlist" {
+                continue;
+            }
+            let mut artist = Artist::new();
+            if artist_tag.name != "artist" {
+                return Err(DatabaseError::InvalidTag(artist_tag.name));
+            }
+
+            if artist_tag.attributes.is_empty() {
+                return Err(DatabaseError::MissingAttribute((
+                    "name".to_string(),
+                    artist_tag.name,
+                )));
+            }
+
+            for (attribute, value) in artist_tag.attributes {
+                if attribute != "name" {
+                    return Err(DatabaseError::InvalidAttribute((attribute, value)));
+                } else {
+                    artist.name = value;
+                }
+            }
+
+            let mut albums = Vec::new();
+            for album_tag in artist_tag.children {
+                if album_tag.name != "album" {
+                    return Err(DatabaseError::InvalidTag(album_tag.name));
+                }
+                let mut album = Album::new();
+                for (attr, val) in album_tag.attributes {
+                    match attr.as_ref() {
+                        "title" => album.title = val,
+                        "tracks" => album.track_count = parse_num(attr, &val, &album_tag.name)?,
+                        _ => return Err(DatabaseError::InvalidAttribute((attr, album_tag.name))),
+                    };
+                }
+
+                let mut tracks = Vec::new();
+                for track_tag in album_tag.children {
+                    let mut track = Track::new();
+                    for (attr, val) in track_tag.attributes {
+                        match attr.as_ref() {
+                            "name" => track.title = val,
+                            "num" => track.track = parse_num(attr, &val, &track_tag.name)?,
+                            _ => {
+                                return Err(DatabaseError::InvalidAttribute((attr, track_tag.name)))
+                            }
+                        };
+                    }
+
+                    if let Some(lyrics) = track_tag.text {
+                        track.lyrics = lyrics;
+                    }
+
+                    for alternate_tag in track_tag.children {
+                        if alternate_tag.name != "alternate" {
+                            return Err(DatabaseError::InvalidTag(alternate_tag.name));
+                        }
+                        let mut alternate = Alternate {
+                            artist: String::new(),
+                            album: String::new(),
+                            title: String::new(),
+                        };
+                        for (attr, val) in alternate_tag.attributes {
+                            match attr.as_ref() {
+                                "artist" => alternate.artist = val,
+                                "album" => alternate.album = val,
+                                "title" => alternate.title = val,
+                                _ => {
+                                    return Err(DatabaseError::InvalidAttribute((
+                                        attr,
+                                        alternate_tag.name,
+                                    )))
+                                }
+                            };
+                        }
+                        track.alternates.push(alternate);
+                    }
+
+                    tracks.push(track);
+                }
+
+                tracks.sort_by(|a, b| a.track.cmp(&b.track));
+                album.tracks = tracks;
+                albums.push(album);
+            }
+            artist.albums = albums;
+            entries.push(artist);
+        }
+    } else {
+        return Err(DatabaseError::Empty);
+    }
+
+    Ok(entries)
+}
+
+pub fn save_tree(path: &str, entries: &[Artist]) -> Result<(), DatabaseError> {
+    let mut root = Element::new("database");
+    root.attributes
+        .insert("version".to_owned(), CURRENT_VERSION.to_string());
+    for artist in entries {
+        let mut artist_el = Element::new("artist");
+        artist_el
+            .attributes
+            .insert("name".to_owned(), artist.name.clone());
+        for album in &artist.albums {
+            let mut album_el = Element::new("album");
+            album_el
+                .attributes
+                .insert("title".to_owned(), album.title.clone());
+            album_el
+                .attributes
+                .insert("tracks".to_owned(), album.track_count.to_string());
+
+            for track in &album.tracks {
+                let mut track_el = Element::new("track");
+                track_el
+                    .attributes
+                    .insert("num".to_owned(), track.track.to_string());
+                track_el
+                    .attributes
+                    .insert("name".to_owned(), track.title.to_string());
+                track_el.text = Some(track.lyrics.clone());
+                for alternate in &track.alternates {
+                    let mut alternate_el = Element::new("alternate");
+                    alternate_el
+                        .attributes
+                        .insert("artist".to_owned(), alternate.artist.clone());
+                    alternate_el
+                        .attributes
+                        .insert("album".to_owned(), alternate.album.clone());
+                    alternate_el
+                        .attributes
+                        .insert("title".to_owned(), alternate.title.clone());
+                    track_el.children.push(alternate_el);
+                }
+                album_el.children.push(track_el);
+            }
+            artist_el.children.push(album_el);
+        }
+        root.children.push(artist_el);
+    }
+    let doc = Document {
+        root: Some(root),
+        ..Document::default()
+    };
+    let mut file = File::create(path)?;
+    doc.write_with(&mut file, true, "  ", true)
+        .map_err(|e| DatabaseError::Xml(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use database::testing::*;
+
+    #[test]
+    fn load_tree_rejects_bad_numbers() {
+        let path = temp_path("bad.xml");
+        fs::write(
+            &path,
+            "<database><artist name=\"a\"><album title=\"b\" tracks=\"many\"/></artist></database>",
+        )
+        .unwrap();
+        match load_tree(&path) {
+            Err(DatabaseError::InvalidAttribute((attr, tag))) => {
+                assert_eq!((attr.as_str(), tag.as_str()), ("tracks", "album"))
+            }
+            other => panic!("expected an invalid attribute, got {:?}", other.map(|_| ())),
+        }
+        remove_dir(&path);
+    }
+}
@@ -0,0 +1,555 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::time::{Duration, Instant};
+
+use bench;
+use database::diff::diff;
+use database::merge::normalize;
+use database::storage::{self, xml, Storage};
+use database::Database;
+use export::epub::{self, EpubSettings};
+use export::setlist::{lyric_sheet, SheetFormat};
+use export::site::{self, SiteFormat, SiteSettings};
+use export::songbook::{self, PageSize, SongbookSettings};
+use export::Selection;
+use players::{mpd, mpris, NowPlaying};
+use providers::FileProvider;
+use server;
+
+const USAGE: &str = "usage: lyrics [command]
+
+Without a command the editor window is opened.
+
+commands:
+    diff <old> <new>        show what changed between two database files
+    migrate <from> <to>     copy a database to another file, files ending in .db, .sqlite
+                            or .sqlite3 are SQLite databases, anything else is XML
+    search <file> <words>   list the tracks whose lyrics contain all the words
+    serve <file> [port]     answer REST requests for the database on localhost, port 8080
+                            unless given, and GraphQL queries at /graphql
+    provide <dir> [port]    answer lyrics lookups from text files laid out as
+                            <dir>/<artist>/<album>/<title>.txt on localhost, port 8081
+                            unless given, for LYRICS_PROVIDER_URL
+    mock-mpris              pretend to be an MPRIS player on the session bus, playing the
+                            songs read as `artist / album / title` lines from stdin
+    mock-mpd <address>      the same for MPD, listening on a local socket if the address
+                            starts with a slash, on that port of localhost if it is a number
+                            and on `host:port` otherwise
+    songbook <file> <pdf> [options] [artist [album]]
+                            print the database, an artist or one of their albums as a PDF
+                            songbook, with --title <title>, --page a4|a5|letter, and
+                            --font <font> and --title-font <font> given like \"Serif 11\"
+    epub <file> <epub> [options] [artist [album]]
+                            write the database, an artist or one of their albums as an
+                            EPUB book, with --title <title>, --author <author>, --language
+                            <language tag> and the image --cover <file> as its cover
+    setlist <file> [name] [--markdown]
+                            list the setlists of the database, or print the lyric sheet of
+                            the one named, as plain text or in Markdown with --markdown
+    site <file> <dir> [options]
+                            write the database as a website to the directory, in Markdown
+                            with --markdown, with --title <title>, and with the templates
+                            found in --templates <dir> instead of the built in ones
+    fmt <file>...           rewrite XML databases in place in the canonical layout
+    bench <file> [runs]     time loading and saving an XML database with the streaming
+                            reader and writer against the treexml document
+
+XML files ending in .gz or .zst are written compressed. If LYRICS_PASSPHRASE is set, it is
+used to read encrypted files and to encrypt the files that are written.
+
+Missing lyrics are looked up in the directory LYRICS_PROVIDER_DIR and at the web service
+LYRICS_PROVIDER_URL, laid out and answering like `provide` does.
+
+MPD is found through MPD_HOST and MPD_PORT, at /run/mpd/socket or on localhost:6600.";
+
+fn passphrase() -> Option<String> {
+    env::var("LYRICS_PASSPHRASE").ok()
+}
+
+fn open(path: &str) -> Result<Database, i32> {
+    let passphrase = passphrase();
+    Database::from_with_progress(path, passphrase.as_ref().map(String::as_str), |_| true).map_err(
+        |e| {
+            eprintln!("lyrics: {}: {}", path, e);
+            1
+        },
+    )
+}
+
+//Like `open`, leaving the lyrics in the file until they are asked for
+fn open_index(path: &str) -> Result<Database, i32> {
+    let passphrase = passphrase();
+    Database::from_index_with_progress(path, passphrase.as_ref().map(String::as_str), |_| true)
+        .map_err(|e| {
+            eprintln!("lyrics: {}: {}", path, e);
+            1
+        })
+}
+
+fn run_diff(args: &[String]) -> Result<(), i32> {
+    let (old, new) = match args {
+        [old, new] => (open(old)?, open(new)?),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+    for change in diff(&old, &new) {
+        println!("{}", change);
+    }
+    Ok(())
+}
+
+fn run_migrate(args: &[String]) -> Result<(), i32> {
+    let (from, to) = match args {
+        [from, to] => (open(from)?, to),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+    let passphrase = passphrase();
+    storage::open(to, passphrase.as_ref().map(String::as_str))
+        .and_then(|mut storage| storage.save(&from.entries, &from.setlists))
+        .map_err(|e| {
+            eprintln!("lyrics: {}: {}", to, e);
+            1
+        })
+}
+
+fn run_search(args: &[String]) -> Result<(), i32> {
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        return Err(2);
+    }
+    let mut db = open_index(&args[0])?;
+    let found = db.search(&args[1..].join(" ")).map_err(|e| {
+        eprintln!("lyrics: {}: {}", args[0], e);
+        1
+    })?;
+    for track in found {
+        println!("{} / {} / {}", track.artist, track.album, track.title);
+    }
+    Ok(())
+}
+
+fn run_fmt(args: &[String]) -> Result<(), i32> {
+    if args.is_empty() {
+        eprintln!("{}", USAGE);
+        return Err(2);
+    }
+    let passphrase = passphrase();
+    let mut result = Ok(());
+    for path in args {
+        //Files are written back compressed and encrypted the way they were found
+        let mut storage = xml::XmlStorage::new(path, passphrase.as_ref().map(String::as_str));
+        let loaded = storage
+            .load(&mut |_| true)
+            .and_then(|entries| Ok((entries, storage.load_setlists()?)));
+        if let Err(e) = loaded.and_then(|(entries, setlists)| storage.save(&entries, &setlists)) {
+            eprintln!("lyrics: {}: {}", path, e);
+            result = Err(1);
+        }
+    }
+    result
+}
+
+fn run_serve(args: &[String]) -> Result<(), i32> {
+    let (path, port) = match args {
+        [path] => (path, 8080),
+        [path, port] => (path, port.parse::<u16>().map_err(|_| 2)?),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+    let db = open(path)?;
+    println!("serving {} on http://127.0.0.1:{}/", path, port);
+    server::serve(db, port).map_err(|e| {
+        eprintln!("lyrics: {}", e);
+        1
+    })
+}
+
+fn run_provide(args: &[String]) -> Result<(), i32> {
+    let (dir, port) = match args {
+        [dir] => (dir, 8081),
+        [dir, port] => (dir, port.parse::<u16>().map_err(|_| 2)?),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+    println!(
+        "providing lyrics from {} on http://127.0.0.1:{}/",
+        dir, port
+    );
+    server::serve_provider(&FileProvider::new(dir.as_str()), port).map_err(|e| {
+        eprintln!("lyrics: {}", e);
+        1
+    })
+}
+
+//Songs from stdin as they are typed, in lines like those `search` prints. Anything else is
+//skipped.
+fn read_songs() -> impl Iterator<Item = NowPlaying> + Send {
+    let lines = BufReader::new(io::stdin()).lines();
+    lines.filter_map(Result::ok).filter_map(|line| {
+        let parts: Vec<&str> = line.splitn(3, " / ").map(str::trim).collect();
+        match *parts {
+            [artist, album, title] => Some(NowPlaying {
+                artist: artist.to_owned(),
+                album: album.to_owned(),
+                title: title.to_owned(),
+            }),
+            _ => None,
+        }
+    })
+}
+
+fn run_mock_mpris(args: &[String]) -> Result<(), i32> {
+    if !args.is_empty() {
+        eprintln!("{}", USAGE);
+        return Err(2);
+    }
+    mpris::serve_mock(read_songs()).map_err(|e| {
+        eprintln!("lyrics: {}", e);
+        1
+    })
+}
+
+fn run_mock_mpd(args: &[String]) -> Result<(), i32> {
+    let address = match args {
+        [address] if address.parse::<u16>().is_ok() => format!("127.0.0.1:{}", address),
+        [address] => address.clone(),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+    mpd::serve_mock(&address, read_songs()).map_err(|e| {
+        eprintln!("lyrics: {}: {}", address, e);
+        1
+    })
+}
+
+//The artist named `artist`, or their album named `album`
+fn find_selection(db: &Database, artist: &str, album: Option<&str>) -> Option<Selection> {
+    let i = db
+        .entries
+        .iter()
+        .position(|entry| normalize(&entry.name) == normalize(artist))?;
+    match album {
+        Some(album) => db.entries[i]
+            .albums
+            .iter()
+            .position(|entry| normalize(&entry.title) == normalize(album))
+            .map(|j| Selection::Album(i, j)),
+        None => Some(Selection::Artist(i)),
+    }
+}
+
+fn run_songbook(args: &[String]) -> Result<(), i32> {
+    let mut settings = SongbookSettings::new();
+    let mut names = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().cloned().ok_or_else(|| {
+                eprintln!("{}", USAGE);
+                2
+            })
+        };
+        match arg.as_str() {
+            "--title" => settings.title = value()?,
+            "--font" => settings.body_font = value()?,
+            "--title-font" => settings.title_font = value()?,
+            "--page" => {
+                let page = value()?;
+                settings.page_size = PageSize::from_id(&page).ok_or_else(|| {
+                    eprintln!("lyrics: unknown page size {}", page);
+                    2
+                })?;
+            }
+            _ => names.push(arg.as_str()),
+        }
+    }
+    let (path, pdf, selection) = match *names {
+        [path, pdf] => (path, pdf, None),
+        [path, pdf, artist] => (path, pdf, Some((artist, None))),
+        [path, pdf, artist, album] => (path, pdf, Some((artist, Some(album)))),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+
+    let mut db = open_index(path)?;
+    let selection = match selection {
+        Some((artist, album)) => find_selection(&db, artist, album).ok_or_else(|| {
+            eprintln!("lyrics: {}: no such artist or album", path);
+            1
+        })?,
+        None => Selection::All,
+    };
+    let songs = db.songs(&selection).map_err(|e| {
+        eprintln!("lyrics: {}: {}", path, e);
+        1
+    })?;
+    songbook::write_pdf(pdf, &songs, &settings).map_err(|e| {
+        eprintln!("lyrics: {}: {}", pdf, e);
+        1
+    })
+}
+
+fn run_epub(args: &[String]) -> Result<(), i32> {
+    let mut settings = EpubSettings::new();
+    let mut names = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().cloned().ok_or_else(|| {
+                eprintln!("{}", USAGE);
+                2
+            })
+        };
+        match arg.as_str() {
+            "--title" => settings.title = value()?,
+            "--author" => settings.author = Some(value()?),
+            "--language" => settings.language = value()?,
+            "--cover" => settings.cover = Some(value()?.into()),
+            _ => names.push(arg.as_str()),
+        }
+    }
+    let (path, book, selection) = match *names {
+        [path, book] => (path, book, None),
+        [path, book, artist] => (path, book, Some((artist, None))),
+        [path, book, artist, album] => (path, book, Some((artist, Some(album)))),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+
+    let mut db = open_index(path)?;
+    let selection = match selection {
+        Some((artist, album)) => find_selection(&db, artist, album).ok_or_else(|| {
+            eprintln!("lyrics: {}: no such artist or album", path);
+            1
+        })?,
+        None => Selection::All,
+    };
+    let songs = db.songs(&selection).map_err(|e| {
+        eprintln!("lyrics: {}: {}", path, e);
+        1
+    })?;
+    epub::write_epub(book, &songs, &settings).map_err(|e| {
+        eprintln!("lyrics: {}: {}", book, e);
+        1
+    })
+}
+
+fn run_setlist(args: &[String]) -> Result<(), i32> {
+    let mut format = SheetFormat::Text;
+    let mut names = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--markdown" => format = SheetFormat::Markdown,
+            _ => names.push(arg.as_str()),
+        }
+    }
+    let (path, name) = match *names {
+        [path] => (path, None),
+        [path, name] => (path, Some(name)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+    let mut db = open_index(path)?;
+    let name = match name {
+        Some(name) => name,
+        None => {
+            for setlist in &db.setlists {
+                println!("{} ({} tracks)", setlist.name, setlist.entries.len());
+            }
+            return Ok(());
+        }
+    };
+    let setlist = db
+        .setlists
+        .iter()
+        .find(|setlist| normalize(&setlist.name) == normalize(name))
+        .cloned()
+        .ok_or_else(|| {
+            eprintln!("lyrics: {}: no setlist named {}", path, name);
+            1
+        })?;
+    let sheet = lyric_sheet(&mut db, &setlist, format).map_err(|e| {
+        eprintln!("lyrics: {}: {}", path, e);
+        1
+    })?;
+    print!("{}", sheet);
+    Ok(())
+}
+
+fn run_site(args: &[String]) -> Result<(), i32> {
+    let mut settings = SiteSettings::new();
+    let mut names = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().cloned().ok_or_else(|| {
+                eprintln!("{}", USAGE);
+                2
+            })
+        };
+        match arg.as_str() {
+            "--markdown" => settings.format = SiteFormat::Markdown,
+            "--title" => settings.title = value()?,
+            "--templates" => settings.templates = Some(value()?.into()),
+            _ => names.push(arg.as_str()),
+        }
+    }
+    let (path, dir) = match *names {
+        [path, dir] => (path, dir),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+    let mut db = open_index(path)?;
+    site::write_site(&mut db, dir, &settings).map_err(|e| {
+        eprintln!("lyrics: {}: {}", dir, e);
+        1
+    })
+}
+
+//Average time of `runs` calls to `f` in milliseconds
+fn time<F>(runs: u32, mut f: F) -> Result<f64, i32>
+where
+    F: FnMut() -> Result<(), ::database::DatabaseError>,
+{
+    let mut total = Duration::new(0, 0);
+    for _ in 0..runs {
+        let start = Instant::now();
+        f().map_err(|e| {
+            eprintln!("lyrics: {}", e);
+            1
+        })?;
+        total += start.elapsed();
+    }
+    let millis = total.as_secs() as f64 * 1000.0 + f64::from(total.subsec_nanos()) / 1_000_000.0;
+    Ok(millis / f64::from(runs))
+}
+
+fn run_bench(args: &[String]) -> Result<(), i32> {
+    let (path, runs) = match args {
+        [path] => (path, 10),
+        [path, runs] => (path, runs.parse::<u32>().map_err(|_| 2)?),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(2);
+        }
+    };
+    if runs == 0 {
+        return Err(2);
+    }
+    let db = open(path)?;
+    let out = env::temp_dir().join("lyrics-bench.xml");
+    let out = out.to_string_lossy();
+
+    println!("{} runs of {}", runs, path);
+    println!(
+        "load, treexml:   {:8.2} ms",
+        time(runs, || bench::load_tree(path).map(|_| ()))?
+    );
+    println!(
+        "load, streaming: {:8.2} ms",
+        time(runs, || Database::from(path).map(|_| ()))?
+    );
+    println!(
+        "save, treexml:   {:8.2} ms",
+        time(runs, || bench::save_tree(&out, &db.entries))?
+    );
+    println!(
+        "save, streaming: {:8.2} ms",
+        time(runs, || storage::open(&out, None)?
+            .save(&db.entries, &db.setlists))?
+    );
+    let _ = fs::remove_file(&*out);
+    Ok(())
+}
+
+//Run a command line subcommand, returning the exit code
+pub fn run(args: &[String]) -> i32 {
+    let result = match args[0].as_str() {
+        "diff" => run_diff(&args[1..]),
+        "migrate" => run_migrate(&args[1..]),
+        "search" => run_search(&args[1..]),
+        "songbook" => run_songbook(&args[1..]),
+        "epub" => run_epub(&args[1..]),
+        "setlist" => run_setlist(&args[1..]),
+        "site" => run_site(&args[1..]),
+        "fmt" => run_fmt(&args[1..]),
+        "serve" => run_serve(&args[1..]),
+        "provide" => run_provide(&args[1..]),
+        "mock-mpris" => run_mock_mpris(&args[1..]),
+        "mock-mpd" => run_mock_mpd(&args[1..]),
+        "bench" => run_bench(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            Err(2)
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(code) => code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::storage::container::{self, Compression};
+    use database::testing::{entries, remove_dir, temp_path, track};
+
+    //Written by someone else, in a layout of their own
+    const HAND_WRITTEN: &str = "<database version=\"1\"><artist name=\"Artist\">\
+                                <album tracks=\"1\" title=\"Album\"><track name=\"One\" num=\"1\">\
+                                red sky</track></album></artist></database>";
+
+    #[test]
+    fn fmt_keeps_the_compression_and_encryption() {
+        let path = temp_path("lyrics.xml.zst");
+        let mut storage = xml::XmlStorage::new(&path, Some("secret"));
+        storage
+            .save(
+                &entries("Artist", "Album", vec![track("One", 1, "red sky")]),
+                &[],
+            )
+            .unwrap();
+        env::set_var("LYRICS_PASSPHRASE", "secret");
+        let result = run_fmt(&[path.clone()]);
+        env::remove_var("LYRICS_PASSPHRASE");
+        assert_eq!(result, Ok(()));
+
+        let (_, format) = container::open(&path, Some("secret")).unwrap();
+        assert_eq!(format.compression, Compression::Zstd);
+        assert!(format.encrypted);
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn fmt_writes_the_canonical_layout() {
+        let path = temp_path("lyrics.xml");
+        fs::write(&path, HAND_WRITTEN).unwrap();
+        assert_eq!(run_fmt(&[path.clone()]), Ok(()));
+        let formatted = fs::read_to_string(&path).unwrap();
+        assert!(formatted.contains("<track num=\"1\" name=\"One\">"));
+        assert_eq!(run_fmt(&[path.clone()]), Ok(()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), formatted);
+        remove_dir(&path);
+    }
+}
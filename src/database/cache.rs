@@ -0,0 +1,94 @@
+use std::collections::{BTreeMap, HashMap};
+
+//Lyrics fetched from the storage, the least recently used are dropped once the cached lyrics
+//take up more than `capacity` bytes
+pub struct LyricsCache {
+    //Lyrics and when they were last used
+    entries: HashMap<u64, (String, u64)>,
+    //Sources by when they were last used, least recently used first
+    order: BTreeMap<u64, u64>,
+    //Counts up with every use
+    clock: u64,
+    bytes: usize,
+    capacity: usize,
+}
+
+impl LyricsCache {
+    pub fn new(capacity: usize) -> LyricsCache {
+        LyricsCache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            capacity,
+        }
+    }
+
+    pub fn get(&mut self, source: u64) -> Option<&str> {
+        let entry = self.entries.get_mut(&source)?;
+        //Mark `source` as the most recently used
+        self.order.remove(&entry.1);
+        self.clock += 1;
+        entry.1 = self.clock;
+        self.order.insert(self.clock, source);
+        Some(entry.0.as_str())
+    }
+
+    pub fn insert(&mut self, source: u64, lyrics: String) {
+        if let Some((old, used)) = self.entries.remove(&source) {
+            self.bytes -= old.len();
+            self.order.remove(&used);
+        }
+        self.bytes += lyrics.len();
+        self.clock += 1;
+        self.entries.insert(source, (lyrics, self.clock));
+        self.order.insert(self.clock, source);
+
+        //Always keep the newest entry, even if it is bigger than the whole cache
+        while self.bytes > self.capacity && self.order.len() > 1 {
+            let (used, oldest) = match self.order.iter().next() {
+                Some((&used, &oldest)) => (used, oldest),
+                None => break,
+            };
+            self.order.remove(&used);
+            if let Some((old, _)) = self.entries.remove(&oldest) {
+                self.bytes -= old.len();
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_lyrics_go_first() {
+        let mut cache = LyricsCache::new(10);
+        cache.insert(1, "aaaa".to_owned());
+        cache.insert(2, "bbbb".to_owned());
+        assert_eq!(cache.get(1), Some("aaaa"));
+        cache.insert(3, "cccc".to_owned());
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some("aaaa"));
+        assert_eq!(cache.get(3), Some("cccc"));
+    }
+
+    #[test]
+    fn oversized_lyrics_are_kept_alone() {
+        let mut cache = LyricsCache::new(4);
+        cache.insert(1, "aa".to_owned());
+        cache.insert(2, "bbbbbbbb".to_owned());
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(2), Some("bbbbbbbb"));
+        cache.insert(2, "b".to_owned());
+        cache.insert(1, "aa".to_owned());
+        assert_eq!(cache.get(2), Some("b"));
+    }
+}
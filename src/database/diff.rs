@@ -0,0 +1,514 @@
+use std::fmt;
+
+use super::merge::normalize;
+use super::metadata::*;
+use super::Database;
+
+//A line of lyrics in a line-level diff
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineChange {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+impl fmt::Display for LineChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LineChange::Same(line) => write!(f, "  {}", line),
+            LineChange::Removed(line) => write!(f, "- {}", line),
+            LineChange::Added(line) => write!(f, "+ {}", line),
+        }
+    }
+}
+
+//Diff two texts line by line using the longest common subsequence of their lines
+pub fn diff_lines(old: &str, new: &str) -> Vec<LineChange> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    //lcs[i][j] is the length of the common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            changes.push(LineChange::Same(old[i].to_owned()));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            changes.push(LineChange::Removed(old[i].to_owned()));
+            i += 1;
+        } else {
+            changes.push(LineChange::Added(new[j].to_owned()));
+            j += 1;
+        }
+    }
+    changes.extend(
+        old[i..]
+            .iter()
+            .map(|line| LineChange::Removed(line.to_string())),
+    );
+    changes.extend(
+        new[j..]
+            .iter()
+            .map(|line| LineChange::Added(line.to_string())),
+    );
+    changes
+}
+
+//A difference between two databases, items are named by their path in the new database
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    ArtistAdded(String),
+    ArtistRemoved(String),
+    ArtistRenamed(String, String),
+    AlbumAdded(String, String),
+    AlbumRemoved(String, String),
+    AlbumRenamed(String, String, String),
+    TrackAdded(String, String, String),
+    TrackRemoved(String, String, String),
+    TrackRenamed(String, String, String, String),
+    LyricsChanged(String, String, String, Vec<LineChange>),
+    SetlistAdded(String),
+    SetlistRemoved(String),
+    //Its tracks, their order or how they are played changed
+    SetlistChanged(String),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::ArtistAdded(artist) => write!(f, "+ artist {}", artist),
+            Change::ArtistRemoved(artist) => write!(f, "- artist {}", artist),
+            Change::ArtistRenamed(old, new) => write!(f, "~ artist {} -> {}", old, new),
+            Change::AlbumAdded(artist, album) => write!(f, "+ album {} / {}", artist, album),
+            Change::AlbumRemoved(artist, album) => write!(f, "- album {} / {}", artist, album),
+            Change::AlbumRenamed(artist, old, new) => {
+                write!(f, "~ album {} / {} -> {}", artist, old, new)
+            }
+            Change::TrackAdded(artist, album, track) => {
+                write!(f, "+ track {} / {} / {}", artist, album, track)
+            }
+            Change::TrackRemoved(artist, album, track) => {
+                write!(f, "- track {} / {} / {}", artist, album, track)
+            }
+            Change::TrackRenamed(artist, album, old, new) => {
+                write!(f, "~ track {} / {} / {} -> {}", artist, album, old, new)
+            }
+            Change::LyricsChanged(artist, album, track, lines) => {
+                write!(f, "~ lyrics {} / {} / {}", artist, album, track)?;
+                for line in lines {
+                    match line {
+                        LineChange::Same(_) => (),
+                        _ => write!(f, "\n    {}", line)?,
+                    }
+                }
+                Ok(())
+            }
+            Change::SetlistAdded(setlist) => write!(f, "+ setlist {}", setlist),
+            Change::SetlistRemoved(setlist) => write!(f, "- setlist {}", setlist),
+            Change::SetlistChanged(setlist) => write!(f, "~ setlist {}", setlist),
+        }
+    }
+}
+
+//Items of two lists matched up by name, or by content when they were renamed
+struct Pairing {
+    pairs: Vec<(usize, usize)>,
+    removed: Vec<usize>,
+    added: Vec<usize>,
+}
+
+fn pair_up<T, N, S>(old: &[T], new: &[T], name: N, similar: S) -> Pairing
+where
+    N: Fn(&T) -> &str,
+    S: Fn(&T, &T) -> bool,
+{
+    let mut used = vec![false; new.len()];
+    let mut matched = vec![None; old.len()];
+
+    for (i, o) in old.iter().enumerate() {
+        let o_name = normalize(name(o));
+        let j = (0..new.len()).find(|&j| !used[j] && normalize(name(&new[j])) == o_name);
+        if let Some(j) = j {
+            used[j] = true;
+            matched[i] = Some(j);
+        }
+    }
+
+    //Whatever is left over on both sides may have been renamed
+    for (i, o) in old.iter().enumerate() {
+        if matched[i].is_some() {
+            continue;
+        }
+        let j = (0..new.len()).find(|&j| !used[j] && similar(o, &new[j]));
+        if let Some(j) = j {
+            used[j] = true;
+            matched[i] = Some(j);
+        }
+    }
+
+    Pairing {
+        pairs: matched
+            .iter()
+            .enumerate()
+            .filter_map(|(i, j)| j.map(|j| (i, j)))
+            .collect(),
+        removed: (0..old.len()).filter(|&i| matched[i].is_none()).collect(),
+        added: (0..new.len()).filter(|&j| !used[j]).collect(),
+    }
+}
+
+//True if at least half of the names are shared between the two lists
+fn overlapping<'a, I, J>(a: I, b: J) -> bool
+where
+    I: Iterator<Item = &'a str>,
+    J: Iterator<Item = &'a str>,
+{
+    let a: Vec<String> = a.map(normalize).collect();
+    let b: Vec<String> = b.map(normalize).collect();
+    let shared = a.iter().filter(|name| b.contains(name)).count();
+    shared > 0 && shared * 2 >= a.len().max(b.len())
+}
+
+//True if at least half of the lines are shared between the two lyrics
+fn similar_lyrics(a: &str, b: &str) -> bool {
+    if a.trim().is_empty() || b.trim().is_empty() {
+        return false;
+    }
+    let same = diff_lines(a, b)
+        .iter()
+        .filter(|line| match line {
+            LineChange::Same(_) => true,
+            _ => false,
+        })
+        .count();
+    same * 4 >= a.lines().count() + b.lines().count()
+}
+
+//Compare two databases by their content rather than their layout on disk
+pub fn diff(old: &Database, new: &Database) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    let artists = pair_up(
+        &old.entries,
+        &new.entries,
+        |artist| &artist.name,
+        |a, b| {
+            overlapping(
+                a.albums.iter().map(|album| album.title.as_str()),
+                b.albums.iter().map(|album| album.title.as_str()),
+            )
+        },
+    );
+    for &i in &artists.removed {
+        changes.push(Change::ArtistRemoved(old.entries[i].name.clone()));
+    }
+    for &(i, j) in &artists.pairs {
+        diff_artist(&old.entries[i], &new.entries[j], &mut changes);
+    }
+    for &j in &artists.added {
+        changes.push(Change::ArtistAdded(new.entries[j].name.clone()));
+    }
+
+    //Setlists are only known by their name
+    for setlist in &old.setlists {
+        match new.setlists.iter().find(|s| s.name == setlist.name) {
+            None => changes.push(Change::SetlistRemoved(setlist.name.clone())),
+            Some(changed) if changed != setlist => {
+                changes.push(Change::SetlistChanged(setlist.name.clone()))
+            }
+            Some(_) => (),
+        }
+    }
+    for setlist in &new.setlists {
+        if !old.setlists.iter().any(|s| s.name == setlist.name) {
+            changes.push(Change::SetlistAdded(setlist.name.clone()));
+        }
+    }
+    changes
+}
+
+fn diff_artist(old: &Artist, new: &Artist, changes: &mut Vec<Change>) {
+    if old.name != new.name {
+        changes.push(Change::ArtistRenamed(old.name.clone(), new.name.clone()));
+    }
+
+    let albums = pair_up(
+        &old.albums,
+        &new.albums,
+        |album| &album.title,
+        |a, b| {
+            overlapping(
+                a.tracks.iter().map(|track| track.title.as_str()),
+                b.tracks.iter().map(|track| track.title.as_str()),
+            )
+        },
+    );
+    for &i in &albums.removed {
+        changes.push(Change::AlbumRemoved(
+            new.name.clone(),
+            old.albums[i].title.clone(),
+        ));
+    }
+    for &(i, j) in &albums.pairs {
+        diff_album(&new.name, &old.albums[i], &new.albums[j], changes);
+    }
+    for &j in &albums.added {
+        changes.push(Change::AlbumAdded(
+            new.name.clone(),
+            new.albums[j].title.clone(),
+        ));
+    }
+}
+
+fn diff_album(artist: &str, old: &Album, new: &Album, changes: &mut Vec<Change>) {
+    if old.title != new.title {
+        changes.push(Change::AlbumRenamed(
+            artist.to_owned(),
+            old.title.clone(),
+            new.title.clone(),
+        ));
+    }
+
+    let tracks = pair_up(
+        &old.tracks,
+        &new.tracks,
+        |track| &track.title,
+        |a, b| similar_lyrics(&a.lyrics, &b.lyrics),
+    );
+    for &i in &tracks.removed {
+        changes.push(Change::TrackRemoved(
+            artist.to_owned(),
+            new.title.clone(),
+            old.tracks[i].title.clone(),
+        ));
+    }
+    for &(i, j) in &tracks.pairs {
+        let (old_track, new_track) = (&old.tracks[i], &new.tracks[j]);
+        if old_track.title != new_track.title {
+            changes.push(Change::TrackRenamed(
+                artist.to_owned(),
+                new.title.clone(),
+                old_track.title.clone(),
+                new_track.title.clone(),
+            ));
+        }
+        let lines = diff_lines(&old_track.lyrics, &new_track.lyrics);
+        let changed = lines.iter().any(|line| match line {
+            LineChange::Same(_) => false,
+            _ => true,
+        });
+        if changed {
+            changes.push(Change::LyricsChanged(
+                artist.to_owned(),
+                new.title.clone(),
+                new_track.title.clone(),
+                lines,
+            ));
+        }
+    }
+    for &j in &tracks.added {
+        changes.push(Change::TrackAdded(
+            artist.to_owned(),
+            new.title.clone(),
+            new.tracks[j].title.clone(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::{entries, setlist, track};
+
+    fn database(entries: Vec<Artist>) -> Database {
+        let mut db = Database::empty();
+        db.entries = entries;
+        db
+    }
+
+    fn lines(changes: &[LineChange]) -> Vec<String> {
+        changes.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn lines_follow_the_longest_common_subsequence() {
+        let changes = diff_lines("a\nb\nc\nd", "a\nc\nx\nd\ne");
+        assert_eq!(lines(&changes), ["  a", "- b", "  c", "+ x", "  d", "+ e"]);
+        assert_eq!(lines(&diff_lines("", "a\nb")), ["+ a", "+ b"]);
+        assert_eq!(lines(&diff_lines("a\nb", "")), ["- a", "- b"]);
+        //Lines moved up are removed where they were
+        assert_eq!(
+            lines(&diff_lines("a\nb\nc", "c\na\nb")),
+            ["+ c", "  a", "  b", "- c"]
+        );
+    }
+
+    #[test]
+    fn renamed_tracks_are_found_by_their_lyrics() {
+        let old = database(entries(
+            "Artist",
+            "Album",
+            vec![
+                track("Song", 1, "one\ntwo\nthree"),
+                track("Gone", 2, "red\nsky"),
+            ],
+        ));
+        let new = database(entries(
+            "Artist",
+            "Album",
+            vec![
+                track("Live song", 1, "one\ntwo\nfour"),
+                track("New", 2, "blue\nsea"),
+            ],
+        ));
+        let name = |s: &str| s.to_owned();
+        assert_eq!(
+            diff(&old, &new),
+            [
+                Change::TrackRemoved(name("Artist"), name("Album"), name("Gone")),
+                Change::TrackRenamed(
+                    name("Artist"),
+                    name("Album"),
+                    name("Song"),
+                    name("Live song")
+                ),
+                Change::LyricsChanged(
+                    name("Artist"),
+                    name("Album"),
+                    name("Live song"),
+                    diff_lines("one\ntwo\nthree", "one\ntwo\nfour"),
+                ),
+                Change::TrackAdded(name("Artist"), name("Album"), name("New")),
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_artists_and_albums_are_found_by_what_they_hold() {
+        let tracks = || {
+            vec![
+                track("One", 1, ""),
+                track("Two", 2, ""),
+                track("Three", 3, ""),
+            ]
+        };
+        let old = database(entries("Artist", "Album", tracks()));
+        let mut renamed = tracks();
+        renamed[2].title = "Four".to_owned();
+        let new = database(entries("Band", "Record", renamed));
+        //With its album renamed too nothing is left to know the artist by
+        assert_eq!(
+            diff(&old, &new)[0],
+            Change::ArtistRemoved("Artist".to_owned())
+        );
+
+        let new = database(entries("Band", "Album", tracks()));
+        assert_eq!(
+            diff(&old, &new),
+            [Change::ArtistRenamed(
+                "Artist".to_owned(),
+                "Band".to_owned()
+            )]
+        );
+
+        let mut renamed = tracks();
+        renamed[2].title = "Four".to_owned();
+        let new = database(entries("Artist", "Record", renamed));
+        let changes: Vec<String> = diff(&old, &new).iter().map(Change::to_string).collect();
+        assert_eq!(
+            changes,
+            [
+                "~ album Artist / Album -> Record",
+                "- track Artist / Record / Three",
+                "+ track Artist / Record / Four",
+            ]
+        );
+    }
+
+    #[test]
+    fn albums_sharing_less_than_half_their_tracks_are_not_renamed() {
+        let old = database(entries(
+            "Artist",
+            "Album",
+            vec![
+                track("One", 1, ""),
+                track("Two", 2, ""),
+                track("Three", 3, ""),
+            ],
+        ));
+        let new = database(entries(
+            "Artist",
+            "Record",
+            vec![
+                track("One", 1, ""),
+                track("Four", 2, ""),
+                track("Five", 3, ""),
+            ],
+        ));
+        assert_eq!(
+            diff(&old, &new),
+            [
+                Change::AlbumRemoved("Artist".to_owned(), "Album".to_owned()),
+                Change::AlbumAdded("Artist".to_owned(), "Record".to_owned()),
+            ]
+        );
+    }
+
+    //What `lyrics diff` prints, a change per line with the changed lines of lyrics indented
+    #[test]
+    fn changes_are_printed_one_per_line() {
+        let old = database(entries(
+            "Artist",
+            "Album",
+            vec![track("Song", 1, "one\ntwo")],
+        ));
+        let new = database(entries(
+            "Artist",
+            "Album",
+            vec![track("Song", 1, "one\n2"), track("New", 2, "")],
+        ));
+        let printed: Vec<String> = diff(&old, &new).iter().map(Change::to_string).collect();
+        assert_eq!(
+            printed.join("\n"),
+            "~ lyrics Artist / Album / Song\n    - two\n    + 2\n\
+             + track Artist / Album / New"
+        );
+    }
+
+    #[test]
+    fn setlists_are_compared_by_name() {
+        let mut old = Database::empty();
+        old.setlists = vec![
+            setlist("Same", &["One"]),
+            setlist("Changed", &["One", "Two"]),
+            setlist("Removed", &[]),
+        ];
+        let mut new = Database::empty();
+        new.setlists = vec![
+            setlist("Added", &["One"]),
+            setlist("Changed", &["Two", "One"]),
+            setlist("Same", &["One"]),
+        ];
+        assert_eq!(
+            diff(&old, &new),
+            [
+                Change::SetlistChanged("Changed".to_owned()),
+                Change::SetlistRemoved("Removed".to_owned()),
+                Change::SetlistAdded("Added".to_owned()),
+            ]
+        );
+    }
+}
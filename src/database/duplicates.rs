@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+
+use super::metadata::*;
+use super::storage::{words, TrackRef};
+use super::Database;
+
+//Position of a track in the database as (artist, album, track) indices
+pub type TrackIndex = (usize, usize, usize);
+
+//Tracks that look like the same song, each with its best similarity to another member
+#[derive(Debug, Clone)]
+pub struct DuplicateGroup {
+    pub tracks: Vec<(TrackIndex, f32)>,
+}
+
+//Shingles shared by more tracks than this are too common to suggest a duplicate
+const MAX_SHINGLE_TRACKS: usize = 50;
+
+//Lowercase words of the title, ignoring anything in brackets such as "(Live)"
+fn title_key(title: &str) -> String {
+    let mut depth = 0;
+    let mut key = String::new();
+    for c in title.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = if depth > 0 { depth - 1 } else { 0 },
+            _ if depth > 0 => (),
+            c if c.is_alphanumeric() => key.extend(c.to_lowercase()),
+            _ => key.push(' '),
+        }
+    }
+    key.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+//Sets of three consecutive words, or of single words for very short lyrics
+fn shingles(lyrics: &str) -> HashSet<String> {
+    let words = words(lyrics);
+    if words.len() < 3 {
+        return words.into_iter().collect();
+    }
+    words.windows(3).map(|w| w.join(" ")).collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let cost = if ca == b[j] { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn title_similarity(a: &str, b: &str) -> f32 {
+    let len = a.chars().count().max(b.chars().count());
+    if len == 0 {
+        return 0.0;
+    }
+    1.0 - levenshtein(a, b) as f32 / len as f32
+}
+
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f32 / union as f32
+}
+
+struct Candidate {
+    index: TrackIndex,
+    title: String,
+    shingles: HashSet<String>,
+}
+
+//Lyrics weigh more than titles. Without lyrics only the title counts, and only for half, since
+//many different songs share a title.
+fn similarity(a: &Candidate, b: &Candidate) -> f32 {
+    let title = title_similarity(&a.title, &b.title);
+    if a.shingles.is_empty() || b.shingles.is_empty() {
+        title * 0.5
+    } else {
+        title * 0.3 + jaccard(&a.shingles, &b.shingles) * 0.7
+    }
+}
+
+fn find(parents: &mut Vec<usize>, i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+//Find groups of tracks with a similarity of at least `threshold`, between 0 and 1
+pub fn find_duplicates(db: &Database, threshold: f32) -> Vec<DuplicateGroup> {
+    let mut candidates = Vec::new();
+    for (i, artist) in db.entries.iter().enumerate() {
+        for (j, album) in artist.albums.iter().enumerate() {
+            for (k, track) in album.tracks.iter().enumerate() {
+                candidates.push(Candidate {
+                    index: (i, j, k),
+                    title: title_key(&track.title),
+                    shingles: shingles(&track.lyrics),
+                });
+            }
+        }
+    }
+
+    //Only compare tracks sharing a title or a reasonably rare shingle
+    let mut by_title: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut by_shingle: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (n, candidate) in candidates.iter().enumerate() {
+        if !candidate.title.is_empty() {
+            by_title
+                .entry(&candidate.title)
+                .or_insert_with(Vec::new)
+                .push(n);
+        }
+        for shingle in &candidate.shingles {
+            by_shingle.entry(shingle).or_insert_with(Vec::new).push(n);
+        }
+    }
+    let mut pairs = HashSet::new();
+    let buckets = by_title.values().chain(
+        by_shingle
+            .values()
+            .filter(|b| b.len() <= MAX_SHINGLE_TRACKS),
+    );
+    for bucket in buckets {
+        for (x, &a) in bucket.iter().enumerate() {
+            for &b in &bucket[x + 1..] {
+                pairs.insert((a, b));
+            }
+        }
+    }
+
+    let mut parents: Vec<usize> = (0..candidates.len()).collect();
+    let mut best = vec![0.0f32; candidates.len()];
+    for (a, b) in pairs {
+        let score = similarity(&candidates[a], &candidates[b]);
+        if score >= threshold {
+            best[a] = best[a].max(score);
+            best[b] = best[b].max(score);
+            let (root_a, root_b) = (find(&mut parents, a), find(&mut parents, b));
+            parents[root_a] = root_b;
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for n in 0..candidates.len() {
+        if best[n] > 0.0 {
+            let root = find(&mut parents, n);
+            groups.entry(root).or_insert_with(Vec::new).push(n);
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = groups
+        .into_iter()
+        .map(|(_, members)| DuplicateGroup {
+            tracks: members
+                .into_iter()
+                .map(|n| (candidates[n].index, best[n]))
+                .collect(),
+        })
+        .collect();
+    groups.sort_by(|a, b| a.tracks[0].0.cmp(&b.tracks[0].0));
+    groups
+}
+
+impl Database {
+    fn track(&self, (artist, album, track): TrackIndex) -> Option<&Track> {
+        self.entries
+            .get(artist)?
+            .albums
+            .get(album)?
+            .tracks
+            .get(track)
+    }
+
+    //Merge duplicates into the track at `keep`, removing them from their albums and recording
+    //where they came from as alternates. Setlist entries of the duplicates move to the kept
+    //track. Returns false if any of the tracks does not exist.
+    pub fn merge_duplicates(&mut self, keep: TrackIndex, others: &[TrackIndex]) -> bool {
+        let mut others: Vec<TrackIndex> = others.iter().cloned().filter(|&i| i != keep).collect();
+        others.sort();
+        others.dedup();
+        if self.track(keep).is_none() || others.iter().any(|&i| self.track(i).is_none()) {
+            return false;
+        }
+
+        let kept_ref = self.track_ref(keep).expect("kept track exists");
+        let moves: Vec<(TrackRef, TrackRef)> = others
+            .iter()
+            .filter_map(|&i| self.track_ref(i))
+            .map(|removed| (removed, kept_ref.clone()))
+            .collect();
+        let mut alternates = Vec::new();
+        let mut lyrics = None;
+        for &(i, j, k) in &others {
+            let artist = &self.entries[i];
+            let album = &artist.albums[j];
+            let track = &album.tracks[k];
+            alternates.push(Alternate {
+                artist: artist.name.clone(),
+                album: album.title.clone(),
+                title: track.title.clone(),
+            });
+            alternates.extend(track.alternates.iter().cloned());
+            if lyrics.is_none() && !track.lyrics.trim().is_empty() {
+                lyrics = Some(track.lyrics.clone());
+            }
+        }
+
+        {
+            let kept = &mut self.entries[keep.0].albums[keep.1].tracks[keep.2];
+            kept.alternates.extend(alternates);
+            if kept.lyrics.trim().is_empty() {
+                if let Some(lyrics) = lyrics {
+                    kept.lyrics = lyrics;
+                    kept.lyrics_source = None;
+                }
+            }
+        }
+
+        //Remove from the back so the remaining indices stay valid
+        for &(artist, album, track) in others.iter().rev() {
+            let album = &mut self.entries[artist].albums[album];
+            album.tracks.remove(track);
+            album.renumber();
+        }
+        self.retarget_setlists(&moves);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::{entries, track};
+
+    //A track on an album of its own for each (artist, title, lyrics)
+    fn database(tracks: &[(&str, &str, &str)]) -> Database {
+        let mut db = Database::empty();
+        for &(artist, title, lyrics) in tracks {
+            db.entries
+                .extend(entries(artist, "Album", vec![track(title, 1, lyrics)]));
+        }
+        db
+    }
+
+    #[test]
+    fn same_title_without_lyrics_is_not_a_duplicate() {
+        let db = database(&[("A", "Intro", ""), ("B", "Intro", "")]);
+        assert!(find_duplicates(&db, 0.75).is_empty());
+    }
+
+    #[test]
+    fn same_lyrics_under_another_title_is_a_duplicate() {
+        let lyrics = "one two three four five six seven eight";
+        let db = database(&[
+            ("A", "Song", lyrics),
+            ("B", "Song (Live)", lyrics),
+            ("C", "Other", ""),
+        ]);
+        let groups = find_duplicates(&db, 0.75);
+        assert_eq!(groups.len(), 1);
+        let tracks: Vec<TrackIndex> = groups[0].tracks.iter().map(|&(index, _)| index).collect();
+        assert_eq!(tracks.len(), 2);
+        assert!(tracks.contains(&(0, 0, 0)) && tracks.contains(&(1, 0, 0)));
+    }
+
+    #[test]
+    fn merged_duplicates_take_their_setlist_entries_along() {
+        let mut db = database(&[("A", "Song", "la la"), ("B", "Song (Live)", "la la")]);
+        let mut setlist = Setlist::new();
+        setlist
+            .entries
+            .push(SetlistEntry::new("B", "Album", "Song (Live)"));
+        setlist
+            .entries
+            .push(SetlistEntry::new("A", "Album", "Song"));
+        db.setlists.push(setlist);
+
+        assert!(db.merge_duplicates((0, 0, 0), &[(1, 0, 0)]));
+        let entries = &db.setlists[0].entries;
+        assert_eq!(entries[0], SetlistEntry::new("A", "Album", "Song"));
+        assert_eq!(entries[1], SetlistEntry::new("A", "Album", "Song"));
+        assert_eq!(db.setlist_track(&entries[0]), Some((0, 0, 0)));
+    }
+}
@@ -3,12 +3,24 @@ use std::error::Error;
 use std::fmt;
 use std::io;
 
+use rusqlite;
+
 #[derive(Debug)]
 pub enum DatabaseError {
     Io(io::Error),
+    Sqlite(rusqlite::Error),
+    Xml(String),
     InvalidAttribute((String, String)),
     InvalidTag(String),
     MissingAttribute((String, String)),
+    UnsupportedVersion(u32),
+    PassphraseNeeded,
+    WrongPassphrase,
+    Encryption(String),
+    ChangedOnDisk,
+    LyricsNotLoaded,
+    Cancelled,
+    History(String),
     Empty,
 }
 
@@ -17,8 +29,18 @@ impl Error for DatabaseError {
         use self::DatabaseError;
         match self {
             DatabaseError::Io(e) => e.description(),
+            DatabaseError::Sqlite(e) => e.description(),
+            DatabaseError::Xml(_) => "Malformed XML",
             DatabaseError::InvalidAttribute(_) => "Invalid attribute",
             DatabaseError::InvalidTag(_) => "Invalid tag",
+            DatabaseError::UnsupportedVersion(_) => "Unsupported database version",
+            DatabaseError::PassphraseNeeded => "Database file is encrypted",
+            DatabaseError::WrongPassphrase => "Wrong passphrase",
+            DatabaseError::Encryption(_) => "Encryption failed",
+            DatabaseError::ChangedOnDisk => "Database file changed on disk",
+            DatabaseError::LyricsNotLoaded => "Lyrics were not loaded",
+            DatabaseError::Cancelled => "Loading was cancelled",
+            DatabaseError::History(_) => "History could not be kept",
             DatabaseError::Empty => "Database file is empty",
             DatabaseError::MissingAttribute(_) => "Missing attribute",
         }
@@ -30,10 +52,36 @@ impl fmt::Display for DatabaseError {
         use self::DatabaseError;
         match self {
             DatabaseError::Io(e) => write!(f, "io error: {}", e.description()),
+            DatabaseError::Sqlite(e) => write!(f, "sqlite error: {}", e),
+            DatabaseError::Xml(e) => write!(f, "xml error: {}", e),
             DatabaseError::InvalidAttribute((a, tag)) => {
                 write!(f, "Invalid attribute {} in tag {}", a, tag)
             }
             DatabaseError::InvalidTag(tag) => write!(f, "Invalid tag {}", tag),
+            DatabaseError::UnsupportedVersion(version) => write!(
+                f,
+                "Database version {} is newer than this program supports",
+                version
+            ),
+            DatabaseError::PassphraseNeeded => {
+                write!(f, "Database file is encrypted, a passphrase is needed")
+            }
+            DatabaseError::WrongPassphrase => {
+                write!(f, "Wrong passphrase, or the file has been tampered with")
+            }
+            DatabaseError::Encryption(e) => write!(f, "encryption error: {}", e),
+            DatabaseError::ChangedOnDisk => write!(
+                f,
+                "Database file changed on disk, lyrics that weren't read yet are lost"
+            ),
+            DatabaseError::LyricsNotLoaded => {
+                write!(
+                    f,
+                    "Lyrics still in the storage were not loaded before writing"
+                )
+            }
+            DatabaseError::Cancelled => write!(f, "Loading was cancelled"),
+            DatabaseError::History(e) => write!(f, "history error: {}", e),
             DatabaseError::Empty => write!(f, "Database file is empty"),
             DatabaseError::MissingAttribute((a, tag)) => {
                 write!(f, "Missing attribute {} in {}", a, tag)
@@ -47,3 +95,9 @@ impl convert::From<io::Error> for DatabaseError {
         DatabaseError::Io(err)
     }
 }
+
+impl convert::From<rusqlite::Error> for DatabaseError {
+    fn from(err: rusqlite::Error) -> DatabaseError {
+        DatabaseError::Sqlite(err)
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::process::Command;
+
+use super::metadata::Artist;
+use super::storage::{self, stream, TrackRef};
+use super::{Database, DatabaseError};
+
+//Name of the database inside the history repository
+const FILE_NAME: &str = "database.xml";
+
+//A saved version of a track's lyrics
+#[derive(Debug, Clone)]
+pub struct Revision {
+    pub id: String,
+    pub author: String,
+    pub date: String,
+    pub message: String,
+    pub lyrics: String,
+}
+
+//A git repository next to the database file, holding a commit of the database as XML for
+//every save
+pub struct History {
+    dir: PathBuf,
+}
+
+impl History {
+    fn dir_for(db_path: &str) -> PathBuf {
+        PathBuf::from(format!("{}.history", db_path))
+    }
+
+    //The history of the database at `db_path`, None if none is kept
+    pub fn open(db_path: &str) -> Option<History> {
+        let dir = History::dir_for(db_path);
+        if dir.join(".git").is_dir() {
+            Some(History { dir })
+        } else {
+            None
+        }
+    }
+
+    pub fn init(db_path: &str) -> Result<History, DatabaseError> {
+        let dir = History::dir_for(db_path);
+        fs::create_dir_all(&dir)?;
+        let history = History { dir };
+        history.git(&["init", "--quiet"])?;
+        Ok(history)
+    }
+
+    fn git(&self, args: &[&str]) -> Result<String, DatabaseError> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.dir)
+            .args(args)
+            .output()?;
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(DatabaseError::History(error.trim().to_owned()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    //Commit `entries`. Returns false if nothing changed since the last commit.
+    pub fn commit(&self, entries: &[Artist], message: &str) -> Result<bool, DatabaseError> {
+        //Written in the canonical layout, so the commit only holds the tracks that changed. The
+        //history is of the lyrics, setlists are left out.
+        stream::write(File::create(self.dir.join(FILE_NAME))?, entries, &[])?;
+
+        self.git(&["add", FILE_NAME])?;
+        if self.git(&["status", "--porcelain"])?.trim().is_empty() {
+            return Ok(false);
+        }
+
+        //Commits are made as whoever git is set up for, or the logged in user otherwise
+        let mut args = Vec::new();
+        let (name, email);
+        if self.git(&["config", "user.name"]).is_err() {
+            let user = env::var("USER").unwrap_or_else(|_| "lyrics".to_owned());
+            name = format!("user.name={}", user);
+            email = format!("user.email={}@localhost", user);
+            args.extend_from_slice(&["-c", &name, "-c", &email]);
+        }
+        args.extend_from_slice(&["commit", "--quiet", "-m", message]);
+        self.git(&args)?;
+        Ok(true)
+    }
+
+    //Commit `entries` with a message naming the tracks that changed since the last commit.
+    //Returns false if nothing changed.
+    pub fn commit_changes(&self, entries: &[Artist]) -> Result<bool, DatabaseError> {
+        let message = match self.git(&["show", &format!("HEAD:{}", FILE_NAME)]) {
+            Ok(xml) => {
+                let (committed, _) = stream::read(xml.as_bytes(), 0, |_| true)?;
+                describe_changes(&committed, entries)
+            }
+            //Nothing was committed yet
+            Err(_) => "Start history".to_owned(),
+        };
+        self.commit(entries, &message)
+    }
+
+    //Every commit that changed the lyrics of `track`, newest first. Each commit is read whole,
+    //so this is best left to another thread. `progress` is given how many of how many commits
+    //were read and stops the reading with `DatabaseError::Cancelled` by returning false.
+    pub fn track_history(
+        &self,
+        track: &TrackRef,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Vec<Revision>, DatabaseError> {
+        let log = self.git(&["log", "--format=%H%x1f%an%x1f%ai%x1f%s", "--", FILE_NAME])?;
+        let count = log.lines().count();
+
+        let mut revisions: Vec<Revision> = Vec::new();
+        for (read, line) in log.lines().rev().enumerate() {
+            if !progress(read, count) {
+                return Err(DatabaseError::Cancelled);
+            }
+            let fields: Vec<&str> = line.split('\x1f').collect();
+            if fields.len() != 4 {
+                continue;
+            }
+            let xml = self.git(&["show", &format!("{}:{}", fields[0], FILE_NAME)])?;
+            let (mut entries, _) = stream::read(xml.as_bytes(), 0, |_| true)?;
+            let lyrics = match track.find_mut(&mut entries) {
+                Some(found) => found.lyrics.clone(),
+                None => continue,
+            };
+            if revisions.last().map_or(false, |last| last.lyrics == lyrics) {
+                continue;
+            }
+            revisions.push(Revision {
+                id: fields[0].to_owned(),
+                author: fields[1].to_owned(),
+                date: fields[2].to_owned(),
+                message: fields[3].to_owned(),
+                lyrics,
+            });
+        }
+        revisions.reverse();
+        Ok(revisions)
+    }
+}
+
+//Tracks by artist, album and title, with their lyrics
+fn lyrics_by_track(entries: &[Artist]) -> HashMap<(&str, &str, &str), &str> {
+    let mut tracks = HashMap::new();
+    for artist in entries {
+        for album in &artist.albums {
+            for track in &album.tracks {
+                let key = (
+                    artist.name.as_str(),
+                    album.title.as_str(),
+                    track.title.as_str(),
+                );
+                tracks.insert(key, track.lyrics.as_str());
+            }
+        }
+    }
+    tracks
+}
+
+//A commit message for going from `old` to `new`: the one track that changed, or how many
+//did, followed by a line for each of them
+fn describe_changes(old: &[Artist], new: &[Artist]) -> String {
+    let old_tracks = lyrics_by_track(old);
+    let new_tracks = lyrics_by_track(new);
+    let mut lines = Vec::new();
+    for artist in new {
+        for album in &artist.albums {
+            for track in &album.tracks {
+                let key = (
+                    artist.name.as_str(),
+                    album.title.as_str(),
+                    track.title.as_str(),
+                );
+                let verb = match old_tracks.get(&key) {
+                    Some(&lyrics) if lyrics == track.lyrics => continue,
+                    Some(_) => "Update",
+                    None => "Add",
+                };
+                lines.push(format!("{} {} / {} / {}", verb, key.0, key.1, key.2));
+            }
+        }
+    }
+    for artist in old {
+        for album in &artist.albums {
+            for track in &album.tracks {
+                let key = (
+                    artist.name.as_str(),
+                    album.title.as_str(),
+                    track.title.as_str(),
+                );
+                if !new_tracks.contains_key(&key) {
+                    lines.push(format!("Remove {} / {} / {}", key.0, key.1, key.2));
+                }
+            }
+        }
+    }
+
+    match lines.len() {
+        //Only the order or the numbers of tracks changed
+        0 => "Rearrange tracks".to_owned(),
+        1 => lines.remove(0),
+        n => format!("Change {} tracks\n\n{}", n, lines.join("\n")),
+    }
+}
+
+impl Database {
+    pub fn has_history(&self) -> bool {
+        History::open(&self.file_path).is_some()
+    }
+
+    //Start keeping a history for the database file, beginning with what it holds now
+    pub fn enable_history(&self) -> Result<(), DatabaseError> {
+        if self.file_path.is_empty() {
+            return Err(DatabaseError::History(
+                "the database has to be saved first".to_owned(),
+            ));
+        }
+        //The history is plain text, it would give the lyrics away
+        if self.passphrase.is_some() {
+            return Err(DatabaseError::History(
+                "encrypted databases can't keep a history".to_owned(),
+            ));
+        }
+        if self.has_history() {
+            return Ok(());
+        }
+        let entries = storage::open(&self.file_path, None)?.load(&mut |_| true)?;
+        History::init(&self.file_path)?.commit(&entries, "Start history")?;
+        Ok(())
+    }
+
+    //The history of the database and the track at (artist, album, track), for reading its
+    //saved versions with `History::track_history`. None if no history is kept or there is no
+    //such track.
+    pub fn track_history(&self, index: (usize, usize, usize)) -> Option<(History, TrackRef)> {
+        let track = self.track_ref(index)?;
+        Some((History::open(&self.file_path)?, track))
+    }
+
+    //Put an earlier version of the lyrics back into the track at (artist, album, track). It
+    //stays unsaved until the database is saved. Returns false if there is no such track.
+    pub fn restore_lyrics(
+        &mut self,
+        (artist, album, track): (usize, usize, usize),
+        revision: &Revision,
+    ) -> bool {
+        let track = self
+            .entries
+            .get_mut(artist)
+            .and_then(|artist| artist.albums.get_mut(album))
+            .and_then(|album| album.tracks.get_mut(track));
+        match track {
+            Some(track) => {
+                track.lyrics = revision.lyrics.clone();
+                track.lyrics_source = None;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::*;
+
+    fn last_message(history: &History) -> String {
+        history
+            .git(&["log", "-1", "--format=%B"])
+            .unwrap()
+            .trim()
+            .to_owned()
+    }
+
+    fn database_with_history(name: &str) -> (Database, History) {
+        let path = temp_path(name);
+        let mut db = Database::empty();
+        db.entries = entries(
+            "Artist",
+            "Album",
+            vec![track("One", 1, "red sky"), track("Two", 2, "blue sea")],
+        );
+        db.save(&path).unwrap();
+        db.enable_history().unwrap();
+        let history = History::open(&path).unwrap();
+        (db, history)
+    }
+
+    #[test]
+    fn commits_name_the_changed_tracks() {
+        let (mut db, history) = database_with_history("messages.xml");
+        let path = db.file_path().to_owned();
+        assert_eq!(last_message(&history), "Start history");
+
+        db.entries[0].albums[0].tracks[0].lyrics = "green grass".to_owned();
+        db.save(&path).unwrap();
+        assert_eq!(last_message(&history), "Update Artist / Album / One");
+
+        db.entries[0].albums[0].tracks[1].title = "Three".to_owned();
+        db.save(&path).unwrap();
+        assert_eq!(
+            last_message(&history),
+            "Change 2 tracks\n\nAdd Artist / Album / Three\nRemove Artist / Album / Two"
+        );
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn track_history_reports_progress_and_stops() {
+        let (mut db, history) = database_with_history("revisions.xml");
+        let path = db.file_path().to_owned();
+        db.entries[0].albums[0].tracks[0].lyrics = "green grass".to_owned();
+        db.save(&path).unwrap();
+        db.entries[0].albums[0].tracks[1].lyrics = "grey rain".to_owned();
+        db.save(&path).unwrap();
+
+        let (history_of_one, one) = db.track_history((0, 0, 0)).unwrap();
+        let mut reports = Vec::new();
+        let revisions = history_of_one
+            .track_history(&one, &mut |read, count| {
+                reports.push((read, count));
+                true
+            })
+            .unwrap();
+        assert_eq!(reports, [(0, 3), (1, 3), (2, 3)]);
+        let lyrics: Vec<&str> = revisions.iter().map(|r| r.lyrics.as_str()).collect();
+        assert_eq!(lyrics, ["green grass", "red sky"]);
+
+        match history.track_history(&one, &mut |_, _| false) {
+            Err(DatabaseError::Cancelled) => (),
+            other => panic!("expected the reading to stop, got {:?}", other),
+        }
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn encrypted_saves_are_not_committed() {
+        let (mut db, history) = database_with_history("encrypted.xml");
+        let path = db.file_path().to_owned();
+        db.set_passphrase(Some("secret")).unwrap();
+        db.entries[0].albums[0].tracks[0].lyrics = "green grass".to_owned();
+        db.save(&path).unwrap();
+        assert_eq!(last_message(&history), "Start history");
+        let committed = fs::read_to_string(history.dir.join(FILE_NAME)).unwrap();
+        assert!(!committed.contains("green grass"));
+        remove_dir(&path);
+    }
+}
@@ -0,0 +1,560 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use super::metadata::*;
+use super::watch::Snapshot;
+use super::{Database, DatabaseError};
+
+//How to settle a track whose lyrics differ between the two databases
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergePolicy {
+    KeepMine,
+    KeepTheirs,
+    //Keep our track and add theirs after it as an alternate version
+    KeepBoth,
+}
+
+//A track present in both databases with different lyrics
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub artist: String,
+    pub album: String,
+    pub mine: Track,
+    pub theirs: Track,
+}
+
+//Names are matched case-insensitively and ignoring surrounding and repeated whitespace
+pub fn normalize(name: &str) -> String {
+    name.split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn same_lyrics(a: &str, b: &str) -> bool {
+    a.lines()
+        .map(str::trim_right)
+        .eq(b.lines().map(str::trim_right))
+}
+
+//Normalized artist, album and track names
+type TrackKey = (String, String, String);
+
+fn index_tracks(entries: &[Artist]) -> HashMap<TrackKey, &Track> {
+    let mut tracks = HashMap::new();
+    for artist in entries {
+        for album in &artist.albums {
+            for track in &album.tracks {
+                let key = (
+                    normalize(&artist.name),
+                    normalize(&album.title),
+                    normalize(&track.title),
+                );
+                tracks.insert(key, track);
+            }
+        }
+    }
+    tracks
+}
+
+fn same_track(a: &Track, b: &Track) -> bool {
+    a.track == b.track && same_lyrics(&a.lyrics, &b.lyrics) && a.alternates == b.alternates
+}
+
+fn find_artist<'a>(entries: &'a mut Vec<Artist>, name: &str) -> &'a mut Artist {
+    match entries
+        .iter()
+        .position(|a| normalize(&a.name) == normalize(name))
+    {
+        Some(i) => &mut entries[i],
+        None => {
+            let mut artist = Artist::new();
+            artist.name = name.to_owned();
+            entries.push(artist);
+            entries.last_mut().unwrap()
+        }
+    }
+}
+
+fn find_album<'a>(artist: &'a mut Artist, title: &str) -> &'a mut Album {
+    match artist
+        .albums
+        .iter()
+        .position(|a| normalize(&a.title) == normalize(title))
+    {
+        Some(i) => &mut artist.albums[i],
+        None => {
+            let mut album = Album::new();
+            album.title = title.to_owned();
+            artist.albums.push(album);
+            artist.albums.last_mut().unwrap()
+        }
+    }
+}
+
+//Title for their version of `title` kept next to ours, one no other track of the album has
+fn alternate_title(album: &Album, title: &str) -> String {
+    let taken = |candidate: &str| {
+        album
+            .tracks
+            .iter()
+            .any(|track| normalize(&track.title) == normalize(candidate))
+    };
+    let mut candidate = format!("{} (alternate)", title);
+    let mut n = 2;
+    while taken(&candidate) {
+        candidate = format!("{} (alternate {})", title, n);
+        n += 1;
+    }
+    candidate
+}
+
+//A name for a setlist that none of `setlists` has
+fn alternate_name(setlists: &[Setlist], name: &str) -> String {
+    let mut candidate = format!("{} (alternate)", name);
+    let mut n = 2;
+    while setlists.iter().any(|setlist| setlist.name == candidate) {
+        candidate = format!("{} (alternate {})", name, n);
+        n += 1;
+    }
+    candidate
+}
+
+//Add `setlist` unless the same one is there already. One of the same name with other entries
+//stays, `setlist` is added next to it under another name.
+fn add_setlist(setlists: &mut Vec<Setlist>, mut setlist: Setlist) {
+    let same = setlists
+        .iter()
+        .find(|s| s.name == setlist.name)
+        .map(|s| *s == setlist);
+    match same {
+        Some(true) => return,
+        Some(false) => setlist.name = alternate_name(setlists, &setlist.name),
+        None => (),
+    }
+    setlists.push(setlist);
+}
+
+//Point setlist entries at the names their tracks have in `entries`. Names are matched like the
+//merge matches them, the setlists may come from a database that spells them differently.
+fn rename_entries(setlists: &mut [Setlist], entries: &[Artist]) {
+    let mut names = HashMap::new();
+    for artist in entries {
+        for album in &artist.albums {
+            for track in &album.tracks {
+                let key = (
+                    normalize(&artist.name),
+                    normalize(&album.title),
+                    normalize(&track.title),
+                );
+                names.insert(key, (&artist.name, &album.title, &track.title));
+            }
+        }
+    }
+    for entry in setlists.iter_mut().flat_map(|s| s.entries.iter_mut()) {
+        let key = (
+            normalize(&entry.artist),
+            normalize(&entry.album),
+            normalize(&entry.title),
+        );
+        if let Some(&(artist, album, title)) = names.get(&key) {
+            entry.artist = artist.clone();
+            entry.album = album.clone();
+            entry.title = title.clone();
+        }
+    }
+}
+
+//Setlists merged like the tracks, matched by name. A setlist changed on both sides keeps our
+//version and theirs is added next to it under another name.
+fn merge3_setlists(base: &[Setlist], mine: Vec<Setlist>, theirs: Vec<Setlist>) -> Vec<Setlist> {
+    let mut mine: Vec<Option<Setlist>> = mine.into_iter().map(Some).collect();
+    let mut merged = Vec::new();
+    let mut conflicting = Vec::new();
+    for setlist in theirs {
+        let original = base.iter().find(|s| s.name == setlist.name);
+        let same_name = mine
+            .iter()
+            .position(|s| s.as_ref().map_or(false, |s| s.name == setlist.name));
+        let kept = match (original, same_name.and_then(|i| mine[i].take())) {
+            (_, Some(m)) if m == setlist => Some(m),
+            (Some(b), Some(m)) if m == *b => Some(setlist),
+            (Some(b), Some(m)) if setlist == *b => Some(m),
+            (_, Some(m)) => {
+                conflicting.push(setlist);
+                Some(m)
+            }
+            //Deleted here, unless they changed it in the meantime
+            (Some(b), None) if setlist == *b => None,
+            (_, None) => Some(setlist),
+        };
+        merged.extend(kept);
+    }
+    //What they don't have was either added here or deleted by them, unless it was changed here
+    for setlist in mine.into_iter().filter_map(|s| s) {
+        if !base.contains(&setlist) {
+            merged.push(setlist);
+        }
+    }
+    for setlist in conflicting {
+        add_setlist(&mut merged, setlist);
+    }
+    merged
+}
+
+impl Database {
+    //Merge another database into this one. Artists, albums and tracks are matched by their
+    //normalized names, anything missing here is copied over. Tracks whose lyrics differ are
+    //settled with `policy` and returned so they can be reviewed. Their setlists are added too,
+    //under another name if one of ours has the name. The lyrics of both are read first.
+    pub fn merge(
+        &mut self,
+        mut other: Database,
+        policy: MergePolicy,
+    ) -> Result<Vec<Conflict>, DatabaseError> {
+        self.load_all_lyrics()?;
+        other.load_all_lyrics()?;
+        let mut conflicts = Vec::new();
+
+        for their_artist in other.entries {
+            let artist_name = normalize(&their_artist.name);
+            let artist = match self
+                .entries
+                .iter_mut()
+                .position(|a| normalize(&a.name) == artist_name)
+            {
+                Some(i) => &mut self.entries[i],
+                None => {
+                    self.entries.push(their_artist);
+                    continue;
+                }
+            };
+
+            for their_album in their_artist.albums {
+                let album_title = normalize(&their_album.title);
+                let album = match artist
+                    .albums
+                    .iter_mut()
+                    .position(|a| normalize(&a.title) == album_title)
+                {
+                    Some(i) => &mut artist.albums[i],
+                    None => {
+                        artist.albums.push(their_album);
+                        continue;
+                    }
+                };
+                album.track_count = album.track_count.max(their_album.track_count);
+
+                for their_track in their_album.tracks {
+                    let track_title = normalize(&their_track.title);
+                    let track = match album
+                        .tracks
+                        .iter_mut()
+                        .find(|t| normalize(&t.title) == track_title)
+                    {
+                        Some(track) => track,
+                        None => {
+                            album.insert_sorted(their_track);
+                            continue;
+                        }
+                    };
+
+                    if same_lyrics(&track.lyrics, &their_track.lyrics)
+                        || their_track.lyrics.trim().is_empty()
+                    {
+                        continue;
+                    }
+                    if track.lyrics.trim().is_empty() {
+                        track.lyrics = their_track.lyrics;
+                        track.lyrics_source = None;
+                        continue;
+                    }
+
+                    conflicts.push(Conflict {
+                        artist: artist.name.clone(),
+                        album: album.title.clone(),
+                        mine: track.clone(),
+                        theirs: their_track,
+                    });
+                }
+            }
+        }
+
+        if policy != MergePolicy::KeepMine {
+            for conflict in &conflicts {
+                self.resolve(conflict, policy);
+            }
+        }
+
+        let mut setlists = other.setlists;
+        rename_entries(&mut setlists, &self.entries);
+        for setlist in setlists {
+            add_setlist(&mut self.setlists, setlist);
+        }
+        Ok(conflicts)
+    }
+
+    //Three-way merge for when the file was changed by someone else while this database has
+    //unsaved edits. `base` is what the file held before the edits, `theirs` what it holds now.
+    //Changes made on one side only are taken over, tracks changed on both sides keep our version
+    //and are returned as conflicts. All lyrics must have been loaded.
+    pub fn merge3(&mut self, base: &Snapshot, theirs: Snapshot) -> Vec<Conflict> {
+        let mut conflicts = Vec::new();
+        let mut merged = theirs.entries;
+        let base_setlists = &base.setlists;
+        let base = &base.entries;
+        {
+            let original = index_tracks(base);
+            let mine = index_tracks(&self.entries);
+            let mut seen = HashSet::new();
+
+            for artist in &mut merged {
+                let artist_name = normalize(&artist.name);
+                for album in &mut artist.albums {
+                    let album_title = normalize(&album.title);
+                    for track in mem::replace(&mut album.tracks, Vec::new()) {
+                        let key = (
+                            artist_name.clone(),
+                            album_title.clone(),
+                            normalize(&track.title),
+                        );
+                        let kept = match (original.get(&key), mine.get(&key)) {
+                            (_, Some(m)) if same_track(m, &track) => Some(track),
+                            (Some(b), Some(m)) if same_track(m, b) => Some(track),
+                            (Some(b), Some(m)) if same_track(&track, b) => Some((*m).clone()),
+                            (_, Some(m)) => {
+                                conflicts.push(Conflict {
+                                    artist: artist.name.clone(),
+                                    album: album.title.clone(),
+                                    mine: (*m).clone(),
+                                    theirs: track,
+                                });
+                                Some((*m).clone())
+                            }
+                            //Deleted here, unless they changed it in the meantime
+                            (Some(b), None) if same_track(&track, b) => None,
+                            (_, None) => Some(track),
+                        };
+                        seen.insert(key);
+                        if let Some(track) = kept {
+                            album.tracks.push(track);
+                        }
+                    }
+                }
+            }
+
+            //What they don't have was either added here or deleted by them
+            for artist in &self.entries {
+                let artist_name = normalize(&artist.name);
+                let new_artist = !base.iter().any(|a| normalize(&a.name) == artist_name);
+                if new_artist {
+                    find_artist(&mut merged, &artist.name);
+                }
+                for album in &artist.albums {
+                    let album_title = normalize(&album.title);
+                    let new_album = new_artist
+                        || !base
+                            .iter()
+                            .filter(|a| normalize(&a.name) == artist_name)
+                            .flat_map(|a| a.albums.iter())
+                            .any(|a| normalize(&a.title) == album_title);
+                    let added: Vec<&Track> = album
+                        .tracks
+                        .iter()
+                        .filter(|track| {
+                            let key = (
+                                artist_name.clone(),
+                                album_title.clone(),
+                                normalize(&track.title),
+                            );
+                            !seen.contains(&key)
+                                && original.get(&key).map_or(true, |b| !same_track(track, b))
+                        })
+                        .collect();
+                    if !new_album && added.is_empty() {
+                        continue;
+                    }
+
+                    let target = find_album(find_artist(&mut merged, &artist.name), &album.title);
+                    target.track_count = target.track_count.max(album.track_count);
+                    for track in added {
+                        target.insert_sorted(track.clone());
+                    }
+                }
+            }
+        }
+
+        self.entries = merged;
+        self.cache.clear();
+        let mine = mem::replace(&mut self.setlists, Vec::new());
+        self.setlists = merge3_setlists(base_setlists, mine, theirs.setlists);
+        rename_entries(&mut self.setlists, &self.entries);
+        conflicts
+    }
+
+    //Settle a single conflict returned by `merge`. Returns false if the track can no longer be
+    //found.
+    pub fn resolve(&mut self, conflict: &Conflict, policy: MergePolicy) -> bool {
+        let album = self
+            .entries
+            .iter_mut()
+            .filter(|a| a.name == conflict.artist)
+            .flat_map(|a| a.albums.iter_mut())
+            .find(|a| a.title == conflict.album);
+        let album = match album {
+            Some(album) => album,
+            None => return false,
+        };
+        let index = match album
+            .tracks
+            .iter()
+            .position(|t| t.title == conflict.mine.title)
+        {
+            Some(index) => index,
+            None => return false,
+        };
+
+        match policy {
+            MergePolicy::KeepMine => (),
+            MergePolicy::KeepTheirs => {
+                let track = &mut album.tracks[index];
+                track.lyrics = conflict.theirs.lyrics.clone();
+                track.lyrics_source = None;
+            }
+            MergePolicy::KeepBoth => {
+                let mut alternate = conflict.theirs.clone();
+                alternate.title = alternate_title(album, &conflict.theirs.title);
+                alternate.track = album.tracks[index].track;
+                album.tracks.insert(index + 1, alternate);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::*;
+
+    fn database(tracks: Vec<Track>) -> Database {
+        let mut db = Database::empty();
+        db.entries = entries("Artist", "Album", tracks);
+        db
+    }
+
+    #[test]
+    fn keep_both_adds_their_version_under_its_own_title() {
+        let mut mine = database(vec![track("Song", 1, "mine"), track("Other", 2, "")]);
+        let theirs = database(vec![track("Song", 1, "theirs")]);
+        let conflicts = mine.merge(theirs, MergePolicy::KeepBoth).unwrap();
+        assert_eq!(conflicts.len(), 1);
+
+        let tracks = &mine.entries[0].albums[0].tracks;
+        let titles: Vec<&str> = tracks.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(titles, ["Song", "Song (alternate)", "Other"]);
+        assert_eq!(tracks[0].lyrics, "mine");
+        assert_eq!(tracks[1].lyrics, "theirs");
+
+        //Resolving it again doesn't reuse the title
+        assert!(mine.resolve(&conflicts[0], MergePolicy::KeepBoth));
+        let tracks = &mine.entries[0].albums[0].tracks;
+        assert_eq!(tracks[1].title, "Song (alternate 2)");
+    }
+
+    fn names(db: &Database) -> Vec<&str> {
+        db.setlists.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    #[test]
+    fn merge_reads_the_lyrics_of_both() {
+        let path = temp_path("mine.xml");
+        let their_path = temp_path("theirs.xml");
+        let mut mine = database(vec![track("Song", 1, "mine")]);
+        mine.save(&path).unwrap();
+        let mut theirs = database(vec![track("Song", 1, "theirs"), track("New", 2, "new")]);
+        theirs.save(&their_path).unwrap();
+
+        let mut mine = Database::from_index(&path).unwrap();
+        let theirs = Database::from_index(&their_path).unwrap();
+        let conflicts = mine.merge(theirs, MergePolicy::KeepTheirs).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        let tracks = &mine.entries[0].albums[0].tracks;
+        assert_eq!(tracks[0].lyrics, "theirs");
+        assert_eq!(tracks[1].lyrics, "new");
+        remove_dir(&path);
+        remove_dir(&their_path);
+    }
+
+    #[test]
+    fn merge_adds_their_setlists_with_our_names() {
+        let mut mine = database(vec![track("Song", 1, "")]);
+        mine.setlists.push(setlist("Gig", &["Song"]));
+        let mut theirs = database(vec![track("Song", 1, "")]);
+        theirs.setlists.push(setlist("Gig", &["Song"]));
+        theirs.setlists.push(setlist("Other gig", &["SONG"]));
+        theirs.setlists[1].entries[0].artist = "artist ".to_owned();
+        theirs.setlists.push(setlist("Gig", &["New", "Song"]));
+        theirs.entries[0].albums[0].tracks.push(track("New", 2, ""));
+        mine.merge(theirs, MergePolicy::KeepMine).unwrap();
+
+        assert_eq!(names(&mine), ["Gig", "Other gig", "Gig (alternate)"]);
+        assert_eq!(mine.setlists[1], setlist("Other gig", &["Song"]));
+        assert_eq!(
+            mine.setlists[2],
+            setlist("Gig (alternate)", &["New", "Song"])
+        );
+    }
+
+    //Each setlist is changed on one side only, except the last which both changed
+    #[test]
+    fn merge3_takes_setlist_changes_from_both_sides() {
+        let mut base = database(vec![track("One", 1, ""), track("Two", 2, "")]);
+        base.setlists = vec![
+            setlist("Changed here", &["One"]),
+            setlist("Changed there", &["One"]),
+            setlist("Deleted here", &["One"]),
+            setlist("Deleted there", &["One"]),
+            setlist("Both", &["One"]),
+        ];
+        let base = base.snapshot();
+
+        let mut mine = database(vec![track("One", 1, ""), track("Two", 2, "")]);
+        mine.setlists = vec![
+            setlist("Changed here", &["One", "Two"]),
+            setlist("Changed there", &["One"]),
+            setlist("Deleted there", &["One"]),
+            setlist("Both", &["Two"]),
+            setlist("Added here", &["Two"]),
+        ];
+        let mut theirs = base.clone();
+        theirs.setlists = vec![
+            setlist("Changed here", &["One"]),
+            setlist("Changed there", &["Two", "One"]),
+            setlist("Deleted here", &["One"]),
+            setlist("Both", &["One", "One"]),
+            setlist("Added there", &["One"]),
+        ];
+        let conflicts = mine.merge3(&base, theirs);
+        assert!(conflicts.is_empty());
+
+        assert_eq!(
+            names(&mine),
+            [
+                "Changed here",
+                "Changed there",
+                "Both",
+                "Added there",
+                "Added here",
+                "Both (alternate)"
+            ]
+        );
+        assert_eq!(mine.setlists[0], setlist("Changed here", &["One", "Two"]));
+        assert_eq!(mine.setlists[1], setlist("Changed there", &["Two", "One"]));
+        assert_eq!(mine.setlists[2], setlist("Both", &["Two"]));
+        assert_eq!(
+            mine.setlists[5],
+            setlist("Both (alternate)", &["One", "One"])
+        );
+    }
+}
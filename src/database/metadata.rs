@@ -1,12 +1,12 @@
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Artist {
     pub name: String,
     pub albums: Vec<Album>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Album {
     pub title: String,
     pub tracks: Vec<Track>,
@@ -18,6 +18,45 @@ pub struct Track {
     pub title: String,
     pub lyrics: String,
     pub track: u8,
+    pub alternates: Vec<Alternate>,
+    //Set while the lyrics are still in the storage, identifies them for `Storage::load_lyrics`
+    pub lyrics_source: Option<u64>,
+}
+
+//The track count of an album and the number of each of its tracks, kept to put them back after
+//the tracks were renumbered
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackNumbers {
+    pub track_count: u8,
+    pub tracks: Vec<u8>,
+}
+
+//Where a duplicate of a track was found before it was merged into it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alternate {
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+}
+
+//Tracks from anywhere in the database in the order they are played
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setlist {
+    pub name: String,
+    pub entries: Vec<SetlistEntry>,
+}
+
+//A track of a setlist, found by the names of its artist and album and its title, with how it
+//is played this time. Empty notes and key and a missing tempo are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetlistEntry {
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+    pub notes: String,
+    pub key: String,
+    //Beats per minute
+    pub tempo: Option<u16>,
 }
 
 impl Artist {
@@ -37,6 +76,45 @@ impl Album {
             tracks: Vec::new(),
         }
     }
+
+    //Number the tracks by their position, starting at 1
+    pub fn renumber(&mut self) {
+        for (i, track) in self.tracks.iter_mut().enumerate() {
+            track.track = (i + 1) as u8;
+        }
+        self.track_count = self.tracks.len() as u8;
+    }
+
+    pub fn numbers(&self) -> TrackNumbers {
+        TrackNumbers {
+            track_count: self.track_count,
+            tracks: self.tracks.iter().map(|track| track.track).collect(),
+        }
+    }
+
+    //Put back numbers taken with `numbers`. Returns false if the album has a different number of
+    //tracks by now.
+    pub fn set_numbers(&mut self, numbers: &TrackNumbers) -> bool {
+        if numbers.tracks.len() != self.tracks.len() {
+            return false;
+        }
+        for (track, &number) in self.tracks.iter_mut().zip(&numbers.tracks) {
+            track.track = number;
+        }
+        self.track_count = numbers.track_count;
+        true
+    }
+
+    //Add a track after those with a lower or equal number, returns where it went
+    pub fn insert_sorted(&mut self, track: Track) -> usize {
+        let index = self
+            .tracks
+            .iter()
+            .position(|t| t.track > track.track)
+            .unwrap_or_else(|| self.tracks.len());
+        self.tracks.insert(index, track);
+        index
+    }
 }
 
 impl Track {
@@ -45,6 +123,30 @@ impl Track {
             track: 0,
             lyrics: String::new(),
             title: String::new(),
+            alternates: Vec::new(),
+            lyrics_source: None,
+        }
+    }
+}
+
+impl Setlist {
+    pub fn new() -> Setlist {
+        Setlist {
+            name: String::new(),
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl SetlistEntry {
+    pub fn new(artist: &str, album: &str, title: &str) -> SetlistEntry {
+        SetlistEntry {
+            artist: artist.to_owned(),
+            album: album.to_owned(),
+            title: title.to_owned(),
+            notes: String::new(),
+            key: String::new(),
+            tempo: None,
         }
     }
 }
@@ -1,157 +1,910 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-use std::path::Path;
-
-use treexml::{Document, Element};
-
+pub mod cache;
+pub mod diff;
+pub mod duplicates;
 pub mod error;
+pub mod history;
+pub mod merge;
 pub mod metadata;
+pub mod recovery;
+pub mod setlists;
+pub mod storage;
+#[cfg(test)]
+pub mod testing;
+pub mod watch;
+use std::collections::HashSet;
+
+use self::cache::LyricsCache;
 pub use self::error::DatabaseError;
+use self::history::History;
+use self::merge::normalize;
+pub use self::merge::{Conflict, MergePolicy};
 use self::metadata::*;
+use self::storage::{lyrics_match, words, Progress, Storage, TrackRef};
+use self::watch::FileState;
+//How many bytes of lyrics fetched on demand are kept around
+const LYRICS_CACHE_BYTES: usize = 4 * 1024 * 1024;
+
 pub struct Database {
     pub entries: Vec<Artist>,
+    pub setlists: Vec<Setlist>,
     file_path: String,
+    storage: Option<Box<dyn Storage>>,
+    cache: LyricsCache,
+    //Encrypts the file when saved elsewhere
+    passphrase: Option<String>,
+    //The file as it was last read or written by us
+    disk_state: Option<FileState>,
 }
 
 impl Database {
     pub fn empty() -> Database {
         Database {
             entries: Vec::new(),
+            setlists: Vec::new(),
             file_path: String::new(),
+            storage: None,
+            cache: LyricsCache::new(LYRICS_CACHE_BYTES),
+            passphrase: None,
+            disk_state: None,
+        }
+    }
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+    pub fn passphrase(&self) -> Option<&str> {
+        self.passphrase.as_ref().map(String::as_str)
+    }
+    //Encrypt the file with `passphrase` from the next save on, None saves it unencrypted. The
+    //lyrics are read first, the file is read the old way until then.
+    pub fn set_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), DatabaseError> {
+        if !self.file_path.is_empty() {
+            let storage = storage::open(&self.file_path, passphrase)?;
+            self.load_all_lyrics()?;
+            self.storage = Some(storage);
         }
+        self.passphrase = passphrase.map(str::to_owned);
+        Ok(())
     }
     pub fn clean(&mut self) {
         self.entries.clear();
+        self.setlists.clear();
     }
     pub fn from(path_str: &str) -> Result<Database, DatabaseError> {
-        let mut entries = Vec::new();
-
-        //Open file for reading
-        let path = Path::new(path_str);
-        let file = File::open(path)?;
-        let mut buf_reader = BufReader::new(file);
-        let mut data = String::new();
-        buf_reader.read_to_string(&mut data)?;
-
-        let doc = Document::parse(data.as_bytes()).unwrap();
-        if let Some(root) = doc.root {
-            if root.name != "database" {
-                return Err(DatabaseError::InvalidTag(root.name));
-            }
-            for artist_tag in root.children {
-                let mut artist = Artist::new();
-                if artist_tag.name != "artist" {
-                    return Err(DatabaseError::InvalidTag(artist_tag.name));
-                }
-
-                if artist_tag.attributes.is_empty() {
-                    return Err(DatabaseError::MissingAttribute((
-                        "name".to_string(),
-                        artist_tag.name,
-                    )));
-                }
+        Database::from_with_progress(path_str, None, |_| true)
+    }
 
-                for (attribute, value) in artist_tag.attributes {
-                    if attribute != "name" {
-                        return Err(DatabaseError::InvalidAttribute((attribute, value)));
-                    } else {
-                        artist.name = value;
-                    }
-                }
+    //Like `from`, calling `progress` as artists are read until it returns false. Encrypted files
+    //need `passphrase`.
+    pub fn from_with_progress<F>(
+        path_str: &str,
+        passphrase: Option<&str>,
+        mut progress: F,
+    ) -> Result<Database, DatabaseError>
+    where
+        F: FnMut(&Progress) -> bool,
+    {
+        Database::open(path_str, passphrase, false, &mut progress)
+    }
 
-                let mut albums = Vec::new();
-                for album_tag in artist_tag.children {
-                    if album_tag.name != "album" {
-                        return Err(DatabaseError::InvalidTag(album_tag.name));
-                    }
-                    let mut album = Album::new();
-                    for (attr, val) in album_tag.attributes {
-                        match attr.as_ref() {
-                            "title" => album.title = val,
-                            "tracks" => album.track_count = val.parse::<u8>().unwrap(),
-                            _ => {
-                                return Err(DatabaseError::InvalidAttribute((attr, album_tag.name)))
-                            }
-                        };
-                    }
+    //Open a database without reading the lyrics, they are fetched when asked for with `lyrics`
+    pub fn from_index(path_str: &str) -> Result<Database, DatabaseError> {
+        Database::from_index_with_progress(path_str, None, |_| true)
+    }
 
-                    let mut tracks = Vec::new();
-                    for track_tag in album_tag.children {
-                        let mut track = Track::new();
-                        for (attr, val) in track_tag.attributes {
-                            match attr.as_ref() {
-                                "name" => track.title = val,
-                                "num" => track.track = val.parse::<u8>().unwrap(),
-                                _ => {
-                                    return Err(DatabaseError::InvalidAttribute((
-                                        attr,
-                                        track_tag.name,
-                                    )))
-                                }
-                            };
-                        }
-
-                        if let Some(lyrics) = track_tag.text {
-                            track.lyrics = lyrics;
-                        }
-
-                        tracks.push(track);
-                    }
+    //Like `from_index`, calling `progress` as artists are read until it returns false. Encrypted
+    //files need `passphrase`.
+    pub fn from_index_with_progress<F>(
+        path_str: &str,
+        passphrase: Option<&str>,
+        mut progress: F,
+    ) -> Result<Database, DatabaseError>
+    where
+        F: FnMut(&Progress) -> bool,
+    {
+        Database::open(path_str, passphrase, true, &mut progress)
+    }
 
-                    tracks.sort_by(|a, b| a.track.cmp(&b.track));
-                    album.tracks = tracks;
-                    albums.push(album);
-                }
-                artist.albums = albums;
-                entries.push(artist);
-            }
+    fn open(
+        path_str: &str,
+        passphrase: Option<&str>,
+        index: bool,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<Database, DatabaseError> {
+        let disk_state = FileState::of(path_str);
+        let mut storage = storage::open(path_str, passphrase)?;
+        let entries = if index {
+            storage.load_index(progress)?
         } else {
-            return Err(DatabaseError::Empty);
-        }
+            storage.load(progress)?
+        };
+        let setlists = storage.load_setlists()?;
 
         Ok(Database {
             entries,
+            setlists,
             file_path: path_str.to_owned(),
+            storage: Some(storage),
+            cache: LyricsCache::new(LYRICS_CACHE_BYTES),
+            passphrase: passphrase.map(str::to_owned),
+            disk_state,
         })
     }
 
-    pub fn save(&self, path: &str) -> Result<(), DatabaseError> {
-        let mut root = Element::new("database");
-        for artist in &self.entries {
-            let mut artist_el = Element::new("artist");
-            artist_el
-                .attributes
-                .insert("name".to_owned(), artist.name.clone());
-            for album in &artist.albums {
-                let mut album_el = Element::new("album");
-                album_el
-                    .attributes
-                    .insert("title".to_owned(), album.title.clone());
-                album_el
-                    .attributes
-                    .insert("tracks".to_owned(), album.track_count.to_string());
+    //Lyrics of the track at (artist, album, track), fetched from the storage if they haven't
+    //been loaded. Returns None if there is no such track.
+    pub fn lyrics(
+        &mut self,
+        (artist, album, track): (usize, usize, usize),
+    ) -> Result<Option<String>, DatabaseError> {
+        let source = match self
+            .entries
+            .get(artist)
+            .and_then(|artist| artist.albums.get(album))
+            .and_then(|album| album.tracks.get(track))
+        {
+            Some(track) => match track.lyrics_source {
+                Some(source) if self.storage.is_some() => source,
+                _ => return Ok(Some(track.lyrics.clone())),
+            },
+            None => return Ok(None),
+        };
+        if let Some(lyrics) = self.cache.get(source) {
+            return Ok(Some(lyrics.to_owned()));
+        }
 
-                for track in &album.tracks {
-                    let mut track_el = Element::new("track");
-                    track_el
-                        .attributes
-                        .insert("num".to_owned(), track.track.to_string());
-                    track_el
-                        .attributes
-                        .insert("name".to_owned(), track.title.to_string());
-                    track_el.text = Some(track.lyrics.clone());
-                    album_el.children.push(track_el);
+        let lyrics = match self.storage {
+            Some(ref mut storage) => storage.load_lyrics(&[source])?.pop().unwrap_or_default(),
+            None => String::new(),
+        };
+        self.cache.insert(source, lyrics.clone());
+        Ok(Some(lyrics))
+    }
+
+    //Read all lyrics still left in the storage into memory. Needed before anything that works
+    //on the whole database, like saving, merging or comparing.
+    pub fn load_all_lyrics(&mut self) -> Result<(), DatabaseError> {
+        let loaded = match self.storage {
+            Some(ref mut storage) => load_stored_lyrics(storage.as_mut(), &mut self.entries)?,
+            None => false,
+        };
+        if loaded {
+            self.cache.clear();
+        }
+        Ok(())
+    }
+
+    //Read the lyrics `entries` left in this database's storage, for a snapshot taken earlier
+    pub fn load_lyrics_of(&mut self, entries: &mut [Artist]) -> Result<(), DatabaseError> {
+        if let Some(ref mut storage) = self.storage {
+            load_stored_lyrics(storage.as_mut(), entries)?;
+        }
+        Ok(())
+    }
+
+    //Move the album at `from` so that it ends up at `to`, both being (artist, album) indices.
+    //Returns false if either position does not exist.
+    pub fn move_album(&mut self, from: (usize, usize), to: (usize, usize)) -> bool {
+        let exists = self
+            .entries
+            .get(from.0)
+            .map_or(false, |artist| from.1 < artist.albums.len());
+        if !exists || to.0 >= self.entries.len() {
+            return false;
+        }
+
+        //The destination is indexed as if the album was already removed
+        let mut len = self.entries[to.0].albums.len();
+        if from.0 == to.0 {
+            len -= 1;
+        }
+        if to.1 > len {
+            return false;
+        }
+
+        let count = self.entries[from.0].albums[from.1].tracks.len();
+        let before: Vec<_> = (0..count)
+            .filter_map(|k| self.track_ref((from.0, from.1, k)))
+            .collect();
+        let album = self.entries[from.0].albums.remove(from.1);
+        self.entries[to.0].albums.insert(to.1, album);
+
+        //Setlists follow the tracks to their new artist
+        let after = (0..count).filter_map(|k| self.track_ref((to.0, to.1, k)));
+        let moves: Vec<_> = before.into_iter().zip(after).collect();
+        self.retarget_setlists(&moves);
+        true
+    }
+
+    //Move the track at `from` so that it ends up at `to`, both being (artist, album, track)
+    //indices, and renumber the affected albums. Returns false if either position does not exist.
+    pub fn move_track(&mut self, from: (usize, usize, usize), to: (usize, usize, usize)) -> bool {
+        let exists = self
+            .entries
+            .get(from.0)
+            .and_then(|artist| artist.albums.get(from.1))
+            .map_or(false, |album| from.2 < album.tracks.len());
+        let mut len = match self
+            .entries
+            .get(to.0)
+            .and_then(|artist| artist.albums.get(to.1))
+        {
+            Some(album) => album.tracks.len(),
+            None => return false,
+        };
+        if !exists {
+            return false;
+        }
+
+        //The destination is indexed as if the track was already removed
+        let same_album = from.0 == to.0 && from.1 == to.1;
+        if same_album {
+            len -= 1;
+        }
+        if to.2 > len {
+            return false;
+        }
+
+        let before = self.track_ref(from);
+        let track = self.entries[from.0].albums[from.1].tracks.remove(from.2);
+        self.entries[to.0].albums[to.1].tracks.insert(to.2, track);
+        if let (Some(before), Some(after)) = (before, self.track_ref(to)) {
+            self.retarget_setlists(&[(before, after)]);
+        }
+
+        self.entries[to.0].albums[to.1].renumber();
+        if !same_album {
+            self.entries[from.0].albums[from.1].renumber();
+        }
+        true
+    }
+
+    //Rename the artist, album or track at `row`, given as tree indices. Setlists and history
+    //find tracks by name, so returns false without renaming if the name is empty or another
+    //artist, album of the artist or track of the album has it, or if the row does not exist.
+    pub fn rename(&mut self, row: &[usize], name: &str) -> bool {
+        if name.is_empty() {
+            return false;
+        }
+        let (before, taken): (Vec<TrackRef>, bool) = match *row {
+            [artist] => {
+                let albums = match self.entries.get(artist) {
+                    Some(found) => &found.albums,
+                    None => return false,
+                };
+                let before = albums
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(b, album)| (0..album.tracks.len()).map(move |c| (artist, b, c)))
+                    .filter_map(|index| self.track_ref(index))
+                    .collect();
+                let taken = self
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .any(|(i, other)| i != artist && other.name == name);
+                (before, taken)
+            }
+            [artist, album] => {
+                let albums = match self.entries.get(artist) {
+                    Some(found) if album < found.albums.len() => &found.albums,
+                    _ => return false,
+                };
+                let before = (0..albums[album].tracks.len())
+                    .filter_map(|c| self.track_ref((artist, album, c)))
+                    .collect();
+                let taken = albums
+                    .iter()
+                    .enumerate()
+                    .any(|(j, other)| j != album && other.title == name);
+                (before, taken)
+            }
+            [artist, album, track] => {
+                let tracks = match self
+                    .entries
+                    .get(artist)
+                    .and_then(|found| found.albums.get(album))
+                {
+                    Some(found) if track < found.tracks.len() => &found.tracks,
+                    _ => return false,
+                };
+                let before = self.track_ref((artist, album, track)).into_iter().collect();
+                let taken = tracks
+                    .iter()
+                    .enumerate()
+                    .any(|(k, other)| k != track && other.title == name);
+                (before, taken)
+            }
+            _ => return false,
+        };
+        if taken {
+            return false;
+        }
+
+        match *row {
+            [artist] => self.entries[artist].name = name.to_owned(),
+            [artist, album] => self.entries[artist].albums[album].title = name.to_owned(),
+            [artist, album, track] => {
+                self.entries[artist].albums[album].tracks[track].title = name.to_owned()
+            }
+            _ => unreachable!(),
+        }
+
+        //Setlists follow the tracks to their new names
+        let moves: Vec<_> = before
+            .into_iter()
+            .map(|from| {
+                let mut to = from.clone();
+                match row.len() {
+                    1 => to.artist = name.to_owned(),
+                    2 => to.album = name.to_owned(),
+                    _ => to.title = name.to_owned(),
                 }
-                artist_el.children.push(album_el);
+                (from, to)
+            })
+            .collect();
+        self.retarget_setlists(&moves);
+        true
+    }
+
+    //Write the database to `path`, the storage is picked by the file extension. The database
+    //is kept in `path` from then on. A `DatabaseError::History` means the file was saved, but
+    //the history couldn't be kept.
+    pub fn save(&mut self, path: &str) -> Result<(), DatabaseError> {
+        if path == self.file_path && self.update_storage()? {
+            //Only the changes were written
+        } else if path == self.file_path && self.storage.is_some() {
+            //Saving rewrites the storage, so lyrics can't be left behind in it
+            self.load_all_lyrics()?;
+            if let Some(ref mut storage) = self.storage {
+                storage.save(&self.entries, &self.setlists)?;
             }
-            root.children.push(artist_el);
+        } else {
+            self.load_all_lyrics()?;
+            let passphrase = self.passphrase.as_ref().map(String::as_str);
+            let mut storage = storage::open(path, passphrase)?;
+            storage.save(&self.entries, &self.setlists)?;
+            self.file_path = path.to_owned();
+            self.storage = Some(storage);
+        }
+        self.accept_disk_state();
+        //Anything autosaved is older than what was just written. The save itself went
+        //through, so a recovery file that can't be removed is only worth a warning.
+        if let Err(e) = self.discard_recovery() {
+            eprintln!("lyrics: could not remove the recovery file: {}", e);
         }
-        let doc = Document {
-            root: Some(root),
-            ..Document::default()
+
+        //The history is plain text, encrypted databases stop adding to the one they kept
+        //before the passphrase was set
+        let history = match self.passphrase {
+            Some(_) => None,
+            None => History::open(&self.file_path),
         };
-        println!("{}", doc);
+        if let Some(history) = history {
+            //The lyrics that weren't changed are left in the storage
+            let mut entries = self.entries.clone();
+            self.load_lyrics_of(&mut entries)
+                .and_then(|()| history.commit_changes(&entries))
+                .map_err(|e| match e {
+                    DatabaseError::History(e) => DatabaseError::History(e),
+                    e => DatabaseError::History(e.to_string()),
+                })?;
+        }
         Ok(())
     }
+
+    //Write the changes to the storage the database was read from, the lyrics that weren't
+    //changed stay where they are. Returns false if the storage has to be rewritten instead.
+    fn update_storage(&mut self) -> Result<bool, DatabaseError> {
+        let storage = match self.storage {
+            Some(ref mut storage) => storage,
+            None => return Ok(false),
+        };
+        if !storage.begin_update(&self.entries, &self.setlists)? {
+            return Ok(false);
+        }
+        let mut inserted = Vec::new();
+        let written = write_tracks(storage.as_mut(), &self.entries, &mut inserted)
+            .and_then(|()| storage.commit_update());
+        if let Err(e) = written {
+            storage.abort_update();
+            return Err(e);
+        }
+
+        //The new tracks are found in the storage from now on, like the others
+        for ((i, j, k), source) in inserted {
+            self.entries[i].albums[j].tracks[k].lyrics_source = Some(source);
+        }
+        Ok(true)
+    }
+
+    //(artist, album, track) of a track as a music player names it. Players don't always know the
+    //album, or know it by another name, then the artist's first track with the title is taken.
+    pub fn find_playing(
+        &self,
+        artist: &str,
+        album: &str,
+        title: &str,
+    ) -> Option<(usize, usize, usize)> {
+        let artist = normalize(artist);
+        let album = normalize(album);
+        let title = normalize(title);
+
+        let mut fallback = None;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if normalize(&entry.name) != artist {
+                continue;
+            }
+            for (j, entry) in entry.albums.iter().enumerate() {
+                let same_album = normalize(&entry.title) == album;
+                for (k, track) in entry.tracks.iter().enumerate() {
+                    if normalize(&track.title) != title {
+                        continue;
+                    }
+                    if same_album {
+                        return Some((i, j, k));
+                    }
+                    fallback = fallback.or(Some((i, j, k)));
+                }
+            }
+        }
+        fallback
+    }
+
+    //Find tracks whose lyrics contain all words of `query`. Lyrics still in the storage are
+    //searched there, the ones in memory may have been edited since and are searched here.
+    pub fn search(&mut self, query: &str) -> Result<Vec<TrackRef>, DatabaseError> {
+        let query_words = words(query);
+        let stored: Vec<u64> = self
+            .entries
+            .iter()
+            .flat_map(|artist| artist.albums.iter())
+            .flat_map(|album| album.tracks.iter())
+            .filter_map(|track| track.lyrics_source)
+            .collect();
+        let found: HashSet<u64> = match self.storage {
+            Some(ref mut storage) if !stored.is_empty() => match storage.search(query)? {
+                Some(found) => found.into_iter().collect(),
+                //Without an index the stored lyrics are read and searched one by one
+                None => {
+                    let lyrics = storage.load_lyrics(&stored)?;
+                    stored
+                        .into_iter()
+                        .zip(lyrics)
+                        .filter(|&(_, ref lyrics)| lyrics_match(&query_words, lyrics))
+                        .map(|(source, _)| source)
+                        .collect()
+                }
+            },
+            _ => HashSet::new(),
+        };
+
+        let mut tracks = Vec::new();
+        for artist in &self.entries {
+            for album in &artist.albums {
+                for track in &album.tracks {
+                    let matched = match track.lyrics_source {
+                        Some(source) => found.contains(&source),
+                        None => lyrics_match(&query_words, &track.lyrics),
+                    };
+                    if matched {
+                        tracks.push(TrackRef {
+                            artist: artist.name.clone(),
+                            album: album.title.clone(),
+                            title: track.title.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(tracks)
+    }
+}
+
+//Update the tracks whose lyrics are in `storage` and insert the others, adding the positions
+//and new sources of those to `inserted`
+fn write_tracks(
+    storage: &mut dyn Storage,
+    entries: &[Artist],
+    inserted: &mut Vec<((usize, usize, usize), u64)>,
+) -> Result<(), DatabaseError> {
+    for (i, artist) in entries.iter().enumerate() {
+        for (j, album) in artist.albums.iter().enumerate() {
+            for (k, track) in album.tracks.iter().enumerate() {
+                match track.lyrics_source {
+                    Some(source) => storage.update_track(source, (i, j, k), track)?,
+                    None => inserted.push(((i, j, k), storage.insert_track((i, j, k), track)?)),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//Read the lyrics of tracks that still have a `lyrics_source` from `storage`. Returns whether
+//there were any.
+fn load_stored_lyrics(
+    storage: &mut dyn Storage,
+    entries: &mut [Artist],
+) -> Result<bool, DatabaseError> {
+    let mut tracks: Vec<&mut Track> = entries
+        .iter_mut()
+        .flat_map(|artist| artist.albums.iter_mut())
+        .flat_map(|album| album.tracks.iter_mut())
+        .filter(|track| track.lyrics_source.is_some())
+        .collect();
+    if tracks.is_empty() {
+        return Ok(false);
+    }
+
+    let sources: Vec<u64> = tracks
+        .iter()
+        .filter_map(|track| track.lyrics_source)
+        .collect();
+    let lyrics = storage.load_lyrics(&sources)?;
+    for (track, lyrics) in tracks.iter_mut().zip(lyrics) {
+        track.lyrics = lyrics;
+        track.lyrics_source = None;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+
+    fn saved(name: &str) -> String {
+        let path = temp_path(name);
+        let mut db = Database::empty();
+        db.entries = entries(
+            "Artist",
+            "Album",
+            vec![track("One", 1, "red sky"), track("Two", 2, "blue sea")],
+        );
+        db.save(&path).unwrap();
+        path
+    }
+
+    fn titles(found: &[TrackRef]) -> Vec<&str> {
+        found.iter().map(|track| track.title.as_str()).collect()
+    }
+
+    //Stored lyrics are found in the storage, edited ones in memory
+    fn search_sees_edits(name: &str) {
+        let path = saved(name);
+        let mut db = Database::from_index(&path).unwrap();
+        assert_eq!(titles(&db.search("red").unwrap()), ["One"]);
+        {
+            let track = &mut db.entries[0].albums[0].tracks[0];
+            track.lyrics = "green grass".to_owned();
+            track.lyrics_source = None;
+        }
+        assert!(db.search("red").unwrap().is_empty());
+        assert_eq!(titles(&db.search("GREEN").unwrap()), ["One"]);
+        assert_eq!(titles(&db.search("blue sea").unwrap()), ["Two"]);
+        assert!(db.search("").unwrap().is_empty());
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn search_sees_unsaved_edits_in_sqlite() {
+        search_sees_edits("search.db");
+    }
+
+    #[test]
+    fn search_sees_unsaved_edits_in_xml() {
+        search_sees_edits("search.xml");
+    }
+
+    #[test]
+    fn save_leaves_no_temporary_file() {
+        let path = saved("atomic.xml");
+        assert!(!::std::path::Path::new(&format!("{}.tmp", path)).exists());
+        let db = Database::from(&path).unwrap();
+        assert_eq!(db.entries[0].albums[0].tracks[1].lyrics, "blue sea");
+        remove_dir(&path);
+    }
+
+    fn autosave_copies_stored_lyrics(name: &str) {
+        let path = saved(name);
+        let mut db = Database::from_index(&path).unwrap();
+        db.entries[0].albums[0].tracks[0].lyrics = "green grass".to_owned();
+        db.entries[0].albums[0].tracks[0].lyrics_source = None;
+        db.autosave().unwrap();
+        assert!(db.entries[0].albums[0].tracks[1].lyrics_source.is_some());
+
+        let recovered = Database::from(&db.recovery_path().unwrap()).unwrap();
+        let tracks = &recovered.entries[0].albums[0].tracks;
+        assert_eq!(tracks[0].lyrics, "green grass");
+        assert_eq!(tracks[1].lyrics, "blue sea");
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn autosave_leaves_stored_lyrics_in_sqlite() {
+        autosave_copies_stored_lyrics("autosave.db");
+    }
+
+    #[test]
+    fn autosave_leaves_stored_lyrics_in_xml() {
+        autosave_copies_stored_lyrics("autosave.xml");
+    }
+
+    //Another program saving over the file moves a new one in its place, the old one is still
+    //open to read the snapshot's lyrics from
+    #[test]
+    fn snapshot_lyrics_outlive_a_replaced_xml_file() {
+        let path = saved("replaced.xml");
+        let mut db = Database::from_index(&path).unwrap();
+        let mut base = db.snapshot();
+
+        let mut other = Database::from(&path).unwrap();
+        other.entries[0].albums[0].tracks[1].lyrics = "grey rain and more".to_owned();
+        other.save(&path).unwrap();
+
+        db.load_lyrics_of(&mut base.entries).unwrap();
+        assert_eq!(base.entries[0].albums[0].tracks[1].lyrics, "blue sea");
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn stored_lyrics_are_not_read_after_sqlite_changes() {
+        let path = saved("changed.db");
+        let mut db = Database::from_index(&path).unwrap();
+        let mut base = db.snapshot();
+
+        let mut other = Database::from(&path).unwrap();
+        other.entries[0].albums[0].tracks.remove(0);
+        other.save(&path).unwrap();
+
+        match db.load_lyrics_of(&mut base.entries) {
+            Err(DatabaseError::ChangedOnDisk) => (),
+            other => panic!("expected the change to be noticed, got {:?}", other),
+        }
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn loading_stops_when_progress_says_so() {
+        let path = saved("cancel.xml");
+        let mut calls = 0;
+        let loaded = Database::from_index_with_progress(&path, None, |_| {
+            calls += 1;
+            false
+        });
+        match loaded {
+            Err(DatabaseError::Cancelled) => assert_eq!(calls, 1),
+            other => panic!("expected the load to stop, got {:?}", other.map(|_| ())),
+        }
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn set_passphrase_encrypts_the_next_save() {
+        let path = saved("encrypted.xml");
+        let mut db = Database::from_index(&path).unwrap();
+        db.set_passphrase(Some("secret")).unwrap();
+        db.save(&path).unwrap();
+
+        assert!(storage::container::is_encrypted(&path).unwrap());
+        let reopened = Database::from_with_progress(&path, Some("secret"), |_| true).unwrap();
+        assert_eq!(reopened.entries[0].albums[0].tracks[1].lyrics, "blue sea");
+        assert!(Database::from_index(&path).is_err());
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn save_as_moves_the_database_to_the_new_file() {
+        let path = saved("first.xml");
+        let mut db = Database::from_index(&path).unwrap();
+        let other = format!("{}.moved.db", path);
+        db.save(&other).unwrap();
+        assert_eq!(db.file_path(), other);
+        assert_eq!(db.recovery_path(), Some(format!("{}.recovery", other)));
+        assert!(!db.changed_on_disk());
+
+        db.entries[0].albums[0].tracks[0].lyrics = "green grass".to_owned();
+        db.save(&other).unwrap();
+        let reopened = Database::from(&other).unwrap();
+        assert_eq!(
+            reopened.entries[0].albums[0].tracks[0].lyrics,
+            "green grass"
+        );
+        remove_dir(&path);
+    }
+
+    //Their change to the first track is overwritten, the second track's lyrics were never read
+    //before and come from the file as it was opened
+    #[test]
+    fn keep_mine_after_the_file_was_replaced_saves() {
+        let path = saved("keep.xml");
+        let mut db = Database::from_index(&path).unwrap();
+        db.entries[0].albums[0].tracks[0].lyrics = "green grass".to_owned();
+        db.entries[0].albums[0].tracks[0].lyrics_source = None;
+
+        let mut other = Database::from(&path).unwrap();
+        other.entries[0].albums[0].tracks[0].lyrics = "theirs".to_owned();
+        other.entries[0].albums[0].tracks[1].lyrics = "theirs too, and longer".to_owned();
+        other.save(&path).unwrap();
+        assert!(db.changed_on_disk());
+
+        db.keep_mine().unwrap();
+        assert!(!db.changed_on_disk());
+        db.save(&path).unwrap();
+
+        let saved = Database::from(&path).unwrap();
+        let tracks = &saved.entries[0].albums[0].tracks;
+        assert_eq!(tracks[0].lyrics, "green grass");
+        assert_eq!(tracks[1].lyrics, "blue sea");
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn keep_mine_after_the_file_was_written_over_fails() {
+        let path = saved("overwritten.xml");
+        let mut db = Database::from_index(&path).unwrap();
+        let xml = ::std::fs::read_to_string(&path).unwrap();
+        ::std::fs::write(&path, xml.replace("blue sea", "grey rain")).unwrap();
+
+        match db.keep_mine() {
+            Err(DatabaseError::ChangedOnDisk) => assert!(db.changed_on_disk()),
+            other => panic!("expected the lost lyrics to be noticed, got {:?}", other),
+        }
+        remove_dir(&path);
+    }
+
+    fn two_albums() -> Database {
+        let mut db = Database::empty();
+        db.entries = entries(
+            "Artist",
+            "Album",
+            vec![track("One", 1, ""), track("Two", 2, "")],
+        );
+        let mut other = Album::new();
+        other.title = "Other".to_owned();
+        db.entries[0].albums.push(other);
+        db
+    }
+
+    #[test]
+    fn rows_are_renamed() {
+        let mut db = two_albums();
+        assert!(db.rename(&[0], "Band"));
+        assert!(db.rename(&[0, 0], "Record"));
+        assert!(db.rename(&[0, 0, 1], "Three"));
+        assert_eq!(db.entries[0].name, "Band");
+        assert_eq!(db.entries[0].albums[0].title, "Record");
+        assert_eq!(db.entries[0].albums[0].tracks[1].title, "Three");
+    }
+
+    #[test]
+    fn names_in_use_are_not_taken() {
+        let mut db = two_albums();
+        db.entries.push(Artist::new());
+        assert!(!db.rename(&[1], "Artist"));
+        assert!(!db.rename(&[0], ""));
+        assert!(!db.rename(&[0, 0], "Other"));
+        assert!(!db.rename(&[0, 0, 0], "Two"));
+        assert!(!db.rename(&[0, 2], "Missing"));
+        assert_eq!(db.entries[1].name, "");
+        assert_eq!(db.entries[0].albums[0].title, "Album");
+        assert_eq!(db.entries[0].albums[0].tracks[0].title, "One");
+        //Keeping the name is no clash with itself
+        assert!(db.rename(&[0, 0, 0], "One"));
+    }
+
+    #[test]
+    fn setlists_follow_renamed_rows() {
+        let mut db = two_albums();
+        db.setlists.push(setlist("Gig", &["Two", "One"]));
+        assert!(db.rename(&[0, 0, 0], "First"));
+        assert!(db.rename(&[0, 0], "Record"));
+        assert!(db.rename(&[0], "Band"));
+        let entries = &db.setlists[0].entries;
+        let names: Vec<_> = entries
+            .iter()
+            .map(|e| (e.artist.as_str(), e.album.as_str(), e.title.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [("Band", "Record", "Two"), ("Band", "Record", "First")]
+        );
+    }
+
+    fn numbered(album: &Album) -> Vec<(&str, u8)> {
+        album
+            .tracks
+            .iter()
+            .map(|track| (track.title.as_str(), track.track))
+            .collect()
+    }
+
+    #[test]
+    fn tracks_moved_within_an_album_are_renumbered() {
+        let mut db = Database::empty();
+        db.entries = entries(
+            "Artist",
+            "Album",
+            vec![
+                track("One", 1, ""),
+                track("Two", 2, ""),
+                track("Three", 3, ""),
+            ],
+        );
+        assert!(db.move_track((0, 0, 0), (0, 0, 2)));
+        assert_eq!(
+            numbered(&db.entries[0].albums[0]),
+            [("Two", 1), ("Three", 2), ("One", 3)]
+        );
+        assert!(db.move_track((0, 0, 2), (0, 0, 1)));
+        assert_eq!(
+            numbered(&db.entries[0].albums[0]),
+            [("Two", 1), ("One", 2), ("Three", 3)]
+        );
+    }
+
+    #[test]
+    fn tracks_moved_to_another_album_renumber_both() {
+        let mut db = two_albums();
+        assert!(db.move_track((0, 0, 0), (0, 1, 0)));
+        let albums = &db.entries[0].albums;
+        assert_eq!(numbered(&albums[0]), [("Two", 1)]);
+        assert_eq!(numbered(&albums[1]), [("One", 1)]);
+        assert_eq!((albums[0].track_count, albums[1].track_count), (1, 1));
+    }
+
+    #[test]
+    fn moves_to_missing_positions_are_refused() {
+        let mut db = two_albums();
+        assert!(!db.move_track((0, 0, 2), (0, 1, 0)));
+        assert!(!db.move_track((0, 0, 0), (0, 1, 1)));
+        //Within the album the last position is the one of the last track
+        assert!(!db.move_track((0, 0, 0), (0, 0, 2)));
+        assert!(!db.move_album((0, 2), (0, 0)));
+        assert!(!db.move_album((0, 0), (1, 0)));
+        assert!(!db.move_album((0, 0), (0, 2)));
+        assert_eq!(numbered(&db.entries[0].albums[0]), [("One", 1), ("Two", 2)]);
+        assert!(db.move_album((0, 0), (0, 1)));
+        assert_eq!(db.entries[0].albums[1].title, "Album");
+    }
+
+    #[test]
+    fn setlists_follow_moved_rows() {
+        let mut db = two_albums();
+        db.entries.extend(entries("Band", "Record", Vec::new()));
+        db.setlists.push(setlist("Gig", &["One", "Two"]));
+        assert!(db.move_track((0, 0, 0), (0, 1, 0)));
+        assert!(db.move_album((0, 0), (1, 1)));
+        let entries = &db.setlists[0].entries;
+        let names: Vec<_> = entries
+            .iter()
+            .map(|e| (e.artist.as_str(), e.album.as_str(), e.title.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [("Artist", "Other", "One"), ("Band", "Album", "Two")]
+        );
+    }
+
+    //Undoing a move puts the track back and gives both albums the numbers they had before
+    #[test]
+    fn undone_moves_restore_track_numbers() {
+        let mut db = two_albums();
+        db.entries[0].albums[0].tracks[0].track = 3;
+        db.entries[0].albums[0].tracks[1].track = 7;
+        db.entries[0].albums[0].track_count = 12;
+        let numbers = db.entries[0].albums[0].numbers();
+        let other_numbers = db.entries[0].albums[1].numbers();
+
+        assert!(db.move_track((0, 0, 1), (0, 1, 0)));
+        assert_eq!(numbered(&db.entries[0].albums[0]), [("One", 1)]);
+        assert!(db.move_track((0, 1, 0), (0, 0, 1)));
+        assert!(db.entries[0].albums[0].set_numbers(&numbers));
+        assert!(db.entries[0].albums[1].set_numbers(&other_numbers));
+
+        let album = &db.entries[0].albums[0];
+        assert_eq!(numbered(album), [("One", 3), ("Two", 7)]);
+        assert_eq!(album.track_count, 12);
+        assert!(db.entries[0].albums[1].tracks.is_empty());
+    }
 }
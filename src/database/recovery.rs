@@ -0,0 +1,160 @@
+use std::fs;
+use std::io;
+
+use super::storage;
+use super::{Database, DatabaseError};
+
+//Autosaves go to a copy of the database next to its file, written as XML and encrypted like
+//the database itself
+impl Database {
+    //Where the autosaves of this database go, None for a database without a file
+    pub fn recovery_path(&self) -> Option<String> {
+        if self.file_path.is_empty() {
+            None
+        } else {
+            Some(format!("{}.recovery", self.file_path))
+        }
+    }
+
+    //Whether an autosave newer than the database file was left behind
+    pub fn has_recovery(&self) -> bool {
+        let recovery = match self.recovery_path() {
+            Some(recovery) => recovery,
+            None => return false,
+        };
+        let modified = |path: &str| fs::metadata(path).and_then(|meta| meta.modified());
+        match (modified(&recovery), modified(&self.file_path)) {
+            (Ok(recovery), Ok(file)) => recovery >= file,
+            (Ok(_), Err(_)) => true,
+            _ => false,
+        }
+    }
+
+    //Write the database to the recovery file, the database file is left alone
+    pub fn autosave(&mut self) -> Result<(), DatabaseError> {
+        let recovery = match self.recovery_path() {
+            Some(recovery) => recovery,
+            None => return Ok(()),
+        };
+        let passphrase = self.passphrase.as_ref().map(String::as_str);
+        let mut target = storage::open(&recovery, passphrase)?;
+        //Lyrics left in the storage are copied over one at a time instead of being loaded
+        let storage = &mut self.storage;
+        let cache = &mut self.cache;
+        target.save_from(&self.entries, &self.setlists, &mut |source| {
+            if let Some(lyrics) = cache.get(source) {
+                return Ok(lyrics.to_owned());
+            }
+            match *storage {
+                Some(ref mut storage) => {
+                    Ok(storage.load_lyrics(&[source])?.pop().unwrap_or_default())
+                }
+                None => Err(DatabaseError::LyricsNotLoaded),
+            }
+        })
+    }
+
+    //Replace the entries and setlists with the ones in the recovery file. They stay unsaved
+    //until the database is saved.
+    pub fn restore_recovery(&mut self) -> Result<(), DatabaseError> {
+        let recovery = match self.recovery_path() {
+            Some(recovery) => recovery,
+            None => return Ok(()),
+        };
+        let passphrase = self.passphrase.as_ref().map(String::as_str);
+        let mut storage = storage::open(&recovery, passphrase)?;
+        self.entries = storage.load(&mut |_| true)?;
+        self.setlists = storage.load_setlists()?;
+        self.cache.clear();
+        Ok(())
+    }
+
+    pub fn discard_recovery(&self) -> Result<(), DatabaseError> {
+        let recovery = match self.recovery_path() {
+            Some(recovery) => recovery,
+            None => return Ok(()),
+        };
+        match fs::remove_file(recovery) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(DatabaseError::from(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::storage::container;
+    use super::super::testing::*;
+    use super::*;
+
+    fn saved(name: &str) -> Database {
+        let path = temp_path(name);
+        let mut db = Database::empty();
+        db.entries = entries("Artist", "Album", vec![track("One", 1, "red sky")]);
+        db.save(&path).unwrap();
+        db
+    }
+
+    #[test]
+    fn autosaves_are_restored() {
+        let mut db = saved("lyrics.xml");
+        assert!(!db.has_recovery());
+        db.entries[0].albums[0].tracks[0].lyrics = "blue sea".to_owned();
+        db.autosave().unwrap();
+        assert!(db.has_recovery());
+
+        //The database file is left alone until the restored entries are saved
+        let mut reopened = Database::from(&db.file_path).unwrap();
+        assert_eq!(reopened.entries[0].albums[0].tracks[0].lyrics, "red sky");
+        assert!(reopened.has_recovery());
+        reopened.restore_recovery().unwrap();
+        assert_eq!(reopened.entries[0].albums[0].tracks[0].lyrics, "blue sea");
+        reopened.save(&db.file_path.clone()).unwrap();
+        assert!(!reopened.has_recovery());
+        assert!(fs::metadata(reopened.recovery_path().unwrap()).is_err());
+        remove_dir(&db.file_path);
+    }
+
+    #[test]
+    fn discarded_autosaves_are_gone() {
+        let mut db = saved("lyrics.xml");
+        db.autosave().unwrap();
+        db.discard_recovery().unwrap();
+        assert!(!db.has_recovery());
+        //Nothing to discard is fine
+        db.discard_recovery().unwrap();
+        remove_dir(&db.file_path);
+    }
+
+    #[test]
+    fn autosaves_are_encrypted_like_the_database() {
+        let mut db = saved("lyrics.xml");
+        db.set_passphrase(Some("secret")).unwrap();
+        db.autosave().unwrap();
+        let recovery = db.recovery_path().unwrap();
+        assert!(container::is_encrypted(&recovery).unwrap());
+        remove_dir(&db.file_path);
+    }
+
+    #[test]
+    fn databases_without_a_file_have_no_recovery() {
+        let mut db = Database::empty();
+        assert_eq!(db.recovery_path(), None);
+        db.autosave().unwrap();
+        assert!(!db.has_recovery());
+    }
+
+    #[test]
+    fn saves_succeed_when_the_recovery_file_stays() {
+        let mut db = saved("lyrics.xml");
+        //A directory in its place can't be removed as a file
+        let recovery = db.recovery_path().unwrap();
+        fs::create_dir(&recovery).unwrap();
+        fs::write(format!("{}/keep", recovery), "").unwrap();
+        let path = db.file_path.clone();
+        db.save(&path).unwrap();
+        assert!(db.discard_recovery().is_err());
+        remove_dir(&path);
+    }
+}
@@ -0,0 +1,61 @@
+use super::metadata::SetlistEntry;
+use super::storage::TrackRef;
+use super::Database;
+
+fn refers_to(entry: &SetlistEntry, track: &TrackRef) -> bool {
+    entry.artist == track.artist && entry.album == track.album && entry.title == track.title
+}
+
+//Setlists refer to tracks by name, so they are pointed at the new names when tracks move
+impl Database {
+    //Names of the track at (artist, album, track)
+    pub fn track_ref(&self, (artist, album, track): (usize, usize, usize)) -> Option<TrackRef> {
+        let artist = self.entries.get(artist)?;
+        let album = artist.albums.get(album)?;
+        Some(TrackRef {
+            artist: artist.name.clone(),
+            album: album.title.clone(),
+            title: album.tracks.get(track)?.title.clone(),
+        })
+    }
+
+    //(artist, album, track) of the track a setlist entry refers to, None if it is gone
+    pub fn setlist_track(&self, entry: &SetlistEntry) -> Option<(usize, usize, usize)> {
+        for (i, artist) in self.entries.iter().enumerate() {
+            if artist.name != entry.artist {
+                continue;
+            }
+            for (j, album) in artist.albums.iter().enumerate() {
+                if album.title != entry.album {
+                    continue;
+                }
+                if let Some(k) = album.tracks.iter().position(|t| t.title == entry.title) {
+                    return Some((i, j, k));
+                }
+            }
+        }
+        None
+    }
+
+    //Point the entries referring to the first track of a pair at the second one. Each entry is
+    //moved at most once, so tracks can swap names.
+    pub fn retarget_setlists(&mut self, moves: &[(TrackRef, TrackRef)]) {
+        for entry in self.setlists.iter_mut().flat_map(|s| s.entries.iter_mut()) {
+            let moved = moves.iter().find(|(from, _)| refers_to(entry, from));
+            if let Some((_, to)) = moved {
+                entry.artist = to.artist.clone();
+                entry.album = to.album.clone();
+                entry.title = to.title.clone();
+            }
+        }
+    }
+
+    //Drop the entries referring to tracks that were deleted
+    pub fn remove_from_setlists(&mut self, removed: &[TrackRef]) {
+        for setlist in &mut self.setlists {
+            setlist
+                .entries
+                .retain(|entry| !removed.iter().any(|track| refers_to(entry, track)));
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use zstd;
+
+use database::DatabaseError;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ENCRYPTED_MAGIC: &[u8] = b"LYRICENC";
+
+//An encrypted file is the magic, the container version, the PBKDF2 iterations as a big endian
+//u32, the salt and the nonce, followed by the sealed contents. The header is authenticated
+//along with the contents.
+const CONTAINER_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = 8 + 1 + 4 + SALT_LEN + NONCE_LEN;
+//For new files, older files keep the count they were written with
+const KDF_ITERATIONS: u32 = 100_000;
+//Counts read from a file must be close to it, a tampered header could otherwise make opening
+//the file take forever before the authentication fails
+const MIN_KDF_ITERATIONS: u32 = KDF_ITERATIONS / 10;
+const MAX_KDF_ITERATIONS: u32 = KDF_ITERATIONS * 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+//How a database file is wrapped on disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Format {
+    pub compression: Compression,
+    pub encrypted: bool,
+}
+
+impl Format {
+    pub const PLAIN: Format = Format {
+        compression: Compression::None,
+        encrypted: false,
+    };
+
+    //Format for a new file, compressed if the extension asks for it
+    pub fn for_path(path: &str, encrypted: bool) -> Format {
+        let compression = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        };
+        Format {
+            compression,
+            encrypted,
+        }
+    }
+}
+
+fn compression_of(header: &[u8]) -> Compression {
+    if header.starts_with(GZIP_MAGIC) {
+        Compression::Gzip
+    } else if header.starts_with(ZSTD_MAGIC) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
+}
+
+//Whether the file at `path` needs a passphrase to be read
+pub fn is_encrypted(path: &str) -> Result<bool, DatabaseError> {
+    let mut header = Vec::new();
+    File::open(path)?
+        .take(ENCRYPTED_MAGIC.len() as u64)
+        .read_to_end(&mut header)?;
+    Ok(header == ENCRYPTED_MAGIC)
+}
+
+//Open a database file for reading, decrypting and decompressing it as needed
+pub fn open(
+    path: &str,
+    passphrase: Option<&str>,
+) -> Result<(Box<dyn Read>, Format), DatabaseError> {
+    let mut file = BufReader::new(File::open(path)?);
+    if !file.fill_buf()?.starts_with(ENCRYPTED_MAGIC) {
+        let (reader, compression) = decompress(file)?;
+        return Ok((
+            reader,
+            Format {
+                compression,
+                encrypted: false,
+            },
+        ));
+    }
+
+    let passphrase = passphrase.ok_or(DatabaseError::PassphraseNeeded)?;
+    let mut sealed = Vec::new();
+    file.read_to_end(&mut sealed)?;
+    let (reader, compression) = decompress(Cursor::new(decrypt(sealed, passphrase)?))?;
+    Ok((
+        reader,
+        Format {
+            compression,
+            encrypted: true,
+        },
+    ))
+}
+
+fn decompress<R>(mut reader: R) -> Result<(Box<dyn Read>, Compression), DatabaseError>
+where
+    R: BufRead + 'static,
+{
+    let compression = compression_of(reader.fill_buf()?);
+    let reader: Box<dyn Read> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(GzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    };
+    Ok((reader, compression))
+}
+
+//Replace the file at `path` with what `write` writes, wrapped in `format`
+pub fn save<F>(
+    path: &str,
+    format: Format,
+    passphrase: Option<&str>,
+    write: F,
+) -> Result<(), DatabaseError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), DatabaseError>,
+{
+    replace(path, |file| {
+        if !format.encrypted {
+            let mut out = BufWriter::new(file);
+            compress(&mut out, format.compression, write)?;
+            out.flush()?;
+            return Ok(());
+        }
+
+        //Nothing unencrypted touches the disk
+        let passphrase = passphrase.ok_or(DatabaseError::PassphraseNeeded)?;
+        let mut plain = Vec::new();
+        compress(&mut plain, format.compression, write)?;
+        let sealed = encrypt(plain, passphrase)?;
+        file.write_all(&sealed)?;
+        Ok(())
+    })
+}
+
+//Write a new file next to `path` and move it over the old one once it is complete, so a save
+//that fails halfway leaves the old file as it was
+fn replace<F>(path: &str, write: F) -> Result<(), DatabaseError>
+where
+    F: FnOnce(&mut File) -> Result<(), DatabaseError>,
+{
+    let temp = format!("{}.tmp", path);
+    let result = File::create(&temp)
+        .map_err(DatabaseError::from)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()?;
+            //The new file is readable by whoever could read the old one
+            if let Ok(meta) = fs::metadata(path) {
+                fs::set_permissions(&temp, meta.permissions())?;
+            }
+            Ok(())
+        })
+        .and_then(|()| fs::rename(&temp, path).map_err(DatabaseError::from));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn compress<W, F>(mut out: W, compression: Compression, write: F) -> Result<(), DatabaseError>
+where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> Result<(), DatabaseError>,
+{
+    match compression {
+        Compression::None => write(&mut out),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(out, flate2::Compression::default());
+            write(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(out, 0)?;
+            write(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+    }
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    iterations: u32,
+) -> Result<LessSafeKey, DatabaseError> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or_else(|| DatabaseError::Encryption("invalid key derivation".to_owned()))?;
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| DatabaseError::Encryption("invalid key".to_owned()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn encrypt(mut data: Vec<u8>, passphrase: &str) -> Result<Vec<u8>, DatabaseError> {
+    //A fresh salt gives a fresh key every save, so the random nonce is never reused with it
+    let rng = SystemRandom::new();
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill(&mut salt)
+        .and_then(|_| rng.fill(&mut nonce))
+        .map_err(|_| DatabaseError::Encryption("no random numbers available".to_owned()))?;
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(ENCRYPTED_MAGIC);
+    header.push(CONTAINER_VERSION);
+    header.extend_from_slice(&KDF_ITERATIONS.to_be_bytes());
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+
+    derive_key(passphrase, &salt, KDF_ITERATIONS)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&header),
+            &mut data,
+        )
+        .map_err(|_| DatabaseError::Encryption("could not encrypt".to_owned()))?;
+    header.extend_from_slice(&data);
+    Ok(header)
+}
+
+fn decrypt(mut data: Vec<u8>, passphrase: &str) -> Result<Vec<u8>, DatabaseError> {
+    if data.len() < HEADER_LEN {
+        return Err(DatabaseError::Encryption("file is truncated".to_owned()));
+    }
+    let mut sealed = data.split_off(HEADER_LEN);
+    let header = data;
+
+    let version = header[ENCRYPTED_MAGIC.len()];
+    if version != CONTAINER_VERSION {
+        return Err(DatabaseError::Encryption(format!(
+            "unknown container version {}",
+            version
+        )));
+    }
+    let mut iterations = [0; 4];
+    iterations.copy_from_slice(&header[9..13]);
+    let iterations = u32::from_be_bytes(iterations);
+    if iterations < MIN_KDF_ITERATIONS || iterations > MAX_KDF_ITERATIONS {
+        return Err(DatabaseError::Encryption(format!(
+            "unsupported key derivation with {} iterations",
+            iterations
+        )));
+    }
+    let salt = &header[13..13 + SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&header[13 + SALT_LEN..]);
+
+    let plain_len = derive_key(passphrase, salt, iterations)?
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&header),
+            &mut sealed,
+        )
+        .map_err(|_| DatabaseError::WrongPassphrase)?
+        .len();
+    sealed.truncate(plain_len);
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use super::*;
+    use database::testing::{remove_dir, temp_path};
+
+    const LYRICS: &[u8] = b"<database version=\"1\"><artist name=\"Artist\"/></database>";
+
+    fn read(path: &str, passphrase: Option<&str>) -> Result<(Vec<u8>, Format), DatabaseError> {
+        let (mut reader, format) = open(path, passphrase)?;
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+        Ok((contents, format))
+    }
+
+    fn is_encryption_error<T>(result: Result<T, DatabaseError>) -> bool {
+        match result {
+            Err(DatabaseError::Encryption(_)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn files_are_read_back_as_written() {
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            for &encrypted in &[false, true] {
+                let path = temp_path("lyrics.xml");
+                let format = Format {
+                    compression,
+                    encrypted,
+                };
+                save(&path, format, Some("secret"), |out| {
+                    out.write_all(LYRICS)?;
+                    Ok(())
+                })
+                .unwrap();
+                assert_eq!(is_encrypted(&path).unwrap(), encrypted);
+                assert_eq!(
+                    read(&path, Some("secret")).unwrap(),
+                    (LYRICS.to_vec(), format)
+                );
+                remove_dir(&path);
+            }
+        }
+    }
+
+    #[test]
+    fn encrypted_files_need_the_right_passphrase() {
+        let sealed = encrypt(LYRICS.to_vec(), "secret").unwrap();
+        assert!(!sealed.windows(LYRICS.len()).any(|window| window == LYRICS));
+        match decrypt(sealed.clone(), "wrong") {
+            Err(DatabaseError::WrongPassphrase) => {}
+            other => panic!("expected a wrong passphrase, got {:?}", other),
+        }
+
+        let path = temp_path("lyrics.xml");
+        fs::write(&path, &sealed).unwrap();
+        match read(&path, None) {
+            Err(DatabaseError::PassphraseNeeded) => {}
+            other => panic!("expected a passphrase to be needed, got {:?}", other),
+        }
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn tampered_files_are_refused() {
+        let sealed = encrypt(LYRICS.to_vec(), "secret").unwrap();
+        //The salt, the nonce and the contents are all authenticated
+        for &at in &[13, 13 + SALT_LEN, HEADER_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            assert!(decrypt(tampered, "secret").is_err(), "byte {}", at);
+        }
+        assert!(is_encryption_error(decrypt(
+            sealed[..HEADER_LEN - 1].to_vec(),
+            "secret"
+        )));
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let mut sealed = encrypt(LYRICS.to_vec(), "secret").unwrap();
+        sealed[ENCRYPTED_MAGIC.len()] = CONTAINER_VERSION + 1;
+        assert!(is_encryption_error(decrypt(sealed, "secret")));
+    }
+
+    #[test]
+    fn iteration_counts_far_from_the_default_are_refused() {
+        let sealed = encrypt(LYRICS.to_vec(), "secret").unwrap();
+        for &iterations in &[
+            0,
+            MIN_KDF_ITERATIONS - 1,
+            MAX_KDF_ITERATIONS + 1,
+            u32::max_value(),
+        ] {
+            let mut tampered = sealed.clone();
+            tampered[9..13].copy_from_slice(&iterations.to_be_bytes());
+            assert!(is_encryption_error(decrypt(tampered, "secret")));
+        }
+    }
+}
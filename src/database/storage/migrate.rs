@@ -0,0 +1,170 @@
+use xml::attribute::OwnedAttribute;
+use xml::reader::{self, XmlEvent};
+
+use database::DatabaseError;
+
+//Version written to the `version` attribute of `<database>`. Files from before the attribute
+//existed are version 1. Each version so far only added to the document, version 2 the
+//attribute and version 3 the setlists, so older documents are read as they are.
+pub const CURRENT_VERSION: u32 = 3;
+
+//Version given by the attributes of the root element, newer ones than this program knows are
+//refused
+pub fn version_of(attributes: &[OwnedAttribute]) -> Result<u32, DatabaseError> {
+    let value = match attributes
+        .iter()
+        .find(|attr| attr.name.local_name == "version")
+    {
+        Some(attr) => &attr.value,
+        None => return Ok(1),
+    };
+    let version = match value.parse::<u32>() {
+        Ok(version) if version >= 1 => version,
+        _ => {
+            return Err(DatabaseError::InvalidAttribute((
+                "version".to_owned(),
+                "database".to_owned(),
+            )))
+        }
+    };
+    if version > CURRENT_VERSION {
+        return Err(DatabaseError::UnsupportedVersion(version));
+    }
+    Ok(version)
+}
+
+//Read the version of a document from its root element
+pub fn read_version<I>(events: I) -> Result<u32, DatabaseError>
+where
+    I: IntoIterator<Item = reader::Result<XmlEvent>>,
+{
+    for event in events {
+        match event.map_err(|e| DatabaseError::Xml(e.to_string()))? {
+            XmlEvent::StartElement { attributes, .. } => return version_of(&attributes),
+            _ => (),
+        }
+    }
+    Err(DatabaseError::Empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use xml::reader::EventReader;
+
+    use super::*;
+    use database::metadata::*;
+    use database::testing::*;
+    use database::Database;
+
+    fn version_of_file(path: &str) -> u32 {
+        let file = BufReader::new(File::open(path).unwrap());
+        read_version(EventReader::new(file)).unwrap()
+    }
+
+    fn alternate(artist: &str, album: &str, title: &str) -> Alternate {
+        Alternate {
+            artist: artist.to_owned(),
+            album: album.to_owned(),
+            title: title.to_owned(),
+        }
+    }
+
+    fn check_version_1(entries: &[Artist]) {
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "Version one");
+        let album = &entries[0].albums[0];
+        assert_eq!(
+            (album.title.as_str(), album.track_count),
+            ("Before versions", 2)
+        );
+        let tracks = &album.tracks;
+        assert_eq!((tracks[0].track, tracks[0].title.as_str()), (1, "Unmarked"));
+        assert_eq!(tracks[0].lyrics, "no version & no attribute");
+        assert!(tracks[0].alternates.is_empty());
+        assert_eq!((tracks[1].track, tracks[1].title.as_str()), (2, "Merged"));
+        assert_eq!(tracks[1].lyrics, "kept after a merge");
+        assert_eq!(
+            tracks[1].alternates,
+            [alternate("Someone else", "Elsewhere", "Merged (live)")]
+        );
+    }
+
+    #[test]
+    fn version_1_file_loads() {
+        assert_eq!(version_of_file("testfiles/version-1.xml"), 1);
+        let db = Database::from("testfiles/version-1.xml").unwrap();
+        check_version_1(&db.entries);
+        assert!(db.setlists.is_empty());
+    }
+
+    #[test]
+    fn version_2_file_loads() {
+        assert_eq!(version_of_file("testfiles/version-2.xml"), 2);
+        let db = Database::from("testfiles/version-2.xml").unwrap();
+        assert_eq!(db.entries.len(), 1);
+        assert_eq!(db.entries[0].name, "Version two");
+        let album = &db.entries[0].albums[0];
+        assert_eq!((album.title.as_str(), album.track_count), ("Marked", 2));
+        let tracks = &album.tracks;
+        assert_eq!(tracks[0].title, "First");
+        assert_eq!(tracks[0].lyrics, "the version is on the root");
+        assert_eq!(tracks[1].title, "Second");
+        assert_eq!(tracks[1].lyrics, "lyrics <in> cdata");
+        assert_eq!(
+            tracks[1].alternates,
+            [alternate("Version one", "Before versions", "Merged")]
+        );
+        assert!(db.setlists.is_empty());
+    }
+
+    #[test]
+    fn version_3_file_loads() {
+        assert_eq!(version_of_file("testfiles/version-3.xml"), 3);
+        let db = Database::from("testfiles/version-3.xml").unwrap();
+        let tracks = &db.entries[0].albums[0].tracks;
+        assert_eq!(tracks[0].lyrics, "first & loud");
+        assert_eq!(tracks[1].lyrics, "last <and> quiet");
+
+        assert_eq!(db.setlists.len(), 2);
+        let tour = &db.setlists[0];
+        assert_eq!(tour.name, "Tour");
+        let mut closer = SetlistEntry::new("Version three", "Played live", "Closer");
+        closer.key = "Am".to_owned();
+        closer.tempo = Some(90);
+        closer.notes = "capo 2".to_owned();
+        let opener = SetlistEntry::new("Version three", "Played live", "Opener");
+        assert_eq!(tour.entries, [closer, opener]);
+        assert_eq!(db.setlists[1].name, "Empty");
+        assert!(db.setlists[1].entries.is_empty());
+    }
+
+    //Saving an old file writes the current version, and nothing is lost on the way
+    #[test]
+    fn version_1_file_is_saved_as_the_current_version() {
+        let path = temp_path("upgraded.xml");
+        let mut db = Database::from("testfiles/version-1.xml").unwrap();
+        db.save(&path).unwrap();
+
+        assert_eq!(version_of_file(&path), CURRENT_VERSION);
+        let upgraded = Database::from(&path).unwrap();
+        check_version_1(&upgraded.entries);
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let path = temp_path("future.xml");
+        ::std::fs::write(&path, "<database version=\"99\"></database>").unwrap();
+        match Database::from(&path) {
+            Err(DatabaseError::UnsupportedVersion(99)) => (),
+            other => panic!(
+                "expected version 99 to be refused, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+        remove_dir(&path);
+    }
+}
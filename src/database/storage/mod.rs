@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use database::metadata::*;
+use database::DatabaseError;
+
+pub mod container;
+pub mod migrate;
+pub mod sqlite;
+pub mod stream;
+pub mod xml;
+pub use self::sqlite::SqliteStorage;
+pub use self::xml::XmlStorage;
+
+//How far loading a database has come
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub bytes: u64,
+    //Size of the file, 0 if it isn't known
+    pub total_bytes: u64,
+    pub artists: usize,
+}
+
+//Where a database is kept between runs
+pub trait Storage: Send {
+    //Read every artist, album and track, calling `progress` along the way. Reading stops with
+    //`DatabaseError::Cancelled` once it returns false.
+    fn load(
+        &mut self,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<Vec<Artist>, DatabaseError>;
+    //Like `load`, but leave the lyrics in the storage and set each track's `lyrics_source`
+    fn load_index(
+        &mut self,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<Vec<Artist>, DatabaseError>;
+    //Fetch the lyrics of tracks from the last `load_index`, in the order of `sources`
+    fn load_lyrics(&mut self, sources: &[u64]) -> Result<Vec<String>, DatabaseError>;
+    //Read the setlists kept with the database
+    fn load_setlists(&mut self) -> Result<Vec<Setlist>, DatabaseError>;
+    //Replace the stored database with `entries` and `setlists`, all lyrics must be loaded
+    fn save(&mut self, entries: &[Artist], setlists: &[Setlist]) -> Result<(), DatabaseError> {
+        self.save_from(entries, setlists, &mut |_| {
+            Err(DatabaseError::LyricsNotLoaded)
+        })
+    }
+    //Like `save`, lyrics left in another storage are fetched by `stored` with the track's
+    //`lyrics_source` one at a time while writing
+    fn save_from(
+        &mut self,
+        entries: &[Artist],
+        setlists: &[Setlist],
+        stored: &mut dyn FnMut(u64) -> Result<String, DatabaseError>,
+    ) -> Result<(), DatabaseError>;
+    //Sources of the tracks from the last `load_index` whose lyrics contain all words of
+    //`query`, None if the storage has no index to search
+    fn search(&mut self, query: &str) -> Result<Option<Vec<u64>>, DatabaseError>;
+
+    //Saving in place, for storages that can write single tracks. `begin_update` replaces the
+    //stored artists, albums and setlists and returns false if the storage has to be rewritten
+    //with `save` instead. Then each track is updated if its lyrics are still in the storage and
+    //inserted otherwise, and `commit_update` deletes the stored tracks that were neither.
+    //Nothing is changed until then, `abort_update` drops the update after an error.
+    fn begin_update(
+        &mut self,
+        _entries: &[Artist],
+        _setlists: &[Setlist],
+    ) -> Result<bool, DatabaseError> {
+        Ok(false)
+    }
+    //Write the track at (artist, album, track) over the one from `source`, keeping its lyrics
+    fn update_track(
+        &mut self,
+        _source: u64,
+        _index: (usize, usize, usize),
+        _track: &Track,
+    ) -> Result<(), DatabaseError> {
+        Err(DatabaseError::LyricsNotLoaded)
+    }
+    //Add the track at (artist, album, track) with its lyrics, returning its source
+    fn insert_track(
+        &mut self,
+        _index: (usize, usize, usize),
+        _track: &Track,
+    ) -> Result<u64, DatabaseError> {
+        Err(DatabaseError::LyricsNotLoaded)
+    }
+    fn commit_update(&mut self) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+    fn abort_update(&mut self) {}
+}
+
+//Identifies a track by the names of its artist and album and its title
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackRef {
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+}
+
+impl TrackRef {
+    pub fn find_mut<'a>(&self, entries: &'a mut [Artist]) -> Option<&'a mut Track> {
+        entries
+            .iter_mut()
+            .filter(|artist| artist.name == self.artist)
+            .flat_map(|artist| artist.albums.iter_mut())
+            .filter(|album| album.title == self.album)
+            .flat_map(|album| album.tracks.iter_mut())
+            .find(|track| track.title == self.title)
+    }
+}
+
+//Lowercase words of a search or of lyrics
+pub fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+//Whether `lyrics` contain all of the `words` of a search, nothing matches an empty search
+pub fn lyrics_match(words_of_query: &[String], lyrics: &str) -> bool {
+    let lyrics = words(lyrics);
+    !words_of_query.is_empty() && words_of_query.iter().all(|word| lyrics.contains(word))
+}
+
+//Open the storage for a database file, SQLite databases are recognized by their extension.
+//XML files are encrypted with `passphrase` when one is given.
+pub fn open(path: &str, passphrase: Option<&str>) -> Result<Box<dyn Storage>, DatabaseError> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+    match extension {
+        "db" | "sqlite" | "sqlite3" => {
+            if passphrase.is_some() {
+                return Err(DatabaseError::Encryption(
+                    "SQLite databases can't be encrypted".to_owned(),
+                ));
+            }
+            Ok(Box::new(SqliteStorage::open(path)?))
+        }
+        _ => Ok(Box::new(XmlStorage::new(path, passphrase))),
+    }
+}
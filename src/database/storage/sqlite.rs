@@ -0,0 +1,740 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::{Connection, OptionalExtension, NO_PARAMS};
+
+use super::{Progress, Storage};
+use database::metadata::*;
+use database::DatabaseError;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS artists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS albums (
+        id INTEGER PRIMARY KEY,
+        artist_id INTEGER NOT NULL REFERENCES artists(id),
+        title TEXT NOT NULL,
+        track_count INTEGER NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tracks (
+        id INTEGER PRIMARY KEY,
+        album_id INTEGER NOT NULL REFERENCES albums(id),
+        title TEXT NOT NULL,
+        num INTEGER NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS lyrics (
+        track_id INTEGER PRIMARY KEY REFERENCES tracks(id),
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS alternates (
+        track_id INTEGER NOT NULL REFERENCES tracks(id),
+        artist TEXT NOT NULL,
+        album TEXT NOT NULL,
+        title TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS setlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS setlist_entries (
+        setlist_id INTEGER NOT NULL REFERENCES setlists(id),
+        artist TEXT NOT NULL,
+        album TEXT NOT NULL,
+        title TEXT NOT NULL,
+        notes TEXT NOT NULL,
+        key TEXT NOT NULL,
+        tempo INTEGER,
+        position INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS albums_artist ON albums(artist_id);
+    CREATE INDEX IF NOT EXISTS tracks_album ON tracks(album_id);
+    CREATE INDEX IF NOT EXISTS alternates_track ON alternates(track_id);
+    CREATE INDEX IF NOT EXISTS setlist_entries_setlist ON setlist_entries(setlist_id);
+
+    CREATE VIRTUAL TABLE IF NOT EXISTS lyrics_fts
+        USING fts5(body, content='lyrics', content_rowid='track_id');
+    CREATE TRIGGER IF NOT EXISTS lyrics_insert AFTER INSERT ON lyrics BEGIN
+        INSERT INTO lyrics_fts(rowid, body) VALUES (new.track_id, new.body);
+    END;
+    CREATE TRIGGER IF NOT EXISTS lyrics_delete AFTER DELETE ON lyrics BEGIN
+        INSERT INTO lyrics_fts(lyrics_fts, rowid, body) VALUES ('delete', old.track_id, old.body);
+    END;
+    CREATE TRIGGER IF NOT EXISTS lyrics_update AFTER UPDATE ON lyrics BEGIN
+        INSERT INTO lyrics_fts(lyrics_fts, rowid, body) VALUES ('delete', old.track_id, old.body);
+        INSERT INTO lyrics_fts(rowid, body) VALUES (new.track_id, new.body);
+    END;
+";
+
+//An embedded SQLite database with a full text index over the lyrics
+pub struct SqliteStorage {
+    conn: Connection,
+    //Changes whenever another connection writes, track ids from the last load or save only
+    //hold while it stays the same
+    indexed_version: Option<i64>,
+    update: Option<Update>,
+}
+
+//A save in place, between `begin_update` and `commit_update`
+struct Update {
+    //Ids of the albums by (artist, album)
+    album_ids: Vec<Vec<i64>>,
+    //Tracks written so far, the others are deleted on commit
+    tracks: HashSet<i64>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<SqliteStorage, DatabaseError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage {
+            conn,
+            indexed_version: None,
+            update: None,
+        })
+    }
+
+    fn data_version(&self) -> Result<i64, DatabaseError> {
+        Ok(self
+            .conn
+            .query_row("PRAGMA data_version", NO_PARAMS, |row| row.get(0))?)
+    }
+
+    //The track ids mean nothing once someone else rewrote the database
+    fn check_index(&self) -> Result<(), DatabaseError> {
+        if Some(self.data_version()?) != self.indexed_version {
+            return Err(DatabaseError::ChangedOnDisk);
+        }
+        Ok(())
+    }
+
+    //Read the whole database, only marking where the lyrics are if `index` is set
+    fn read(
+        &mut self,
+        index: bool,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<Vec<Artist>, DatabaseError> {
+        let mut entries = Vec::new();
+        let mut artists = HashMap::new();
+        let mut albums = HashMap::new();
+        let mut tracks = HashMap::new();
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, name FROM artists ORDER BY position")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (id, name) = row?;
+            let mut artist = Artist::new();
+            artist.name = name;
+            artists.insert(id, entries.len());
+            entries.push(artist);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id, artist_id, title, track_count FROM albums ORDER BY artist_id, position",
+        )?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (id, artist_id, title, track_count) = row?;
+            let i = *artists
+                .get(&artist_id)
+                .ok_or_else(|| orphan("artist_id", "albums"))?;
+            let mut album = Album::new();
+            album.title = title;
+            album.track_count = track_count as u8;
+            albums.insert(id, (i, entries[i].albums.len()));
+            entries[i].albums.push(album);
+        }
+
+        //Leave the lyrics out of the query when they aren't wanted
+        let body = if index { "NULL" } else { "body" };
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT tracks.id, album_id, title, num, {} FROM tracks
+             LEFT JOIN lyrics ON lyrics.track_id = tracks.id
+             ORDER BY album_id, position",
+            body
+        ))?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+        for row in rows {
+            let (id, album_id, title, num, body) = row?;
+            let (i, j) = *albums
+                .get(&album_id)
+                .ok_or_else(|| orphan("album_id", "tracks"))?;
+            let mut track = Track::new();
+            track.title = title;
+            track.track = num as u8;
+            if index {
+                track.lyrics_source = Some(id as u64);
+            } else {
+                track.lyrics = body.unwrap_or_default();
+            }
+            let album = &mut entries[i].albums[j];
+            tracks.insert(id, (i, j, album.tracks.len()));
+            album.tracks.push(track);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT track_id, artist, album, title FROM alternates ORDER BY track_id, position",
+        )?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                Alternate {
+                    artist: row.get(1)?,
+                    album: row.get(2)?,
+                    title: row.get(3)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (track_id, alternate) = row?;
+            let (i, j, k) = *tracks
+                .get(&track_id)
+                .ok_or_else(|| orphan("track_id", "alternates"))?;
+            entries[i].albums[j].tracks[k].alternates.push(alternate);
+        }
+
+        let go_on = progress(&Progress {
+            bytes: 0,
+            total_bytes: 0,
+            artists: entries.len(),
+        });
+        if !go_on {
+            return Err(DatabaseError::Cancelled);
+        }
+        Ok(entries)
+    }
+}
+
+//Add the artists and their albums, returning the ids of the albums by (artist, album)
+fn insert_artists(conn: &Connection, entries: &[Artist]) -> Result<Vec<Vec<i64>>, DatabaseError> {
+    let mut album_ids = Vec::with_capacity(entries.len());
+    for (i, artist) in entries.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO artists (name, position) VALUES (?1, ?2)",
+            params![artist.name, i as i64],
+        )?;
+        let artist_id = conn.last_insert_rowid();
+
+        let mut ids = Vec::with_capacity(artist.albums.len());
+        for (j, album) in artist.albums.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO albums (artist_id, title, track_count, position)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![artist_id, album.title, album.track_count as i64, j as i64],
+            )?;
+            ids.push(conn.last_insert_rowid());
+        }
+        album_ids.push(ids);
+    }
+    Ok(album_ids)
+}
+
+//Add a track with `lyrics` as the `position`th of an album, returning its id
+fn insert_track(
+    conn: &Connection,
+    album_id: i64,
+    position: usize,
+    track: &Track,
+    lyrics: &str,
+) -> Result<i64, DatabaseError> {
+    conn.execute(
+        "INSERT INTO tracks (album_id, title, num, position) VALUES (?1, ?2, ?3, ?4)",
+        params![album_id, track.title, track.track as i64, position as i64],
+    )?;
+    let track_id = conn.last_insert_rowid();
+    conn.execute(
+        "INSERT INTO lyrics (track_id, body) VALUES (?1, ?2)",
+        params![track_id, lyrics],
+    )?;
+    insert_alternates(conn, track_id, track)?;
+    Ok(track_id)
+}
+
+fn insert_alternates(conn: &Connection, track_id: i64, track: &Track) -> Result<(), DatabaseError> {
+    for (n, alternate) in track.alternates.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO alternates (track_id, artist, album, title, position)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                track_id,
+                alternate.artist,
+                alternate.album,
+                alternate.title,
+                n as i64
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn delete_track(conn: &Connection, track_id: i64) -> Result<(), DatabaseError> {
+    conn.execute("DELETE FROM lyrics WHERE track_id = ?1", params![track_id])?;
+    conn.execute("DELETE FROM tracks WHERE id = ?1", params![track_id])?;
+    Ok(())
+}
+
+fn insert_setlists(conn: &Connection, setlists: &[Setlist]) -> Result<(), DatabaseError> {
+    for (i, setlist) in setlists.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO setlists (name, position) VALUES (?1, ?2)",
+            params![setlist.name, i as i64],
+        )?;
+        let setlist_id = conn.last_insert_rowid();
+        for (n, entry) in setlist.entries.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO setlist_entries
+                 (setlist_id, artist, album, title, notes, key, tempo, position)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    setlist_id,
+                    entry.artist,
+                    entry.album,
+                    entry.title,
+                    entry.notes,
+                    entry.key,
+                    entry.tempo.map(i64::from),
+                    n as i64
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+//A row of `table` whose `column` refers to a row that isn't there
+fn orphan(column: &str, table: &str) -> DatabaseError {
+    DatabaseError::InvalidAttribute((column.to_owned(), table.to_owned()))
+}
+
+//Quote every word so FTS5 doesn't read the query as its own syntax
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Storage for SqliteStorage {
+    fn load(
+        &mut self,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<Vec<Artist>, DatabaseError> {
+        self.indexed_version = Some(self.data_version()?);
+        self.read(false, progress)
+    }
+
+    fn load_index(
+        &mut self,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<Vec<Artist>, DatabaseError> {
+        self.indexed_version = Some(self.data_version()?);
+        self.read(true, progress)
+    }
+
+    //Sources are track ids
+    fn load_lyrics(&mut self, sources: &[u64]) -> Result<Vec<String>, DatabaseError> {
+        self.check_index()?;
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT body FROM lyrics WHERE track_id = ?1")?;
+        let mut lyrics = Vec::with_capacity(sources.len());
+        for &id in sources {
+            let body = stmt
+                .query_row(params![id as i64], |row| row.get::<_, String>(0))
+                .optional()?;
+            lyrics.push(body.unwrap_or_default());
+        }
+        Ok(lyrics)
+    }
+
+    fn load_setlists(&mut self) -> Result<Vec<Setlist>, DatabaseError> {
+        let mut setlists = Vec::new();
+        let mut ids = HashMap::new();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, name FROM setlists ORDER BY position")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (id, name) = row?;
+            let mut setlist = Setlist::new();
+            setlist.name = name;
+            ids.insert(id, setlists.len());
+            setlists.push(setlist);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT setlist_id, artist, album, title, notes, key, tempo FROM setlist_entries
+             ORDER BY setlist_id, position",
+        )?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                SetlistEntry {
+                    artist: row.get(1)?,
+                    album: row.get(2)?,
+                    title: row.get(3)?,
+                    notes: row.get(4)?,
+                    key: row.get(5)?,
+                    tempo: row.get::<_, Option<i64>>(6)?.map(|tempo| tempo as u16),
+                },
+            ))
+        })?;
+        for row in rows {
+            let (setlist_id, entry) = row?;
+            let index = ids
+                .get(&setlist_id)
+                .ok_or_else(|| orphan("setlist_id", "setlist_entries"))?;
+            setlists[*index].entries.push(entry);
+        }
+        Ok(setlists)
+    }
+
+    fn save_from(
+        &mut self,
+        entries: &[Artist],
+        setlists: &[Setlist],
+        stored: &mut dyn FnMut(u64) -> Result<String, DatabaseError>,
+    ) -> Result<(), DatabaseError> {
+        let tx = self.conn.transaction()?;
+        tx.execute_batch(
+            "DELETE FROM setlist_entries;
+             DELETE FROM setlists;
+             DELETE FROM alternates;
+             DELETE FROM lyrics;
+             DELETE FROM tracks;
+             DELETE FROM albums;
+             DELETE FROM artists;",
+        )?;
+
+        let album_ids = insert_artists(&tx, entries)?;
+        for (artist, ids) in entries.iter().zip(&album_ids) {
+            for (album, &album_id) in artist.albums.iter().zip(ids) {
+                for (k, track) in album.tracks.iter().enumerate() {
+                    let lyrics = match track.lyrics_source {
+                        Some(source) => Cow::Owned(stored(source)?),
+                        None => Cow::Borrowed(track.lyrics.as_str()),
+                    };
+                    insert_track(&tx, album_id, k, track, &lyrics)?;
+                }
+            }
+        }
+        insert_setlists(&tx, setlists)?;
+        tx.commit()?;
+        //Nothing from before is left for an older index to refer to
+        self.indexed_version = Some(self.data_version()?);
+        Ok(())
+    }
+
+    //Sources are track ids, found through the full text index
+    fn search(&mut self, query: &str) -> Result<Option<Vec<u64>>, DatabaseError> {
+        self.check_index()?;
+        let query = fts_query(query);
+        if query.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT rowid FROM lyrics_fts WHERE lyrics_fts MATCH ?1")?;
+        let rows = stmt.query_map(params![query], |row| row.get::<_, i64>(0))?;
+        let mut found = Vec::new();
+        for row in rows {
+            found.push(row? as u64);
+        }
+        Ok(Some(found))
+    }
+
+    //Track ids from an index of another version of the database mean nothing, the storage is
+    //rewritten then
+    fn begin_update(
+        &mut self,
+        entries: &[Artist],
+        setlists: &[Setlist],
+    ) -> Result<bool, DatabaseError> {
+        if Some(self.data_version()?) != self.indexed_version {
+            return Ok(false);
+        }
+        //Tracks point at the old albums until they are updated
+        self.conn.execute_batch(
+            "BEGIN;
+             PRAGMA defer_foreign_keys = ON;",
+        )?;
+        let album_ids = self
+            .conn
+            .execute_batch(
+                "DELETE FROM setlist_entries;
+                 DELETE FROM setlists;
+                 DELETE FROM alternates;
+                 DELETE FROM albums;
+                 DELETE FROM artists;",
+            )
+            .map_err(DatabaseError::from)
+            .and_then(|()| insert_artists(&self.conn, entries))
+            .and_then(|album_ids| insert_setlists(&self.conn, setlists).map(|()| album_ids));
+        match album_ids {
+            Ok(album_ids) => {
+                self.update = Some(Update {
+                    album_ids,
+                    tracks: HashSet::new(),
+                });
+                Ok(true)
+            }
+            Err(e) => {
+                self.abort_update();
+                Err(e)
+            }
+        }
+    }
+
+    fn update_track(
+        &mut self,
+        source: u64,
+        (artist, album, track_index): (usize, usize, usize),
+        track: &Track,
+    ) -> Result<(), DatabaseError> {
+        let update = self.update.as_mut().ok_or(DatabaseError::LyricsNotLoaded)?;
+        let album_id = update.album_ids[artist][album];
+        let changed = self.conn.execute(
+            "UPDATE tracks SET album_id = ?1, title = ?2, num = ?3, position = ?4 WHERE id = ?5",
+            params![
+                album_id,
+                track.title,
+                track.track as i64,
+                track_index as i64,
+                source as i64
+            ],
+        )?;
+        //The index said it was there
+        if changed == 0 {
+            return Err(DatabaseError::ChangedOnDisk);
+        }
+        insert_alternates(&self.conn, source as i64, track)?;
+        update.tracks.insert(source as i64);
+        Ok(())
+    }
+
+    fn insert_track(
+        &mut self,
+        (artist, album, track_index): (usize, usize, usize),
+        track: &Track,
+    ) -> Result<u64, DatabaseError> {
+        let update = self.update.as_mut().ok_or(DatabaseError::LyricsNotLoaded)?;
+        let album_id = update.album_ids[artist][album];
+        let track_id = insert_track(&self.conn, album_id, track_index, track, &track.lyrics)?;
+        update.tracks.insert(track_id);
+        Ok(track_id as u64)
+    }
+
+    fn commit_update(&mut self) -> Result<(), DatabaseError> {
+        let written = match self.update.take() {
+            Some(update) => update.tracks,
+            None => return Ok(()),
+        };
+        let ids = {
+            let mut stmt = self.conn.prepare("SELECT id FROM tracks")?;
+            let rows = stmt.query_map(NO_PARAMS, |row| row.get::<_, i64>(0))?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        for id in ids {
+            if !written.contains(&id) {
+                delete_track(&self.conn, id)?;
+            }
+        }
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn abort_update(&mut self) {
+        if self.update.take().is_some() || !self.conn.is_autocommit() {
+            let _ = self.conn.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::*;
+    use database::Database;
+
+    #[test]
+    fn rows_of_missing_parents_are_refused() {
+        let orphans = [
+            "INSERT INTO albums VALUES (7, 7, 'Album', 0, 1)",
+            "INSERT INTO tracks VALUES (7, 7, 'Two', 2, 1)",
+            "INSERT INTO alternates VALUES (7, 'Artist', 'Album', 'One', 0)",
+            "INSERT INTO setlist_entries VALUES (7, 'Artist', 'Album', 'One', '', '', NULL, 0)",
+        ];
+        for orphan in &orphans {
+            let path = temp_path("orphan.db");
+            let mut db = Database::empty();
+            db.entries = entries("Artist", "Album", vec![track("One", 1, "")]);
+            db.save(&path).unwrap();
+            //Written by something that doesn't check the references
+            Connection::open(&path)
+                .unwrap()
+                .execute_batch(&format!("PRAGMA foreign_keys = OFF; {};", orphan))
+                .unwrap();
+            match Database::from(&path) {
+                Err(DatabaseError::InvalidAttribute(_)) => (),
+                other => panic!(
+                    "expected {} to be refused, got {:?}",
+                    orphan,
+                    other.map(|_| ())
+                ),
+            }
+            remove_dir(&path);
+        }
+    }
+
+    fn track_id(path: &str, title: &str) -> Option<i64> {
+        Connection::open(path)
+            .unwrap()
+            .query_row(
+                "SELECT id FROM tracks WHERE title = ?1",
+                params![title],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap()
+    }
+
+    fn count(path: &str, table: &str) -> i64 {
+        Connection::open(path)
+            .unwrap()
+            .query_row(
+                &format!("SELECT COUNT(*) FROM {}", table),
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    fn saved(name: &str) -> String {
+        let path = temp_path(name);
+        let mut db = Database::empty();
+        db.entries = entries(
+            "Artist",
+            "Album",
+            vec![
+                track("One", 1, "red sky"),
+                track("Two", 2, "blue sea"),
+                track("Three", 3, "grey rain"),
+            ],
+        );
+        db.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn saves_write_only_the_changes() {
+        let path = saved("in-place.db");
+        let (one, two) = (track_id(&path, "One"), track_id(&path, "Two"));
+        let mut db = Database::from_index(&path).unwrap();
+        {
+            let tracks = &mut db.entries[0].albums[0].tracks;
+            tracks[1].title = "Second".to_owned();
+            tracks[2].lyrics = "green grass".to_owned();
+            tracks[2].lyrics_source = None;
+            tracks.remove(0);
+            tracks.push(track("Four", 4, "white snow"));
+        }
+        db.save(&path).unwrap();
+
+        //Lyrics that weren't changed were neither read nor written
+        assert!(db.entries[0].albums[0].tracks[0].lyrics.is_empty());
+        assert_eq!(track_id(&path, "Second"), two);
+        assert_eq!(track_id(&path, "One"), None);
+        assert!(one.is_some());
+        assert_eq!(count(&path, "tracks"), 3);
+        assert_eq!(count(&path, "lyrics"), 3);
+
+        //New tracks are updated in place by the next save
+        let four = track_id(&path, "Four");
+        db.entries[0].albums[0].tracks[2].title = "Fourth".to_owned();
+        db.save(&path).unwrap();
+        assert_eq!(track_id(&path, "Fourth"), four);
+
+        let mut reread = Database::from_index(&path).unwrap();
+        let tracks: Vec<(String, String)> = (0..3)
+            .map(|k| {
+                let title = reread.entries[0].albums[0].tracks[k].title.clone();
+                (title, reread.lyrics((0, 0, k)).unwrap().unwrap())
+            })
+            .collect();
+        let tracks: Vec<(&str, &str)> = tracks
+            .iter()
+            .map(|(title, lyrics)| (title.as_str(), lyrics.as_str()))
+            .collect();
+        assert_eq!(
+            tracks,
+            [
+                ("Second", "blue sea"),
+                ("Three", "green grass"),
+                ("Fourth", "white snow")
+            ]
+        );
+        let found: Vec<String> = reread
+            .search("rain")
+            .unwrap()
+            .into_iter()
+            .chain(reread.search("red").unwrap())
+            .map(|track| track.title)
+            .collect();
+        assert!(found.is_empty());
+        assert_eq!(reread.search("snow").unwrap()[0].title, "Fourth");
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn failed_saves_change_nothing() {
+        let path = saved("failed.db");
+        let mut db = Database::from_index(&path).unwrap();
+        {
+            let tracks = &mut db.entries[0].albums[0].tracks;
+            tracks[0].title = "First".to_owned();
+            tracks[1].lyrics_source = Some(999);
+        }
+        match db.save(&path) {
+            Err(DatabaseError::ChangedOnDisk) => (),
+            other => panic!("expected the save to fail, got {:?}", other),
+        }
+        let reread = Database::from(&path).unwrap();
+        let titles: Vec<&str> = reread.entries[0].albums[0]
+            .tracks
+            .iter()
+            .map(|track| track.title.as_str())
+            .collect();
+        assert_eq!(titles, ["One", "Two", "Three"]);
+        assert_eq!(reread.entries[0].albums[0].tracks[1].lyrics, "blue sea");
+        remove_dir(&path);
+    }
+}
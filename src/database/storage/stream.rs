@@ -0,0 +1,498 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::rc::Rc;
+
+use xml::reader::{self, EventReader, XmlEvent};
+
+use super::migrate::{version_of, CURRENT_VERSION};
+use super::Progress;
+use database::metadata::*;
+use database::DatabaseError;
+
+//Counts the bytes read so far for progress reports and lyrics offsets
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+//Progress is reported after every artist, and after this many bytes within one
+const PROGRESS_BYTES: u64 = 64 * 1024;
+
+fn xml_error(e: reader::Error) -> DatabaseError {
+    DatabaseError::Xml(e.to_string())
+}
+
+fn parse_num(attr: String, val: &str, tag: &str) -> Result<u8, DatabaseError> {
+    val.parse::<u8>()
+        .map_err(|_| DatabaseError::InvalidAttribute((attr, tag.to_owned())))
+}
+
+//Read a database and its setlists while parsing it, without building the whole document first.
+//`progress` is called after every artist and every `PROGRESS_BYTES` read, and stops the
+//reading by returning false. `total_bytes` is the size of the input if known.
+pub fn read<R, F>(
+    reader: R,
+    total_bytes: u64,
+    progress: F,
+) -> Result<(Vec<Artist>, Vec<Setlist>), DatabaseError>
+where
+    R: Read,
+    F: FnMut(&Progress) -> bool,
+{
+    let count = Rc::new(Cell::new(0));
+    //Buffer outside the counter, the parser reads a byte at a time
+    let parser = EventReader::new(BufReader::new(CountingReader {
+        inner: reader,
+        count: count.clone(),
+    }));
+    parse(parser, &count, total_bytes, false, progress)
+}
+
+//Like `read`, but skip the lyrics. Each track's `lyrics_source` is set to the offset of its
+//lyrics in the input, for `read_lyrics`.
+pub fn read_index<R, F>(
+    reader: R,
+    total_bytes: u64,
+    progress: F,
+) -> Result<(Vec<Artist>, Vec<Setlist>), DatabaseError>
+where
+    R: Read,
+    F: FnMut(&Progress) -> bool,
+{
+    let count = Rc::new(Cell::new(0));
+    //Count every byte the parser takes, so the count is exact when a track starts
+    let parser = EventReader::new(CountingReader {
+        inner: BufReader::new(reader),
+        count: count.clone(),
+    });
+    parse(parser, &count, total_bytes, true, progress)
+}
+
+//Read the lyrics of one track from `reader`, which starts where `read_index` found them
+pub fn read_lyrics<R: Read>(reader: R) -> Result<String, DatabaseError> {
+    //Reopen the track element so the rest of it parses as a document of its own
+    let parser = EventReader::new((&b"<track>"[..]).chain(reader));
+    let mut lyrics = String::new();
+    let mut depth = 0;
+    for event in parser {
+        match event.map_err(xml_error)? {
+            XmlEvent::StartElement { .. } => depth += 1,
+            XmlEvent::EndElement { .. } => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if depth == 1 {
+                    lyrics.push_str(&text);
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(lyrics)
+}
+
+fn report<F>(
+    progress: &mut F,
+    bytes: u64,
+    total_bytes: u64,
+    artists: usize,
+) -> Result<(), DatabaseError>
+where
+    F: FnMut(&Progress) -> bool,
+{
+    let go_on = progress(&Progress {
+        bytes,
+        total_bytes,
+        artists,
+    });
+    if go_on {
+        Ok(())
+    } else {
+        Err(DatabaseError::Cancelled)
+    }
+}
+
+fn parse<R, F>(
+    parser: EventReader<R>,
+    count: &Cell<u64>,
+    total_bytes: u64,
+    index: bool,
+    mut progress: F,
+) -> Result<(Vec<Artist>, Vec<Setlist>), DatabaseError>
+where
+    R: Read,
+    F: FnMut(&Progress) -> bool,
+{
+    let mut entries = Vec::new();
+    let mut setlists = Vec::new();
+    let mut setlist = Setlist::new();
+    //Whether the element at depth 1 is a setlist rather than an artist
+    let mut in_setlist = false;
+    let mut artist = Artist::new();
+    let mut album = Album::new();
+    let mut track = Track::new();
+    let mut depth = 0;
+    let mut has_root = false;
+    //Bytes read at the last progress report
+    let mut reported = 0;
+
+    for event in parser {
+        match event.map_err(xml_error)? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                if depth == 0 {
+                    version_of(&attributes)?;
+                }
+                let tag = name.local_name;
+                let attributes = attributes
+                    .into_iter()
+                    .map(|attr| (attr.name.local_name, attr.value));
+                match (depth, tag.as_ref()) {
+                    (0, "database") => has_root = true,
+                    (1, "artist") => {
+                        in_setlist = false;
+                        artist = Artist::new();
+                        let mut has_name = false;
+                        for (attribute, value) in attributes {
+                            if attribute != "name" {
+                                return Err(DatabaseError::InvalidAttribute((attribute, value)));
+                            }
+                            artist.name = value;
+                            has_name = true;
+                        }
+                        if !has_name {
+                            return Err(DatabaseError::MissingAttribute(("name".to_string(), tag)));
+                        }
+                    }
+                    (1, "setlist") => {
+                        in_setlist = true;
+                        setlist = Setlist::new();
+                        for (attr, val) in attributes {
+                            if attr != "name" {
+                                return Err(DatabaseError::InvalidAttribute((attr, tag)));
+                            }
+                            setlist.name = val;
+                        }
+                    }
+                    (2, "entry") if in_setlist => {
+                        let mut entry = SetlistEntry::new("", "", "");
+                        for (attr, val) in attributes {
+                            match attr.as_ref() {
+                                "artist" => entry.artist = val,
+                                "album" => entry.album = val,
+                                "title" => entry.title = val,
+                                "key" => entry.key = val,
+                                "tempo" => match val.parse::<u16>() {
+                                    Ok(tempo) => entry.tempo = Some(tempo),
+                                    Err(_) => {
+                                        return Err(DatabaseError::InvalidAttribute((attr, tag)))
+                                    }
+                                },
+                                "notes" => entry.notes = val,
+                                _ => return Err(DatabaseError::InvalidAttribute((attr, tag))),
+                            };
+                        }
+                        setlist.entries.push(entry);
+                    }
+                    (2, "album") if !in_setlist => {
+                        album = Album::new();
+                        for (attr, val) in attributes {
+                            match attr.as_ref() {
+                                "title" => album.title = val,
+                                "tracks" => album.track_count = parse_num(attr, &val, &tag)?,
+                                _ => return Err(DatabaseError::InvalidAttribute((attr, tag))),
+                            };
+                        }
+                    }
+                    (3, "track") if !in_setlist => {
+                        track = Track::new();
+                        if index {
+                            track.lyrics_source = Some(count.get());
+                        }
+                        for (attr, val) in attributes {
+                            match attr.as_ref() {
+                                "name" => track.title = val,
+                                "num" => track.track = parse_num(attr, &val, &tag)?,
+                                _ => return Err(DatabaseError::InvalidAttribute((attr, tag))),
+                            };
+                        }
+                    }
+                    (4, "alternate") => {
+                        let mut alternate = Alternate {
+                            artist: String::new(),
+                            album: String::new(),
+                            title: String::new(),
+                        };
+                        for (attr, val) in attributes {
+                            match attr.as_ref() {
+                                "artist" => alternate.artist = val,
+                                "album" => alternate.album = val,
+                                "title" => alternate.title = val,
+                                _ => return Err(DatabaseError::InvalidAttribute((attr, tag))),
+                            };
+                        }
+                        track.alternates.push(alternate);
+                    }
+                    _ => return Err(DatabaseError::InvalidTag(tag)),
+                }
+                depth += 1;
+            }
+            XmlEvent::EndElement { .. } => {
+                depth -= 1;
+                match depth {
+                    3 => {
+                        album.tracks.push(track);
+                        track = Track::new();
+                    }
+                    2 if !in_setlist => {
+                        album.tracks.sort_by(|a, b| a.track.cmp(&b.track));
+                        artist.albums.push(album);
+                        album = Album::new();
+                    }
+                    1 if in_setlist => {
+                        setlists.push(setlist);
+                        setlist = Setlist::new();
+                    }
+                    1 => {
+                        entries.push(artist);
+                        artist = Artist::new();
+                        reported = count.get();
+                        report(&mut progress, reported, total_bytes, entries.len())?;
+                    }
+                    _ => (),
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if depth == 4 && !index {
+                    track.lyrics.push_str(&text);
+                }
+            }
+            _ => (),
+        }
+        if count.get() - reported >= PROGRESS_BYTES {
+            reported = count.get();
+            report(&mut progress, reported, total_bytes, entries.len())?;
+        }
+    }
+
+    if !has_root {
+        return Err(DatabaseError::Empty);
+    }
+    Ok((entries, setlists))
+}
+
+//Escape an attribute value. Whitespace other than spaces is escaped as well, parsers would
+//turn it into spaces otherwise.
+fn escape_attribute(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+//Wrap lyrics in CDATA, splitting it where the lyrics contain its end marker
+fn cdata(text: &str) -> String {
+    format!("<![CDATA[{}]]>", text.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+//Write a database in the canonical layout, so that the same database always gives the same
+//bytes and a change to a track only changes the lines of that track. Attributes are written
+//in a fixed order, each element start is on a line of its own indented by two spaces per level
+//and lyrics go in CDATA after the track's alternates, keeping their line breaks. Nothing can
+//follow the lyrics before the track ends, parsers would add it to them. Setlists come after
+//the artists. All lyrics must be loaded.
+pub fn write<W: Write>(
+    out: W,
+    entries: &[Artist],
+    setlists: &[Setlist],
+) -> Result<(), DatabaseError> {
+    write_from(out, entries, setlists, &mut |_| {
+        Err(DatabaseError::LyricsNotLoaded)
+    })
+}
+
+//Like `write`, lyrics left in a storage are fetched by `stored` with the track's
+//`lyrics_source` as each track is written
+pub fn write_from<W: Write>(
+    out: W,
+    entries: &[Artist],
+    setlists: &[Setlist],
+    stored: &mut dyn FnMut(u64) -> Result<String, DatabaseError>,
+) -> Result<(), DatabaseError> {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"utf-8\"?>")?;
+    writeln!(out, "<database version=\"{}\">", CURRENT_VERSION)?;
+    for artist in entries {
+        writeln!(
+            out,
+            "  <artist name=\"{}\">",
+            escape_attribute(&artist.name)
+        )?;
+        for album in &artist.albums {
+            writeln!(
+                out,
+                "    <album title=\"{}\" tracks=\"{}\">",
+                escape_attribute(&album.title),
+                album.track_count
+            )?;
+            for track in &album.tracks {
+                let lyrics = match track.lyrics_source {
+                    Some(source) => Cow::Owned(stored(source)?),
+                    None => Cow::Borrowed(track.lyrics.as_str()),
+                };
+                let start = format!(
+                    "      <track num=\"{}\" name=\"{}\"",
+                    track.track,
+                    escape_attribute(&track.title)
+                );
+                //Never self-closing, lyrics are found again right after the start tag
+                if lyrics.is_empty() && track.alternates.is_empty() {
+                    writeln!(out, "{}></track>", start)?;
+                    continue;
+                }
+                writeln!(out, "{}>", start)?;
+                for alternate in &track.alternates {
+                    writeln!(
+                        out,
+                        "        <alternate artist=\"{}\" album=\"{}\" title=\"{}\"/>",
+                        escape_attribute(&alternate.artist),
+                        escape_attribute(&alternate.album),
+                        escape_attribute(&alternate.title)
+                    )?;
+                }
+                writeln!(out, "        {}</track>", cdata(&lyrics))?;
+            }
+            writeln!(out, "    </album>")?;
+        }
+        writeln!(out, "  </artist>")?;
+    }
+    for setlist in setlists {
+        writeln!(
+            out,
+            "  <setlist name=\"{}\">",
+            escape_attribute(&setlist.name)
+        )?;
+        for entry in &setlist.entries {
+            write!(
+                out,
+                "    <entry artist=\"{}\" album=\"{}\" title=\"{}\"",
+                escape_attribute(&entry.artist),
+                escape_attribute(&entry.album),
+                escape_attribute(&entry.title)
+            )?;
+            if !entry.key.is_empty() {
+                write!(out, " key=\"{}\"", escape_attribute(&entry.key))?;
+            }
+            if let Some(tempo) = entry.tempo {
+                write!(out, " tempo=\"{}\"", tempo)?;
+            }
+            if !entry.notes.is_empty() {
+                write!(out, " notes=\"{}\"", escape_attribute(&entry.notes))?;
+            }
+            writeln!(out, "/>")?;
+        }
+        writeln!(out, "  </setlist>")?;
+    }
+    writeln!(out, "</database>")?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::{entries, setlist, track};
+
+    fn canonical(xml: &str) -> String {
+        let (entries, setlists) = read(xml.as_bytes(), 0, |_| true).unwrap();
+        written(&entries, &setlists)
+    }
+
+    fn written(entries: &[Artist], setlists: &[Setlist]) -> String {
+        let mut out = Vec::new();
+        write(&mut out, entries, setlists).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn canonical_output_is_written_again_byte_for_byte() {
+        let mut entries = entries(
+            "Artist",
+            "Album",
+            vec![
+                track("One", 1, "red sky\n\nblue sea\n"),
+                track("Two", 2, ""),
+            ],
+        );
+        entries[0].albums[0].tracks[1].alternates.push(Alternate {
+            artist: "Other".to_owned(),
+            album: "Live".to_owned(),
+            title: "Two (live)".to_owned(),
+        });
+        let mut setlists = vec![setlist("Friday", &["Two", "One"])];
+        setlists[0].entries[0].key = "G".to_owned();
+        setlists[0].entries[0].tempo = Some(120);
+        setlists[0].entries[1].notes = "slow".to_owned();
+
+        let first = written(&entries, &setlists);
+        assert_eq!(canonical(&first), first);
+        assert_eq!(canonical(&canonical(&first)), first);
+    }
+
+    #[test]
+    fn attributes_are_written_in_a_fixed_order() {
+        let xml = canonical(
+            "<database version=\"1\"><artist name=\"A\"><album tracks=\"1\" title=\"B\">\
+             <track name=\"C\" num=\"1\"><alternate title=\"F\" album=\"E\" artist=\"D\"/>\
+             </track></album></artist><setlist name=\"S\"><entry notes=\"n\" tempo=\"90\" \
+             key=\"G\" title=\"C\" album=\"B\" artist=\"A\"/></setlist></database>",
+        );
+        assert!(xml.contains("<album title=\"B\" tracks=\"1\">"));
+        assert!(xml.contains("<track num=\"1\" name=\"C\">"));
+        assert!(xml.contains("<alternate artist=\"D\" album=\"E\" title=\"F\"/>"));
+        assert!(xml.contains(
+            "<entry artist=\"A\" album=\"B\" title=\"C\" key=\"G\" tempo=\"90\" notes=\"n\"/>"
+        ));
+    }
+
+    #[test]
+    fn cdata_end_markers_in_lyrics_are_split() {
+        let lyrics = "a]]>b]]]>c";
+        let xml = written(&entries("A", "B", vec![track("C", 1, lyrics)]), &[]);
+        assert!(xml.contains("<![CDATA[a]]]]><![CDATA[>b]]]]]><![CDATA[>c]]></track>"));
+        let (read_back, _) = read(xml.as_bytes(), 0, |_| true).unwrap();
+        assert_eq!(read_back[0].albums[0].tracks[0].lyrics, lyrics);
+    }
+
+    #[test]
+    fn quotes_and_line_breaks_in_attributes_are_escaped() {
+        let title = "say \"hi\"\n\tto <you> & me\r";
+        let xml = written(&entries("A", "B", vec![track(title, 1, "")]), &[]);
+        assert!(xml.contains("name=\"say &quot;hi&quot;&#10;&#9;to &lt;you&gt; &amp; me&#13;\""));
+        let (read_back, _) = read(xml.as_bytes(), 0, |_| true).unwrap();
+        assert_eq!(read_back[0].albums[0].tracks[0].title, title);
+    }
+}
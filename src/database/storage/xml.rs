@@ -0,0 +1,185 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::io::{Read, Seek, SeekFrom};
+
+use xml::reader::EventReader;
+
+use super::container::{self, Format};
+use super::migrate;
+use super::{stream, Progress, Storage};
+use database::metadata::*;
+use database::watch::FileState;
+use database::DatabaseError;
+
+//The whole database as one XML file, streamed in and out element by element. The file can be
+//compressed and encrypted, it is saved the way it was found.
+pub struct XmlStorage {
+    path: String,
+    passphrase: Option<String>,
+    format: Format,
+    //The file the lyrics offsets point into
+    indexed: Option<Indexed>,
+    //Found by the last load, they are read along with the artists
+    setlists: Option<Vec<Setlist>>,
+}
+
+//The file from the last `load_index`, held open so its lyrics can still be read after
+//another program moved a new file in its place
+struct Indexed {
+    file: File,
+    state: Option<FileState>,
+}
+
+impl XmlStorage {
+    pub fn new(path: &str, passphrase: Option<&str>) -> XmlStorage {
+        XmlStorage {
+            path: path.to_owned(),
+            passphrase: passphrase.map(str::to_owned),
+            format: Format::for_path(path, passphrase.is_some()),
+            indexed: None,
+            setlists: None,
+        }
+    }
+
+    fn open(&mut self) -> Result<Box<dyn Read>, DatabaseError> {
+        let (reader, format) =
+            container::open(&self.path, self.passphrase.as_ref().map(String::as_str))?;
+        self.format = format;
+        Ok(reader)
+    }
+}
+
+impl Storage for XmlStorage {
+    fn load(
+        &mut self,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<Vec<Artist>, DatabaseError> {
+        let reader = self.open()?;
+        //Progress is counted in unpacked bytes, the size on disk only fits plain files
+        let total_bytes = if self.format == Format::PLAIN {
+            fs::metadata(&self.path)?.len()
+        } else {
+            0
+        };
+        let (entries, setlists) = stream::read(reader, total_bytes, progress)?;
+        self.setlists = Some(setlists);
+        Ok(entries)
+    }
+
+    fn load_index(
+        &mut self,
+        progress: &mut dyn FnMut(&Progress) -> bool,
+    ) -> Result<Vec<Artist>, DatabaseError> {
+        self.indexed = None;
+        let reader = self.open()?;
+        //Lyrics can only be found again by their offset in plain files
+        let (entries, setlists) = if self.format != Format::PLAIN {
+            stream::read(reader, 0, progress)?
+        } else {
+            let mut file = File::open(&self.path)?;
+            //Newer documents are refused before the file is held on to
+            migrate::read_version(EventReader::new(BufReader::new(&mut file)))?;
+            file.seek(SeekFrom::Start(0))?;
+            let state = FileState::of_file(&file);
+            let total_bytes = file.metadata()?.len();
+            //Read through the held file, so the offsets are sure to point into it
+            let index = stream::read_index(file.try_clone()?, total_bytes, progress)?;
+            self.indexed = Some(Indexed { file, state });
+            index
+        };
+        self.setlists = Some(setlists);
+        Ok(entries)
+    }
+
+    //Sources are byte offsets into the file
+    fn load_lyrics(&mut self, sources: &[u64]) -> Result<Vec<String>, DatabaseError> {
+        let indexed = match self.indexed {
+            Some(ref mut indexed) => indexed,
+            None => return Err(DatabaseError::ChangedOnDisk),
+        };
+        //The offsets mean nothing once someone else wrote into the file itself
+        if FileState::of_file(&indexed.file) != indexed.state {
+            return Err(DatabaseError::ChangedOnDisk);
+        }
+        let mut lyrics = Vec::with_capacity(sources.len());
+        for &offset in sources {
+            indexed.file.seek(SeekFrom::Start(offset))?;
+            lyrics.push(stream::read_lyrics(BufReader::new(&mut indexed.file))?);
+        }
+        Ok(lyrics)
+    }
+
+    fn load_setlists(&mut self) -> Result<Vec<Setlist>, DatabaseError> {
+        if let Some(ref setlists) = self.setlists {
+            return Ok(setlists.clone());
+        }
+        //Skipping the lyrics is the quickest way through the file
+        let (_, setlists) = stream::read_index(self.open()?, 0, |_| true)?;
+        self.setlists = Some(setlists.clone());
+        Ok(setlists)
+    }
+
+    fn save_from(
+        &mut self,
+        entries: &[Artist],
+        setlists: &[Setlist],
+        stored: &mut dyn FnMut(u64) -> Result<String, DatabaseError>,
+    ) -> Result<(), DatabaseError> {
+        let passphrase = self.passphrase.as_ref().map(String::as_str);
+        container::save(&self.path, self.format, passphrase, |out| {
+            stream::write_from(out, entries, setlists, stored)
+        })?;
+        //The saved entries don't point into the file, there is nothing left to read from it
+        self.indexed = None;
+        self.setlists = Some(setlists.to_vec());
+        Ok(())
+    }
+
+    //There is no index, the lyrics are read and searched by the database
+    fn search(&mut self, _: &str) -> Result<Option<Vec<u64>>, DatabaseError> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::*;
+
+    #[test]
+    fn progress_is_reported_within_big_artists() {
+        let lyrics = "a line of lyrics that goes on for a while\n".repeat(2000);
+        let tracks = (0..4)
+            .map(|k| track(&format!("Track {}", k), k as u8 + 1, &lyrics))
+            .collect::<Vec<_>>();
+        let path = temp_path("big.xml");
+        let mut storage = XmlStorage::new(&path, None);
+        storage
+            .save(&entries("Artist", "Album", tracks), &[])
+            .unwrap();
+
+        let mut reports = Vec::new();
+        let loaded = storage
+            .load(&mut |progress| {
+                reports.push((progress.bytes, progress.artists));
+                true
+            })
+            .unwrap();
+        assert_eq!(loaded.len(), 1);
+        let total_bytes = fs::metadata(&path).unwrap().len();
+        assert!(reports.len() > 4, "only {} reports", reports.len());
+        assert!(reports.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(reports.iter().all(|&(bytes, _)| bytes <= total_bytes));
+        assert_eq!(
+            reports.iter().filter(|&&(_, artists)| artists == 1).count(),
+            1
+        );
+
+        //Stopping in the middle of the artist stops the reading
+        match storage.load(&mut |_| false) {
+            Err(DatabaseError::Cancelled) => (),
+            other => panic!("expected the load to stop, got {:?}", other.map(|_| ())),
+        }
+        remove_dir(&path);
+    }
+}
@@ -0,0 +1,56 @@
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::metadata::*;
+
+static NEXT_PATH: AtomicUsize = AtomicUsize::new(0);
+
+//A path in a fresh directory of its own, removed with `remove_dir`
+pub fn temp_path(name: &str) -> String {
+    let n = NEXT_PATH.fetch_add(1, Ordering::SeqCst);
+    let dir = env::temp_dir().join(format!("lyrics-test-{}-{}", process::id(), n));
+    fs::create_dir_all(&dir).unwrap();
+    dir.join(name).to_string_lossy().into_owned()
+}
+
+pub fn remove_dir(path: &str) {
+    if let Some(dir) = ::std::path::Path::new(path).parent() {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+pub fn track(title: &str, num: u8, lyrics: &str) -> Track {
+    Track {
+        title: title.to_owned(),
+        lyrics: lyrics.to_owned(),
+        track: num,
+        alternates: Vec::new(),
+        lyrics_source: None,
+    }
+}
+
+//One artist with one album of `tracks`
+pub fn entries(artist: &str, album: &str, tracks: Vec<Track>) -> Vec<Artist> {
+    let mut new_album = Album::new();
+    new_album.title = album.to_owned();
+    new_album.track_count = tracks.len() as u8;
+    new_album.tracks = tracks;
+    let mut new_artist = Artist::new();
+    new_artist.name = artist.to_owned();
+    new_artist.albums.push(new_album);
+    vec![new_artist]
+}
+
+//A setlist of tracks of the artist and album made by `entries`, given by title
+pub fn setlist(name: &str, titles: &[&str]) -> Setlist {
+    let mut setlist = Setlist::new();
+    setlist.name = name.to_owned();
+    for title in titles {
+        setlist
+            .entries
+            .push(SetlistEntry::new("Artist", "Album", title));
+    }
+    setlist
+}
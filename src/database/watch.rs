@@ -0,0 +1,132 @@
+use std::fs::{self, File, Metadata};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use inotify::{Inotify, WatchMask};
+
+use super::metadata::{Artist, Setlist};
+use super::{Database, DatabaseError};
+
+//What the file held before the unsaved edits, to merge against if it changes on disk
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub entries: Vec<Artist>,
+    pub setlists: Vec<Setlist>,
+}
+
+//How long the watching thread sleeps between looking for events
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+//What a file looked like when it was last read or written, to tell whether someone else wrote
+//it since
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileState {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileState {
+    //None if the file can't be looked at
+    pub fn of(path: &str) -> Option<FileState> {
+        FileState::from_metadata(fs::metadata(path).ok()?)
+    }
+
+    //Of an open file, which stays the same file when another one is moved to its path
+    pub fn of_file(file: &File) -> Option<FileState> {
+        FileState::from_metadata(file.metadata().ok()?)
+    }
+
+    fn from_metadata(meta: Metadata) -> Option<FileState> {
+        Some(FileState {
+            modified: meta.modified().ok()?,
+            len: meta.len(),
+        })
+    }
+}
+
+//Notices when a database file is written or replaced by another program. Watching stops when
+//this is dropped.
+pub struct FileWatcher {
+    stop: Arc<AtomicBool>,
+}
+
+impl FileWatcher {
+    //Call `changed` from another thread whenever the file at `path` has been written
+    pub fn new<F>(path: &str, changed: F) -> Result<FileWatcher, DatabaseError>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let path = Path::new(path);
+        let name = path.file_name().map(|name| name.to_owned());
+        //Watch the directory, programs often write a new file and move it over the old one
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut inotify = Inotify::init()?;
+        inotify.add_watch(
+            dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+        )?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            while !stopped.load(Ordering::SeqCst) {
+                let events = match inotify.read_events(&mut buffer) {
+                    Ok(events) => events,
+                    Err(_) => return,
+                };
+                if events
+                    .into_iter()
+                    .any(|event| event.name == name.as_ref().map(|n| n.as_os_str()))
+                {
+                    changed();
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+        Ok(FileWatcher { stop })
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Database {
+    //Whether the file was written by someone else since it was opened or saved
+    pub fn changed_on_disk(&self) -> bool {
+        !self.file_path.is_empty() && FileState::of(&self.file_path) != self.disk_state
+    }
+
+    //Stop reporting the file as changed, the entries in memory are kept as they are
+    pub fn accept_disk_state(&mut self) {
+        self.disk_state = FileState::of(&self.file_path);
+    }
+
+    //Keep the entries in memory over what another program wrote to the file, the next save
+    //overwrites it. Lyrics left in the storage are read first, from the file that was opened,
+    //which fails if it was written over rather than replaced.
+    pub fn keep_mine(&mut self) -> Result<(), DatabaseError> {
+        self.load_all_lyrics()?;
+        self.accept_disk_state();
+        Ok(())
+    }
+
+    //A copy of all entries and setlists to merge against if the file changes on disk. Lyrics
+    //left in the storage stay there, `load_lyrics_of` reads them when the merge needs them.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            entries: self.entries.clone(),
+            setlists: self.setlists.clone(),
+        }
+    }
+}
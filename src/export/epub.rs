@@ -0,0 +1,657 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::digest;
+
+use super::zip::ZipWriter;
+use super::{escape, verses, ExportError, Song};
+
+const DEFAULT_TITLE: &str = "Songbook";
+const DEFAULT_LANGUAGE: &str = "en";
+//More artists than this and the book is by various artists
+const MAX_AUTHORS: usize = 3;
+
+const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="EPUB/package.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+const STYLE: &str = "body {
+    font-family: serif;
+    line-height: 1.4;
+}
+h1 {
+    margin-bottom: 0.2em;
+}
+.source, .author {
+    margin-top: 0;
+    font-style: italic;
+}
+.lyrics p {
+    margin: 0 0 1em 0;
+    text-indent: 0;
+}
+.contents ol {
+    list-style: none;
+    padding-left: 1em;
+}
+.cover {
+    margin: 0;
+    padding: 0;
+    text-align: center;
+}
+.cover img {
+    max-width: 100%;
+    max-height: 100%;
+}
+";
+
+pub struct EpubSettings {
+    pub title: String,
+    //The artists of the book if none is given
+    pub author: Option<String>,
+    //BCP 47 tag of the language of the lyrics
+    pub language: String,
+    //JPEG, PNG, GIF or SVG image shown before the contents
+    pub cover: Option<PathBuf>,
+}
+
+impl EpubSettings {
+    pub fn new() -> EpubSettings {
+        EpubSettings {
+            title: DEFAULT_TITLE.to_owned(),
+            author: None,
+            language: DEFAULT_LANGUAGE.to_owned(),
+            cover: None,
+        }
+    }
+}
+
+//Escape text for XHTML, leaving out the control characters XML doesn't allow
+fn xml(text: &str) -> String {
+    escape(text)
+        .chars()
+        .filter(|&c| c >= ' ' || c == '\t' || c == '\n')
+        .collect()
+}
+
+//Names and titles on one line
+fn one_line(text: &str) -> String {
+    xml(&text.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+//Links need some text to click on
+fn name(text: &str, fallback: &str) -> String {
+    if text.trim().is_empty() {
+        fallback.to_owned()
+    } else {
+        one_line(text)
+    }
+}
+
+fn media_type(path: &Path) -> Result<&'static str, ExportError> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_ref().map(String::as_str) {
+        Some("jpg") | Some("jpeg") => Ok("image/jpeg"),
+        Some("png") => Ok("image/png"),
+        Some("gif") => Ok("image/gif"),
+        Some("svg") => Ok("image/svg+xml"),
+        _ => Err(ExportError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the cover must be a JPEG, PNG, GIF or SVG image",
+        ))),
+    }
+}
+
+//(year, month, day, hour, minute, second) in UTC
+fn now() -> (u32, u32, u32, u32, u32, u32) {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    //Days to a civil date, with years starting in March so leap days come last
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year as u32,
+        month as u32,
+        day as u32,
+        (time / 3600) as u32,
+        (time / 60 % 60) as u32,
+        (time % 60) as u32,
+    )
+}
+
+//The same book gets the same identifier every time it is made, so that readers see a new
+//version of it instead of another book
+fn identifier(songs: &[Song], settings: &EpubSettings) -> String {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(settings.title.as_bytes());
+    for song in songs {
+        for part in &[&song.artist, &song.album, &song.title] {
+            context.update(&[0]);
+            context.update(part.as_bytes());
+        }
+    }
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&context.finish().as_ref()[..16]);
+    //A UUID of version 8, whose bits are ours to choose. Name based ones of version 5 are made
+    //with SHA-1.
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        hex[..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..].concat()
+    )
+}
+
+fn author(songs: &[Song], settings: &EpubSettings) -> String {
+    if let Some(ref author) = settings.author {
+        return author.clone();
+    }
+    let mut artists: Vec<&str> = Vec::new();
+    for song in songs {
+        if !artists.contains(&song.artist.as_str()) {
+            artists.push(&song.artist);
+        }
+    }
+    if artists.len() > MAX_AUTHORS {
+        "Various artists".to_owned()
+    } else {
+        artists.join(", ")
+    }
+}
+
+fn chapter_file(index: usize) -> String {
+    format!("track{}.xhtml", index + 1)
+}
+
+//An XHTML document with the style sheet
+fn document(title: &str, language: &str, body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{language}" xml:lang="{language}">
+<head>
+<meta charset="UTF-8"/>
+<title>{title}</title>
+<link rel="stylesheet" type="text/css" href="style.css"/>
+</head>
+<body>
+{body}</body>
+</html>
+"#,
+        language = xml(language),
+        title = title,
+        body = body
+    )
+}
+
+fn chapter(song: &Song, language: &str) -> String {
+    let title = name(&song.title, "Untitled");
+    let mut body = format!(
+        "<section epub:type=\"chapter\">\n<h1>{}</h1>\n<p class=\"source\">{} — {}</p>\n\
+         <div class=\"lyrics\">\n",
+        title,
+        one_line(&song.artist),
+        one_line(&song.album)
+    );
+    for verse in verses(&song.lyrics) {
+        let lines: Vec<String> = verse.lines().map(xml).collect();
+        body.push_str(&format!("<p>{}</p>\n", lines.join("<br/>\n")));
+    }
+    body.push_str("</div>\n</section>\n");
+    document(&title, language, &body)
+}
+
+//Songs grouped by artist and then album as they follow each other, as indices into the songs
+fn groups(songs: &[Song]) -> Vec<(usize, Vec<(usize, Vec<usize>)>)> {
+    let mut artists: Vec<(usize, Vec<(usize, Vec<usize>)>)> = Vec::new();
+    for (index, song) in songs.iter().enumerate() {
+        let same_artist = artists
+            .last()
+            .map_or(false, |&(first, _)| songs[first].artist == song.artist);
+        if !same_artist {
+            artists.push((index, Vec::new()));
+        }
+        let albums = &mut artists.last_mut().unwrap().1;
+        let same_album = albums
+            .last()
+            .map_or(false, |&(first, _)| songs[first].album == song.album);
+        if !same_album {
+            albums.push((index, Vec::new()));
+        }
+        albums.last_mut().unwrap().1.push(index);
+    }
+    artists
+}
+
+//The title page, with the table of contents that readers navigate the book by: artists,
+//their albums and the tracks on them, each leading to the first track below it
+fn nav(songs: &[Song], title: &str, author: &str, language: &str) -> String {
+    let mut body = format!(
+        "<h1>{}</h1>\n<p class=\"author\">{}</p>\n\
+         <nav epub:type=\"toc\" id=\"toc\" class=\"contents\">\n<h2>Contents</h2>\n<ol>\n",
+        one_line(title),
+        one_line(author)
+    );
+    for (first, albums) in groups(songs) {
+        body.push_str(&format!(
+            "<li><a href=\"{}\">{}</a>\n<ol>\n",
+            chapter_file(first),
+            name(&songs[first].artist, "Unknown artist")
+        ));
+        for (first, tracks) in albums {
+            body.push_str(&format!(
+                "<li><a href=\"{}\">{}</a>\n<ol>\n",
+                chapter_file(first),
+                name(&songs[first].album, "Unknown album")
+            ));
+            for index in tracks {
+                body.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    chapter_file(index),
+                    name(&songs[index].title, "Untitled")
+                ));
+            }
+            body.push_str("</ol>\n</li>\n");
+        }
+        body.push_str("</ol>\n</li>\n");
+    }
+    body.push_str("</ol>\n</nav>\n<nav epub:type=\"landmarks\" hidden=\"hidden\">\n<ol>\n");
+    body.push_str("<li><a epub:type=\"toc\" href=\"#toc\">Contents</a></li>\n");
+    if !songs.is_empty() {
+        body.push_str(&format!(
+            "<li><a epub:type=\"bodymatter\" href=\"{}\">Lyrics</a></li>\n",
+            chapter_file(0)
+        ));
+    }
+    body.push_str("</ol>\n</nav>\n");
+    document(&one_line(title), language, &body)
+}
+
+//The same contents for EPUB 2 readers, after the contents page itself. Entries leading to the
+//same track share its place in the reading order.
+fn ncx(songs: &[Song], identifier: &str, title: &str) -> String {
+    let mut ids = 0..;
+    let mut point = |out: &mut String, label: String, index: usize| {
+        out.push_str(&format!(
+            "<navPoint id=\"point{}\" playOrder=\"{}\">\n<navLabel><text>{}</text></navLabel>\n\
+             <content src=\"{}\"/>\n",
+            ids.next().unwrap_or(0),
+            index + 2,
+            label,
+            chapter_file(index)
+        ));
+    };
+    let mut map = String::from(
+        "<navPoint id=\"contents\" playOrder=\"1\">\n\
+         <navLabel><text>Contents</text></navLabel>\n<content src=\"nav.xhtml\"/>\n</navPoint>\n",
+    );
+    for (first, albums) in groups(songs) {
+        point(
+            &mut map,
+            name(&songs[first].artist, "Unknown artist"),
+            first,
+        );
+        for (first, tracks) in albums {
+            point(&mut map, name(&songs[first].album, "Unknown album"), first);
+            for index in tracks {
+                point(&mut map, name(&songs[index].title, "Untitled"), index);
+                map.push_str("</navPoint>\n");
+            }
+            map.push_str("</navPoint>\n");
+        }
+        map.push_str("</navPoint>\n");
+    }
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+<head>
+<meta name="dtb:uid" content="{}"/>
+<meta name="dtb:depth" content="3"/>
+<meta name="dtb:totalPageCount" content="0"/>
+<meta name="dtb:maxPageNumber" content="0"/>
+</head>
+<docTitle><text>{}</text></docTitle>
+<navMap>
+{}</navMap>
+</ncx>
+"#,
+        identifier,
+        one_line(title),
+        map
+    )
+}
+
+//The file naming everything in the book and the order it is read in
+fn package(
+    songs: &[Song],
+    settings: &EpubSettings,
+    identifier: &str,
+    author: &str,
+    modified: (u32, u32, u32, u32, u32, u32),
+    cover: Option<(&str, &str)>,
+) -> String {
+    let (year, month, day, hour, minute, second) = modified;
+    let mut metadata = format!(
+        "<dc:identifier id=\"uid\">{}</dc:identifier>\n<dc:title>{}</dc:title>\n\
+         <dc:language>{}</dc:language>\n\
+         <meta property=\"dcterms:modified\">{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z</meta>\n",
+        identifier,
+        one_line(&settings.title),
+        xml(&settings.language),
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second
+    );
+    if !author.is_empty() {
+        metadata.push_str(&format!("<dc:creator>{}</dc:creator>\n", one_line(author)));
+    }
+    let mut manifest = String::from(
+        "<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" \
+         properties=\"nav\"/>\n\
+         <item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\"/>\n\
+         <item id=\"style\" href=\"style.css\" media-type=\"text/css\"/>\n",
+    );
+    let mut spine = String::new();
+    if let Some((file, media_type)) = cover {
+        //For readers that only know the EPUB 2 way of finding the cover
+        metadata.push_str("<meta name=\"cover\" content=\"cover-image\"/>\n");
+        manifest.push_str(&format!(
+            "<item id=\"cover-image\" href=\"{}\" media-type=\"{}\" \
+             properties=\"cover-image\"/>\n\
+             <item id=\"cover\" href=\"cover.xhtml\" media-type=\"application/xhtml+xml\"/>\n",
+            file, media_type
+        ));
+        spine.push_str("<itemref idref=\"cover\"/>\n");
+    }
+    spine.push_str("<itemref idref=\"nav\"/>\n");
+    for index in 0..songs.len() {
+        manifest.push_str(&format!(
+            "<item id=\"track{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n",
+            index + 1,
+            chapter_file(index)
+        ));
+        spine.push_str(&format!("<itemref idref=\"track{}\"/>\n", index + 1));
+    }
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid" xml:lang="{}">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+{}</metadata>
+<manifest>
+{}</manifest>
+<spine toc="ncx">
+{}</spine>
+</package>
+"#,
+        xml(&settings.language),
+        metadata,
+        manifest,
+        spine
+    )
+}
+
+//Write `songs` as an EPUB 3 book with one chapter per track
+pub fn write_epub<P: AsRef<Path>>(
+    path: P,
+    songs: &[Song],
+    settings: &EpubSettings,
+) -> Result<(), ExportError> {
+    //Read the cover first so that a missing one doesn't leave half a book behind
+    let cover = match settings.cover {
+        Some(ref cover) => {
+            let media_type = media_type(cover)?;
+            let extension = cover.extension().unwrap_or_default().to_string_lossy();
+            let file = format!("cover.{}", extension.to_lowercase());
+            Some((file, media_type, fs::read(cover)?))
+        }
+        None => None,
+    };
+    let identifier = identifier(songs, settings);
+    let author = author(songs, settings);
+    let modified = now();
+    let language = &settings.language;
+
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?), modified);
+    //Readers recognize the book by this coming first, uncompressed
+    zip.store("mimetype", b"application/epub+zip")?;
+    zip.deflate("META-INF/container.xml", CONTAINER.as_bytes())?;
+    zip.deflate(
+        "EPUB/package.opf",
+        package(
+            songs,
+            settings,
+            &identifier,
+            &author,
+            modified,
+            cover
+                .as_ref()
+                .map(|(file, media_type, _)| (file.as_str(), *media_type)),
+        )
+        .as_bytes(),
+    )?;
+    zip.deflate(
+        "EPUB/nav.xhtml",
+        nav(songs, &settings.title, &author, language).as_bytes(),
+    )?;
+    zip.deflate(
+        "EPUB/toc.ncx",
+        ncx(songs, &identifier, &settings.title).as_bytes(),
+    )?;
+    zip.deflate("EPUB/style.css", STYLE.as_bytes())?;
+    if let Some((file, _, image)) = cover {
+        //Images are compressed already
+        zip.store(&format!("EPUB/{}", file), &image)?;
+        let body = format!(
+            "<section epub:type=\"cover\" class=\"cover\">\n<img src=\"{}\" alt=\"{}\"/>\n\
+             </section>\n",
+            file,
+            one_line(&settings.title)
+        );
+        zip.deflate(
+            "EPUB/cover.xhtml",
+            document("Cover", language, &body).as_bytes(),
+        )?;
+    }
+    for (index, song) in songs.iter().enumerate() {
+        zip.deflate(
+            &format!("EPUB/{}", chapter_file(index)),
+            chapter(song, language).as_bytes(),
+        )?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+    use xml::reader::{EventReader, XmlEvent};
+
+    use super::*;
+    use database::testing::*;
+
+    fn u16_at(data: &[u8], at: usize) -> usize {
+        data[at] as usize | (data[at + 1] as usize) << 8
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> usize {
+        u16_at(data, at) | u16_at(data, at + 2) << 16
+    }
+
+    //A file of the archive: its name, whether it is stored, where its data starts and the data
+    struct Unzipped {
+        name: String,
+        stored: bool,
+        offset: usize,
+        data: String,
+    }
+
+    //The files listed in the central directory, in order
+    fn unzip(zip: &[u8]) -> Vec<Unzipped> {
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(zip, end), 0x0605_4b50);
+        let mut at = u32_at(zip, end + 16);
+        let mut files = Vec::new();
+        for _ in 0..u16_at(zip, end + 10) {
+            assert_eq!(u32_at(zip, at), 0x0201_4b50);
+            let method = u16_at(zip, at + 10);
+            let compressed = u32_at(zip, at + 20);
+            let name_length = u16_at(zip, at + 28);
+            let header = u32_at(zip, at + 42);
+            let name = String::from_utf8(zip[at + 46..at + 46 + name_length].to_vec()).unwrap();
+            at += 46 + name_length;
+
+            let offset = header + 30 + u16_at(zip, header + 26) + u16_at(zip, header + 28);
+            let raw = &zip[offset..offset + compressed];
+            let mut data = Vec::new();
+            if method == 0 {
+                data.extend_from_slice(raw);
+            } else {
+                DeflateDecoder::new(raw).read_to_end(&mut data).unwrap();
+            }
+            files.push(Unzipped {
+                name,
+                stored: method == 0,
+                offset,
+                data: String::from_utf8_lossy(&data).into_owned(),
+            });
+        }
+        files
+    }
+
+    //Start elements named `name` with their attributes, the document has to be well-formed
+    fn elements(document: &str, name: &str) -> Vec<HashMap<String, String>> {
+        let mut found = Vec::new();
+        for event in EventReader::from_str(document) {
+            if let XmlEvent::StartElement {
+                name: element,
+                attributes,
+                ..
+            } = event.unwrap()
+            {
+                if element.local_name == name {
+                    found.push(
+                        attributes
+                            .into_iter()
+                            .map(|a| (a.name.local_name, a.value))
+                            .collect(),
+                    );
+                }
+            }
+        }
+        found
+    }
+
+    fn check_well_formed(document: &str) {
+        for event in EventReader::from_str(document) {
+            event.unwrap();
+        }
+    }
+
+    fn song(artist: &str, album: &str, title: &str, lyrics: &str) -> Song {
+        Song {
+            artist: artist.to_owned(),
+            album: album.to_owned(),
+            title: title.to_owned(),
+            lyrics: lyrics.to_owned(),
+        }
+    }
+
+    #[test]
+    fn book_is_a_valid_epub() {
+        let path = temp_path("book.epub");
+        let cover = format!("{}.png", path);
+        fs::write(&cover, b"not really a png").unwrap();
+        let mut settings = EpubSettings::new();
+        settings.title = "Songs & <more>".to_owned();
+        settings.cover = Some(PathBuf::from(&cover));
+        let songs = [
+            song("Artist", "Album", "One", "red sky\n\nblue sea"),
+            song("Artist", "Album", "", "no title \u{1}"),
+            song("Other", "", "Three", ""),
+        ];
+        write_epub(&path, &songs, &settings).unwrap();
+        let files = unzip(&fs::read(&path).unwrap());
+        let file = |name: &str| -> &str {
+            match files.iter().find(|f| f.name == name) {
+                Some(file) => &file.data,
+                None => panic!("{} is missing", name),
+            }
+        };
+
+        assert_eq!(files[0].name, "mimetype");
+        assert!(files[0].stored);
+        assert_eq!(files[0].offset, 38);
+        assert_eq!(files[0].data, "application/epub+zip");
+
+        let rootfiles = elements(file("META-INF/container.xml"), "rootfile");
+        assert_eq!(rootfiles.len(), 1);
+        assert_eq!(rootfiles[0]["full-path"], "EPUB/package.opf");
+
+        let package = file("EPUB/package.opf");
+        let items = elements(package, "item");
+        for item in &items {
+            file(&format!("EPUB/{}", item["href"]));
+        }
+        let ids: Vec<&String> = items.iter().map(|item| &item["id"]).collect();
+        let spine = elements(package, "itemref");
+        assert_eq!(spine.len(), 2 + songs.len());
+        for itemref in &spine {
+            assert!(ids.contains(&&itemref["idref"]));
+        }
+
+        for document in &["EPUB/nav.xhtml", "EPUB/toc.ncx", "EPUB/cover.xhtml"] {
+            check_well_formed(file(document));
+        }
+        for index in 0..songs.len() {
+            check_well_formed(file(&format!("EPUB/{}", chapter_file(index))));
+        }
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn identifier_is_a_version_8_uuid() {
+        let songs = [song("Artist", "Album", "One", "")];
+        let settings = EpubSettings::new();
+        let id = identifier(&songs, &settings);
+        assert_eq!(id, identifier(&songs, &settings));
+        let uuid = id.trim_start_matches("urn:uuid:");
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "8");
+        assert!("89ab".contains(&uuid[19..20]));
+    }
+}
@@ -0,0 +1,185 @@
+pub mod epub;
+pub mod presentation;
+pub mod setlist;
+pub mod site;
+pub mod songbook;
+pub mod template;
+mod zip;
+
+use std::convert;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use cairo;
+
+use database::{Database, DatabaseError};
+
+pub use self::songbook::SongbookSettings;
+
+//A track with everything needed to print it
+#[derive(Debug, Clone)]
+pub struct Song {
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+    pub lyrics: String,
+}
+
+//Which tracks of the database go into an export
+#[derive(Debug, Clone)]
+pub enum Selection {
+    All,
+    Artist(usize),
+    Album(usize, usize),
+    //(artist, album, track) in the order they are wanted
+    Tracks(Vec<(usize, usize, usize)>),
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    Database(DatabaseError),
+    Pdf(cairo::Status),
+    Template(String),
+}
+
+impl Error for ExportError {
+    fn description(&self) -> &str {
+        match self {
+            ExportError::Io(e) => e.description(),
+            ExportError::Database(e) => e.description(),
+            ExportError::Pdf(_) => "PDF could not be written",
+            ExportError::Template(_) => "Malformed template",
+        }
+    }
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::Io(e) => write!(f, "io error: {}", e),
+            ExportError::Database(e) => write!(f, "{}", e),
+            ExportError::Pdf(status) => write!(f, "pdf error: {:?}", status),
+            ExportError::Template(e) => write!(f, "template error: {}", e),
+        }
+    }
+}
+
+impl convert::From<io::Error> for ExportError {
+    fn from(err: io::Error) -> ExportError {
+        ExportError::Io(err)
+    }
+}
+
+impl convert::From<DatabaseError> for ExportError {
+    fn from(err: DatabaseError) -> ExportError {
+        ExportError::Database(err)
+    }
+}
+
+//Make text safe to put in Pango markup, HTML or XHTML
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+//Markdown takes anything at the start of a line as markup, and some characters anywhere.
+//Lyrics are passed a line at a time, names are kept on one line.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+    {
+        if "\\`*_[]<>#|&".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    //Lists
+    if escaped.starts_with('-') || escaped.starts_with('+') {
+        escaped.insert(0, '\\');
+    }
+    let digits = escaped.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0 && escaped[digits..].starts_with('.') {
+        escaped.insert(digits, '\\');
+    }
+    escaped
+}
+
+//Verses are separated by blank lines
+pub fn verses(lyrics: &str) -> Vec<String> {
+    let mut verses = Vec::new();
+    let mut verse: Vec<&str> = Vec::new();
+    for line in lyrics.lines().map(str::trim_right) {
+        if !line.trim().is_empty() {
+            verse.push(line);
+        } else if !verse.is_empty() {
+            verses.push(verse.join("\n"));
+            verse.clear();
+        }
+    }
+    if !verse.is_empty() {
+        verses.push(verse.join("\n"));
+    }
+    verses
+}
+
+impl Selection {
+    //(artist, album, track) of every track selected, tracks that don't exist are left out
+    pub fn tracks(&self, db: &Database) -> Vec<(usize, usize, usize)> {
+        let album_tracks = |i: usize, j: usize| {
+            let count = db
+                .entries
+                .get(i)
+                .and_then(|artist| artist.albums.get(j))
+                .map_or(0, |album| album.tracks.len());
+            (0..count).map(move |k| (i, j, k))
+        };
+        match self {
+            Selection::All => (0..db.entries.len())
+                .flat_map(|i| Selection::Artist(i).tracks(db))
+                .collect(),
+            Selection::Artist(i) => {
+                let albums = db.entries.get(*i).map_or(0, |artist| artist.albums.len());
+                (0..albums).flat_map(|j| album_tracks(*i, j)).collect()
+            }
+            Selection::Album(i, j) => album_tracks(*i, *j).collect(),
+            Selection::Tracks(tracks) => tracks
+                .iter()
+                .cloned()
+                .filter(|&(i, j, k)| {
+                    db.entries
+                        .get(i)
+                        .and_then(|artist| artist.albums.get(j))
+                        .map_or(false, |album| k < album.tracks.len())
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Database {
+    //The selected tracks with their lyrics, read from the storage if they haven't been yet
+    pub fn songs(&mut self, selection: &Selection) -> Result<Vec<Song>, DatabaseError> {
+        let mut songs = Vec::new();
+        for index in selection.tracks(self) {
+            let lyrics = self.lyrics(index)?.unwrap_or_default();
+            let (i, j, k) = index;
+            let artist = &self.entries[i];
+            let album = &artist.albums[j];
+            songs.push(Song {
+                artist: artist.name.clone(),
+                album: album.title.clone(),
+                title: album.tracks[k].title.clone(),
+                lyrics,
+            });
+        }
+        Ok(songs)
+    }
+}
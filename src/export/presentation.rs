@@ -0,0 +1,97 @@
+use super::verses;
+
+//Used until another font is picked, sizes are in points
+const DEFAULT_FONT: &str = "Sans Bold 56";
+const DEFAULT_SIZE: f64 = 56.0;
+
+//How much of the lyrics is shown at once
+#[derive(Clone, Copy, PartialEq)]
+pub enum Unit {
+    Verse,
+    Line,
+}
+
+#[derive(Clone)]
+pub struct PresentationSettings {
+    //Pango font description, like "Sans Bold 56"
+    pub font: String,
+    pub unit: Unit,
+}
+
+impl PresentationSettings {
+    pub fn new() -> PresentationSettings {
+        PresentationSettings {
+            font: DEFAULT_FONT.to_owned(),
+            unit: Unit::Verse,
+        }
+    }
+}
+
+//Split the lyrics into what is shown at once, after a first slide with the title
+pub fn slides(title: &str, lyrics: &str, unit: Unit) -> Vec<String> {
+    let mut slides = vec![title.to_owned()];
+    match unit {
+        Unit::Line => slides.extend(
+            lyrics
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned),
+        ),
+        Unit::Verse => slides.extend(verses(lyrics)),
+    }
+    slides
+}
+
+//The family and style of a font description, and its size
+pub fn split_font(font: &str) -> (String, f64) {
+    let mut parts = font.trim().rsplitn(2, ' ');
+    match (
+        parts.next().and_then(|size| size.parse().ok()),
+        parts.next(),
+    ) {
+        (Some(size), Some(family)) => (family.to_owned(), size),
+        _ => (font.to_owned(), DEFAULT_SIZE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LYRICS: &str = "\n  red sky  \nblue sea\n \n\n  green grass\n\n";
+
+    #[test]
+    fn verses_are_split_at_blank_lines() {
+        assert_eq!(
+            slides("One", LYRICS, Unit::Verse),
+            ["One", "  red sky\nblue sea", "  green grass"]
+        );
+    }
+
+    #[test]
+    fn lines_are_shown_one_at_a_time() {
+        assert_eq!(
+            slides("One", LYRICS, Unit::Line),
+            ["One", "red sky", "blue sea", "green grass"]
+        );
+    }
+
+    #[test]
+    fn tracks_without_lyrics_only_show_the_title() {
+        assert_eq!(slides("One", "", Unit::Verse), ["One"]);
+        assert_eq!(slides("One", " \n\n", Unit::Line), ["One"]);
+    }
+
+    #[test]
+    fn fonts_are_split_into_family_and_size() {
+        assert_eq!(split_font("Sans Bold 56"), ("Sans Bold".to_owned(), 56.0));
+        assert_eq!(split_font(" Serif 12.5 "), ("Serif".to_owned(), 12.5));
+        //Without a size the default is taken
+        assert_eq!(
+            split_font("Sans Bold"),
+            ("Sans Bold".to_owned(), DEFAULT_SIZE)
+        );
+        assert_eq!(split_font("56"), ("56".to_owned(), DEFAULT_SIZE));
+    }
+}
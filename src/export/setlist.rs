@@ -0,0 +1,218 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use database::metadata::{Setlist, SetlistEntry};
+use database::{Database, DatabaseError};
+
+use super::{escape_markdown, verses, ExportError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SheetFormat {
+    Text,
+    Markdown,
+}
+
+impl SheetFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            SheetFormat::Text => "txt",
+            SheetFormat::Markdown => "md",
+        }
+    }
+
+    //Markdown for files ending in .md or .markdown, plain text for anything else
+    pub fn for_path<P: AsRef<Path>>(path: P) -> SheetFormat {
+        let extension = path
+            .as_ref()
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_ref().map(String::as_str) {
+            Some("md") | Some("markdown") => SheetFormat::Markdown,
+            _ => SheetFormat::Text,
+        }
+    }
+}
+
+fn one_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+//Key and tempo of an entry, empty if neither is known
+fn playing(entry: &SetlistEntry) -> String {
+    let mut parts = Vec::new();
+    if !entry.key.trim().is_empty() {
+        parts.push(format!("Key: {}", one_line(&entry.key)));
+    }
+    if let Some(tempo) = entry.tempo {
+        parts.push(format!("{} bpm", tempo));
+    }
+    parts.join(", ")
+}
+
+fn text_entry(sheet: &mut String, number: usize, entry: &SetlistEntry, lyrics: Option<&str>) {
+    sheet.push_str(&format!("{}. {}\n", number, one_line(&entry.title)));
+    sheet.push_str(&format!(
+        "   {} — {}\n",
+        one_line(&entry.artist),
+        one_line(&entry.album)
+    ));
+    let playing = playing(entry);
+    if !playing.is_empty() {
+        sheet.push_str(&format!("   {}\n", playing));
+    }
+    for line in entry.notes.lines().filter(|line| !line.trim().is_empty()) {
+        sheet.push_str(&format!("   {}\n", line.trim()));
+    }
+    sheet.push('\n');
+    match lyrics {
+        Some(lyrics) => {
+            for verse in verses(lyrics) {
+                sheet.push_str(&verse);
+                sheet.push_str("\n\n");
+            }
+        }
+        None => sheet.push_str("Not in the database\n\n"),
+    }
+    sheet.push('\n');
+}
+
+fn markdown_entry(sheet: &mut String, number: usize, entry: &SetlistEntry, lyrics: Option<&str>) {
+    sheet.push_str(&format!(
+        "## {}. {}\n\n*{} — {}*\n\n",
+        number,
+        escape_markdown(&entry.title),
+        escape_markdown(&entry.artist),
+        escape_markdown(&entry.album)
+    ));
+    let playing = playing(entry);
+    if !playing.is_empty() {
+        sheet.push_str(&format!("**{}**\n\n", escape_markdown(&playing)));
+    }
+    let notes: Vec<String> = entry
+        .notes
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| format!("> {}", escape_markdown(line)))
+        .collect();
+    if !notes.is_empty() {
+        sheet.push_str(&notes.join("\\\n"));
+        sheet.push_str("\n\n");
+    }
+    match lyrics {
+        Some(lyrics) => {
+            for verse in verses(lyrics) {
+                let lines: Vec<String> = verse.lines().map(escape_markdown).collect();
+                sheet.push_str(&lines.join("\\\n"));
+                sheet.push_str("\n\n");
+            }
+        }
+        None => sheet.push_str("*Not in the database*\n\n"),
+    }
+}
+
+//The lyrics of the tracks of `setlist` in the order they are played, each with its key, tempo
+//and notes. Tracks that are no longer in the database are listed without lyrics.
+pub fn lyric_sheet(
+    db: &mut Database,
+    setlist: &Setlist,
+    format: SheetFormat,
+) -> Result<String, DatabaseError> {
+    let name = one_line(&setlist.name);
+    let mut sheet = match format {
+        SheetFormat::Text => format!("{}\n{}\n\n", name, "=".repeat(name.chars().count())),
+        SheetFormat::Markdown => format!("# {}\n\n", escape_markdown(&name)),
+    };
+    for (i, entry) in setlist.entries.iter().enumerate() {
+        let lyrics = match db.setlist_track(entry) {
+            Some(index) => db.lyrics(index)?,
+            None => None,
+        };
+        let lyrics = lyrics.as_ref().map(String::as_str);
+        match format {
+            SheetFormat::Text => text_entry(&mut sheet, i + 1, entry, lyrics),
+            SheetFormat::Markdown => markdown_entry(&mut sheet, i + 1, entry, lyrics),
+        }
+    }
+    //One line break at the end
+    let end = sheet.trim_right().len();
+    sheet.truncate(end);
+    sheet.push('\n');
+    Ok(sheet)
+}
+
+pub fn write_sheet<P: AsRef<Path>>(
+    path: P,
+    db: &mut Database,
+    setlist: &Setlist,
+    format: SheetFormat,
+) -> Result<(), ExportError> {
+    let sheet = lyric_sheet(db, setlist, format)?;
+    File::create(path)?.write_all(sheet.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::*;
+
+    //A track without lyrics played first, one with lyrics and one that was deleted
+    fn played(format: SheetFormat) -> String {
+        let mut db = Database::empty();
+        db.entries = entries(
+            "Artist",
+            "Album",
+            vec![
+                track("One", 1, "red sky\nblue sea\n\ngreen grass"),
+                track("Two", 2, ""),
+            ],
+        );
+        let mut setlist = setlist("Friday #1", &["Two", "One", "Gone"]);
+        setlist.entries[0].key = "G".to_owned();
+        setlist.entries[0].tempo = Some(120);
+        setlist.entries[0].notes = "slow start\n\nend on G".to_owned();
+        lyric_sheet(&mut db, &setlist, format).unwrap()
+    }
+
+    #[test]
+    fn text_sheets_number_the_tracks() {
+        assert_eq!(
+            played(SheetFormat::Text),
+            "Friday #1\n=========\n\n\
+             1. Two\n   Artist — Album\n   Key: G, 120 bpm\n   slow start\n   end on G\n\n\n\
+             2. One\n   Artist — Album\n\nred sky\nblue sea\n\ngreen grass\n\n\n\
+             3. Gone\n   Artist — Album\n\nNot in the database\n"
+        );
+    }
+
+    #[test]
+    fn markdown_sheets_number_the_tracks() {
+        assert_eq!(
+            played(SheetFormat::Markdown),
+            "# Friday \\#1\n\n\
+             ## 1. Two\n\n*Artist — Album*\n\n**Key: G, 120 bpm**\n\n\
+             > slow start\\\n> end on G\n\n\
+             ## 2. One\n\n*Artist — Album*\n\nred sky\\\nblue sea\n\ngreen grass\n\n\
+             ## 3. Gone\n\n*Artist — Album*\n\n*Not in the database*\n"
+        );
+    }
+
+    #[test]
+    fn only_what_is_known_of_the_playing_is_shown() {
+        let mut entry = SetlistEntry::new("Artist", "Album", "One");
+        assert_eq!(playing(&entry), "");
+        entry.tempo = Some(90);
+        assert_eq!(playing(&entry), "90 bpm");
+        entry.key = " F#\nminor ".to_owned();
+        assert_eq!(playing(&entry), "Key: F# minor, 90 bpm");
+    }
+
+    #[test]
+    fn sheets_are_markdown_by_extension() {
+        assert_eq!(SheetFormat::for_path("set.md"), SheetFormat::Markdown);
+        assert_eq!(SheetFormat::for_path("set.Markdown"), SheetFormat::Markdown);
+        assert_eq!(SheetFormat::for_path("set.txt"), SheetFormat::Text);
+        assert_eq!(SheetFormat::for_path("set"), SheetFormat::Text);
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use database::metadata::Artist;
+use database::storage::words;
+use database::Database;
+
+use super::template::{Fields, Template, Value};
+use super::{escape, escape_markdown, verses, ExportError};
+
+const DEFAULT_TITLE: &str = "Lyrics";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SiteFormat {
+    Html,
+    Markdown,
+}
+
+impl SiteFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            SiteFormat::Html => "html",
+            SiteFormat::Markdown => "md",
+        }
+    }
+
+    fn escape(self) -> fn(&str) -> String {
+        match self {
+            SiteFormat::Html => escape,
+            SiteFormat::Markdown => escape_markdown,
+        }
+    }
+}
+
+pub struct SiteSettings {
+    //Shown on every page
+    pub title: String,
+    pub format: SiteFormat,
+    //Directory with templates to use instead of the built in ones, named like them
+    pub templates: Option<PathBuf>,
+}
+
+impl SiteSettings {
+    pub fn new() -> SiteSettings {
+        SiteSettings {
+            title: DEFAULT_TITLE.to_owned(),
+            format: SiteFormat::Html,
+            templates: None,
+        }
+    }
+}
+
+//The templates a site is made with, by file name
+fn builtin(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "page.html" => include_str!("templates/page.html"),
+        "index.html" => include_str!("templates/index.html"),
+        "artist.html" => include_str!("templates/artist.html"),
+        "album.html" => include_str!("templates/album.html"),
+        "track.html" => include_str!("templates/track.html"),
+        "style.css" => include_str!("templates/style.css"),
+        "search.js" => include_str!("templates/search.js"),
+        "page.md" => include_str!("templates/page.md"),
+        "index.md" => include_str!("templates/index.md"),
+        "artist.md" => include_str!("templates/artist.md"),
+        "album.md" => include_str!("templates/album.md"),
+        "track.md" => include_str!("templates/track.md"),
+        _ => return None,
+    })
+}
+
+//Lowercase letters and digits with dashes between, safe in any URL and file name
+fn slug(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_right_matches('-');
+    if slug.is_empty() {
+        "untitled".to_owned()
+    } else {
+        slug.to_owned()
+    }
+}
+
+//`slug` followed by a number if another page in the same directory has it already
+fn unique_slug(slug: String, used: &mut HashSet<String>) -> String {
+    let mut unique = slug.clone();
+    let mut n = 2;
+    while !used.insert(unique.clone()) {
+        unique = format!("{}-{}", slug, n);
+        n += 1;
+    }
+    unique
+}
+
+fn text<S: Into<String>>(text: S) -> Value {
+    Value::Text(text.into())
+}
+
+fn summary(count: usize, one: &str, many: &str) -> String {
+    if count == 1 {
+        format!("1 {}", one)
+    } else {
+        format!("{} {}", count, many)
+    }
+}
+
+//Where each page goes: the directory of every artist and album, and the file of every track
+struct Paths {
+    artists: Vec<String>,
+    albums: Vec<Vec<String>>,
+    tracks: Vec<Vec<Vec<String>>>,
+}
+
+impl Paths {
+    fn new(entries: &[Artist], extension: &str) -> Paths {
+        let mut paths = Paths {
+            artists: Vec::new(),
+            albums: Vec::new(),
+            tracks: Vec::new(),
+        };
+        let mut artists = HashSet::new();
+        for artist in entries {
+            paths
+                .artists
+                .push(unique_slug(slug(&artist.name), &mut artists));
+            let mut albums = HashSet::new();
+            let mut album_paths = Vec::new();
+            let mut track_paths = Vec::new();
+            for album in &artist.albums {
+                album_paths.push(unique_slug(slug(&album.title), &mut albums));
+                let mut tracks = HashSet::new();
+                track_paths.push(
+                    album
+                        .tracks
+                        .iter()
+                        .map(|track| {
+                            let name = format!("{:02}-{}", track.track, slug(&track.title));
+                            format!("{}.{}", unique_slug(name, &mut tracks), extension)
+                        })
+                        .collect(),
+                );
+            }
+            paths.albums.push(album_paths);
+            paths.tracks.push(track_paths);
+        }
+        paths
+    }
+}
+
+//The template or other file named `name`, from the templates directory if it has it
+fn source(settings: &SiteSettings, name: &str) -> Result<String, ExportError> {
+    if let Some(ref dir) = settings.templates {
+        match fs::read_to_string(dir.join(name)) {
+            Ok(source) => return Ok(source),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(ExportError::Io(e)),
+        }
+    }
+    builtin(name)
+        .map(str::to_owned)
+        .ok_or_else(|| ExportError::Template(format!("{}: no such template", name)))
+}
+
+//The template for pages of the kind `name` in the format of the site
+fn template(settings: &SiteSettings, name: &str) -> Result<Template, ExportError> {
+    let name = format!("{}.{}", name, settings.format.extension());
+    Template::parse(&source(settings, &name)?)
+        .map_err(|e| ExportError::Template(format!("{}: {}", name, e)))
+}
+
+struct Site<'a> {
+    dir: &'a Path,
+    settings: &'a SiteSettings,
+    //Every page is rendered inside it
+    page: Template,
+}
+
+impl<'a> Site<'a> {
+    //Write the page at `path` in the site, `body` rendered with `fields` inside the page
+    //template. Pages can link to others from `root`, the way back to the top of the site.
+    fn write(
+        &self,
+        path: &str,
+        root: &str,
+        title: &str,
+        body: &Template,
+        mut fields: Fields,
+    ) -> Result<(), ExportError> {
+        let escape = self.settings.format.escape();
+        fields.push(("site", text(self.settings.title.as_str())));
+        fields.push(("root", text(root)));
+        let page = vec![
+            ("title", text(title)),
+            ("site", text(self.settings.title.as_str())),
+            ("root", text(root)),
+            ("body", text(body.render(&fields, escape))),
+        ];
+        let path = self.dir.join(path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.page.render(&page, escape))?;
+        Ok(())
+    }
+}
+
+//Lines of each verse, the last line is marked so no line break has to follow it
+fn verse_fields(lyrics: &str) -> Value {
+    Value::List(
+        verses(lyrics)
+            .iter()
+            .map(|verse| {
+                let lines: Vec<&str> = verse.lines().collect();
+                let count = lines.len();
+                let lines = lines
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, line)| {
+                        vec![
+                            ("line", text(line)),
+                            ("last", text(if i + 1 == count { "yes" } else { "" })),
+                        ]
+                    })
+                    .collect();
+                vec![("lines", Value::List(lines))]
+            })
+            .collect(),
+    )
+}
+
+//Write the database as a website in `dir`: an index of the artists, a page for each artist
+//listing their albums, for each album with its tracks and for each track with its lyrics.
+//Search looks words up in `search-index.json`, which is also written as a script for HTML.
+pub fn write_site<P: AsRef<Path>>(
+    db: &mut Database,
+    dir: P,
+    settings: &SiteSettings,
+) -> Result<(), ExportError> {
+    db.load_all_lyrics()?;
+    let entries = &db.entries;
+    let extension = settings.format.extension();
+    let index_page = format!("index.{}", extension);
+    let paths = Paths::new(entries, extension);
+
+    let site = Site {
+        dir: dir.as_ref(),
+        settings,
+        page: template(settings, "page")?,
+    };
+    let index = template(settings, "index")?;
+    let artist_page = template(settings, "artist")?;
+    let album_page = template(settings, "album")?;
+    let track_page = template(settings, "track")?;
+
+    let artists = entries
+        .iter()
+        .enumerate()
+        .map(|(i, artist)| {
+            let tracks = artist.albums.iter().map(|album| album.tracks.len()).sum();
+            vec![
+                ("name", text(artist.name.as_str())),
+                ("url", text(format!("{}/{}", paths.artists[i], index_page))),
+                (
+                    "summary",
+                    text(format!(
+                        "{}, {}",
+                        summary(artist.albums.len(), "album", "albums"),
+                        summary(tracks, "track", "tracks")
+                    )),
+                ),
+            ]
+        })
+        .collect();
+    site.write(
+        &index_page,
+        "",
+        &settings.title,
+        &index,
+        vec![("artists", Value::List(artists))],
+    )?;
+
+    let mut search_tracks = Vec::new();
+    let mut search_words: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, artist) in entries.iter().enumerate() {
+        let albums = artist
+            .albums
+            .iter()
+            .enumerate()
+            .map(|(j, album)| {
+                vec![
+                    ("title", text(album.title.as_str())),
+                    (
+                        "url",
+                        text(format!("{}/{}", paths.albums[i][j], index_page)),
+                    ),
+                    (
+                        "summary",
+                        text(summary(album.tracks.len(), "track", "tracks")),
+                    ),
+                ]
+            })
+            .collect();
+        site.write(
+            &format!("{}/{}", paths.artists[i], index_page),
+            "../",
+            &artist.name,
+            &artist_page,
+            vec![
+                ("name", text(artist.name.as_str())),
+                ("albums", Value::List(albums)),
+            ],
+        )?;
+
+        for (j, album) in artist.albums.iter().enumerate() {
+            let album_dir = format!("{}/{}", paths.artists[i], paths.albums[i][j]);
+            let files = &paths.tracks[i][j];
+            let tracks = album
+                .tracks
+                .iter()
+                .zip(files)
+                .map(|(track, file)| {
+                    let has_lyrics = if track.lyrics.trim().is_empty() {
+                        ""
+                    } else {
+                        "yes"
+                    };
+                    vec![
+                        ("number", text(track.track.to_string())),
+                        ("title", text(track.title.as_str())),
+                        ("url", text(file.as_str())),
+                        ("has_lyrics", text(has_lyrics)),
+                    ]
+                })
+                .collect();
+            let artist_url = format!("../{}", index_page);
+            site.write(
+                &format!("{}/{}", album_dir, index_page),
+                "../../",
+                &album.title,
+                &album_page,
+                vec![
+                    ("title", text(album.title.as_str())),
+                    ("artist", text(artist.name.as_str())),
+                    ("artist_url", text(artist_url.as_str())),
+                    ("tracks", Value::List(tracks)),
+                ],
+            )?;
+
+            for (k, track) in album.tracks.iter().enumerate() {
+                let mut fields = vec![
+                    ("title", text(track.title.as_str())),
+                    ("artist", text(artist.name.as_str())),
+                    ("artist_url", text(artist_url.as_str())),
+                    ("album", text(album.title.as_str())),
+                    ("album_url", text(index_page.as_str())),
+                    ("verses", verse_fields(&track.lyrics)),
+                ];
+                if k > 0 {
+                    fields.push(("previous", text(album.tracks[k - 1].title.as_str())));
+                    fields.push(("previous_url", text(files[k - 1].as_str())));
+                }
+                if k + 1 < album.tracks.len() {
+                    fields.push(("next", text(album.tracks[k + 1].title.as_str())));
+                    fields.push(("next_url", text(files[k + 1].as_str())));
+                }
+                site.write(
+                    &format!("{}/{}", album_dir, files[k]),
+                    "../../",
+                    &track.title,
+                    &track_page,
+                    fields,
+                )?;
+
+                let id = search_tracks.len();
+                search_tracks.push(json!({
+                    "title": track.title,
+                    "artist": artist.name,
+                    "album": album.title,
+                    "url": format!("{}/{}", album_dir, files[k]),
+                }));
+                //Split the way `search.js` splits what is typed
+                let text = [&track.title, &artist.name, &album.title, &track.lyrics];
+                let found: HashSet<String> = text.iter().flat_map(|text| words(text)).collect();
+                for word in found {
+                    search_words.entry(word).or_insert_with(Vec::new).push(id);
+                }
+            }
+        }
+    }
+
+    let search_index = json!({ "tracks": search_tracks, "words": search_words });
+    fs::write(site.dir.join("search-index.json"), search_index.to_string())?;
+    if settings.format == SiteFormat::Html {
+        fs::write(
+            site.dir.join("search-index.js"),
+            format!("var searchIndex = {};\n", search_index),
+        )?;
+        for asset in &["style.css", "search.js"] {
+            fs::write(site.dir.join(asset), source(settings, asset)?)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{self, Value as Json};
+
+    use super::*;
+    use database::testing::*;
+
+    #[test]
+    fn names_are_turned_into_slugs() {
+        assert_eq!(slug("Hello, World!"), "hello-world");
+        assert_eq!(slug("  AC/DC -- Live  "), "ac-dc-live");
+        assert_eq!(slug("Ça va"), "ça-va");
+        assert_eq!(slug("?!"), "untitled");
+        assert_eq!(slug(""), "untitled");
+    }
+
+    #[test]
+    fn slugs_in_use_are_numbered() {
+        let mut used = HashSet::new();
+        let slugs: Vec<String> = ["live", "live", "live-2", "live"]
+            .iter()
+            .map(|name| unique_slug(name.to_string(), &mut used))
+            .collect();
+        assert_eq!(slugs, ["live", "live-2", "live-2-2", "live-3"]);
+    }
+
+    #[test]
+    fn pages_with_the_same_slug_get_their_own_paths() {
+        let mut entries = entries(
+            "Artist",
+            "Live!",
+            vec![track("One", 1, ""), track("One?", 1, "")],
+        );
+        let mut other = entries[0].albums[0].clone();
+        other.title = "Live".to_owned();
+        entries[0].albums.push(other);
+        let paths = Paths::new(&entries, "html");
+        assert_eq!(paths.albums[0], ["live", "live-2"]);
+        assert_eq!(paths.tracks[0][0], ["01-one.html", "01-one-2.html"]);
+    }
+
+    #[test]
+    fn the_search_index_holds_the_words_of_every_track() {
+        let path = temp_path("site");
+        let mut db = Database::empty();
+        db.entries = entries(
+            "Artist",
+            "Album",
+            vec![track("One", 1, "Red sky, red sea"), track("Two", 2, "blue")],
+        );
+        write_site(&mut db, &path, &SiteSettings::new()).unwrap();
+
+        let index: Json = serde_json::from_str(
+            &fs::read_to_string(format!("{}/search-index.json", path)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            index["tracks"][1],
+            json!({
+                "title": "Two",
+                "artist": "Artist",
+                "album": "Album",
+                "url": "artist/album/02-two.html",
+            })
+        );
+        //Each track is listed once for a word, whatever it is in
+        assert_eq!(index["words"]["red"], json!([0]));
+        assert_eq!(index["words"]["blue"], json!([1]));
+        assert_eq!(index["words"]["artist"], json!([0, 1]));
+        assert_eq!(index["words"]["two"], json!([1]));
+        assert!(index["words"].get("Red").is_none());
+        let script = fs::read_to_string(format!("{}/search-index.js", path)).unwrap();
+        assert_eq!(script, format!("var searchIndex = {};\n", index));
+        remove_dir(&path);
+    }
+}
@@ -0,0 +1,452 @@
+use std::fs::File;
+use std::mem;
+use std::path::Path;
+
+use cairo::prelude::SurfaceExt;
+use cairo::{Context, PDFSurface, Status};
+use pango::{self, Alignment, EllipsizeMode, FontDescription, Layout, LayoutExt, WrapMode};
+use pangocairo;
+
+use super::{escape, verses, ExportError, Song};
+
+const DEFAULT_TITLE: &str = "Songbook";
+const DEFAULT_TITLE_FONT: &str = "Serif Bold 18";
+const DEFAULT_BODY_FONT: &str = "Serif 11";
+//Space left around the text, in points
+const MARGIN: f64 = 54.0;
+//Room kept for the page number below the text
+const FOOTER: f64 = 24.0;
+//Width of the page numbers in the table of contents
+const NUMBER_WIDTH: f64 = 36.0;
+//Space between verses and below headings, in lines of the body font
+const GAP: f64 = 0.8;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PageSize {
+    A4,
+    A5,
+    Letter,
+}
+
+impl PageSize {
+    pub const ALL: [PageSize; 3] = [PageSize::A4, PageSize::A5, PageSize::Letter];
+
+    //Width and height in points
+    pub fn points(self) -> (f64, f64) {
+        match self {
+            PageSize::A4 => (595.0, 842.0),
+            PageSize::A5 => (420.0, 595.0),
+            PageSize::Letter => (612.0, 792.0),
+        }
+    }
+
+    //How the size is given on the command line
+    pub fn id(self) -> &'static str {
+        match self {
+            PageSize::A4 => "a4",
+            PageSize::A5 => "a5",
+            PageSize::Letter => "letter",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<PageSize> {
+        PageSize::ALL
+            .iter()
+            .cloned()
+            .find(|size| size.id() == id.to_lowercase())
+    }
+
+    //The paper size's name for printers
+    pub fn paper_name(self) -> &'static str {
+        match self {
+            PageSize::A4 => "iso_a4",
+            PageSize::A5 => "iso_a5",
+            PageSize::Letter => "na_letter",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SongbookSettings {
+    //Shown on the title page
+    pub title: String,
+    pub page_size: PageSize,
+    //Pango font descriptions, like "Serif 11"
+    pub title_font: String,
+    pub body_font: String,
+}
+
+impl SongbookSettings {
+    pub fn new() -> SongbookSettings {
+        SongbookSettings {
+            title: DEFAULT_TITLE.to_owned(),
+            page_size: PageSize::A4,
+            title_font: DEFAULT_TITLE_FONT.to_owned(),
+            body_font: DEFAULT_BODY_FONT.to_owned(),
+        }
+    }
+}
+
+//Text placed on a page, in points from its top left corner
+struct Block {
+    markup: String,
+    font: String,
+    x: f64,
+    y: f64,
+    width: f64,
+    alignment: Alignment,
+    //Cut off with an ellipsis instead of wrapped
+    single_line: bool,
+}
+
+impl Block {
+    fn new(markup: String, font: &str, x: f64, y: f64, width: f64) -> Block {
+        Block {
+            markup,
+            font: font.to_owned(),
+            x,
+            y,
+            width,
+            alignment: Alignment::Left,
+            single_line: false,
+        }
+    }
+
+    fn aligned(self, alignment: Alignment) -> Block {
+        Block { alignment, ..self }
+    }
+
+    fn single_line(self) -> Block {
+        Block {
+            single_line: true,
+            ..self
+        }
+    }
+
+    fn layout(&self, context: &pango::Context) -> Layout {
+        let layout = Layout::new(context);
+        layout.set_font_description(&FontDescription::from_string(&self.font));
+        layout.set_width((self.width * f64::from(pango::SCALE)) as i32);
+        layout.set_alignment(self.alignment);
+        if self.single_line {
+            layout.set_ellipsize(EllipsizeMode::End);
+        } else {
+            layout.set_wrap(WrapMode::WordChar);
+        }
+        layout.set_markup(&self.markup);
+        layout
+    }
+
+    fn height(&self, context: &pango::Context) -> f64 {
+        f64::from(self.layout(context).get_size().1) / f64::from(pango::SCALE)
+    }
+}
+
+//Text is measured and drawn in points, whatever the device
+fn pango_context(cr: &Context) -> pango::Context {
+    let context = pangocairo::functions::create_context(cr).expect("pango context for cairo");
+    pangocairo::functions::context_set_resolution(&context, 72.0);
+    context
+}
+
+//Names can span lines, that doesn't fit in the contents
+fn one_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+//Fills pages from the top, starting a new one when the next block doesn't fit
+struct Pages {
+    pages: Vec<Vec<Block>>,
+    top: f64,
+    bottom: f64,
+    y: f64,
+}
+
+impl Pages {
+    fn new_page(&mut self) {
+        self.pages.push(Vec::new());
+        self.y = self.top;
+    }
+
+    fn fits(&self, height: f64) -> bool {
+        self.y + height <= self.bottom
+    }
+
+    fn push(&mut self, mut block: Block, height: f64) {
+        block.y = self.y;
+        self.y += height;
+        self.pages.last_mut().expect("a page to fill").push(block);
+    }
+}
+
+//A songbook laid out on pages: a title page, the table of contents and each song starting on
+//a page of its own
+pub struct Songbook {
+    pages: Vec<Vec<Block>>,
+}
+
+impl Songbook {
+    //Lay out `songs` on pages `width` by `height` points large, measuring text with `cr`
+    pub fn layout(
+        cr: &Context,
+        width: f64,
+        height: f64,
+        songs: &[Song],
+        settings: &SongbookSettings,
+    ) -> Songbook {
+        let context = pango_context(cr);
+        let text_width = width - 2.0 * MARGIN;
+        let block = |markup: String, font: &str| Block::new(markup, font, MARGIN, 0.0, text_width);
+        let line_height = block("Ag".to_owned(), &settings.body_font).height(&context);
+        let gap = line_height * GAP;
+        let mut pages = Pages {
+            pages: Vec::new(),
+            top: MARGIN,
+            bottom: height - MARGIN - FOOTER,
+            y: MARGIN,
+        };
+
+        //Songs are laid out first, they are numbered once the contents are known
+        let mut starts = Vec::new();
+        for song in songs {
+            pages.new_page();
+            starts.push(pages.pages.len() - 1);
+
+            let title = block(escape(&song.title), &settings.title_font);
+            let title_height = title.height(&context);
+            pages.push(title, title_height);
+            let source = if song.album.is_empty() {
+                escape(&song.artist)
+            } else {
+                format!("{} — {}", escape(&song.artist), escape(&song.album))
+            };
+            let source = block(format!("<i>{}</i>", source), &settings.body_font);
+            let source_height = source.height(&context);
+            pages.push(source, source_height + gap);
+
+            let verses = verses(&song.lyrics);
+            if verses.is_empty() {
+                pages.push(
+                    block("<i>No lyrics</i>".to_owned(), &settings.body_font),
+                    line_height,
+                );
+            }
+            for verse in verses {
+                let whole = block(escape(&verse), &settings.body_font);
+                let verse_height = whole.height(&context);
+                if pages.fits(verse_height) {
+                    pages.push(whole, verse_height + gap);
+                    continue;
+                }
+                //Verses are kept together unless they are longer than a page
+                if verse_height <= pages.bottom - pages.top {
+                    pages.new_page();
+                    pages.push(whole, verse_height + gap);
+                    continue;
+                }
+                for line in verse.lines() {
+                    let line = block(escape(line), &settings.body_font);
+                    let line_height = line.height(&context);
+                    if !pages.fits(line_height) {
+                        pages.new_page();
+                    }
+                    pages.push(line, line_height);
+                }
+                pages.y += gap;
+            }
+        }
+        let song_pages = mem::replace(&mut pages.pages, Vec::new());
+
+        //One line per song below the heading
+        let heading = block("Contents".to_owned(), &settings.title_font);
+        let heading_height = heading.height(&context) + gap;
+        let first_lines = ((pages.bottom - pages.top - heading_height) / line_height) as usize;
+        //A line always goes on a page, even when it is taller than the page
+        let next_lines = (((pages.bottom - pages.top) / line_height) as usize).max(1);
+        let contents_pages = if songs.len() <= first_lines {
+            1
+        } else {
+            1 + (songs.len() - first_lines + next_lines - 1) / next_lines
+        };
+        //The title page comes first
+        let offset = 1 + contents_pages;
+
+        pages.new_page();
+        pages.push(heading, heading_height);
+        for (song, start) in songs.iter().zip(&starts) {
+            if !pages.fits(line_height) {
+                pages.new_page();
+            }
+            let entry = block(
+                format!(
+                    "{}  <span foreground=\"#666666\">{}</span>",
+                    escape(&one_line(&song.title)),
+                    escape(&one_line(&song.artist))
+                ),
+                &settings.body_font,
+            );
+            let entry = Block {
+                width: text_width - NUMBER_WIDTH,
+                ..entry.single_line()
+            };
+            let number = Block::new(
+                (start + offset + 1).to_string(),
+                &settings.body_font,
+                width - MARGIN - NUMBER_WIDTH,
+                0.0,
+                NUMBER_WIDTH,
+            )
+            .aligned(Alignment::Right);
+            //On the same line
+            pages.push(entry, 0.0);
+            pages.push(number, line_height);
+        }
+        let mut all = vec![Vec::new()];
+        all.append(&mut pages.pages);
+        all.extend(song_pages);
+
+        //The title a third of the way down the first page
+        let title = block(
+            format!("<span size=\"xx-large\">{}</span>", escape(&settings.title)),
+            &settings.title_font,
+        )
+        .aligned(Alignment::Center);
+        let title_height = title.height(&context);
+        let count = if songs.len() == 1 {
+            "1 song".to_owned()
+        } else {
+            format!("{} songs", songs.len())
+        };
+        let count = block(count, &settings.body_font).aligned(Alignment::Center);
+        let y = height / 3.0;
+        all[0].push(Block { y, ..title });
+        all[0].push(Block {
+            y: y + title_height + gap,
+            ..count
+        });
+
+        //Numbered from the title page on, which doesn't show its number
+        for (i, page) in all.iter_mut().enumerate().skip(1) {
+            page.push(
+                Block::new(
+                    (i + 1).to_string(),
+                    &settings.body_font,
+                    MARGIN,
+                    height - MARGIN,
+                    text_width,
+                )
+                .aligned(Alignment::Center),
+            );
+        }
+        Songbook { pages: all }
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    //Draw page number `page`, counting from 0, with `cr`
+    pub fn draw_page(&self, cr: &Context, page: usize) {
+        let context = pango_context(cr);
+        cr.set_source_rgb(0.0, 0.0, 0.0);
+        for block in self.pages.get(page).into_iter().flat_map(|blocks| blocks) {
+            let layout = block.layout(&context);
+            cr.move_to(block.x, block.y);
+            pangocairo::functions::show_layout(cr, &layout);
+        }
+    }
+}
+
+//Write a songbook of `songs` to the PDF file at `path`
+pub fn write_pdf<P: AsRef<Path>>(
+    path: P,
+    songs: &[Song],
+    settings: &SongbookSettings,
+) -> Result<(), ExportError> {
+    //Cairo can't say why a file couldn't be created, make sure it can be
+    File::create(&path)?;
+    let (width, height) = settings.page_size.points();
+    let surface = PDFSurface::create(&path, width, height);
+    let cr = Context::new(&surface);
+    let book = Songbook::layout(&cr, width, height, songs, settings);
+    for page in 0..book.page_count() {
+        book.draw_page(&cr, page);
+        cr.show_page();
+    }
+    surface.finish();
+    match surface.status() {
+        Status::Success => Ok(()),
+        status => Err(ExportError::Pdf(status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cairo::{Format, ImageSurface};
+
+    use super::*;
+
+    fn songs(count: usize) -> Vec<Song> {
+        (0..count)
+            .map(|i| Song {
+                artist: "Artist".to_owned(),
+                album: "Album".to_owned(),
+                title: format!("Song {}", i),
+                lyrics: "red sky\nblue sea\n\ngreen grass\n".repeat(i % 20),
+            })
+            .collect()
+    }
+
+    //Laid out on a surface of its own, only text is measured
+    fn laid_out(songs: &[Song], settings: &SongbookSettings) -> Songbook {
+        let surface = ImageSurface::create(Format::ARgb32, 1, 1).unwrap();
+        let cr = Context::new(&surface);
+        let (width, height) = settings.page_size.points();
+        Songbook::layout(&cr, width, height, songs, settings)
+    }
+
+    //The pages the songs start on, counting from 1, and the page numbers the contents give
+    fn starts_and_numbers(book: &Songbook, songs: &[Song]) -> (Vec<usize>, Vec<usize>) {
+        let starts: Vec<usize> = songs
+            .iter()
+            .map(|song| {
+                let start = book
+                    .pages
+                    .iter()
+                    .position(|page| page.first().map(|block| &block.markup) == Some(&song.title));
+                start.unwrap() + 1
+            })
+            .collect();
+        //The contents come after the title page, before the first song
+        let numbers = book.pages[1..starts[0] - 1]
+            .iter()
+            .flat_map(|page| page.iter())
+            .filter(|block| block.alignment == Alignment::Right)
+            .map(|block| block.markup.parse().unwrap())
+            .collect();
+        (starts, numbers)
+    }
+
+    #[test]
+    fn contents_give_the_pages_songs_start_on() {
+        let mut settings = SongbookSettings::new();
+        settings.page_size = PageSize::A5;
+        //Enough songs for the contents to go on for pages
+        let songs = songs(120);
+        let book = laid_out(&songs, &settings);
+        let (starts, numbers) = starts_and_numbers(&book, &songs);
+        assert_eq!(numbers, starts);
+        assert!(starts[0] > 3, "the contents fit on one page");
+    }
+
+    #[test]
+    fn lines_taller_than_a_page_are_laid_out() {
+        let mut settings = SongbookSettings::new();
+        settings.page_size = PageSize::A5;
+        settings.body_font = "Serif 500".to_owned();
+        let songs = songs(3);
+        let book = laid_out(&songs, &settings);
+        let (starts, numbers) = starts_and_numbers(&book, &songs);
+        assert_eq!(numbers, starts);
+    }
+}
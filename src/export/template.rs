@@ -0,0 +1,262 @@
+//A small part of Mustache: `{{name}}` is replaced by an escaped value, `{{{name}}}` by the
+//value as it is, `{{#name}}...{{/name}}` is repeated for each item of a list or shown if a
+//text isn't empty, `{{^name}}...{{/name}}` is shown if it is, and `{{! ...}}` is left out.
+
+use std::mem;
+
+//What a name in a template stands for
+pub enum Value {
+    Text(String),
+    List(Vec<Fields>),
+}
+
+pub type Fields = Vec<(&'static str, Value)>;
+
+enum Part {
+    Literal(String),
+    //Name, and whether the value is escaped
+    Variable(String, bool),
+    //Name, whether it is shown for missing or empty values instead, and what is inside
+    Section(String, bool, Vec<Part>),
+}
+
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+//Tags that don't show anything take their whole line if they are alone on it. `literal` is
+//the text since the last tag, `at_line_start` whether that tag ended a line.
+fn standalone(literal: &str, rest: &str, at_line_start: bool) -> Option<(usize, usize)> {
+    let line_start = match literal.rfind('\n') {
+        Some(i) => i + 1,
+        None if at_line_start => 0,
+        None => return None,
+    };
+    if !literal[line_start..].trim().is_empty() {
+        return None;
+    }
+    let line_end = match rest.find('\n') {
+        Some(i) => i + 1,
+        None => rest.len(),
+    };
+    if !rest[..line_end].trim().is_empty() {
+        return None;
+    }
+    Some((line_start, line_end))
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, String> {
+        //Sections being read, with the parts found before each of them
+        let mut open: Vec<(String, bool, Vec<Part>)> = Vec::new();
+        let mut parts = Vec::new();
+        let mut rest = source;
+        let mut at_line_start = true;
+        while let Some(start) = rest.find("{{") {
+            let mut literal = &rest[..start];
+            let tag = &rest[start + 2..];
+            let (tag, after) = if tag.starts_with('{') {
+                let end = tag.find("}}}").ok_or_else(|| "unclosed {{{".to_owned())?;
+                (&tag[..end], &tag[end + 3..])
+            } else {
+                let end = tag.find("}}").ok_or_else(|| "unclosed {{".to_owned())?;
+                (&tag[..end], &tag[end + 2..])
+            };
+            rest = after;
+
+            let kind = tag.chars().next().unwrap_or(' ');
+            let line = if "#^/!".contains(kind) {
+                standalone(literal, rest, at_line_start)
+            } else {
+                None
+            };
+            at_line_start = line.is_some();
+            if let Some((line_start, line_end)) = line {
+                literal = &literal[..line_start];
+                rest = &rest[line_end..];
+            }
+            if !literal.is_empty() {
+                parts.push(Part::Literal(literal.to_owned()));
+            }
+
+            let name = tag
+                .trim_left_matches(|c| "{#^/!".contains(c))
+                .trim()
+                .to_owned();
+            match kind {
+                '{' => parts.push(Part::Variable(name, false)),
+                '#' | '^' => {
+                    open.push((name, kind == '^', parts));
+                    parts = Vec::new();
+                }
+                '/' => {
+                    let (opened, inverted, outer) = open
+                        .pop()
+                        .ok_or_else(|| format!("{{{{/{}}}}} closes nothing", name))?;
+                    if opened != name {
+                        return Err(format!("{{{{/{}}}}} closes {}", name, opened));
+                    }
+                    let inner = mem::replace(&mut parts, outer);
+                    parts.push(Part::Section(name, inverted, inner));
+                }
+                '!' => (),
+                _ => parts.push(Part::Variable(name, true)),
+            }
+        }
+        if let Some((name, _, _)) = open.pop() {
+            return Err(format!("{} is never closed", name));
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        Ok(Template { parts })
+    }
+
+    //Fill in the template with `fields`, passing text values through `escape` unless the
+    //template says not to
+    pub fn render(&self, fields: &Fields, escape: fn(&str) -> String) -> String {
+        let mut out = String::new();
+        render_parts(&self.parts, &mut vec![fields], escape, &mut out);
+        out
+    }
+}
+
+//Names are looked up in the innermost list item first
+fn lookup<'a>(scopes: &[&'a Fields], name: &str) -> Option<&'a Value> {
+    scopes
+        .iter()
+        .rev()
+        .filter_map(|fields| {
+            fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value)
+        })
+        .next()
+}
+
+fn render_parts<'a>(
+    parts: &[Part],
+    scopes: &mut Vec<&'a Fields>,
+    escape: fn(&str) -> String,
+    out: &mut String,
+) {
+    for part in parts {
+        match part {
+            Part::Literal(text) => out.push_str(text),
+            Part::Variable(name, escaped) => match lookup(scopes, name) {
+                Some(Value::Text(text)) if *escaped => out.push_str(&escape(text)),
+                Some(Value::Text(text)) => out.push_str(text),
+                _ => (),
+            },
+            Part::Section(name, inverted, inner) => match lookup(scopes, name) {
+                Some(Value::List(items)) if !*inverted => {
+                    for item in items {
+                        scopes.push(item);
+                        render_parts(inner, scopes, escape, out);
+                        scopes.pop();
+                    }
+                }
+                Some(Value::Text(text)) if !*inverted => {
+                    if !text.is_empty() {
+                        render_parts(inner, scopes, escape, out);
+                    }
+                }
+                Some(Value::List(items)) if items.is_empty() => {
+                    render_parts(inner, scopes, escape, out)
+                }
+                Some(Value::Text(text)) if text.is_empty() => {
+                    render_parts(inner, scopes, escape, out)
+                }
+                None if *inverted => render_parts(inner, scopes, escape, out),
+                _ => (),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::escape;
+    use super::*;
+
+    fn rendered(source: &str, fields: &Fields) -> String {
+        Template::parse(source).unwrap().render(fields, escape)
+    }
+
+    fn items(names: &[&str]) -> Value {
+        Value::List(
+            names
+                .iter()
+                .map(|name| vec![("name", Value::Text(name.to_string()))])
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn broken_templates_are_refused() {
+        for (source, error) in &[
+            ("a {{name", "unclosed {{"),
+            ("a {{{name}}", "unclosed {{{"),
+            ("{{/items}}", "{{/items}} closes nothing"),
+            ("{{#items}}{{/names}}", "{{/names}} closes items"),
+            ("{{#items}}{{^empty}}{{/empty}}", "items is never closed"),
+        ] {
+            match Template::parse(source) {
+                Err(e) => assert_eq!(e, *error),
+                Ok(_) => panic!("{} was parsed", source),
+            }
+        }
+    }
+
+    #[test]
+    fn tags_alone_on_a_line_take_the_line() {
+        let source =
+            "<ul>\n  {{#items}}\n  <li>{{name}}</li>\n  {{/items}}\n  {{! a comment }}\n</ul>";
+        let fields = vec![("items", items(&["a", "b"]))];
+        assert_eq!(
+            rendered(source, &fields),
+            "<ul>\n  <li>a</li>\n  <li>b</li>\n</ul>"
+        );
+        //Variables and tags sharing their line with text keep it
+        assert_eq!(
+            rendered("{{#items}}{{name}} {{/items}}\n", &fields),
+            "a b \n"
+        );
+    }
+
+    #[test]
+    fn sections_show_for_lists_and_texts() {
+        let source = "{{#items}}[{{name}}]{{/items}}{{^items}}none{{/items}}";
+        assert_eq!(rendered(source, &vec![("items", items(&["a"]))]), "[a]");
+        assert_eq!(rendered(source, &vec![("items", items(&[]))]), "none");
+        assert_eq!(rendered(source, &Vec::new()), "none");
+
+        let source = "{{#note}}note: {{note}}{{/note}}{{^note}}no note{{/note}}";
+        let note = |text: &str| vec![("note", Value::Text(text.to_owned()))];
+        assert_eq!(rendered(source, &note("slow")), "note: slow");
+        assert_eq!(rendered(source, &note("")), "no note");
+    }
+
+    #[test]
+    fn names_are_looked_up_from_the_innermost_item_out() {
+        let fields = vec![
+            ("site", Value::Text("Lyrics".to_owned())),
+            ("name", Value::Text("outer".to_owned())),
+            ("items", items(&["inner"])),
+        ];
+        assert_eq!(
+            rendered("{{#items}}{{name}} on {{site}}{{/items}}", &fields),
+            "inner on Lyrics"
+        );
+    }
+
+    #[test]
+    fn values_are_escaped_unless_in_triple_braces() {
+        let fields = vec![("text", Value::Text("<b>R&B</b>".to_owned()))];
+        assert_eq!(
+            rendered("{{text}} {{{text}}}", &fields),
+            "&lt;b&gt;R&amp;B&lt;/b&gt; <b>R&B</b>"
+        );
+    }
+}
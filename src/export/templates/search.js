@@ -0,0 +1,59 @@
+//Looks up the words typed in the search box in searchIndex, written by the exporter as
+//{ "tracks": [{ "title", "artist", "album", "url" }], "words": { word: [track, ...] } }
+(function () {
+    var input = document.getElementById("search");
+    var results = document.getElementById("results");
+    var root = document.body.getAttribute("data-root") || "";
+    var words = Object.keys(searchIndex.words);
+
+    //Lowercase words, the way the exporter splits the lyrics
+    function split(text) {
+        return text.toLowerCase().split(/[^\p{L}\p{N}]+/u).filter(function (word) {
+            return word.length > 0;
+        });
+    }
+
+    //Tracks with a word starting with `prefix`
+    function matching(prefix) {
+        var found = {};
+        words.forEach(function (word) {
+            if (word.lastIndexOf(prefix, 0) === 0) {
+                searchIndex.words[word].forEach(function (track) {
+                    found[track] = true;
+                });
+            }
+        });
+        return found;
+    }
+
+    function search(query) {
+        var found = null;
+        split(query).forEach(function (prefix) {
+            var tracks = matching(prefix);
+            if (found === null) {
+                found = tracks;
+                return;
+            }
+            Object.keys(found).forEach(function (track) {
+                if (!tracks[track]) {
+                    delete found[track];
+                }
+            });
+        });
+        return Object.keys(found || {}).slice(0, 50).map(function (track) {
+            return searchIndex.tracks[track];
+        });
+    }
+
+    input.addEventListener("input", function () {
+        results.innerHTML = "";
+        search(input.value).forEach(function (track) {
+            var link = document.createElement("a");
+            link.href = root + track.url;
+            link.textContent = track.title + " — " + track.artist;
+            var item = document.createElement("li");
+            item.appendChild(link);
+            results.appendChild(item);
+        });
+    });
+})();
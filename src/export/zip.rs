@@ -0,0 +1,161 @@
+//Just enough of the zip format to write an EPUB book: files are stored or deflated, with
+//UTF-8 names and without extra fields, so that readers can find the mimetype at offset 38
+
+use std::io::{self, Write};
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+//Names are UTF-8
+const FLAGS: u16 = 0x0800;
+//Zip 2.0, which is what deflate needs
+const VERSION: u16 = 20;
+
+struct Entry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    offset: u32,
+}
+
+pub struct ZipWriter<W: Write> {
+    out: W,
+    written: u32,
+    //MS-DOS date and time the files were modified
+    date: u16,
+    time: u16,
+    entries: Vec<Entry>,
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push(value as u8);
+    buf.push((value >> 8) as u8);
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    push_u16(buf, value as u16);
+    push_u16(buf, (value >> 16) as u16);
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "file too large for a zip archive")
+}
+
+impl<W: Write> ZipWriter<W> {
+    //`modified` is (year, month, day, hour, minute, second)
+    pub fn new(out: W, modified: (u32, u32, u32, u32, u32, u32)) -> ZipWriter<W> {
+        let (year, month, day, hour, minute, second) = modified;
+        let year = year.max(1980).min(2107) - 1980;
+        ZipWriter {
+            out,
+            written: 0,
+            date: ((year << 9) | (month << 5) | day) as u16,
+            time: ((hour << 11) | (minute << 5) | (second / 2)) as u16,
+            entries: Vec::new(),
+        }
+    }
+
+    //Add a file as it is
+    pub fn store(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.add(name, STORED, data)
+    }
+
+    //Add a compressed file
+    pub fn deflate(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.add(name, DEFLATED, data)
+    }
+
+    fn add(&mut self, name: &str, method: u16, data: &[u8]) -> io::Result<()> {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let compressed = if method == DEFLATED {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        } else {
+            data.to_vec()
+        };
+        if data.len() > u32::max_value() as usize {
+            return Err(too_large());
+        }
+        let entry = Entry {
+            name: name.to_owned(),
+            method,
+            crc: crc.sum(),
+            compressed: compressed.len() as u32,
+            size: data.len() as u32,
+            offset: self.written,
+        };
+        let mut header = Vec::new();
+        push_u32(&mut header, 0x0403_4b50);
+        push_u16(&mut header, VERSION);
+        push_u16(&mut header, FLAGS);
+        push_u16(&mut header, method);
+        push_u16(&mut header, self.time);
+        push_u16(&mut header, self.date);
+        push_u32(&mut header, entry.crc);
+        push_u32(&mut header, entry.compressed);
+        push_u32(&mut header, entry.size);
+        push_u16(&mut header, name.len() as u16);
+        push_u16(&mut header, 0);
+        header.extend_from_slice(name.as_bytes());
+        self.write(&header)?;
+        self.write(&compressed)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.written = self
+            .written
+            .checked_add(data.len() as u32)
+            .ok_or_else(too_large)?;
+        Ok(())
+    }
+
+    //Write the central directory that lists the files, the archive is done after that
+    pub fn finish(mut self) -> io::Result<W> {
+        let start = self.written;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            push_u32(&mut directory, 0x0201_4b50);
+            push_u16(&mut directory, VERSION);
+            push_u16(&mut directory, VERSION);
+            push_u16(&mut directory, FLAGS);
+            push_u16(&mut directory, entry.method);
+            push_u16(&mut directory, self.time);
+            push_u16(&mut directory, self.date);
+            push_u32(&mut directory, entry.crc);
+            push_u32(&mut directory, entry.compressed);
+            push_u32(&mut directory, entry.size);
+            push_u16(&mut directory, entry.name.len() as u16);
+            //Extra field, comment, disk, internal and external attributes
+            push_u16(&mut directory, 0);
+            push_u16(&mut directory, 0);
+            push_u16(&mut directory, 0);
+            push_u16(&mut directory, 0);
+            push_u32(&mut directory, 0);
+            push_u32(&mut directory, entry.offset);
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+        let count = self.entries.len() as u16;
+        let mut end = Vec::new();
+        push_u32(&mut end, 0x0605_4b50);
+        push_u16(&mut end, 0);
+        push_u16(&mut end, 0);
+        push_u16(&mut end, count);
+        push_u16(&mut end, count);
+        push_u32(&mut end, directory.len() as u32);
+        push_u32(&mut end, start);
+        push_u16(&mut end, 0);
+        self.write(&directory)?;
+        self.write(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
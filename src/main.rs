@@ -1,21 +1,52 @@
 #![feature(use_extern_macros)]
 #![feature(extern_prelude)]
 
+extern crate cairo;
+extern crate dbus;
+extern crate flate2;
+extern crate gdk;
 extern crate gtk;
+extern crate inotify;
+#[macro_use]
+extern crate juniper;
+extern crate pango;
+extern crate pangocairo;
 #[macro_use]
 extern crate relm;
 #[macro_use]
 extern crate relm_derive;
 
+extern crate percent_encoding;
+extern crate ring;
+#[macro_use]
+extern crate rusqlite;
+#[macro_use]
+extern crate serde_json;
+extern crate tiny_http;
 extern crate treexml;
+extern crate xml;
+extern crate zstd;
 
 use relm::Widget;
 
+use std::env;
+use std::process;
+
+mod bench;
+mod cli;
 mod database;
+mod export;
+mod players;
+mod providers;
+mod server;
 
 mod windows;
 use windows::*;
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        process::exit(cli::run(&args));
+    }
     MainWindow::run(()).unwrap();
 }
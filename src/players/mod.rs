@@ -0,0 +1,64 @@
+pub mod mpd;
+pub mod mpris;
+
+use std::convert;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use dbus;
+
+pub use self::mpd::MpdWatcher;
+pub use self::mpris::MprisWatcher;
+
+//The song a music player is playing, as it describes it
+#[derive(Debug, Clone, PartialEq)]
+pub struct NowPlaying {
+    pub artist: String,
+    //Empty if the player doesn't know it
+    pub album: String,
+    pub title: String,
+}
+
+#[derive(Debug)]
+pub enum PlayerError {
+    Io(io::Error),
+    DBus(dbus::Error),
+    Protocol(String),
+}
+
+impl Error for PlayerError {
+    fn description(&self) -> &str {
+        match self {
+            PlayerError::Io(e) => e.description(),
+            PlayerError::DBus(_) => "D-Bus request failed",
+            PlayerError::Protocol(_) => "Player refused a command",
+        }
+    }
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlayerError::Io(e) => write!(f, "io error: {}", e),
+            PlayerError::DBus(e) => write!(
+                f,
+                "d-bus error: {}",
+                e.message().unwrap_or_else(|| e.name().unwrap_or("unknown"))
+            ),
+            PlayerError::Protocol(e) => write!(f, "protocol error: {}", e),
+        }
+    }
+}
+
+impl convert::From<io::Error> for PlayerError {
+    fn from(err: io::Error) -> PlayerError {
+        PlayerError::Io(err)
+    }
+}
+
+impl convert::From<dbus::Error> for PlayerError {
+    fn from(err: dbus::Error) -> PlayerError {
+        PlayerError::DBus(err)
+    }
+}
@@ -0,0 +1,462 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::{NowPlaying, PlayerError};
+
+//Where MPD listens unless MPD_HOST says otherwise
+const DEFAULT_SOCKET: &str = "/run/mpd/socket";
+const DEFAULT_PORT: &str = "6600";
+//How long a client of the fake server waits in `idle` before looking for `noidle`
+const IDLE_POLL: Duration = Duration::from_millis(100);
+//How long connecting to MPD over the network may take, an unreachable host would take minutes
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+//Where to find MPD, from MPD_HOST and MPD_PORT like other MPD clients do. Without those the
+//usual local socket is used if it is there, TCP on localhost if not.
+pub fn default_address() -> String {
+    let port = env::var("MPD_PORT").unwrap_or_else(|_| DEFAULT_PORT.to_owned());
+    match env::var("MPD_HOST") {
+        Ok(ref host) if host.starts_with('/') => host.clone(),
+        Ok(host) => format!("{}:{}", host, port),
+        Err(_) if Path::new(DEFAULT_SOCKET).exists() => DEFAULT_SOCKET.to_owned(),
+        Err(_) => format!("localhost:{}", port),
+    }
+}
+
+//A connection to MPD. Addresses starting with a slash are local sockets, anything else is
+//`host:port`.
+enum Socket {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Socket {
+    fn connect(address: &str) -> io::Result<Socket> {
+        if address.starts_with('/') {
+            return UnixStream::connect(address).map(Socket::Unix);
+        }
+        //Every address the host has is tried, like `TcpStream::connect` does
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "no address for the host");
+        for address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(Socket::Tcp(stream)),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            Socket::Unix(stream) => stream.try_clone().map(Socket::Unix),
+            Socket::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Socket::Unix(stream) => stream.shutdown(Shutdown::Both),
+            Socket::Tcp(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Unix(stream) => stream.read(buf),
+            Socket::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Unix(stream) => stream.write(buf),
+            Socket::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Unix(stream) => stream.flush(),
+            Socket::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+//Speaks the MPD protocol: a command per line, answered by `key: value` lines ending in `OK`,
+//or a single `ACK` line if the command failed
+struct Client {
+    reader: BufReader<Socket>,
+    writer: Socket,
+}
+
+impl Client {
+    fn connect(socket: &Socket) -> Result<Client, PlayerError> {
+        let mut client = Client {
+            reader: BufReader::new(socket.try_clone()?),
+            writer: socket.try_clone()?,
+        };
+        let greeting = client.line()?;
+        if !greeting.starts_with("OK MPD ") {
+            return Err(PlayerError::Protocol(format!(
+                "not an MPD server: {}",
+                greeting
+            )));
+        }
+        Ok(client)
+    }
+
+    fn line(&mut self) -> Result<String, PlayerError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(PlayerError::Protocol("connection closed".to_owned()));
+        }
+        Ok(line.trim_right_matches('\n').to_owned())
+    }
+
+    //Send `command` and collect the `key: value` pairs answering it
+    fn command(&mut self, command: &str) -> Result<Vec<(String, String)>, PlayerError> {
+        writeln!(self.writer, "{}", command)?;
+        let mut pairs = Vec::new();
+        loop {
+            let line = self.line()?;
+            if line == "OK" {
+                return Ok(pairs);
+            }
+            if line.starts_with("ACK ") {
+                return Err(PlayerError::Protocol(line[4..].to_owned()));
+            }
+            if let Some(i) = line.find(": ") {
+                pairs.push((line[..i].to_owned(), line[i + 2..].to_owned()));
+            }
+        }
+    }
+
+    //What is being played, None while stopped or paused
+    fn playing(&mut self) -> Result<Option<NowPlaying>, PlayerError> {
+        let status = self.command("status")?;
+        if tag(&status, "state").map_or(true, |state| state != "play") {
+            return Ok(None);
+        }
+        Ok(now_playing(&self.command("currentsong")?))
+    }
+}
+
+fn tag(pairs: &[(String, String)], name: &str) -> Option<String> {
+    pairs
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.clone())
+}
+
+//The song with the tags `currentsong` answered with, None without an artist or title
+fn now_playing(tags: &[(String, String)]) -> Option<NowPlaying> {
+    //Untagged files are known by their name
+    let file_name = || {
+        tag(tags, "file").and_then(|file| {
+            Path::new(&file)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        })
+    };
+    Some(NowPlaying {
+        artist: tag(tags, "Artist").or_else(|| tag(tags, "AlbumArtist"))?,
+        album: tag(tags, "Album").unwrap_or_default(),
+        title: tag(tags, "Title").or_else(file_name)?,
+    })
+}
+
+//Follows what MPD plays. Watching stops when this is dropped.
+pub struct MpdWatcher {
+    socket: Socket,
+    stop: Arc<AtomicBool>,
+}
+
+impl MpdWatcher {
+    //Call `changed` from another thread whenever MPD at `address` starts playing another song,
+    //and right away if it is playing already. If the connection is lost the error is passed
+    //instead and watching stops.
+    pub fn new<F>(address: &str, changed: F) -> Result<MpdWatcher, PlayerError>
+    where
+        F: Fn(Result<NowPlaying, PlayerError>) + Send + 'static,
+    {
+        let socket = Socket::connect(address)?;
+        let mut client = Client::connect(&socket)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            let mut last = None;
+            let error = loop {
+                match client.playing() {
+                    Ok(Some(song)) => {
+                        if last.as_ref() != Some(&song) {
+                            changed(Ok(song.clone()));
+                            last = Some(song);
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => break e,
+                }
+                //Answered once the player has done something
+                if let Err(e) = client.command("idle player") {
+                    break e;
+                }
+            };
+            //Dropping the watcher closes the connection, that is nothing to report
+            if !stopped.load(Ordering::SeqCst) {
+                changed(Err(error));
+            }
+        });
+        Ok(MpdWatcher { socket, stop })
+    }
+}
+
+impl Drop for MpdWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.socket.shutdown();
+    }
+}
+
+//The song the fake server is playing, `version` counts the changes. Clients are hung up on
+//once it is `finished`.
+struct MockState {
+    version: u32,
+    song: Option<NowPlaying>,
+    finished: bool,
+}
+
+type SharedState = Arc<(Mutex<MockState>, Condvar)>;
+
+fn mock_status(state: &MockState) -> String {
+    let status = if state.song.is_some() { "play" } else { "stop" };
+    format!("volume: 100\nrepeat: 0\nrandom: 0\nstate: {}\n", status)
+}
+
+fn mock_current_song(state: &MockState) -> String {
+    let song = match state.song {
+        Some(ref song) => song,
+        None => return String::new(),
+    };
+    let mut tags = format!(
+        "file: {} - {}.ogg\nArtist: {}\n",
+        song.artist, song.title, song.artist
+    );
+    if !song.album.is_empty() {
+        tags.push_str(&format!("Album: {}\n", song.album));
+    }
+    tags.push_str(&format!(
+        "Title: {}\nPos: 0\nId: {}\n",
+        song.title, state.version
+    ));
+    tags
+}
+
+//Answer the commands of one client of the fake server until it goes away
+fn serve_mock_client(socket: &Socket, state: &SharedState) -> io::Result<()> {
+    let mut writer = socket.try_clone()?;
+    //Commands are read on their own thread, so that `noidle` can end an `idle`
+    let (sender, commands) = mpsc::channel();
+    let reader = BufReader::new(socket.try_clone()?);
+    thread::spawn(move || {
+        for line in reader.lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    });
+
+    let (ref lock, ref wake) = **state;
+    //Changes are reported by the next `idle`, even if they happened before it
+    let mut seen = lock.lock().unwrap().version;
+    writer.write_all(b"OK MPD 0.21.0\n")?;
+    for command in commands.iter() {
+        let name = command.split_whitespace().next().unwrap_or_default();
+        let answer = match name {
+            "status" => mock_status(&lock.lock().unwrap()),
+            "currentsong" => mock_current_song(&lock.lock().unwrap()),
+            "ping" => String::new(),
+            "close" => return Ok(()),
+            "idle" => {
+                let mut current = lock.lock().unwrap();
+                loop {
+                    if current.version != seen {
+                        seen = current.version;
+                        break "changed: player\n".to_owned();
+                    }
+                    if current.finished {
+                        return Ok(());
+                    }
+                    //Only `noidle` may be sent while idle
+                    match commands.try_recv() {
+                        Ok(ref command) if command == "noidle" => break String::new(),
+                        Ok(_) | Err(TryRecvError::Disconnected) => return Ok(()),
+                        Err(TryRecvError::Empty) => (),
+                    }
+                    current = wake.wait_timeout(current, IDLE_POLL).unwrap().0;
+                }
+            }
+            _ => {
+                let error = format!("ACK [5@0] {{{}}} unknown command \"{}\"\n", name, name);
+                writer.write_all(error.as_bytes())?;
+                continue;
+            }
+        };
+        writer.write_all(answer.as_bytes())?;
+        writer.write_all(b"OK\n")?;
+    }
+    Ok(())
+}
+
+//Hand each connection made to the fake server to its own thread
+fn accept_mock_clients<F>(mut accept: F, state: SharedState)
+where
+    F: FnMut() -> io::Result<Socket> + Send + 'static,
+{
+    thread::spawn(move || {
+        while let Ok(socket) = accept() {
+            let state = state.clone();
+            thread::spawn(move || {
+                let _ = serve_mock_client(&socket, &state);
+                //Also ends the thread reading its commands
+                let _ = socket.shutdown();
+            });
+        }
+    });
+}
+
+//Pretend to be MPD listening at `address`, playing each song of `songs` in turn as they
+//arrive. Runs until there are no more songs, then hangs up on clients waiting in `idle`.
+pub fn serve_mock<I>(address: &str, songs: I) -> Result<(), PlayerError>
+where
+    I: Iterator<Item = NowPlaying>,
+{
+    let state: SharedState = Arc::new((
+        Mutex::new(MockState {
+            version: 0,
+            song: None,
+            finished: false,
+        }),
+        Condvar::new(),
+    ));
+    if address.starts_with('/') {
+        let listener = UnixListener::bind(address)?;
+        accept_mock_clients(
+            move || listener.accept().map(|(stream, _)| Socket::Unix(stream)),
+            state.clone(),
+        );
+    } else {
+        let listener = TcpListener::bind(address)?;
+        accept_mock_clients(
+            move || listener.accept().map(|(stream, _)| Socket::Tcp(stream)),
+            state.clone(),
+        );
+    }
+
+    let (ref lock, ref wake) = *state;
+    for song in songs {
+        let mut current = lock.lock().unwrap();
+        current.version += 1;
+        current.song = Some(song);
+        wake.notify_all();
+    }
+    lock.lock().unwrap().finished = true;
+    wake.notify_all();
+    if address.starts_with('/') {
+        let _ = fs::remove_file(address);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use database::testing::*;
+
+    fn song(title: &str) -> NowPlaying {
+        NowPlaying {
+            artist: "Artist".to_owned(),
+            album: "Album".to_owned(),
+            title: title.to_owned(),
+        }
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn untagged_songs_are_known_by_their_file_name() {
+        let tags = [
+            pair("file", "music/Some Song.ogg"),
+            pair("AlbumArtist", "Band"),
+        ];
+        let song = now_playing(&tags).unwrap();
+        assert_eq!(
+            (song.artist.as_str(), song.title.as_str()),
+            ("Band", "Some Song")
+        );
+        assert_eq!(now_playing(&[pair("Title", "No artist")]), None);
+    }
+
+    //Every song played is reported once, then the mock hangs up and that is reported too
+    #[test]
+    fn watcher_follows_the_mock_server() {
+        let path = temp_path("mpd.socket");
+        let (play, songs) = mpsc::channel();
+        let address = path.clone();
+        let mock = thread::spawn(move || serve_mock(&address, songs.into_iter()));
+        while !Path::new(&path).exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (reported, changes) = mpsc::channel();
+        let watcher = MpdWatcher::new(&path, move |change| {
+            let _ = reported.send(change);
+        })
+        .unwrap();
+        let timeout = Duration::from_secs(5);
+        for next in &[song("One"), song("Two")] {
+            play.send(next.clone()).unwrap();
+            match changes.recv_timeout(timeout).unwrap() {
+                Ok(ref song) => assert_eq!(song, next),
+                Err(e) => panic!("expected {:?}, got {}", next, e),
+            }
+        }
+
+        drop(play);
+        mock.join().unwrap().unwrap();
+        assert!(changes.recv_timeout(timeout).unwrap().is_err());
+        drop(watcher);
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn nothing_listening_is_an_error() {
+        let path = temp_path("missing.socket");
+        assert!(MpdWatcher::new(&path, |_| ()).is_err());
+        assert!(MpdWatcher::new("127.0.0.1:1", |_| ()).is_err());
+        remove_dir(&path);
+    }
+}
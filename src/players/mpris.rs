@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use dbus::arg::{RefArg, Variant};
+use dbus::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+use dbus::{BusType, Connection, Message, MessageType, NameFlag, Path, SignalArgs};
+
+use super::{NowPlaying, PlayerError};
+
+//Players own a bus name starting with this
+const BUS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+//Taken by `serve_mock`
+const MOCK_BUS_NAME: &str = "org.mpris.MediaPlayer2.lyrics_mock";
+
+//How long a player gets to answer, in milliseconds
+const TIMEOUT_MS: i32 = 2000;
+//How long the bus is waited on before looking whether to stop, in milliseconds
+const POLL_MS: u32 = 250;
+
+type PropertyMap = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+//The song described by a player's Metadata property, None without an artist and title
+fn now_playing(metadata: &PropertyMap) -> Option<NowPlaying> {
+    let text = |key: &str| {
+        let value = &metadata.get(key)?.0;
+        //Artists come as a list, only the first one is used. Some players send a single string.
+        match value.as_str() {
+            Some(text) => Some(text.to_owned()),
+            None => value.as_iter()?.next()?.as_str().map(str::to_owned),
+        }
+    };
+    Some(NowPlaying {
+        artist: text("xesam:artist")?,
+        album: text("xesam:album").unwrap_or_default(),
+        title: text("xesam:title")?,
+    })
+}
+
+//The bus names of all players on the session bus
+fn players(conn: &Connection) -> Result<Vec<String>, PlayerError> {
+    let call = Message::new_method_call(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        "org.freedesktop.DBus",
+        "ListNames",
+    )
+    .expect("valid method call");
+    let reply = conn.send_with_reply_and_block(call, TIMEOUT_MS)?;
+    let names: Vec<String> = reply.read1().map_err(::dbus::Error::from)?;
+    Ok(names
+        .into_iter()
+        .filter(|name| name.starts_with(BUS_PREFIX))
+        .collect())
+}
+
+//What the player owning `name` is playing, None if it is paused, stopped or doesn't say
+fn playing(conn: &Connection, name: &str) -> Option<NowPlaying> {
+    let player = conn.with_path(name, OBJECT_PATH, TIMEOUT_MS);
+    let status: String = player.get(PLAYER_INTERFACE, "PlaybackStatus").ok()?;
+    if status != "Playing" {
+        return None;
+    }
+    let metadata: PropertyMap = player.get(PLAYER_INTERFACE, "Metadata").ok()?;
+    now_playing(&metadata)
+}
+
+//Connect to the session bus and ask to be told about changes to any player
+fn connect() -> Result<Connection, PlayerError> {
+    let conn = Connection::get_private(BusType::Session)?;
+    conn.add_match(&format!(
+        "type='signal',interface='{}',member='PropertiesChanged',path='{}'",
+        PROPERTIES_INTERFACE, OBJECT_PATH
+    ))?;
+    Ok(conn)
+}
+
+//Follows the MPRIS players on the session bus. Watching stops when this is dropped.
+pub struct MprisWatcher {
+    stop: Arc<AtomicBool>,
+}
+
+impl MprisWatcher {
+    //Call `changed` from another thread whenever a player starts playing another song, and
+    //right away if one is playing already
+    pub fn new<F>(changed: F) -> Result<MprisWatcher, PlayerError>
+    where
+        F: Fn(NowPlaying) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        //A connection has to stay on the thread it was made on, only whether it worked is sent
+        let (started, result) = mpsc::channel();
+        thread::spawn(move || {
+            let conn = match connect() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    let _ = started.send(Err(e));
+                    return;
+                }
+            };
+            let mut last = players(&conn)
+                .ok()
+                .and_then(|names| names.iter().filter_map(|name| playing(&conn, name)).next());
+            let _ = started.send(Ok(()));
+            if let Some(ref song) = last {
+                changed(song.clone());
+            }
+
+            while !stopped.load(Ordering::SeqCst) {
+                for message in conn.incoming(POLL_MS) {
+                    match PropertiesPropertiesChanged::from_message(&message) {
+                        Some(ref signal) if signal.interface_name == PLAYER_INTERFACE => (),
+                        _ => continue,
+                    }
+                    //The signal only has what changed, ask the player for the whole picture
+                    let song = match message.sender().and_then(|sender| playing(&conn, &sender)) {
+                        Some(song) => song,
+                        None => continue,
+                    };
+                    if last.as_ref() != Some(&song) {
+                        changed(song.clone());
+                        last = Some(song);
+                    }
+                }
+            }
+        });
+
+        match result.recv() {
+            Ok(Err(e)) => Err(e),
+            _ => Ok(MprisWatcher { stop }),
+        }
+    }
+}
+
+impl Drop for MprisWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+//Properties of the mock player, it is stopped until the first song arrives
+fn mock_properties(song: Option<&NowPlaying>, number: u32) -> PropertyMap {
+    let mut properties = PropertyMap::new();
+    let mut metadata = PropertyMap::new();
+    let status = match song {
+        Some(song) => {
+            let track_id =
+                Path::new(format!("/org/lyrics/mock/track{}", number)).expect("valid object path");
+            metadata.insert("mpris:trackid".to_owned(), Variant(Box::new(track_id)));
+            metadata.insert(
+                "xesam:artist".to_owned(),
+                Variant(Box::new(vec![song.artist.clone()])),
+            );
+            metadata.insert(
+                "xesam:album".to_owned(),
+                Variant(Box::new(song.album.clone())),
+            );
+            metadata.insert(
+                "xesam:title".to_owned(),
+                Variant(Box::new(song.title.clone())),
+            );
+            "Playing"
+        }
+        None => "Stopped",
+    };
+    properties.insert(
+        "PlaybackStatus".to_owned(),
+        Variant(Box::new(status.to_owned())),
+    );
+    properties.insert("Metadata".to_owned(), Variant(Box::new(metadata)));
+    properties
+}
+
+//Answer a Properties call made to the mock player
+fn mock_reply(call: &Message, mut properties: PropertyMap) -> Option<Message> {
+    if call.interface().as_ref().map(|i| &**i) != Some(PROPERTIES_INTERFACE) {
+        return Message::new_error(
+            call,
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            "Only properties can be read",
+        );
+    }
+    let interface = call.get1::<&str>();
+    if interface != Some(PLAYER_INTERFACE) {
+        return Message::new_error(
+            call,
+            "org.freedesktop.DBus.Error.UnknownInterface",
+            "Only the player interface is there",
+        );
+    }
+    match call.member().as_ref().map(|m| &**m) {
+        Some("GetAll") => Message::new_method_return(call).map(|reply| reply.append1(properties)),
+        Some("Get") => match call
+            .get2::<&str, &str>()
+            .1
+            .and_then(|name| properties.remove(name))
+        {
+            Some(value) => Message::new_method_return(call).map(|reply| reply.append1(value)),
+            None => Message::new_error(
+                call,
+                "org.freedesktop.DBus.Error.UnknownProperty",
+                "No such property",
+            ),
+        },
+        _ => Message::new_error(
+            call,
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            "Properties can't be changed",
+        ),
+    }
+}
+
+//Pretend to be a player on the session bus that plays each song of `songs` in turn, as they
+//arrive. Runs until there are no more songs.
+pub fn serve_mock<I>(songs: I) -> Result<(), PlayerError>
+where
+    I: Iterator<Item = NowPlaying> + Send + 'static,
+{
+    let conn = Connection::get_private(BusType::Session)?;
+    conn.register_name(MOCK_BUS_NAME, NameFlag::DoNotQueue.value())?;
+    conn.register_object_path(OBJECT_PATH)?;
+
+    //Songs can take a while to come, the bus is served meanwhile
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for song in songs {
+            if sender.send(song).is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut number = 0;
+    let mut song = None;
+    loop {
+        for call in conn.incoming(POLL_MS) {
+            if call.msg_type() != MessageType::MethodCall {
+                continue;
+            }
+            if let Some(reply) = mock_reply(&call, mock_properties(song.as_ref(), number)) {
+                let _ = conn.send(reply);
+            }
+        }
+
+        match receiver.try_recv() {
+            Ok(next) => song = Some(next),
+            Err(TryRecvError::Empty) => continue,
+            Err(TryRecvError::Disconnected) => return Ok(()),
+        }
+        number += 1;
+        let signal = PropertiesPropertiesChanged {
+            interface_name: PLAYER_INTERFACE.to_owned(),
+            changed_properties: mock_properties(song.as_ref(), number),
+            invalidated_properties: Vec::new(),
+        };
+        let _ = conn.send(signal.to_emit_message(&Path::from(OBJECT_PATH)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    //A session bus of its own, so the test doesn't depend on the desktop it runs in and doesn't
+    //meet real players. It is stopped when dropped.
+    struct SessionBus(Child);
+
+    impl SessionBus {
+        fn start() -> SessionBus {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(&["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("dbus-daemon is needed to test the MPRIS watcher");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+            SessionBus(daemon)
+        }
+    }
+
+    impl Drop for SessionBus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn song(artist: &str, title: &str) -> NowPlaying {
+        NowPlaying {
+            artist: artist.to_owned(),
+            album: "Album".to_owned(),
+            title: title.to_owned(),
+        }
+    }
+
+    fn metadata(artist: Box<dyn RefArg>) -> PropertyMap {
+        let mut metadata = PropertyMap::new();
+        metadata.insert("xesam:artist".to_owned(), Variant(artist));
+        metadata.insert(
+            "xesam:title".to_owned(),
+            Variant(Box::new("Title".to_owned())),
+        );
+        metadata
+    }
+
+    #[test]
+    fn artist_can_be_a_list_or_a_string() {
+        let listed = metadata(Box::new(vec!["First".to_owned(), "Second".to_owned()]));
+        let single = metadata(Box::new("Only".to_owned()));
+        let playing = |metadata| now_playing(&metadata).map(|song| (song.artist, song.album));
+        assert_eq!(playing(listed), Some(("First".to_owned(), String::new())));
+        assert_eq!(playing(single), Some(("Only".to_owned(), String::new())));
+        assert_eq!(playing(PropertyMap::new()), None);
+    }
+
+    #[test]
+    fn watcher_follows_the_mock_player() {
+        let _bus = SessionBus::start();
+        let (reported, changes) = mpsc::channel();
+        let _watcher = MprisWatcher::new(move |song| {
+            let _ = reported.send(song);
+        })
+        .unwrap();
+        let (play, songs) = mpsc::channel();
+        let mock = thread::spawn(move || serve_mock(songs.into_iter()));
+
+        for next in &[song("Artist", "One"), song("Artist", "Two")] {
+            play.send(next.clone()).unwrap();
+            assert_eq!(
+                changes.recv_timeout(Duration::from_secs(5)).as_ref(),
+                Ok(next)
+            );
+        }
+        drop(play);
+        mock.join().unwrap().unwrap();
+    }
+}
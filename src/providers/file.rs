@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{Candidate, LyricsProvider, ProviderError};
+use database::merge::normalize;
+
+//Lyrics kept as text files in a directory, laid out as <artist>/<album>/<title>.txt. Names are
+//matched like the merge matches them. A title found on another album of the artist is offered
+//too, after those on the asked for album.
+pub struct FileProvider {
+    dir: PathBuf,
+}
+
+//Entries of a directory whose normalized name, without the extension for files, is `name`
+fn matching(dir: &Path, name: &str, files: bool) -> Result<Vec<PathBuf>, ProviderError> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let stem = if files {
+            if path.extension().and_then(|e| e.to_str()) != Some("txt") {
+                continue;
+            }
+            path.file_stem()
+        } else {
+            if !path.is_dir() {
+                continue;
+            }
+            path.file_name()
+        };
+        if stem.map_or(false, |stem| normalize(&stem.to_string_lossy()) == name) {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+impl FileProvider {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> FileProvider {
+        FileProvider { dir: dir.into() }
+    }
+}
+
+impl LyricsProvider for FileProvider {
+    fn name(&self) -> &str {
+        "lyrics files"
+    }
+
+    fn lookup(
+        &self,
+        artist: &str,
+        album: &str,
+        title: &str,
+    ) -> Result<Vec<Candidate>, ProviderError> {
+        let (album, title) = (normalize(album), normalize(title));
+        let mut first = Vec::new();
+        let mut rest = Vec::new();
+        for artist_dir in matching(&self.dir, &normalize(artist), false)? {
+            for album_dir in fs::read_dir(&artist_dir)? {
+                let album_dir = album_dir?.path();
+                if !album_dir.is_dir() {
+                    continue;
+                }
+                let same_album = album_dir
+                    .file_name()
+                    .map_or(false, |name| normalize(&name.to_string_lossy()) == album);
+                for path in matching(&album_dir, &title, true)? {
+                    let candidate = Candidate {
+                        lyrics: fs::read_to_string(&path)?,
+                        source: path.display().to_string(),
+                    };
+                    if same_album {
+                        first.push(candidate);
+                    } else {
+                        rest.push(candidate);
+                    }
+                }
+            }
+        }
+        first.extend(rest);
+        Ok(first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::testing::*;
+
+    fn write(dir: &str, path: &str, lyrics: &str) {
+        let path = Path::new(dir).join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, lyrics).unwrap();
+    }
+
+    fn lyrics(found: &[Candidate]) -> Vec<&str> {
+        found.iter().map(|c| c.lyrics.as_str()).collect()
+    }
+
+    #[test]
+    fn names_are_matched_normalized() {
+        let path = temp_path("files");
+        write(&path, "The  Artist/First Album/My Song.txt", "red sky");
+        write(&path, "The  Artist/First Album/My Song.md", "not lyrics");
+        write(&path, "The  Artist/First Album/Other.txt", "blue sea");
+        let provider = FileProvider::new(&path);
+
+        let found = provider
+            .lookup(" the artist", "FIRST ALBUM", "my   song")
+            .unwrap();
+        assert_eq!(lyrics(&found), ["red sky"]);
+        assert!(found[0].source.ends_with("My Song.txt"));
+        assert!(provider
+            .lookup("Nobody", "First Album", "My Song")
+            .unwrap()
+            .is_empty());
+        remove_dir(&path);
+    }
+
+    //Other albums of the artist come after the asked for album, whatever their names
+    #[test]
+    fn asked_for_album_comes_first() {
+        let path = temp_path("files");
+        write(&path, "Artist/A Live/Song.txt", "live");
+        write(&path, "Artist/B Studio/Song.txt", "studio");
+        write(&path, "Artist/C Demo/song.txt", "demo");
+        let provider = FileProvider::new(&path);
+
+        let found = provider.lookup("Artist", "B Studio", "Song").unwrap();
+        assert_eq!(lyrics(&found)[0], "studio");
+        let mut rest = lyrics(&found)[1..].to_vec();
+        rest.sort();
+        assert_eq!(rest, ["demo", "live"]);
+        remove_dir(&path);
+    }
+}
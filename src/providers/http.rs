@@ -0,0 +1,236 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serde_json::{self, Value};
+
+use super::{Candidate, LyricsProvider, ProviderError};
+
+//How long to wait for the server before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+//Lyrics of a track are a few kilobytes, a response bigger than this is refused
+const MAX_RESPONSE_BYTES: u64 = 4 * 1024 * 1024;
+
+//Asks a web service at GET <base>/lyrics?artist=..&album=..&title=.. for a JSON list of
+//{"lyrics", "source"} objects. Only plain http is spoken, which is enough for services on the
+//local network and for `lyrics provide`.
+pub struct HttpProvider {
+    base: String,
+}
+
+fn encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+//Connect to the first of the addresses `address` resolves to that answers within `TIMEOUT`
+fn connect(address: &str) -> Result<TcpStream, ProviderError> {
+    let mut last_error = None;
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.map_or_else(
+        || ProviderError::Http(format!("{} has no address", address)),
+        ProviderError::Io,
+    ))
+}
+
+//The status and body of a GET request to an http:// url
+fn get(url: &str) -> Result<(u16, String), ProviderError> {
+    let rest = if url.starts_with("http://") {
+        &url["http://".len()..]
+    } else {
+        return Err(ProviderError::Http(format!(
+            "only http:// urls work, not {}",
+            url
+        )));
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = connect(&address)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+        path, host
+    )?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_BYTES + 1)
+        .read_to_end(&mut response)?;
+    if response.len() as u64 > MAX_RESPONSE_BYTES {
+        return Err(ProviderError::Http("the response is too large".to_owned()));
+    }
+    let response = String::from_utf8_lossy(&response);
+
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(i) => (&response[..i], &response[i + 4..]),
+        None => return Err(ProviderError::Http("incomplete response".to_owned())),
+    };
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| ProviderError::Http("no status in the response".to_owned()))?;
+    Ok((status, body.to_owned()))
+}
+
+impl HttpProvider {
+    pub fn new(base: &str) -> HttpProvider {
+        HttpProvider {
+            base: base.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+impl LyricsProvider for HttpProvider {
+    fn name(&self) -> &str {
+        &self.base
+    }
+
+    fn lookup(
+        &self,
+        artist: &str,
+        album: &str,
+        title: &str,
+    ) -> Result<Vec<Candidate>, ProviderError> {
+        let url = format!(
+            "{}/lyrics?artist={}&album={}&title={}",
+            self.base,
+            encode(artist),
+            encode(album),
+            encode(title)
+        );
+        let body = match get(&url)? {
+            (200, body) => body,
+            (404, _) => return Ok(Vec::new()),
+            (status, _) => return Err(ProviderError::Http(format!("status {}", status))),
+        };
+
+        let found = match serde_json::from_str(&body) {
+            Ok(Value::Array(found)) => found,
+            Ok(_) => return Err(ProviderError::Format("expected a list".to_owned())),
+            Err(e) => return Err(ProviderError::Format(e.to_string())),
+        };
+        let mut candidates = Vec::new();
+        for item in found {
+            let lyrics = match item.get("lyrics").and_then(Value::as_str) {
+                Some(lyrics) => lyrics.to_owned(),
+                None => return Err(ProviderError::Format("lyrics missing".to_owned())),
+            };
+            //Without a source of its own, the lyrics are at least known to come from here
+            let source = item
+                .get("source")
+                .and_then(Value::as_str)
+                .map_or_else(|| self.base.clone(), str::to_owned);
+            candidates.push(Candidate { lyrics, source });
+        }
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    //A server on a port of its own that answers one request with `body`
+    fn answering(status: &str, body: &str) -> HttpProvider {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let status = status.to_owned();
+        let body = body.to_owned();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let mut stream = reader.into_inner();
+            //The client may stop reading before the end
+            let _ = write!(stream, "HTTP/1.0 {}\r\n\r\n{}", status, body);
+        });
+        HttpProvider::new(&base)
+    }
+
+    #[test]
+    fn not_found_is_no_lyrics() {
+        let provider = answering("404 Not Found", "");
+        assert!(provider.lookup("a", "b", "c").unwrap().is_empty());
+    }
+
+    #[test]
+    fn other_statuses_are_errors() {
+        match answering("500 Internal Server Error", "").lookup("a", "b", "c") {
+            Err(ProviderError::Http(_)) => (),
+            other => panic!("expected an http error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_json_is_an_error() {
+        match answering("200 OK", "[{\"lyrics\": ").lookup("a", "b", "c") {
+            Err(ProviderError::Format(_)) => (),
+            other => panic!("expected a format error, got {:?}", other),
+        }
+        match answering("200 OK", "{}").lookup("a", "b", "c") {
+            Err(ProviderError::Format(_)) => (),
+            other => panic!("expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_source_is_the_provider() {
+        let provider = answering("200 OK", "[{\"lyrics\": \"red sky\"}]");
+        let found = provider.lookup("a", "b", "c").unwrap();
+        assert_eq!(found[0].lyrics, "red sky");
+        assert_eq!(found[0].source, provider.name());
+    }
+
+    #[test]
+    fn responses_over_the_limit_are_refused() {
+        let lyrics = "a".repeat(MAX_RESPONSE_BYTES as usize);
+        let body = format!("[{{\"lyrics\": \"{}\"}}]", lyrics);
+        match answering("200 OK", &body).lookup("a", "b", "c") {
+            Err(ProviderError::Http(_)) => (),
+            other => panic!("expected an http error, got {:?}", other.map(|c| c.len())),
+        }
+    }
+
+    #[test]
+    fn hosts_without_a_server_are_errors() {
+        //Bound and let go again, so nothing listens on the port
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let provider = HttpProvider::new(&format!("http://{}", address));
+        match provider.lookup("a", "b", "c") {
+            Err(ProviderError::Io(_)) => (),
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+}
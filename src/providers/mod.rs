@@ -0,0 +1,96 @@
+pub mod file;
+pub mod http;
+
+use std::convert;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+pub use self::file::FileProvider;
+pub use self::http::HttpProvider;
+
+//Lyrics a provider found for a track
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub lyrics: String,
+    //Where the lyrics come from, shown to the user before they are used
+    pub source: String,
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    Io(io::Error),
+    Http(String),
+    Format(String),
+}
+
+impl Error for ProviderError {
+    fn description(&self) -> &str {
+        match self {
+            ProviderError::Io(e) => e.description(),
+            ProviderError::Http(_) => "HTTP request failed",
+            ProviderError::Format(_) => "Malformed answer",
+        }
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProviderError::Io(e) => write!(f, "io error: {}", e),
+            ProviderError::Http(e) => write!(f, "http error: {}", e),
+            ProviderError::Format(e) => write!(f, "malformed answer: {}", e),
+        }
+    }
+}
+
+impl convert::From<io::Error> for ProviderError {
+    fn from(err: io::Error) -> ProviderError {
+        ProviderError::Io(err)
+    }
+}
+
+//Finds lyrics for tracks somewhere outside the database
+pub trait LyricsProvider: Send {
+    //Shown to the user when the provider fails
+    fn name(&self) -> &str;
+    //Candidates for the track, best first. Finding none is not an error.
+    fn lookup(
+        &self,
+        artist: &str,
+        album: &str,
+        title: &str,
+    ) -> Result<Vec<Candidate>, ProviderError>;
+}
+
+//The providers set up with LYRICS_PROVIDER_DIR and LYRICS_PROVIDER_URL
+pub fn configured() -> Vec<Box<dyn LyricsProvider>> {
+    let mut providers: Vec<Box<dyn LyricsProvider>> = Vec::new();
+    if let Ok(dir) = env::var("LYRICS_PROVIDER_DIR") {
+        providers.push(Box::new(FileProvider::new(dir)));
+    }
+    if let Ok(url) = env::var("LYRICS_PROVIDER_URL") {
+        providers.push(Box::new(HttpProvider::new(&url)));
+    }
+    providers
+}
+
+//Ask every provider in turn. A failing provider doesn't stop the others, its error is returned
+//next to what the rest found.
+pub fn lookup_all(
+    providers: &[Box<dyn LyricsProvider>],
+    artist: &str,
+    album: &str,
+    title: &str,
+) -> (Vec<Candidate>, Vec<String>) {
+    let mut candidates = Vec::new();
+    let mut errors = Vec::new();
+    for provider in providers {
+        match provider.lookup(artist, album, title) {
+            Ok(found) => candidates.extend(found),
+            Err(e) => errors.push(format!("{}: {}", provider.name(), e)),
+        }
+    }
+    (candidates, errors)
+}
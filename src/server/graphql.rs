@@ -0,0 +1,459 @@
+use std::cell::RefCell;
+
+use juniper::http::GraphQLRequest;
+use juniper::parser::{Lexer, Token};
+use juniper::{self, FieldError, FieldResult, InputValue, RootNode};
+use serde_json::{self, Value};
+
+use super::{rollback_on_error, save, Request, Response};
+use database::metadata::*;
+use database::Database;
+
+//Shared by every request, both REST and GraphQL
+pub struct Context {
+    pub db: RefCell<Database>,
+}
+
+impl juniper::Context for Context {}
+
+impl Context {
+    pub fn new(db: Database) -> Context {
+        Context {
+            db: RefCell::new(db),
+        }
+    }
+}
+
+//Objects point into the database by index, they are only kept for a single request
+pub struct ArtistNode(usize);
+pub struct AlbumNode(usize, usize);
+pub struct TrackNode(usize, usize, usize);
+
+pub struct Query;
+pub struct Mutation;
+
+pub type Schema = RootNode<'static, Query, Mutation>;
+
+pub fn schema() -> Schema {
+    Schema::new(Query, Mutation)
+}
+
+//Case-insensitive substring match
+fn contains(text: &str, part: &str) -> bool {
+    text.to_lowercase().contains(&part.to_lowercase())
+}
+
+fn matches(text: &str, exact: &Option<String>, part: &Option<String>) -> bool {
+    exact.as_ref().map_or(true, |exact| text == exact)
+        && part.as_ref().map_or(true, |part| contains(text, part))
+}
+
+//Skip `offset` items and keep the next `first`, or all of them
+fn page<T>(items: Vec<T>, first: Option<i32>, offset: Option<i32>) -> FieldResult<Vec<T>> {
+    let offset = offset.unwrap_or(0);
+    if offset < 0 || first.map_or(false, |first| first < 0) {
+        return Err(FieldError::from("first and offset can't be negative"));
+    }
+    let first = first.map_or(items.len(), |first| first as usize);
+    Ok(items
+        .into_iter()
+        .skip(offset as usize)
+        .take(first)
+        .collect())
+}
+
+fn find_album(db: &Database, artist: &str, album: &str) -> FieldResult<(usize, usize)> {
+    let a = db
+        .entries
+        .iter()
+        .position(|x| x.name == artist)
+        .ok_or_else(|| FieldError::from(format!("no artist named {}", artist)))?;
+    let b = db.entries[a]
+        .albums
+        .iter()
+        .position(|x| x.title == album)
+        .ok_or_else(|| FieldError::from(format!("no album named {}", album)))?;
+    Ok((a, b))
+}
+
+fn find_track(
+    db: &Database,
+    artist: &str,
+    album: &str,
+    title: &str,
+) -> FieldResult<(usize, usize, usize)> {
+    let (a, b) = find_album(db, artist, album)?;
+    let c = db.entries[a].albums[b]
+        .tracks
+        .iter()
+        .position(|x| x.title == title)
+        .ok_or_else(|| FieldError::from(format!("no track named {}", title)))?;
+    Ok((a, b, c))
+}
+
+//Changes are written to the database file before they are answered
+fn persist(db: &mut Database) -> FieldResult<()> {
+    save(db).map_err(|e| FieldError::from(format!("could not save: {}", e)))
+}
+
+graphql_object!(Query: Context |&self| {
+    field artists(
+        &executor,
+        name: Option<String>,
+        name_contains: Option<String> as "Only artists whose name contains this, ignoring case",
+        first: Option<i32>,
+        offset: Option<i32>
+    ) -> FieldResult<Vec<ArtistNode>> {
+        let db = executor.context().db.borrow();
+        let artists = db
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|&(_, artist)| matches(&artist.name, &name, &name_contains))
+            .map(|(a, _)| ArtistNode(a))
+            .collect();
+        page(artists, first, offset)
+    }
+
+    field artist(&executor, name: String) -> Option<ArtistNode> {
+        let db = executor.context().db.borrow();
+        db.entries.iter().position(|artist| artist.name == name).map(ArtistNode)
+    }
+
+    field search(
+        &executor,
+        query: String as "Words that all have to be in the lyrics",
+        first: Option<i32>,
+        offset: Option<i32>
+    ) -> FieldResult<Vec<TrackNode>> {
+        let mut db = executor.context().db.borrow_mut();
+        let found = db.search(&query)?;
+        let tracks = found
+            .iter()
+            .filter_map(|t| find_track(&db, &t.artist, &t.album, &t.title).ok())
+            .map(|(a, b, c)| TrackNode(a, b, c))
+            .collect();
+        page(tracks, first, offset)
+    }
+});
+
+graphql_object!(ArtistNode: Context as "Artist" |&self| {
+    field name(&executor) -> String {
+        executor.context().db.borrow().entries[self.0].name.clone()
+    }
+
+    field album_count(&executor) -> i32 {
+        executor.context().db.borrow().entries[self.0].albums.len() as i32
+    }
+
+    field albums(
+        &executor,
+        title: Option<String>,
+        title_contains: Option<String>,
+        first: Option<i32>,
+        offset: Option<i32>
+    ) -> FieldResult<Vec<AlbumNode>> {
+        let db = executor.context().db.borrow();
+        let albums = db.entries[self.0]
+            .albums
+            .iter()
+            .enumerate()
+            .filter(|&(_, album)| matches(&album.title, &title, &title_contains))
+            .map(|(b, _)| AlbumNode(self.0, b))
+            .collect();
+        page(albums, first, offset)
+    }
+});
+
+graphql_object!(AlbumNode: Context as "Album" |&self| {
+    field title(&executor) -> String {
+        executor.context().db.borrow().entries[self.0].albums[self.1].title.clone()
+    }
+
+    field track_count(&executor) -> i32 {
+        i32::from(executor.context().db.borrow().entries[self.0].albums[self.1].track_count)
+    }
+
+    field artist() -> ArtistNode {
+        ArtistNode(self.0)
+    }
+
+    field tracks(
+        &executor,
+        title: Option<String>,
+        title_contains: Option<String>,
+        has_lyrics: Option<bool>,
+        first: Option<i32>,
+        offset: Option<i32>
+    ) -> FieldResult<Vec<TrackNode>> {
+        let db = executor.context().db.borrow();
+        let tracks = db.entries[self.0].albums[self.1]
+            .tracks
+            .iter()
+            .enumerate()
+            .filter(|&(_, track)| matches(&track.title, &title, &title_contains))
+            .filter(|&(_, track)| {
+                has_lyrics.map_or(true, |has| has == !track.lyrics.trim().is_empty())
+            })
+            .map(|(c, _)| TrackNode(self.0, self.1, c))
+            .collect();
+        page(tracks, first, offset)
+    }
+});
+
+graphql_object!(TrackNode: Context as "Track" |&self| {
+    field num(&executor) -> i32 {
+        let db = executor.context().db.borrow();
+        i32::from(db.entries[self.0].albums[self.1].tracks[self.2].track)
+    }
+
+    field title(&executor) -> String {
+        let db = executor.context().db.borrow();
+        db.entries[self.0].albums[self.1].tracks[self.2].title.clone()
+    }
+
+    field lyrics(&executor) -> String {
+        let db = executor.context().db.borrow();
+        db.entries[self.0].albums[self.1].tracks[self.2].lyrics.clone()
+    }
+
+    field alternates(&executor) -> Vec<Alternate> {
+        let db = executor.context().db.borrow();
+        db.entries[self.0].albums[self.1].tracks[self.2].alternates.clone()
+    }
+
+    field album() -> AlbumNode {
+        AlbumNode(self.0, self.1)
+    }
+
+    field artist() -> ArtistNode {
+        ArtistNode(self.0)
+    }
+});
+
+graphql_object!(Alternate: Context |&self| {
+    description: "Where a duplicate of the track was found before it was merged into it"
+
+    field artist() -> &String {
+        &self.artist
+    }
+
+    field album() -> &String {
+        &self.album
+    }
+
+    field title() -> &String {
+        &self.title
+    }
+});
+
+graphql_object!(Mutation: Context |&self| {
+    field update_lyrics(
+        &executor,
+        artist: String,
+        album: String,
+        title: String,
+        lyrics: String
+    ) -> FieldResult<TrackNode> {
+        let mut db = executor.context().db.borrow_mut();
+        rollback_on_error(&mut db, |db| {
+            let (a, b, c) = find_track(db, &artist, &album, &title)?;
+            {
+                let track = &mut db.entries[a].albums[b].tracks[c];
+                track.lyrics = lyrics;
+                track.lyrics_source = None;
+            }
+            persist(db)?;
+            Ok(TrackNode(a, b, c))
+        })
+    }
+
+    field add_track(
+        &executor,
+        artist: String,
+        album: String,
+        title: String,
+        lyrics: Option<String>,
+        num: Option<i32> as "Position on the album, after the last track if not given"
+    ) -> FieldResult<TrackNode> {
+        let mut db = executor.context().db.borrow_mut();
+        rollback_on_error(&mut db, |db| {
+            let (a, b) = find_album(db, &artist, &album)?;
+            let c = {
+                let album = &mut db.entries[a].albums[b];
+                if album.tracks.iter().any(|track| track.title == title) {
+                    return Err(FieldError::from(format!("there already is a track named {}", title)));
+                }
+                let mut track = Track::new();
+                track.title = title;
+                track.lyrics = lyrics.unwrap_or_default();
+                track.track = match num {
+                    Some(num) if num >= 0 && num <= i32::from(u8::max_value()) => num as u8,
+                    Some(_) => return Err(FieldError::from("num must be between 0 and 255")),
+                    None => album.tracks.iter().map(|t| t.track).max().unwrap_or(0) + 1,
+                };
+                let index = album.insert_sorted(track);
+                album.track_count = album.track_count.max(album.tracks.len() as u8);
+                index
+            };
+            persist(db)?;
+            Ok(TrackNode(a, b, c))
+        })
+    }
+
+    field delete_track(
+        &executor,
+        artist: String,
+        album: String,
+        title: String
+    ) -> FieldResult<bool> {
+        let mut db = executor.context().db.borrow_mut();
+        rollback_on_error(&mut db, |db| {
+            let (a, b, c) = find_track(db, &artist, &album, &title)?;
+            let removed: Vec<_> = db.track_ref((a, b, c)).into_iter().collect();
+            db.entries[a].albums[b].tracks.remove(c);
+            db.remove_from_setlists(&removed);
+            persist(db)?;
+            Ok(true)
+        })
+    }
+});
+
+//Whether `query` defines a mutation. Operations start with their type outside of any braces or
+//parentheses, a document that can't be read is left for the parser to refuse.
+fn has_mutation(query: &str) -> bool {
+    let mut depth = 0;
+    for token in Lexer::new(query) {
+        match token.map(|token| token.item) {
+            Ok(Token::CurlyOpen) | Ok(Token::ParenOpen) => depth += 1,
+            Ok(Token::CurlyClose) | Ok(Token::ParenClose) => depth -= 1,
+            Ok(Token::Name("mutation")) if depth == 0 => return true,
+            Ok(Token::EndOfFile) | Err(_) => return false,
+            Ok(_) => (),
+        }
+    }
+    false
+}
+
+//Answer a GraphQL query, sent as JSON in a POST body or in the query string of a GET. A GET
+//can't change anything, any page could make a browser send one.
+pub fn handle(schema: &Schema, context: &Context, request: &Request) -> Response {
+    let graphql_request = match (request.method.as_str(), &request.body) {
+        ("POST", &Some(ref body)) => match serde_json::from_value(body.clone()) {
+            Ok(graphql_request) => graphql_request,
+            Err(e) => return Response::error(400, &format!("invalid GraphQL request: {}", e)),
+        },
+        ("GET", _) => {
+            let query = match request.query.get("query") {
+                Some(query) => query.clone(),
+                None => return Response::error(400, "a query is needed"),
+            };
+            if has_mutation(&query) {
+                return Response::error(405, "mutations need a POST request");
+            }
+            let variables = match request.query.get("variables") {
+                Some(variables) => match serde_json::from_str::<InputValue>(variables) {
+                    Ok(variables) => Some(variables),
+                    Err(e) => return Response::error(400, &format!("invalid variables: {}", e)),
+                },
+                None => None,
+            };
+            GraphQLRequest::new(
+                query,
+                request.query.get("operationName").cloned(),
+                variables,
+            )
+        }
+        _ => return Response::error(405, "GraphQL takes GET and POST requests"),
+    };
+
+    let response = graphql_request.execute(schema, context);
+    let status = if response.is_ok() { 200 } else { 400 };
+    Response::json(
+        status,
+        serde_json::to_value(&response).unwrap_or(Value::Null),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use database::testing::*;
+
+    fn saved(name: &str) -> (String, Context) {
+        let path = temp_path(name);
+        let mut db = Database::empty();
+        db.entries = entries("Artist", "Album", vec![track("One", 1, "red sky")]);
+        db.save(&path).unwrap();
+        (path.clone(), Context::new(Database::from(&path).unwrap()))
+    }
+
+    fn get(query: &str) -> Request {
+        let mut parameters = HashMap::new();
+        parameters.insert("query".to_owned(), query.to_owned());
+        Request {
+            method: "GET".to_owned(),
+            path: vec!["graphql".to_owned()],
+            query: parameters,
+            if_match: None,
+            if_none_match: None,
+            body: None,
+        }
+    }
+
+    fn post(query: &str) -> Request {
+        Request {
+            method: "POST".to_owned(),
+            path: vec!["graphql".to_owned()],
+            query: HashMap::new(),
+            if_match: None,
+            if_none_match: None,
+            body: Some(json!({ "query": query })),
+        }
+    }
+
+    const UPDATE: &str = "mutation { updateLyrics(artist: \"Artist\", album: \"Album\", \
+                          title: \"One\", lyrics: \"blue sea\") { title } }";
+
+    fn lyrics(context: &Context) -> String {
+        context.db.borrow().entries[0].albums[0].tracks[0]
+            .lyrics
+            .clone()
+    }
+
+    #[test]
+    fn mutations_are_only_run_on_post() {
+        let (path, context) = saved("get.xml");
+        let schema = schema();
+        assert_eq!(handle(&schema, &context, &get(UPDATE)).status, 405);
+        assert_eq!(
+            handle(
+                &schema,
+                &context,
+                &get(&format!(" # a comment\n{}", UPDATE))
+            )
+            .status,
+            405
+        );
+        assert_eq!(lyrics(&context), "red sky");
+
+        let query = "{ artist(name: \"Artist\") { name mutation: albumCount } }";
+        assert_eq!(handle(&schema, &context, &get(query)).status, 200);
+        assert_eq!(handle(&schema, &context, &post(UPDATE)).status, 200);
+        assert_eq!(lyrics(&context), "blue sea");
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn mutations_that_cannot_be_saved_are_undone() {
+        let (path, context) = saved("unsaved.xml");
+        remove_dir(&path);
+        let schema = schema();
+        let response = handle(&schema, &context, &post(UPDATE));
+        let errors = response.body.as_ref().and_then(|body| body.get("errors"));
+        assert!(errors.is_some());
+        assert_eq!(lyrics(&context), "red sky");
+    }
+}
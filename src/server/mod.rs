@@ -0,0 +1,378 @@
+pub mod graphql;
+pub mod rest;
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use percent_encoding::percent_decode;
+use serde_json::{self, Value};
+use tiny_http::{Header, Request as HttpRequest, Response as HttpResponse, Server};
+
+use database::{Database, DatabaseError};
+use providers::LyricsProvider;
+
+//A request as the handlers see it, with the path split and decoded
+pub struct Request {
+    pub method: String,
+    pub path: Vec<String>,
+    pub query: HashMap<String, String>,
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+    pub body: Option<Value>,
+}
+
+//What a handler answers with, sent as JSON
+pub struct Response {
+    pub status: u16,
+    pub body: Option<Value>,
+    pub etag: Option<String>,
+}
+
+impl Response {
+    pub fn json(status: u16, body: Value) -> Response {
+        Response {
+            status,
+            body: Some(body),
+            etag: None,
+        }
+    }
+
+    pub fn empty(status: u16) -> Response {
+        Response {
+            status,
+            body: None,
+            etag: None,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Response {
+        Response::json(status, json!({ "error": message }))
+    }
+
+    pub fn with_etag(mut self, etag: String) -> Response {
+        self.etag = Some(etag);
+        self
+    }
+}
+
+//Write the database back to its file. Changes are answered as saved even if the history
+//couldn't be kept, the file holds them.
+pub fn save(db: &mut Database) -> Result<(), DatabaseError> {
+    let path = db.file_path().to_owned();
+    match db.save(&path) {
+        Err(DatabaseError::History(_)) => Ok(()),
+        result => result,
+    }
+}
+
+//Undo whatever `change` did to the database if it fails, so the database keeps matching its
+//file. `change` saves the database itself once it is done.
+pub fn rollback_on_error<T, E, F>(db: &mut Database, change: F) -> Result<T, E>
+where
+    F: FnOnce(&mut Database) -> Result<T, E>,
+{
+    let entries = db.entries.clone();
+    let setlists = db.setlists.clone();
+    let result = change(db);
+    if result.is_err() {
+        db.entries = entries;
+        db.setlists = setlists;
+    }
+    result
+}
+
+//Bodies are JSON describing a single item, anything bigger is refused
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+//Read a request body of at most `MAX_BODY_BYTES`
+fn read_body<R: Read>(reader: R) -> Result<String, Response> {
+    let mut body = String::new();
+    if reader
+        .take(MAX_BODY_BYTES + 1)
+        .read_to_string(&mut body)
+        .is_err()
+    {
+        return Err(Response::error(400, "could not read the request body"));
+    }
+    if body.len() as u64 > MAX_BODY_BYTES {
+        return Err(Response::error(413, "the request body is too large"));
+    }
+    Ok(body)
+}
+
+fn decode(text: &str) -> String {
+    percent_decode(text.replace('+', " ").as_bytes())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn header(request: &HttpRequest, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str().to_owned())
+}
+
+//Whether `origin` is a page served from this machine
+fn is_local_origin(origin: &str) -> bool {
+    let host = match origin.find("://") {
+        Some(i) => &origin[i + 3..],
+        None => return false,
+    };
+    let host = if host.starts_with('[') {
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    host == "localhost" || host == "127.0.0.1" || host == "[::1]"
+}
+
+//Any page a browser has open can send requests to localhost. Requests from pages elsewhere are
+//refused by their origin, and bodies have to be JSON, which browsers don't send to another
+//origin without asking it first.
+fn check_sender(
+    origin: Option<&str>,
+    content_type: Option<&str>,
+    has_body: bool,
+) -> Result<(), Response> {
+    if let Some(origin) = origin {
+        if !is_local_origin(origin) {
+            return Err(Response::error(
+                403,
+                "requests from other sites are refused",
+            ));
+        }
+    }
+    let is_json = content_type.map_or(false, |content_type| {
+        let media_type = content_type.split(';').next().unwrap_or("");
+        media_type.trim().eq_ignore_ascii_case("application/json")
+    });
+    if has_body && !is_json {
+        return Err(Response::error(415, "bodies have to be application/json"));
+    }
+    Ok(())
+}
+
+fn parse_request(request: &mut HttpRequest) -> Result<Request, Response> {
+    let (path, query) = {
+        let url = request.url();
+        match url.find('?') {
+            Some(i) => (url[..i].to_owned(), url[i + 1..].to_owned()),
+            None => (url.to_owned(), String::new()),
+        }
+    };
+    //Path segments are decoded one by one, names can contain slashes
+    let path = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            percent_decode(segment.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .collect();
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(i) => (decode(&pair[..i]), decode(&pair[i + 1..])),
+            None => (decode(pair), String::new()),
+        })
+        .collect();
+
+    let body = read_body(request.as_reader())?;
+    check_sender(
+        header(request, "Origin").as_ref().map(String::as_str),
+        header(request, "Content-Type").as_ref().map(String::as_str),
+        !body.trim().is_empty(),
+    )?;
+    let body = if body.trim().is_empty() {
+        None
+    } else {
+        match serde_json::from_str(&body) {
+            Ok(body) => Some(body),
+            Err(e) => return Err(Response::error(400, &format!("invalid JSON: {}", e))),
+        }
+    };
+
+    Ok(Request {
+        method: request.method().as_str().to_owned(),
+        path,
+        query,
+        if_match: header(request, "If-Match"),
+        if_none_match: header(request, "If-None-Match"),
+        body,
+    })
+}
+
+fn respond(request: HttpRequest, response: Response) {
+    let body = response
+        .body
+        .map_or_else(String::new, |body| body.to_string());
+    let mut http = HttpResponse::from_string(body).with_status_code(response.status);
+    if response.status != 204 && response.status != 304 {
+        http.add_header(
+            Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
+        );
+    }
+    if let Some(etag) = response.etag {
+        http.add_header(Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap());
+    }
+    //The client may be gone already, there is nobody to tell then
+    let _ = request.respond(http);
+}
+
+//Answer requests on localhost until the process is stopped, GraphQL at /graphql and REST
+//everywhere else. Requests are handled one at a time, each write is saved to the database file
+//before it is answered.
+pub fn serve(db: Database, port: u16) -> Result<(), String> {
+    let server = Server::http(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    let schema = graphql::schema();
+    let context = graphql::Context::new(db);
+    for mut request in server.incoming_requests() {
+        let response = match parse_request(&mut request) {
+            Ok(ref parsed) if parsed.path == ["graphql"] => {
+                graphql::handle(&schema, &context, parsed)
+            }
+            Ok(parsed) => rest::handle(&mut context.db.borrow_mut(), &parsed),
+            Err(response) => response,
+        };
+        respond(request, response);
+    }
+    Ok(())
+}
+
+//Answer GET /lyrics?artist=..&album=..&title=.. with what `provider` finds, in the form
+//`HttpProvider` reads. Lets the HTTP provider be tried against lyrics files on this machine.
+pub fn serve_provider(provider: &dyn LyricsProvider, port: u16) -> Result<(), String> {
+    let server = Server::http(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    provide(&server, provider);
+    Ok(())
+}
+
+fn provide(server: &Server, provider: &dyn LyricsProvider) {
+    for mut request in server.incoming_requests() {
+        let response = match parse_request(&mut request) {
+            Ok(ref parsed) if parsed.method == "GET" && parsed.path == ["lyrics"] => {
+                let field = |name: &str| parsed.query.get(name).map_or("", String::as_str);
+                match provider.lookup(field("artist"), field("album"), field("title")) {
+                    Ok(ref found) if found.is_empty() => Response::error(404, "no lyrics found"),
+                    Ok(found) => Response::json(
+                        200,
+                        Value::Array(
+                            found
+                                .into_iter()
+                                .map(|c| json!({ "lyrics": c.lyrics, "source": c.source }))
+                                .collect(),
+                        ),
+                    ),
+                    Err(e) => Response::error(500, &e.to_string()),
+                }
+            }
+            Ok(_) => Response::error(404, "unknown path"),
+            Err(response) => response,
+        };
+        respond(request, response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+
+    use super::*;
+    use database::testing::*;
+    use providers::{FileProvider, HttpProvider};
+
+    #[test]
+    fn bodies_up_to_the_limit_are_read() {
+        let body = vec![b' '; MAX_BODY_BYTES as usize];
+        assert_eq!(read_body(&body[..]).ok().map(|b| b.len()), Some(body.len()));
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_refused() {
+        let body = vec![b' '; MAX_BODY_BYTES as usize + 1];
+        match read_body(&body[..]) {
+            Err(response) => assert_eq!(response.status, 413),
+            Ok(_) => panic!("a body over the limit was read"),
+        }
+    }
+
+    #[test]
+    fn pages_elsewhere_are_refused() {
+        for origin in &[
+            "http://localhost:8080",
+            "http://127.0.0.1",
+            "https://[::1]:8080",
+        ] {
+            assert!(
+                check_sender(Some(origin), None, false).is_ok(),
+                "{}",
+                origin
+            );
+        }
+        for origin in &[
+            "http://example.com",
+            "http://localhost.example.com",
+            "http://127.0.0.1.example.com:8080",
+            "null",
+        ] {
+            match check_sender(Some(origin), None, false) {
+                Err(response) => assert_eq!(response.status, 403),
+                Ok(()) => panic!("a request from {} was taken", origin),
+            }
+        }
+    }
+
+    #[test]
+    fn bodies_have_to_be_json() {
+        assert!(check_sender(None, Some("application/json"), true).is_ok());
+        assert!(check_sender(None, Some("Application/JSON; charset=utf-8"), true).is_ok());
+        assert!(check_sender(None, None, false).is_ok());
+        for content_type in &[
+            None,
+            Some("text/plain"),
+            Some("application/x-www-form-urlencoded"),
+        ] {
+            match check_sender(None, *content_type, true) {
+                Err(response) => assert_eq!(response.status, 415),
+                Ok(()) => panic!("a {:?} body was taken", content_type),
+            }
+        }
+    }
+
+    //Lyrics files served on a port of their own, read back through the HTTP provider
+    fn provided(dir: &str) -> HttpProvider {
+        let server = Server::http(("127.0.0.1", 0)).unwrap();
+        let base = format!("http://{}", server.server_addr());
+        let provider = FileProvider::new(dir);
+        thread::spawn(move || provide(&server, &provider));
+        HttpProvider::new(&base)
+    }
+
+    #[test]
+    fn provided_lyrics_are_read_back() {
+        let path = temp_path("Artist");
+        fs::create_dir_all(format!("{}/Album", path)).unwrap();
+        fs::write(format!("{}/Album/One.txt", path), "red sky").unwrap();
+        let dir = path.trim_end_matches("Artist");
+
+        let found = provided(dir).lookup("artist", "album", "one").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].lyrics, "red sky");
+        assert!(found[0].source.ends_with("One.txt"));
+        remove_dir(&path);
+    }
+
+    //The provider answers 404 when it finds nothing, which isn't an error
+    #[test]
+    fn nothing_provided_is_no_lyrics() {
+        let path = temp_path("empty");
+        fs::create_dir_all(&path).unwrap();
+        assert!(provided(&path).lookup("a", "b", "c").unwrap().is_empty());
+        remove_dir(&path);
+    }
+}
@@ -0,0 +1,526 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use serde_json::{Map, Value};
+
+use super::{rollback_on_error, save, Request, Response};
+use database::metadata::*;
+use database::storage::TrackRef;
+use database::Database;
+
+//What a request path points at, by index into the database
+#[derive(Clone, Copy)]
+enum Resource {
+    Artists,
+    Artist(usize),
+    Albums(usize),
+    Album(usize, usize),
+    Tracks(usize, usize),
+    Track(usize, usize, usize),
+    Search,
+}
+
+fn not_found(what: &str, name: &str) -> Response {
+    Response::error(404, &format!("no {} named {}", what, name))
+}
+
+//Walk the path /artists/<name>/albums/<title>/tracks/<title>
+fn resolve(db: &Database, path: &[&str]) -> Result<Resource, Response> {
+    if path == ["search"] {
+        return Ok(Resource::Search);
+    }
+    let unknown = || Response::error(404, "unknown path");
+    if path.first() != Some(&"artists") {
+        return Err(unknown());
+    }
+    if path.len() == 1 {
+        return Ok(Resource::Artists);
+    }
+
+    let artist = db
+        .entries
+        .iter()
+        .position(|a| a.name == path[1])
+        .ok_or_else(|| not_found("artist", path[1]))?;
+    match path.get(2) {
+        None => return Ok(Resource::Artist(artist)),
+        Some(&"albums") if path.len() == 3 => return Ok(Resource::Albums(artist)),
+        Some(&"albums") => (),
+        Some(_) => return Err(unknown()),
+    }
+
+    let album = db.entries[artist]
+        .albums
+        .iter()
+        .position(|a| a.title == path[3])
+        .ok_or_else(|| not_found("album", path[3]))?;
+    match path.get(4) {
+        None => return Ok(Resource::Album(artist, album)),
+        Some(&"tracks") if path.len() == 5 => return Ok(Resource::Tracks(artist, album)),
+        Some(&"tracks") => (),
+        Some(_) => return Err(unknown()),
+    }
+
+    let track = db.entries[artist].albums[album]
+        .tracks
+        .iter()
+        .position(|t| t.title == path[5])
+        .ok_or_else(|| not_found("track", path[5]))?;
+    if path.len() == 6 {
+        Ok(Resource::Track(artist, album, track))
+    } else {
+        Err(unknown())
+    }
+}
+
+//Names of the tracks below `resource`, for the setlists to follow them when they change
+fn tracks_of(db: &Database, resource: Resource) -> Vec<TrackRef> {
+    let indices: Vec<(usize, usize, usize)> = match resource {
+        Resource::Artist(a) => db.entries[a]
+            .albums
+            .iter()
+            .enumerate()
+            .flat_map(|(b, album)| (0..album.tracks.len()).map(move |c| (a, b, c)))
+            .collect(),
+        Resource::Album(a, b) => (0..db.entries[a].albums[b].tracks.len())
+            .map(|c| (a, b, c))
+            .collect(),
+        Resource::Track(a, b, c) => vec![(a, b, c)],
+        _ => Vec::new(),
+    };
+    indices
+        .into_iter()
+        .filter_map(|index| db.track_ref(index))
+        .collect()
+}
+
+fn track_summary(track: &Track) -> Value {
+    json!({ "num": track.track, "title": track.title })
+}
+
+fn artist_json(artist: &Artist) -> Value {
+    let albums: Vec<&str> = artist.albums.iter().map(|a| a.title.as_str()).collect();
+    json!({ "name": artist.name, "albums": albums })
+}
+
+fn album_json(album: &Album) -> Value {
+    let tracks: Vec<Value> = album.tracks.iter().map(track_summary).collect();
+    json!({ "title": album.title, "track_count": album.track_count, "tracks": tracks })
+}
+
+fn track_json(track: &Track) -> Value {
+    let alternates: Vec<Value> = track
+        .alternates
+        .iter()
+        .map(|a| json!({ "artist": a.artist, "album": a.album, "title": a.title }))
+        .collect();
+    json!({
+        "num": track.track,
+        "title": track.title,
+        "lyrics": track.lyrics,
+        "alternates": alternates,
+    })
+}
+
+fn represent(db: &mut Database, resource: Resource, request: &Request) -> Result<Value, Response> {
+    let entries = &db.entries;
+    let value = match resource {
+        Resource::Artists => Value::Array(entries.iter().map(artist_json).collect()),
+        Resource::Artist(a) => artist_json(&entries[a]),
+        Resource::Albums(a) => Value::Array(entries[a].albums.iter().map(album_json).collect()),
+        Resource::Album(a, b) => album_json(&entries[a].albums[b]),
+        Resource::Tracks(a, b) => Value::Array(
+            entries[a].albums[b]
+                .tracks
+                .iter()
+                .map(track_summary)
+                .collect(),
+        ),
+        Resource::Track(a, b, c) => track_json(&entries[a].albums[b].tracks[c]),
+        Resource::Search => {
+            let query = request.query.get("q").map_or("", String::as_str);
+            let found = db
+                .search(query)
+                .map_err(|e| Response::error(500, &format!("could not search: {}", e)))?;
+            Value::Array(
+                found
+                    .iter()
+                    .map(|t| json!({ "artist": t.artist, "album": t.album, "title": t.title }))
+                    .collect(),
+            )
+        }
+    };
+    Ok(value)
+}
+
+//Representations are hashed, so any change to a resource changes its tag
+fn etag(value: &Value) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.to_string().hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+fn get(db: &mut Database, resource: Resource, request: &Request) -> Result<Response, Response> {
+    let value = represent(db, resource, request)?;
+    let tag = etag(&value);
+    if request.if_none_match.as_ref() == Some(&tag) {
+        return Ok(Response::empty(304).with_etag(tag));
+    }
+    Ok(Response::json(200, value).with_etag(tag))
+}
+
+//Changes need the tag of the version they were made against, so nobody overwrites a change
+//they haven't seen
+fn check_precondition(
+    db: &mut Database,
+    resource: Resource,
+    request: &Request,
+) -> Result<(), Response> {
+    let expected = match request.if_match {
+        Some(ref expected) => expected,
+        None => return Err(Response::error(428, "an If-Match header is needed")),
+    };
+    if expected != "*" && *expected != etag(&represent(db, resource, request)?) {
+        return Err(Response::error(
+            412,
+            "the resource was changed by someone else",
+        ));
+    }
+    Ok(())
+}
+
+fn body(request: &Request) -> Result<&Map<String, Value>, Response> {
+    match request.body {
+        Some(Value::Object(ref fields)) => Ok(fields),
+        _ => Err(Response::error(400, "a JSON object is needed")),
+    }
+}
+
+fn string_field(fields: &Map<String, Value>, name: &str) -> Result<Option<String>, Response> {
+    match fields.get(name) {
+        None => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(Response::error(400, &format!("{} must be a string", name))),
+    }
+}
+
+fn number_field(fields: &Map<String, Value>, name: &str) -> Result<Option<u8>, Response> {
+    match fields.get(name) {
+        None => Ok(None),
+        Some(value) => match value.as_u64() {
+            Some(number) if number <= u64::from(u8::max_value()) => Ok(Some(number as u8)),
+            _ => Err(Response::error(
+                400,
+                &format!("{} must be a number up to 255", name),
+            )),
+        },
+    }
+}
+
+fn required(value: Option<String>, name: &str) -> Result<String, Response> {
+    value.ok_or_else(|| Response::error(400, &format!("{} is needed", name)))
+}
+
+fn taken(what: &str, name: &str) -> Response {
+    Response::error(409, &format!("there already is a {} named {}", what, name))
+}
+
+//Save the change, answering with `resource` as it is now
+fn persist(
+    db: &mut Database,
+    status: u16,
+    resource: Resource,
+    request: &Request,
+) -> Result<Response, Response> {
+    if let Err(e) = save(db) {
+        return Err(Response::error(500, &format!("could not save: {}", e)));
+    }
+    let value = represent(db, resource, request)?;
+    let tag = etag(&value);
+    Ok(Response::json(status, value).with_etag(tag))
+}
+
+fn create(db: &mut Database, resource: Resource, request: &Request) -> Result<Response, Response> {
+    let fields = body(request)?;
+    let created = match resource {
+        Resource::Artists => {
+            let name = required(string_field(fields, "name")?, "name")?;
+            if db.entries.iter().any(|a| a.name == name) {
+                return Err(taken("artist", &name));
+            }
+            let mut artist = Artist::new();
+            artist.name = name;
+            db.entries.push(artist);
+            Resource::Artist(db.entries.len() - 1)
+        }
+        Resource::Albums(a) => {
+            let title = required(string_field(fields, "title")?, "title")?;
+            let artist = &mut db.entries[a];
+            if artist.albums.iter().any(|album| album.title == title) {
+                return Err(taken("album", &title));
+            }
+            let mut album = Album::new();
+            album.title = title;
+            album.track_count = number_field(fields, "track_count")?.unwrap_or(0);
+            artist.albums.push(album);
+            Resource::Album(a, artist.albums.len() - 1)
+        }
+        Resource::Tracks(a, b) => {
+            let title = required(string_field(fields, "title")?, "title")?;
+            let album = &mut db.entries[a].albums[b];
+            if album.tracks.iter().any(|track| track.title == title) {
+                return Err(taken("track", &title));
+            }
+            let mut track = Track::new();
+            track.title = title;
+            track.lyrics = string_field(fields, "lyrics")?.unwrap_or_default();
+            track.track = match number_field(fields, "num")? {
+                Some(num) => num,
+                None => album.tracks.iter().map(|t| t.track).max().unwrap_or(0) + 1,
+            };
+            let index = album.insert_sorted(track);
+            album.track_count = album.track_count.max(album.tracks.len() as u8);
+            Resource::Track(a, b, index)
+        }
+        _ => return Err(Response::error(405, "only collections can be added to")),
+    };
+    persist(db, 201, created, request)
+}
+
+fn update(db: &mut Database, resource: Resource, request: &Request) -> Result<Response, Response> {
+    check_precondition(db, resource, request)?;
+    let fields = body(request)?;
+    let before = tracks_of(db, resource);
+    let updated = match resource {
+        Resource::Artist(a) => {
+            if let Some(name) = string_field(fields, "name")? {
+                if db
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .any(|(i, x)| i != a && x.name == name)
+                {
+                    return Err(taken("artist", &name));
+                }
+                db.entries[a].name = name;
+            }
+            resource
+        }
+        Resource::Album(a, b) => {
+            let artist = &mut db.entries[a];
+            if let Some(title) = string_field(fields, "title")? {
+                if artist
+                    .albums
+                    .iter()
+                    .enumerate()
+                    .any(|(i, x)| i != b && x.title == title)
+                {
+                    return Err(taken("album", &title));
+                }
+                artist.albums[b].title = title;
+            }
+            if let Some(track_count) = number_field(fields, "track_count")? {
+                artist.albums[b].track_count = track_count;
+            }
+            resource
+        }
+        Resource::Track(a, b, c) => {
+            let album = &mut db.entries[a].albums[b];
+            if let Some(title) = string_field(fields, "title")? {
+                if album
+                    .tracks
+                    .iter()
+                    .enumerate()
+                    .any(|(i, x)| i != c && x.title == title)
+                {
+                    return Err(taken("track", &title));
+                }
+                album.tracks[c].title = title;
+            }
+            if let Some(lyrics) = string_field(fields, "lyrics")? {
+                album.tracks[c].lyrics = lyrics;
+                album.tracks[c].lyrics_source = None;
+            }
+            match number_field(fields, "num")? {
+                //Keep the tracks in order, the track can move
+                Some(num) => {
+                    let mut track = album.tracks.remove(c);
+                    track.track = num;
+                    Resource::Track(a, b, album.insert_sorted(track))
+                }
+                None => resource,
+            }
+        }
+        _ => return Err(Response::error(405, "only single items can be changed")),
+    };
+    //A renamed item takes the setlist entries of its tracks along
+    let moves: Vec<(TrackRef, TrackRef)> = before
+        .into_iter()
+        .zip(tracks_of(db, updated))
+        .filter(|(old, new)| old != new)
+        .collect();
+    db.retarget_setlists(&moves);
+    persist(db, 200, updated, request)
+}
+
+fn delete(db: &mut Database, resource: Resource, request: &Request) -> Result<Response, Response> {
+    check_precondition(db, resource, request)?;
+    let removed = tracks_of(db, resource);
+    match resource {
+        Resource::Artist(a) => {
+            db.entries.remove(a);
+        }
+        Resource::Album(a, b) => {
+            db.entries[a].albums.remove(b);
+        }
+        Resource::Track(a, b, c) => {
+            db.entries[a].albums[b].tracks.remove(c);
+        }
+        _ => return Err(Response::error(405, "only single items can be deleted")),
+    }
+    db.remove_from_setlists(&removed);
+    match save(db) {
+        Ok(()) => Ok(Response::empty(204)),
+        Err(e) => Err(Response::error(500, &format!("could not save: {}", e))),
+    }
+}
+
+//Answer a request on the artists, albums and tracks of the database
+pub fn handle(db: &mut Database, request: &Request) -> Response {
+    let path: Vec<&str> = request.path.iter().map(String::as_str).collect();
+    let resource = match resolve(db, &path) {
+        Ok(resource) => resource,
+        Err(response) => return response,
+    };
+    //A change that can't be saved is undone, the database keeps matching its file
+    let result = match request.method.as_str() {
+        "GET" => get(db, resource, request),
+        "POST" => rollback_on_error(db, |db| create(db, resource, request)),
+        "PUT" => rollback_on_error(db, |db| update(db, resource, request)),
+        "DELETE" => rollback_on_error(db, |db| delete(db, resource, request)),
+        _ => Err(Response::error(405, "method not allowed")),
+    };
+    match result {
+        Ok(response) | Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::*;
+    use database::testing::*;
+
+    fn request(method: &str, path: &[&str], body: Value) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.iter().map(|p| p.to_string()).collect(),
+            query: HashMap::new(),
+            if_match: Some("*".to_owned()),
+            if_none_match: None,
+            body: Some(body),
+        }
+    }
+
+    fn saved(name: &str) -> (String, Database) {
+        let path = temp_path(name);
+        let mut db = Database::empty();
+        db.entries = entries("Artist", "Album", vec![track("One", 1, "red sky")]);
+        db.save(&path).unwrap();
+        (path.clone(), Database::from(&path).unwrap())
+    }
+
+    #[test]
+    fn changes_are_saved() {
+        let (path, mut db) = saved("rest.xml");
+        let added = request("POST", &["artists"], json!({ "name": "Other" }));
+        assert_eq!(handle(&mut db, &added).status, 201);
+        let reopened = Database::from(&path).unwrap();
+        assert_eq!(reopened.entries[1].name, "Other");
+        remove_dir(&path);
+    }
+
+    //Every method that changes the database leaves it as it was if the change isn't saved
+    #[test]
+    fn changes_that_cannot_be_saved_are_undone() {
+        let (path, mut db) = saved("unsaved.xml");
+        remove_dir(&path);
+
+        let added = request("POST", &["artists"], json!({ "name": "Other" }));
+        assert_eq!(handle(&mut db, &added).status, 500);
+        let renamed = request("PUT", &["artists", "Artist"], json!({ "name": "Renamed" }));
+        assert_eq!(handle(&mut db, &renamed).status, 500);
+        let deleted = request("DELETE", &["artists", "Artist"], Value::Null);
+        assert_eq!(handle(&mut db, &deleted).status, 500);
+
+        assert_eq!(db.entries.len(), 1);
+        assert_eq!(db.entries[0].name, "Artist");
+        assert_eq!(db.entries[0].albums[0].tracks[0].lyrics, "red sky");
+    }
+
+    //The title is changed before the track count turns out to be wrong
+    #[test]
+    fn refused_changes_are_undone() {
+        let (path, mut db) = saved("refused.xml");
+        let changed = request(
+            "PUT",
+            &["artists", "Artist", "albums", "Album"],
+            json!({ "title": "Renamed", "track_count": "many" }),
+        );
+        assert_eq!(handle(&mut db, &changed).status, 400);
+        assert_eq!(db.entries[0].albums[0].title, "Album");
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn setlists_follow_renamed_and_deleted_tracks() {
+        let (path, mut db) = saved("setlists.xml");
+        let mut setlist = Setlist::new();
+        setlist
+            .entries
+            .push(SetlistEntry::new("Artist", "Album", "One"));
+        db.setlists.push(setlist);
+
+        let renamed = request("PUT", &["artists", "Artist"], json!({ "name": "Renamed" }));
+        assert_eq!(handle(&mut db, &renamed).status, 200);
+        let entry = SetlistEntry::new("Renamed", "Album", "One");
+        assert_eq!(db.setlists[0].entries, [entry]);
+
+        let path_to_track = ["artists", "Renamed", "albums", "Album", "tracks", "One"];
+        let retitled = request("PUT", &path_to_track, json!({ "title": "Uno", "num": 4 }));
+        assert_eq!(handle(&mut db, &retitled).status, 200);
+        let entry = SetlistEntry::new("Renamed", "Album", "Uno");
+        assert_eq!(db.setlists[0].entries, [entry]);
+
+        let deleted = request(
+            "DELETE",
+            &["artists", "Renamed", "albums", "Album"],
+            Value::Null,
+        );
+        assert_eq!(handle(&mut db, &deleted).status, 204);
+        assert!(db.setlists[0].entries.is_empty());
+        remove_dir(&path);
+    }
+
+    #[test]
+    fn searches_that_fail_are_errors() {
+        let (path, _) = saved("search.xml");
+        let mut db = Database::from_index(&path).unwrap();
+        let mut search = request("GET", &["search"], Value::Null);
+        search.query.insert("q".to_owned(), "red".to_owned());
+        assert_eq!(handle(&mut db, &search).status, 200);
+
+        //Someone else wrote into the file, the lyrics left in it can't be found anymore
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"<!-- changed -->")
+            .unwrap();
+        assert_eq!(handle(&mut db, &search).status, 500);
+        remove_dir(&path);
+    }
+}
@@ -1,27 +1,46 @@
 use gtk::prelude::*;
 use gtk::{
-    Builder, Entry, EntryBuffer, Label, ListBox, ListBoxRow, Orientation, TextBuffer, TextView,
-    Window,
+    Builder, ButtonsType, DialogFlags, Entry, EntryBuffer, Label, ListBox, ListBoxRow,
+    MessageDialog, MessageType, Orientation, TextBuffer, TextView, ToolButton, Window,
 };
 
-use relm::{Relm, Update, Widget};
+use relm::{Channel, Relm, Update, Widget};
+
+use std::thread;
+
+use candidatedialog::pick_candidate;
+use providers::{self, Candidate};
+
+//What the providers found for one track, by its position in the album
+type Found = (usize, Vec<Candidate>);
 
 #[derive(Msg)]
 pub enum Msg {
     SelectedTrack,
+    Save,
+    //Emitted for the parent window, carries the album title and (title, lyrics) of each track
+    Saved(String, Vec<(String, String)>),
+    FetchLyrics,
+    //Candidates for each track looked up, and the errors of providers that failed
+    Fetched(Vec<Found>, Vec<String>),
     Quit,
 }
 
 pub struct Model {
+    artist: String,
     entries: Vec<TrackEntry>,
     album_buffer: EntryBuffer,
+    //Delivers the lookup thread's answer while lyrics are being fetched
+    fetching: Option<Channel<Msg>>,
 }
 
 pub struct AlbumWindow {
+    relm: Relm<AlbumWindow>,
     window: Window,
     model: Model,
     lyrics_view: TextView,
     track_list_box: ListBox,
+    button_fetch: ToolButton,
 }
 
 struct TrackEntry {
@@ -57,14 +76,39 @@ impl TrackEntry {
             lyrics_buffer,
         }
     }
+
+    fn lyrics(&self) -> String {
+        let (start, end) = self.lyrics_buffer.get_bounds();
+        self.lyrics_buffer
+            .get_text(&start, &end, false)
+            .unwrap_or_default()
+    }
+}
+
+impl AlbumWindow {
+    fn show_message(&self, kind: MessageType, message: &str) {
+        let dialog = MessageDialog::new(
+            Some(&self.window),
+            DialogFlags::all(),
+            kind,
+            ButtonsType::Close,
+            message,
+        );
+        dialog.run();
+        dialog.destroy();
+    }
 }
 
 impl Update for AlbumWindow {
     type Model = Model;
-    type ModelParam = (String, Vec<(String, String)>);
+    //Artist, album title and (title, lyrics) of each track
+    type ModelParam = (String, String, Vec<(String, String)>);
     type Msg = Msg;
 
-    fn model(_: &Relm<Self>, (title, tracks): (String, Vec<(String, String)>)) -> Model {
+    fn model(
+        _: &Relm<Self>,
+        (artist, title, tracks): (String, String, Vec<(String, String)>),
+    ) -> Model {
         let mut entries = Vec::new();
         let album_buffer = EntryBuffer::new(Some(title.as_str()));
 
@@ -73,8 +117,10 @@ impl Update for AlbumWindow {
         }
 
         Model {
+            artist,
             entries,
             album_buffer,
+            fetching: None,
         }
     }
 
@@ -86,6 +132,97 @@ impl Update for AlbumWindow {
                     &self.model.entries[row.get_index() as usize].lyrics_buffer,
                 ));
             }
+            Msg::Save => {
+                let title = self.model.album_buffer.get_text();
+                let tracks = self
+                    .model
+                    .entries
+                    .iter()
+                    .map(|entry| (entry.title.get_text(), entry.lyrics()))
+                    .collect();
+                self.window.set_title(&title);
+                self.relm.stream().emit(Msg::Saved(title, tracks));
+            }
+            //Handled by the parent window
+            Msg::Saved(_, _) => (),
+            Msg::FetchLyrics => {
+                if self.model.fetching.is_some() {
+                    return;
+                }
+                let providers = providers::configured();
+                if providers.is_empty() {
+                    self.show_message(
+                        MessageType::Info,
+                        "No lyrics providers are set up, set LYRICS_PROVIDER_DIR or \
+                         LYRICS_PROVIDER_URL",
+                    );
+                    return;
+                }
+                let missing: Vec<(usize, String)> = self
+                    .model
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, entry)| entry.lyrics().trim().is_empty())
+                    .map(|(i, entry)| (i, entry.title.get_text()))
+                    .collect();
+                if missing.is_empty() {
+                    return;
+                }
+
+                //Providers can be slow, look them up on another thread
+                let stream = self.relm.stream().clone();
+                let (channel, sender) = Channel::new(move |msg| stream.emit(msg));
+                let artist = self.model.artist.clone();
+                let album = self.model.album_buffer.get_text();
+                thread::spawn(move || {
+                    let mut found = Vec::new();
+                    let mut errors = Vec::new();
+                    for (i, title) in missing {
+                        let (candidates, failed) =
+                            providers::lookup_all(&providers, &artist, &album, &title);
+                        found.push((i, candidates));
+                        errors.extend(failed);
+                    }
+                    //The window may be closed already, nobody is left to tell then
+                    let _ = sender.send(Msg::Fetched(found, errors));
+                });
+                self.model.fetching = Some(channel);
+                self.button_fetch.set_sensitive(false);
+            }
+            Msg::Fetched(found, mut errors) => {
+                self.model.fetching = None;
+                self.button_fetch.set_sensitive(true);
+                errors.dedup();
+                if !errors.is_empty() {
+                    self.show_message(
+                        MessageType::Warning,
+                        &format!("Some lookups failed:\n{}", errors.join("\n")),
+                    );
+                }
+
+                //Nothing is kept until the user has seen where it comes from
+                let found: Vec<Found> = found
+                    .into_iter()
+                    .filter(|&(_, ref candidates)| !candidates.is_empty())
+                    .collect();
+                if found.is_empty() {
+                    self.show_message(MessageType::Info, "No lyrics were found");
+                    return;
+                }
+                for (n, &(i, ref candidates)) in found.iter().enumerate() {
+                    let entry = &self.model.entries[i];
+                    //Filled in by hand while the lookup ran
+                    if !entry.lyrics().trim().is_empty() {
+                        continue;
+                    }
+                    let title = entry.title.get_text();
+                    let remaining = found.len() - n;
+                    if let Some(c) = pick_candidate(&self.window, &title, candidates, remaining) {
+                        entry.lyrics_buffer.set_text(&candidates[c].lyrics);
+                    }
+                }
+            }
             Msg::Quit => {
                 self.window.destroy();
             }
@@ -104,6 +241,7 @@ impl Widget for AlbumWindow {
         let builder = Builder::new_from_string(glade_src);
 
         get_object!(window, Window, builder);
+        window.set_title(&model.album_buffer.get_text());
 
         get_object!(album_entry, Entry, builder);
         album_entry.set_buffer(&model.album_buffer);
@@ -117,6 +255,8 @@ impl Widget for AlbumWindow {
         }
 
         get_object!(lyrics_view, TextView, builder);
+        get_object!(button_save, ToolButton, builder);
+        get_object!(button_fetch, ToolButton, builder);
 
         window.show_all();
 
@@ -133,11 +273,15 @@ impl Widget for AlbumWindow {
             connect_row_selected(_, _),
             Msg::SelectedTrack
         );
+        connect!(relm, button_save, connect_clicked(_), Msg::Save);
+        connect!(relm, button_fetch, connect_clicked(_), Msg::FetchLyrics);
         AlbumWindow {
+            relm: relm.clone(),
             window,
             model,
             lyrics_view,
             track_list_box,
+            button_fetch,
         }
     }
 }
@@ -0,0 +1,82 @@
+use gtk::prelude::*;
+use gtk::{
+    CellRendererText, Dialog, DialogFlags, Label, ListStore, Orientation, Paned, ScrolledWindow,
+    TextBuffer, TextView, TreePath, TreeView, TreeViewColumn, Window,
+};
+
+use providers::Candidate;
+
+//Show the lyrics found for a track with where each came from. Returns the index of the
+//candidate to use, None if the track should be left empty.
+pub fn pick_candidate(
+    parent: &Window,
+    title: &str,
+    candidates: &[Candidate],
+    remaining: usize,
+) -> Option<usize> {
+    let dialog = Dialog::new_with_buttons(
+        Some("Found lyrics"),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Use", 0), ("Skip", 1)],
+    );
+    dialog.set_default_size(700, 400);
+
+    let heading = Label::new(Some(format!("{} ({} left)", title, remaining).as_str()));
+
+    let store = ListStore::new(&[String::static_type()]);
+    for candidate in candidates {
+        store.insert_with_values(None, &[0], &[&candidate.source]);
+    }
+    let tree_view = TreeView::new_with_model(&store);
+    let cell = CellRendererText::new();
+    let column = TreeViewColumn::new();
+    column.set_title("Source");
+    column.pack_start(&cell, true);
+    column.add_attribute(&cell, "text", 0);
+    tree_view.append_column(&column);
+
+    let buffer = TextBuffer::new(None);
+    let text_view = TextView::new_with_buffer(&buffer);
+    text_view.set_editable(false);
+
+    let lyrics: Vec<String> = candidates.iter().map(|c| c.lyrics.clone()).collect();
+    tree_view.get_selection().connect_changed(move |selection| {
+        let index = selection
+            .get_selected()
+            .and_then(|(model, iter)| model.get_path(&iter))
+            .and_then(|path| path.get_indices().first().cloned());
+        if let Some(index) = index {
+            buffer.set_text(&lyrics[index as usize]);
+        }
+    });
+    tree_view
+        .get_selection()
+        .select_path(&TreePath::new_from_indicesv(&[0]));
+
+    let list = ScrolledWindow::new(None, None);
+    list.add(&tree_view);
+    let text = ScrolledWindow::new(None, None);
+    text.add(&text_view);
+    let paned = Paned::new(Orientation::Horizontal);
+    paned.pack1(&list, true, false);
+    paned.pack2(&text, true, false);
+
+    let content = dialog.get_content_area();
+    content.pack_start(&heading, false, false, 5);
+    content.pack_start(&paned, true, true, 0);
+    dialog.show_all();
+
+    let used = dialog.run() == 0;
+    let index = tree_view
+        .get_selection()
+        .get_selected()
+        .and_then(|(model, iter)| model.get_path(&iter))
+        .and_then(|path| path.get_indices().first().map(|&i| i as usize));
+    dialog.destroy();
+    if used {
+        index
+    } else {
+        None
+    }
+}
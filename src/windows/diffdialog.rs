@@ -0,0 +1,41 @@
+use gtk::prelude::*;
+use gtk::{Dialog, DialogFlags, ScrolledWindow, TextBuffer, TextView, Window};
+
+use database::diff::Change;
+
+//Show the changes between two databases in a read-only text view
+pub fn show_diff(parent: &Window, title: &str, changes: &[Change]) {
+    let dialog = Dialog::new_with_buttons(
+        Some(title),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Close", 0)],
+    );
+    dialog.set_default_size(500, 400);
+
+    let buffer = TextBuffer::new(None);
+    if changes.is_empty() {
+        buffer.set_text("No changes");
+    } else {
+        let text = changes
+            .iter()
+            .map(|change| change.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        buffer.set_text(&text);
+    }
+
+    let text_view = TextView::new_with_buffer(&buffer);
+    text_view.set_editable(false);
+    text_view.set_monospace(true);
+
+    let scrolled = ScrolledWindow::new(None, None);
+    scrolled.add(&text_view);
+    dialog
+        .get_content_area()
+        .pack_start(&scrolled, true, true, 0);
+    dialog.show_all();
+
+    dialog.run();
+    dialog.destroy();
+}
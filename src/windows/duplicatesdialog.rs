@@ -0,0 +1,113 @@
+use gtk::prelude::*;
+use gtk::{
+    CellRendererText, Dialog, DialogFlags, ScrolledWindow, TreeStore, TreeView, TreeViewColumn,
+    Window,
+};
+
+use database::duplicates::{find_duplicates, DuplicateGroup};
+use database::Database;
+
+//Minimum similarity for two tracks to be listed as duplicates
+const THRESHOLD: f32 = 0.75;
+
+fn add_column(tree_view: &TreeView, title: &str, column: i32) {
+    let cell = CellRendererText::new();
+    let view_column = TreeViewColumn::new();
+    view_column.set_title(title);
+    view_column.pack_start(&cell, true);
+    view_column.add_attribute(&cell, "text", column);
+    tree_view.append_column(&view_column);
+}
+
+//One row per group with its tracks below it. Columns 2 and 3 hold the group and track index,
+//the track index is -1 on group rows.
+fn fill_store(store: &TreeStore, db: &Database, groups: &[DuplicateGroup]) {
+    store.clear();
+    for (g, group) in groups.iter().enumerate() {
+        let label = format!("{} tracks", group.tracks.len());
+        let iter = store.insert_with_values(
+            None,
+            None,
+            &[0, 1, 2, 3],
+            &[&label, &String::new(), &(g as u32), &-1],
+        );
+        for (t, &((artist, album, track), score)) in group.tracks.iter().enumerate() {
+            let artist = &db.entries[artist];
+            let album = &artist.albums[album];
+            let label = format!(
+                "{} / {} / {}",
+                artist.name, album.title, album.tracks[track].title
+            );
+            let score = format!("{:.0}%", score * 100.0);
+            store.insert_with_values(
+                Some(&iter),
+                None,
+                &[0, 1, 2, 3],
+                &[&label, &score, &(g as u32), &(t as i32)],
+            );
+        }
+    }
+}
+
+//List groups of likely duplicate tracks and merge a group into the selected track until the
+//user closes the dialog. Returns true if the database was changed.
+pub fn review_duplicates(parent: &Window, db: &mut Database) -> bool {
+    let dialog = Dialog::new_with_buttons(
+        Some("Duplicates"),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Keep selected, merge the rest", 0), ("Close", 1)],
+    );
+    dialog.set_default_size(600, 400);
+
+    let store = TreeStore::new(&[
+        String::static_type(),
+        String::static_type(),
+        u32::static_type(),
+        i32::static_type(),
+    ]);
+    let tree_view = TreeView::new_with_model(&store);
+    add_column(&tree_view, "Track", 0);
+    add_column(&tree_view, "Similarity", 1);
+
+    let scrolled = ScrolledWindow::new(None, None);
+    scrolled.add(&tree_view);
+    dialog
+        .get_content_area()
+        .pack_start(&scrolled, true, true, 0);
+    dialog.show_all();
+
+    let mut changed = false;
+    loop {
+        let groups = find_duplicates(db, THRESHOLD);
+        fill_store(&store, db, &groups);
+        tree_view.expand_all();
+
+        if dialog.run() != 0 {
+            break;
+        }
+
+        let selected = tree_view
+            .get_selection()
+            .get_selected()
+            .and_then(|(model, iter)| {
+                let group = model.get_value(&iter, 2).get::<u32>()?;
+                let track = model.get_value(&iter, 3).get::<i32>()?;
+                Some((group as usize, track))
+            });
+        if let Some((group, track)) = selected {
+            //Group rows don't say which track to keep
+            if track < 0 {
+                continue;
+            }
+            let tracks: Vec<_> = groups[group]
+                .tracks
+                .iter()
+                .map(|&(index, _)| index)
+                .collect();
+            changed |= db.merge_duplicates(tracks[track as usize], &tracks);
+        }
+    }
+    dialog.destroy();
+    changed
+}
@@ -0,0 +1,90 @@
+use gtk::prelude::*;
+use gtk::{
+    CellRendererText, Dialog, DialogFlags, ListStore, Orientation, Paned, ScrolledWindow,
+    TextBuffer, TextView, TreePath, TreeSelection, TreeView, TreeViewColumn, Window,
+};
+
+use database::history::Revision;
+
+fn add_column(tree_view: &TreeView, title: &str, column: i32) {
+    let cell = CellRendererText::new();
+    let view_column = TreeViewColumn::new();
+    view_column.set_title(title);
+    view_column.pack_start(&cell, true);
+    view_column.add_attribute(&cell, "text", column);
+    tree_view.append_column(&view_column);
+}
+
+fn selected_index(selection: &TreeSelection) -> Option<usize> {
+    let (model, iter) = selection.get_selected()?;
+    let path = model.get_path(&iter)?;
+    path.get_indices().first().map(|&i| i as usize)
+}
+
+//List the saved versions of a track's lyrics next to the lyrics of the selected one. Returns
+//the index of the revision to restore, None if the dialog was closed.
+pub fn pick_revision(parent: &Window, title: &str, revisions: &[Revision]) -> Option<usize> {
+    let dialog = Dialog::new_with_buttons(
+        Some(format!("History of {}", title).as_str()),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Restore", 0), ("Close", 1)],
+    );
+    dialog.set_default_size(800, 400);
+
+    let store = ListStore::new(&[
+        String::static_type(),
+        String::static_type(),
+        String::static_type(),
+        String::static_type(),
+    ]);
+    for revision in revisions {
+        //Short commit ids, like git shows them
+        let commit: String = revision.id.chars().take(7).collect();
+        store.insert_with_values(
+            None,
+            &[0, 1, 2, 3],
+            &[&commit, &revision.date, &revision.author, &revision.message],
+        );
+    }
+    let tree_view = TreeView::new_with_model(&store);
+    add_column(&tree_view, "Commit", 0);
+    add_column(&tree_view, "Date", 1);
+    add_column(&tree_view, "Author", 2);
+    add_column(&tree_view, "Message", 3);
+
+    let buffer = TextBuffer::new(None);
+    let text_view = TextView::new_with_buffer(&buffer);
+    text_view.set_editable(false);
+
+    let lyrics: Vec<String> = revisions.iter().map(|r| r.lyrics.clone()).collect();
+    tree_view.get_selection().connect_changed(move |selection| {
+        if let Some(index) = selected_index(selection) {
+            buffer.set_text(&lyrics[index]);
+        }
+    });
+    if !revisions.is_empty() {
+        tree_view
+            .get_selection()
+            .select_path(&TreePath::new_from_indicesv(&[0]));
+    }
+
+    let list = ScrolledWindow::new(None, None);
+    list.add(&tree_view);
+    let text = ScrolledWindow::new(None, None);
+    text.add(&text_view);
+    let paned = Paned::new(Orientation::Horizontal);
+    paned.pack1(&list, true, false);
+    paned.pack2(&text, true, false);
+    dialog.get_content_area().pack_start(&paned, true, true, 0);
+    dialog.show_all();
+
+    let restore = dialog.run() == 0;
+    let index = selected_index(&tree_view.get_selection());
+    dialog.destroy();
+    if restore {
+        index
+    } else {
+        None
+    }
+}
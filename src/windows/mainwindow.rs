@@ -1,59 +1,432 @@
+use gdk::ScreenExt;
 use gtk::prelude::*;
 use gtk::{
-    Builder, Button, ButtonsType, DialogFlags, FileChooserAction, FileChooserDialog, Label, Menu,
-    MenuItem, MessageDialog, MessageType, TreeStore, TreeView, TreeViewColumn, Window,
+    Builder, Button, ButtonsType, CheckMenuItem, ComboBoxText, DialogFlags, FileChooserAction,
+    FileChooserDialog, Label, ListStore, Menu, MenuItem, MessageDialog, MessageType, ProgressBar,
+    TreePath, TreeStore, TreeView, TreeViewColumn, TreeViewDropPosition, Window,
 };
 
-use relm::{init, Component, Relm, Update, Widget};
+use relm::{init, interval, Channel, Component, Relm, Update, Widget};
 
+use std::cmp;
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 
-use database::Database;
+use database::diff::diff;
+use database::history::Revision;
+use database::metadata::{Album, Artist, Setlist, SetlistEntry, Track, TrackNumbers};
+use database::storage::{container, Progress, TrackRef};
+use database::watch::{FileWatcher, Snapshot};
+use database::{Conflict, Database, DatabaseError, MergePolicy};
+use export::presentation::PresentationSettings;
+use export::setlist::write_sheet;
+use export::{Selection, SongbookSettings};
+use players::mpd;
+use players::{MpdWatcher, MprisWatcher, NowPlaying, PlayerError};
 
 use albumwindow::AlbumWindow;
+use albumwindow::Msg as AlbumMsg;
+use diffdialog::show_diff;
+use duplicatesdialog::review_duplicates;
+use historydialog::pick_revision;
+use mergedialog::review_conflict;
+use passphrasedialog::{ask_new_passphrase, ask_passphrase};
+use presentationdialog::ask_presentation;
+use presentationwindow::Msg as PresentationMsg;
+use presentationwindow::PresentationWindow;
+use setlistdialog::{ask_setlist_name, ask_sheet_path};
+use songbookdialog::make_songbook;
 
-fn update_treestore(db: &mut Database, input: &TreeStore) {
+//An open album editor and the (artist, album) position of its album in the database
+struct AlbumEditor {
+    window: Component<AlbumWindow>,
+    album: (usize, usize),
+}
+
+//A reversible change to the database, kept for undo
+enum Edit {
+    //(artist, album) positions before and after the move
+    MoveAlbum((usize, usize), (usize, usize)),
+    //(artist, album, track) positions before and after the move, and the numbers of the source
+    //and destination albums before it
+    MoveTrack(
+        (usize, usize, usize),
+        (usize, usize, usize),
+        TrackNumbers,
+        TrackNumbers,
+    ),
+    //A track moved back, the source and destination albums get the numbers they had before
+    //instead of being renumbered
+    UnmoveTrack(
+        (usize, usize, usize),
+        (usize, usize, usize),
+        TrackNumbers,
+        TrackNumbers,
+    ),
+}
+
+impl Edit {
+    fn apply(&self, db: &mut Database) -> bool {
+        match *self {
+            Edit::MoveAlbum(from, to) => db.move_album(from, to),
+            Edit::MoveTrack(from, to, _, _) => db.move_track(from, to),
+            Edit::UnmoveTrack(from, to, ref from_numbers, ref to_numbers) => {
+                if !db.move_track(from, to) {
+                    return false;
+                }
+                db.entries[from.0].albums[from.1].set_numbers(from_numbers);
+                db.entries[to.0].albums[to.1].set_numbers(to_numbers);
+                true
+            }
+        }
+    }
+
+    fn reverse(&self) -> Edit {
+        match *self {
+            Edit::MoveAlbum(from, to) => Edit::MoveAlbum(to, from),
+            Edit::MoveTrack(from, to, ref from_numbers, ref to_numbers) => {
+                Edit::UnmoveTrack(to, from, to_numbers.clone(), from_numbers.clone())
+            }
+            Edit::UnmoveTrack(from, to, ref from_numbers, ref to_numbers) => {
+                Edit::MoveTrack(to, from, to_numbers.clone(), from_numbers.clone())
+            }
+        }
+    }
+
+    //Where the album at the (artist, album) position `album` is after the edit is applied
+    fn album_position(&self, album: (usize, usize)) -> (usize, usize) {
+        match *self {
+            Edit::MoveAlbum(from, to) => {
+                if album == from {
+                    return to;
+                }
+                let (artist, mut index) = album;
+                if artist == from.0 && index > from.1 {
+                    index -= 1;
+                }
+                if artist == to.0 && index >= to.1 {
+                    index += 1;
+                }
+                (artist, index)
+            }
+            Edit::MoveTrack(..) | Edit::UnmoveTrack(..) => album,
+        }
+    }
+
+    fn is_noop(&self) -> bool {
+        match *self {
+            Edit::MoveAlbum(from, to) => from == to,
+            Edit::MoveTrack(from, to, ..) | Edit::UnmoveTrack(from, to, ..) => from == to,
+        }
+    }
+
+    //(artist, album) of the albums whose tracks change
+    fn track_albums(&self) -> Option<((usize, usize), (usize, usize))> {
+        match *self {
+            Edit::MoveAlbum(..) => None,
+            Edit::MoveTrack(from, to, ..) | Edit::UnmoveTrack(from, to, ..) => {
+                Some(((from.0, from.1), (to.0, to.1)))
+            }
+        }
+    }
+
+    //Tree path of the moved row after the edit is applied
+    fn dest_path(&self) -> TreePath {
+        match *self {
+            Edit::MoveAlbum(_, (artist, album)) => {
+                TreePath::new_from_indicesv(&[artist as i32, album as i32])
+            }
+            Edit::MoveTrack(_, (artist, album, track), ..)
+            | Edit::UnmoveTrack(_, (artist, album, track), ..) => {
+                TreePath::new_from_indicesv(&[artist as i32, album as i32, track as i32])
+            }
+        }
+    }
+}
+
+//Work out where a row dragged from `src` and dropped on `dest` ends up. Albums can only be
+//dropped into artists and tracks into albums, anything else is rejected.
+fn drop_destination(
+    db: &Database,
+    src: &[usize],
+    dest: &[usize],
+    pos: TreeViewDropPosition,
+) -> Option<Edit> {
+    let (before, into) = match pos {
+        TreeViewDropPosition::Before => (true, false),
+        TreeViewDropPosition::After => (false, false),
+        TreeViewDropPosition::IntoOrBefore => (true, true),
+        TreeViewDropPosition::IntoOrAfter => (false, true),
+        _ => return None,
+    };
+
+    let depth = src.len();
+    if depth != 2 && depth != 3 {
+        return None;
+    }
+
+    let (parent, mut index) = if into && dest.len() + 1 == depth {
+        //Dropped onto the new parent, append to it
+        let count = match *dest {
+            [artist] => db.entries.get(artist)?.albums.len(),
+            [artist, album] => db.entries.get(artist)?.albums.get(album)?.tracks.len(),
+            _ => return None,
+        };
+        (dest, count)
+    } else if dest.len() == depth {
+        let index = dest[depth - 1] + if before { 0 } else { 1 };
+        (&dest[..depth - 1], index)
+    } else {
+        return None;
+    };
+
+    //Indices after the dragged row shift down once it is removed
+    if parent == &src[..depth - 1] && src[depth - 1] < index {
+        index -= 1;
+    }
+
+    match (src, parent) {
+        (&[artist, album], &[to_artist]) => {
+            Some(Edit::MoveAlbum((artist, album), (to_artist, index)))
+        }
+        (&[artist, album, track], &[to_artist, to_album]) => {
+            let numbers = |artist: usize, album: usize| {
+                db.entries
+                    .get(artist)
+                    .and_then(|artist| artist.albums.get(album))
+                    .map(Album::numbers)
+            };
+            Some(Edit::MoveTrack(
+                (artist, album, track),
+                (to_artist, to_album, index),
+                numbers(artist, album)?,
+                numbers(to_artist, to_album)?,
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn new_tree_store() -> TreeStore {
+    TreeStore::new(&[String::static_type(), i32::static_type()])
+}
+
+fn update_treestore(db: &Database, input: &TreeStore) {
     input.clear();
-    for artist in &db.entries {
+    append_artists(&db.entries, input);
+}
+
+//Add a row for each of `artists` with their albums and tracks below it
+fn append_artists(artists: &[Artist], input: &TreeStore) {
+    for artist in artists {
         let iter = input.insert_with_values(None, None, &[0], &[&artist.name]);
 
         for album in &artist.albums {
             let iter = input.insert_with_values(Some(&iter), None, &[0], &[&album.title]);
 
             for track in &album.tracks {
-                input.insert_with_values(
-                    Some(&iter),
-                    None,
-                    &[0, 1],
-                    &[&track.title, &track.lyrics],
-                );
+                input.insert_with_values(Some(&iter), None, &[0], &[&track.title]);
             }
         }
     }
-    db.clean();
+}
+
+//One row per entry of the setlist: the track, which is marked if it is no longer in the
+//database, and the key, tempo and notes
+fn update_setlist_store(db: &Database, setlist: Option<usize>, store: &ListStore) {
+    store.clear();
+    let setlist = match setlist.and_then(|i| db.setlists.get(i)) {
+        Some(setlist) => setlist,
+        None => return,
+    };
+    for entry in &setlist.entries {
+        let mut track = format!("{} — {}", entry.title, entry.artist);
+        if db.setlist_track(entry).is_none() {
+            track.push_str(" (missing)");
+        }
+        let tempo = entry
+            .tempo
+            .map_or_else(String::new, |tempo| tempo.to_string());
+        store.insert_with_values(
+            None,
+            &[0, 1, 2, 3],
+            &[&track, &entry.key, &tempo, &entry.notes],
+        );
+    }
+}
+
+//A database being read on another thread. Dropping this stops the thread.
+struct Loading {
+    id: u32,
+    path: String,
+    //Tells the thread to stop reading
+    cancel: Arc<AtomicBool>,
+    //Delivers the thread's messages, kept for as long as the load is wanted
+    _channel: Channel<Msg>,
+    //Set once the database is read, while its rows are added
+    filling: Option<Filling>,
+}
+
+impl Drop for Loading {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+//A database that was read, its rows go to a store of their own a few artists at a time so
+//the window keeps responding. Both replace the open ones when all rows are in.
+struct Filling {
+    db: Database,
+    store: TreeStore,
+    artists: usize,
+}
+
+//How many artists are added to the store at a time
+const FILL_ARTISTS: usize = 200;
+
+//The saved versions of a track being read on another thread. Dropping this stops the thread.
+struct ReadingHistory {
+    id: u32,
+    index: (usize, usize, usize),
+    track: TrackRef,
+    title: String,
+    cancel: Arc<AtomicBool>,
+    //Delivers the thread's messages
+    _channel: Channel<Msg>,
+}
+
+impl Drop for ReadingHistory {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+//Keeps an eye on the open database file for changes made by other programs
+struct Watching {
+    _watcher: FileWatcher,
+    //Delivers the watcher's messages
+    _channel: Channel<Msg>,
+}
+
+//Follows what a music player is playing
+struct Following<W> {
+    _watcher: W,
+    //Delivers the watcher's messages
+    _channel: Channel<Msg>,
+}
+
+//How often unsaved changes are written to the recovery file
+const AUTOSAVE_INTERVAL_MS: u32 = 60 * 1000;
+
+//The last part of a path, for showing to the user
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map_or_else(
+        || path.to_owned(),
+        |name| name.to_string_lossy().into_owned(),
+    )
 }
 
 #[derive(Msg)]
 pub enum Msg {
     SelectedItem,
     MenuOpen,
+    MenuSave,
+    Autosave,
+    LoadProgress(u32, Progress),
+    Loaded(u32, Result<Database, DatabaseError>),
+    FillTree(u32),
+    HistoryProgress(u32, usize, usize),
+    HistoryRead(u32, Result<Vec<Revision>, DatabaseError>),
+    CancelLoad,
+    FileChanged,
+    MenuMerge,
+    MenuCompare,
+    MenuPassphrase,
+    Songbook,
     AddArtist,
     EditAlbum,
+    AlbumSaved(u32, String, Vec<(String, String)>),
+    AlbumClosed(u32),
+    //Tree indices and new name of an inline edited row
+    RenameRow(Vec<usize>, String),
+    DropRow(Vec<usize>, Vec<usize>, TreeViewDropPosition),
+    Undo,
+    FindDuplicates,
+    KeepHistory,
+    TrackHistory,
+    FollowMpris,
+    MprisPlaying(NowPlaying),
+    FollowMpd,
+    StopFollowMpd,
+    MpdPlaying(Result<NowPlaying, PlayerError>),
+    Present,
+    PresentationClosed,
+    ToggleSetlists,
+    SetlistChosen,
+    NewSetlist,
+    RenameSetlist,
+    DeleteSetlist,
+    ExportSetlist,
+    AddToSetlist,
+    RemoveFromSetlist,
+    //Up if set, down otherwise
+    MoveInSetlist(bool),
+    //Row, column and new text of an edited cell
+    SetlistEdited(usize, u32, String),
+    SetlistSelected,
     Quit,
 }
 
 pub struct Model {
     db: Database,
     tree_store: gtk::TreeStore,
+    undo_stack: Vec<Edit>,
+    //Changed since the last save or autosave
+    needs_autosave: bool,
+    //What the file held before the unsaved edits, to merge against if it changes on disk
+    base: Option<Snapshot>,
+    //How the last presentation was made
+    presentation_settings: PresentationSettings,
+    //How the last songbook looked
+    songbook_settings: SongbookSettings,
+    setlist_store: ListStore,
+    //The setlist shown in the panel
+    setlist: Option<usize>,
 }
 
 pub struct MainWindow {
+    relm: Relm<MainWindow>,
     tree_view: TreeView,
     model: Model,
     window: Window,
     text_viewer: Label,
-    albumwin: Option<Component<AlbumWindow>>,
+    //By window id
+    albumwins: HashMap<u32, AlbumEditor>,
+    next_albumwin_id: u32,
+    //The file changed on disk while album editors were open
+    reload_pending: bool,
     context_menu: Menu,
+    load_bar: gtk::Box,
+    load_progress: ProgressBar,
+    loading: Option<Loading>,
+    reading_history: Option<ReadingHistory>,
+    next_load_id: u32,
+    watching: Option<Watching>,
+    menu_follow_mpris: CheckMenuItem,
+    mpris: Option<Following<MprisWatcher>>,
+    menu_follow_mpd: CheckMenuItem,
+    follow_panel: gtk::Box,
+    follow_title: Label,
+    follow_lyrics: Label,
+    mpd: Option<Following<MpdWatcher>>,
+    presentation: Option<Component<PresentationWindow>>,
+    menu_setlists: CheckMenuItem,
+    setlist_panel: gtk::Box,
+    setlist_combo: ComboBoxText,
+    setlist_view: TreeView,
 }
 
 impl Update for MainWindow {
@@ -65,30 +438,57 @@ impl Update for MainWindow {
     fn model(_: &Relm<Self>, _: ()) -> Model {
         Model {
             db: Database::empty(),
-            tree_store: TreeStore::new(&[
+            tree_store: new_tree_store(),
+            undo_stack: Vec::new(),
+            needs_autosave: false,
+            base: None,
+            presentation_settings: PresentationSettings::new(),
+            songbook_settings: SongbookSettings::new(),
+            setlist_store: ListStore::new(&[
+                String::static_type(),
+                String::static_type(),
                 String::static_type(),
                 String::static_type(),
-                i32::static_type(),
             ]),
+            setlist: None,
         }
     }
 
+    fn subscriptions(&mut self, relm: &Relm<Self>) {
+        interval(relm.stream(), AUTOSAVE_INTERVAL_MS, || Msg::Autosave);
+    }
+
     fn update(&mut self, event: Msg) {
         match event {
             Msg::SelectedItem => {
                 let selection = self.tree_view.get_selection();
                 if let Some((model, iter)) = selection.get_selected() {
-                    let mut path = model.get_path(&iter).expect("failed to get path");
+                    let path = model.get_path(&iter).expect("failed to get path");
 
-                    if path.get_depth() != 3 {
-                        return;
-                    }
+                    let index = match *path.get_indices() {
+                        [artist, album, track] => (artist as usize, album as usize, track as usize),
+                        _ => return,
+                    };
 
-                    //TODO this leaks memory, fix
-                    if let Some(lyrics) = model.get_value(&iter, 1).get::<String>() {
-                        self.text_viewer.set_text(&lyrics);
-                    } else {
-                        self.text_viewer.set_text("");
+                    //Lyrics are only read from the database file once they are shown
+                    match self.model.db.lyrics(index) {
+                        Ok(lyrics) => {
+                            let lyrics = lyrics.unwrap_or_default();
+                            self.text_viewer.set_text(&lyrics);
+                            if let Some(ref presentation) = self.presentation {
+                                let title = model
+                                    .get_value(&iter, 0)
+                                    .get::<String>()
+                                    .unwrap_or_default();
+                                presentation
+                                    .stream()
+                                    .emit(PresentationMsg::Show(title, lyrics));
+                            }
+                        }
+                        Err(e) => {
+                            self.text_viewer.set_text("");
+                            self.show_error(&format!("Could not read lyrics: {}", e));
+                        }
                     }
                 }
             }
@@ -114,45 +514,1369 @@ impl Update for MainWindow {
                         );
                         dialog.run();
                     } else {
-                        self.model.db = Database::from(file.to_str().unwrap()).unwrap();
-                        // self.model.db.save("").unwrap();
-                        update_treestore(&mut self.model.db, &self.model.tree_store);
+                        self.open_file(file.to_string_lossy().into_owned(), false);
                     }
                 }
                 dialog.destroy();
             }
+            Msg::MenuSave => {
+                let path = match self.model.db.file_path() {
+                    "" => match self.ask_save_path() {
+                        Some(path) => path,
+                        None => return,
+                    },
+                    path => path.to_owned(),
+                };
+                let moved = path != self.model.db.file_path();
+                let history = match self.model.db.save(&path) {
+                    Ok(()) => None,
+                    Err(DatabaseError::History(e)) => Some(e),
+                    Err(e) => {
+                        self.show_error(&format!("Could not save {}: {}", path, e));
+                        return;
+                    }
+                };
+                self.model.needs_autosave = false;
+                self.model.base = None;
+                if moved {
+                    self.watch_file();
+                }
+                if let Some(e) = history {
+                    self.show_error(&format!("Saved, but the history was not kept: {}", e));
+                }
+            }
+            Msg::Autosave => {
+                if !self.model.needs_autosave || self.loading.is_some() {
+                    return;
+                }
+                //Only complain once, the next change tries again
+                self.model.needs_autosave = false;
+                if let Err(e) = self.model.db.autosave() {
+                    self.show_error(&format!("Could not autosave: {}", e));
+                }
+            }
+            Msg::LoadProgress(id, progress) => {
+                if !self.is_loading(id) {
+                    return;
+                }
+                if progress.total_bytes > 0 {
+                    self.load_progress
+                        .set_fraction(progress.bytes as f64 / progress.total_bytes as f64);
+                } else {
+                    self.load_progress.pulse();
+                }
+                self.load_progress
+                    .set_text(format!("{} artists read", progress.artists).as_str());
+            }
+            Msg::Loaded(id, result) => {
+                if !self.is_loading(id) {
+                    return;
+                }
+                match result {
+                    Ok(db) => {
+                        if let Some(ref mut loading) = self.loading {
+                            loading.filling = Some(Filling {
+                                db,
+                                store: new_tree_store(),
+                                artists: 0,
+                            });
+                        }
+                        self.load_progress.set_fraction(0.0);
+                        self.relm.stream().emit(Msg::FillTree(id));
+                        return;
+                    }
+                    Err(DatabaseError::WrongPassphrase) => {
+                        let loading = self.loading.take().expect("no load in progress");
+                        self.load_bar.hide();
+                        self.open_file(loading.path.clone(), true);
+                    }
+                    Err(e) => {
+                        let loading = self.loading.take().expect("no load in progress");
+                        self.load_bar.hide();
+                        self.show_error(&format!("Could not read {}: {}", loading.path, e));
+                    }
+                }
+            }
+            Msg::FillTree(id) => {
+                if !self.is_loading(id) {
+                    return;
+                }
+                let done = match self.loading.as_mut().and_then(|l| l.filling.as_mut()) {
+                    Some(filling) => {
+                        let count = filling.db.entries.len();
+                        let end = cmp::min(filling.artists + FILL_ARTISTS, count);
+                        append_artists(&filling.db.entries[filling.artists..end], &filling.store);
+                        filling.artists = end;
+                        if count > 0 {
+                            self.load_progress.set_fraction(end as f64 / count as f64);
+                        }
+                        self.load_progress
+                            .set_text(format!("{} of {} artists listed", end, count).as_str());
+                        end == count
+                    }
+                    None => return,
+                };
+                if !done {
+                    self.relm.stream().emit(Msg::FillTree(id));
+                    return;
+                }
+
+                let mut loading = self.loading.take().expect("no load in progress");
+                self.load_bar.hide();
+                let filling = loading.filling.take().expect("no database to fill in");
+                self.model.db = filling.db;
+                self.model.tree_store = filling.store;
+                self.tree_view.set_model(Some(&self.model.tree_store));
+                self.model.undo_stack.clear();
+                self.model.needs_autosave = false;
+                self.model.base = None;
+                self.model.setlist = None;
+                self.update_setlists();
+                self.watch_file();
+                if self.model.db.has_recovery() {
+                    self.offer_recovery();
+                }
+            }
+            Msg::CancelLoad => {
+                //Dropping the load stops its thread, anything it still sends is ignored
+                self.loading = None;
+                self.reading_history = None;
+                self.load_bar.hide();
+            }
+            Msg::FileChanged => {
+                //Our own saves are noticed too, those leave nothing to do
+                if self.loading.is_some() || !self.model.db.changed_on_disk() {
+                    return;
+                }
+                //Reloading or merging moves the albums out from under the editors, it is
+                //offered once they are closed
+                if !self.albumwins.is_empty() {
+                    self.reload_pending = true;
+                    return;
+                }
+                self.offer_reload();
+            }
+            Msg::MenuMerge => {
+                //Open editors would overwrite the merged tracks when saved
+                if !self.albumwins.is_empty() {
+                    self.show_error("Close the album editors before merging");
+                    return;
+                }
+                let dialog = FileChooserDialog::new(
+                    Some("Merge..."),
+                    Some(&self.window),
+                    FileChooserAction::Open,
+                );
+                dialog.add_button("Merge", 0);
+                dialog.add_button("Close", 1);
+                let result = dialog.run();
+                let filename = dialog.get_filename();
+                dialog.destroy();
+                if result != 0 {
+                    return;
+                }
+
+                let filename = filename.expect("Failed to get filename");
+                let other = match self.read_other(&filename.to_string_lossy()) {
+                    Some(other) => other,
+                    None => return,
+                };
+                if !self.load_all_lyrics() {
+                    return;
+                }
+                self.keep_base();
+
+                //Keep our lyrics until the user has reviewed each conflict
+                let conflicts = match self.model.db.merge(other, MergePolicy::KeepMine) {
+                    Ok(conflicts) => conflicts,
+                    Err(e) => {
+                        self.show_error(&format!("Could not read lyrics: {}", e));
+                        return;
+                    }
+                };
+                self.review_conflicts(&conflicts);
+
+                self.model.undo_stack.clear();
+                self.changed();
+                update_treestore(&self.model.db, &self.model.tree_store);
+                self.update_setlists();
+            }
+            Msg::MenuCompare => {
+                let dialog = FileChooserDialog::new(
+                    Some("Compare with..."),
+                    Some(&self.window),
+                    FileChooserAction::Open,
+                );
+                dialog.add_button("Compare", 0);
+                dialog.add_button("Close", 1);
+                let result = dialog.run();
+                let filename = dialog.get_filename();
+                dialog.destroy();
+                if result != 0 {
+                    return;
+                }
+
+                let filename = filename.expect("Failed to get filename");
+                if !self.load_all_lyrics() {
+                    return;
+                }
+                //Show what would change going from the other file to the open database
+                if let Some(other) = self.read_other(&filename.to_string_lossy()) {
+                    show_diff(
+                        &self.window,
+                        &format!("Changes from {}", filename.to_string_lossy()),
+                        &diff(&other, &self.model.db),
+                    );
+                }
+            }
+            Msg::MenuPassphrase => {
+                let passphrase = match ask_new_passphrase(&self.window) {
+                    Some(passphrase) => passphrase,
+                    None => return,
+                };
+                let passphrase = passphrase.as_ref().map(String::as_str);
+                if let Err(e) = self.model.db.set_passphrase(passphrase) {
+                    self.show_error(&format!("Could not set the passphrase: {}", e));
+                }
+            }
             Msg::AddArtist => {
                 //TODO: pop up dialog to ask for name
+                self.keep_base();
+                self.model.db.entries.push(Artist::new());
                 self.model
                     .tree_store
                     .insert_with_values(None, None, &[0], &[&String::new()]);
+                self.changed();
             }
             Msg::EditAlbum => {
+                //It would be left behind by the database being loaded
+                if self.loading.is_some() {
+                    self.show_error("Wait for the database to finish loading");
+                    return;
+                }
+                let (model, iter) = match self.tree_view.get_selection().get_selected() {
+                    Some(selected) => selected,
+                    None => return,
+                };
                 //Pass album and track data to the editing window
-                let (model, iter) = self.tree_view.get_selection().get_selected().unwrap();
-                let title = model.get_value(&iter, 0).get::<String>().unwrap();
+                let indices = match model.get_path(&iter) {
+                    Some(path) => path.get_indices(),
+                    None => return,
+                };
+                let (artist, album) = match *indices {
+                    [artist, album] => (artist as usize, album as usize),
+                    _ => return,
+                };
+
+                //Only one editor per album, bring the existing one to the front
+                if let Some(editor) = self.album_editor((artist, album)) {
+                    editor.window.widget().present();
+                    return;
+                }
 
-                let iter = model.iter_children(Some(&iter)).unwrap();
+                let (artist_name, album_title, titles) = match self
+                    .model
+                    .db
+                    .entries
+                    .get(artist)
+                    .and_then(|found| Some((found, found.albums.get(album)?)))
+                {
+                    Some((found, found_album)) => (
+                        found.name.clone(),
+                        found_album.title.clone(),
+                        found_album
+                            .tracks
+                            .iter()
+                            .map(|t| t.title.clone())
+                            .collect::<Vec<_>>(),
+                    ),
+                    None => return,
+                };
                 let mut tracks = Vec::new();
-                loop {
-                    let entry = (
-                        model.get_value(&iter, 0).get::<String>().unwrap(),
-                        model.get_value(&iter, 1).get::<String>().unwrap(),
-                    );
+                for (track, title) in titles.into_iter().enumerate() {
+                    match self.model.db.lyrics((artist, album, track)) {
+                        Ok(lyrics) => tracks.push((title, lyrics.unwrap_or_default())),
+                        Err(e) => {
+                            self.show_error(&format!("Could not read lyrics: {}", e));
+                            return;
+                        }
+                    }
+                }
+
+                let albumwin =
+                    init::<AlbumWindow>((artist_name, album_title, tracks)).expect("album window");
+
+                //Messages are routed by window id, since the album's position changes on moves
+                let id = self.next_albumwin_id;
+                self.next_albumwin_id += 1;
+                connect!(
+                    albumwin@AlbumMsg::Saved(ref title, ref tracks),
+                    self.relm,
+                    Msg::AlbumSaved(id, title.clone(), tracks.clone())
+                );
+                connect!(albumwin@AlbumMsg::Quit, self.relm, Msg::AlbumClosed(id));
+
+                self.albumwins.insert(
+                    id,
+                    AlbumEditor {
+                        window: albumwin,
+                        album: (artist, album),
+                    },
+                );
+            }
+            Msg::AlbumSaved(id, title, tracks) => {
+                let (artist, album) = match self.albumwins.get(&id) {
+                    Some(editor) => editor.album,
+                    None => return,
+                };
+                let old_count = match self
+                    .model
+                    .db
+                    .entries
+                    .get(artist)
+                    .and_then(|found| found.albums.get(album))
+                {
+                    Some(found) => found.tracks.len(),
+                    None => {
+                        self.show_error("The edited album is no longer in the database");
+                        return;
+                    }
+                };
+                self.keep_base();
+                //Setlists and history tell albums apart by their title
+                if !self.model.db.rename(&[artist, album], &title) {
+                    self.show_error(&format!(
+                        "Could not call the album \"{}\", the title is empty or already taken",
+                        title
+                    ));
+                    return;
+                }
+
+                let tracks: Vec<Track> = {
+                    let old = &self.model.db.entries[artist].albums[album].tracks;
+                    tracks
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, (title, lyrics))| {
+                            //The editor keeps tracks in place, numbers and alternates stay with
+                            //their position
+                            let old = old.get(i);
+                            Track {
+                                title,
+                                lyrics,
+                                track: old.map_or((i + 1) as u8, |track| track.track),
+                                alternates: old
+                                    .map_or_else(Vec::new, |track| track.alternates.clone()),
+                                lyrics_source: None,
+                            }
+                        })
+                        .collect()
+                };
 
-                    tracks.push(entry);
-                    if !model.iter_next(&iter) {
-                        break;
+                //Setlist entries follow their track to its new title, which is by position
+                //like the alternates
+                let moves: Vec<(TrackRef, TrackRef)> = (0..old_count)
+                    .zip(tracks.iter())
+                    .filter_map(|(i, track)| {
+                        let before = self.model.db.track_ref((artist, album, i))?;
+                        let mut after = before.clone();
+                        after.title = track.title.clone();
+                        Some((before, after))
+                    })
+                    .collect();
+                {
+                    let found = &mut self.model.db.entries[artist].albums[album];
+                    found.track_count = tracks.len() as u8;
+                    found.tracks = tracks.clone();
+                }
+                self.model.db.retarget_setlists(&moves);
+                self.changed();
+                self.update_setlist_entries();
+
+                //Refresh the album row and its tracks in the tree
+                let path = TreePath::new_from_indicesv(&[artist as i32, album as i32]);
+                let store = &self.model.tree_store;
+                if let Some(album_iter) = store.get_iter(&path) {
+                    store.set(&album_iter, &[0], &[&title]);
+                    while let Some(child) = store.iter_children(Some(&album_iter)) {
+                        store.remove(&child);
+                    }
+                    for track in &tracks {
+                        store.insert_with_values(Some(&album_iter), None, &[0], &[&track.title]);
                     }
                 }
+            }
+            Msg::AlbumClosed(id) => {
+                self.albumwins.remove(&id);
+                if self.reload_pending && self.albumwins.is_empty() {
+                    self.reload_pending = false;
+                    self.relm.stream().emit(Msg::FileChanged);
+                }
+            }
+            Msg::RenameRow(row, name) => {
+                //The editor would put the old names back when saved
+                if row.len() > 1 && self.album_editor((row[0], row[1])).is_some() {
+                    self.show_error("Rename the album and its tracks in its editor");
+                    return;
+                }
+                self.keep_base();
+                if !self.model.db.rename(&row, &name) {
+                    self.show_error(&format!(
+                        "Could not rename to \"{}\", the name is empty or already taken",
+                        name
+                    ));
+                    return;
+                }
+                self.changed();
+                self.update_setlist_entries();
+
+                let indices: Vec<i32> = row.iter().map(|&i| i as i32).collect();
+                let store = &self.model.tree_store;
+                if let Some(iter) = store.get_iter(&TreePath::new_from_indicesv(&indices)) {
+                    store.set(&iter, &[0], &[&name]);
+                }
+            }
+            Msg::DropRow(src, dest, pos) => {
+                let edit = match drop_destination(&self.model.db, &src, &dest, pos) {
+                    Some(edit) => edit,
+                    None => return,
+                };
+                if edit.is_noop() {
+                    return;
+                }
 
-                self.albumwin = Some(init::<AlbumWindow>((title, tracks)).expect("album window"));
+                if self.is_edit_blocked(&edit) {
+                    return;
+                }
+
+                self.apply_edit(&edit);
+                self.model.undo_stack.push(edit);
+            }
+            Msg::Undo => {
+                let edit = match self.model.undo_stack.pop() {
+                    Some(edit) => edit.reverse(),
+                    None => return,
+                };
+                if self.is_edit_blocked(&edit) {
+                    self.model.undo_stack.push(edit.reverse());
+                    self.show_error("Close the album editors of the moved rows before undoing");
+                    return;
+                }
+                self.apply_edit(&edit);
+            }
+            Msg::Songbook => {
+                //Starts with the selected artist, album or track
+                let selected = self
+                    .tree_view
+                    .get_selection()
+                    .get_selected()
+                    .and_then(|(model, iter)| model.get_path(&iter))
+                    .map(|path| path.get_indices());
+                let selection = match selected.as_ref().map(|indices| &indices[..]) {
+                    Some(&[artist]) => Selection::Artist(artist as usize),
+                    Some(&[artist, album]) => Selection::Album(artist as usize, album as usize),
+                    Some(&[artist, album, track]) => {
+                        Selection::Tracks(vec![(artist as usize, album as usize, track as usize)])
+                    }
+                    _ => Selection::Tracks(Vec::new()),
+                };
+                make_songbook(
+                    &self.window,
+                    &mut self.model.db,
+                    &selection,
+                    &mut self.model.songbook_settings,
+                );
+            }
+            Msg::FindDuplicates => {
+                //Open editors would write the merged tracks back when saved
+                if !self.albumwins.is_empty() {
+                    self.show_error("Close the album editors before merging duplicates");
+                    return;
+                }
+                if !self.load_all_lyrics() {
+                    return;
+                }
+                self.keep_base();
+                if review_duplicates(&self.window, &mut self.model.db) {
+                    self.model.undo_stack.clear();
+                    self.changed();
+                    update_treestore(&self.model.db, &self.model.tree_store);
+                    self.update_setlists();
+                }
+            }
+            Msg::KeepHistory => {
+                //From now on every save is committed to it
+                if let Err(e) = self.model.db.enable_history() {
+                    self.show_error(&format!("Could not keep a history: {}", e));
+                }
+            }
+            Msg::TrackHistory => {
+                let (model, iter) = match self.tree_view.get_selection().get_selected() {
+                    Some(selected) => selected,
+                    None => return,
+                };
+                let (index, title) = match model.get_path(&iter).map(|path| path.get_indices()) {
+                    Some(indices) => match *indices {
+                        [artist, album, track] => (
+                            (artist as usize, album as usize, track as usize),
+                            model
+                                .get_value(&iter, 0)
+                                .get::<String>()
+                                .unwrap_or_default(),
+                        ),
+                        _ => return,
+                    },
+                    None => return,
+                };
+                //The load bar shows how far reading has come, it can only show one thing
+                if self.loading.is_some() || self.reading_history.is_some() {
+                    return;
+                }
+                let (history, track) = match self.model.db.track_history(index) {
+                    Some(found) => found,
+                    None => {
+                        self.show_error("No history is kept for this database");
+                        return;
+                    }
+                };
+
+                let id = self.next_load_id;
+                self.next_load_id += 1;
+                let cancel = Arc::new(AtomicBool::new(false));
+                let cancelled = cancel.clone();
+                let stream = self.relm.stream().clone();
+                let (channel, sender) = Channel::new(move |msg| stream.emit(msg));
+                let thread_track = track.clone();
+                thread::spawn(move || {
+                    let result = history.track_history(&thread_track, &mut |read, count| {
+                        let _ = sender.send(Msg::HistoryProgress(id, read, count));
+                        !cancelled.load(Ordering::SeqCst)
+                    });
+                    let _ = sender.send(Msg::HistoryRead(id, result));
+                });
+
+                self.load_progress.set_fraction(0.0);
+                self.load_progress
+                    .set_text(format!("Reading the history of {}", title).as_str());
+                self.load_bar.show();
+                self.reading_history = Some(ReadingHistory {
+                    id,
+                    index,
+                    track,
+                    title,
+                    cancel,
+                    _channel: channel,
+                });
+            }
+            Msg::HistoryProgress(id, read, count) => {
+                if self.reading_history.as_ref().map_or(true, |r| r.id != id) {
+                    return;
+                }
+                if count > 0 {
+                    self.load_progress.set_fraction(read as f64 / count as f64);
+                }
+                self.load_progress
+                    .set_text(format!("{} of {} saves read", read, count).as_str());
+            }
+            Msg::HistoryRead(id, result) => {
+                let reading = match self.reading_history.take() {
+                    Some(reading) => reading,
+                    None => return,
+                };
+                if reading.id != id {
+                    self.reading_history = Some(reading);
+                    return;
+                }
+                self.load_bar.hide();
+                let revisions = match result {
+                    Ok(revisions) => revisions,
+                    Err(e) => {
+                        self.show_error(&format!("Could not read the history: {}", e));
+                        return;
+                    }
+                };
+                let index = reading.index;
+                //The rows may have changed while the history was read
+                if self.model.db.track_ref(index).as_ref() != Some(&reading.track) {
+                    self.show_error("The track was moved while its history was read");
+                    return;
+                }
+                let revision = match pick_revision(&self.window, &reading.title, &revisions) {
+                    Some(i) => &revisions[i],
+                    None => return,
+                };
+
+                //An open editor would overwrite the restored lyrics when saved
+                if self.album_editor((index.0, index.1)).is_some() {
+                    self.show_error("Close the album's editor before restoring its lyrics");
+                    return;
+                }
+                self.keep_base();
+                if self.model.db.restore_lyrics(index, revision) {
+                    self.changed();
+                    self.text_viewer.set_text(&revision.lyrics);
+                }
+            }
+            Msg::FollowMpris => {
+                if !self.menu_follow_mpris.get_active() {
+                    self.mpris = None;
+                    return;
+                }
+                let stream = self.relm.stream().clone();
+                let (channel, sender) = Channel::new(move |msg| stream.emit(msg));
+                let watcher = MprisWatcher::new(move |song| {
+                    let _ = sender.send(Msg::MprisPlaying(song));
+                });
+                match watcher {
+                    Ok(watcher) => {
+                        self.mpris = Some(Following {
+                            _watcher: watcher,
+                            _channel: channel,
+                        })
+                    }
+                    Err(e) => {
+                        self.show_error(&format!("Could not follow the player: {}", e));
+                        self.menu_follow_mpris.set_active(false);
+                    }
+                }
+            }
+            Msg::MprisPlaying(song) => {
+                if self.mpris.is_none() || self.loading.is_some() {
+                    return;
+                }
+                match self
+                    .model
+                    .db
+                    .find_playing(&song.artist, &song.album, &song.title)
+                {
+                    Some(index) => self.show_track(index),
+                    None => {
+                        self.tree_view.get_selection().unselect_all();
+                        self.text_viewer.set_text(&format!(
+                            "{} by {} is not in the database",
+                            song.title, song.artist
+                        ));
+                    }
+                }
+            }
+            Msg::FollowMpd => {
+                if !self.menu_follow_mpd.get_active() {
+                    self.mpd = None;
+                    self.follow_panel.hide();
+                    return;
+                }
+                let address = mpd::default_address();
+                let stream = self.relm.stream().clone();
+                let (channel, sender) = Channel::new(move |msg| stream.emit(msg));
+                let watcher = MpdWatcher::new(&address, move |song| {
+                    let _ = sender.send(Msg::MpdPlaying(song));
+                });
+                match watcher {
+                    Ok(watcher) => {
+                        self.mpd = Some(Following {
+                            _watcher: watcher,
+                            _channel: channel,
+                        });
+                        self.follow_title
+                            .set_text(&format!("Nothing is playing on {}", address));
+                        self.follow_lyrics.set_text("");
+                        self.follow_panel.show();
+                    }
+                    Err(e) => {
+                        self.show_error(&format!("Could not connect to MPD at {}: {}", address, e));
+                        self.menu_follow_mpd.set_active(false);
+                    }
+                }
+            }
+            Msg::StopFollowMpd => self.menu_follow_mpd.set_active(false),
+            Msg::MpdPlaying(Ok(song)) => {
+                if self.mpd.is_none() {
+                    return;
+                }
+                self.follow_title
+                    .set_text(&format!("{} by {}", song.title, song.artist));
+                let lyrics =
+                    match self
+                        .model
+                        .db
+                        .find_playing(&song.artist, &song.album, &song.title)
+                    {
+                        Some(index) => self.model.db.lyrics(index),
+                        None => Ok(None),
+                    };
+                match lyrics {
+                    Ok(Some(lyrics)) => self.follow_lyrics.set_text(&lyrics),
+                    Ok(None) => self.follow_lyrics.set_text("Not in the database"),
+                    Err(e) => {
+                        self.follow_lyrics.set_text("");
+                        self.show_error(&format!("Could not read lyrics: {}", e));
+                    }
+                }
+            }
+            Msg::MpdPlaying(Err(e)) => {
+                if self.mpd.is_none() {
+                    return;
+                }
+                self.menu_follow_mpd.set_active(false);
+                self.show_error(&format!("Lost the connection to MPD: {}", e));
+            }
+            Msg::Present => {
+                //The open presentation follows the selection already
+                if let Some(ref presentation) = self.presentation {
+                    presentation.widget().present();
+                    return;
+                }
+                let (model, iter) = match self.tree_view.get_selection().get_selected() {
+                    Some(selected) => selected,
+                    None => return,
+                };
+                let index = match model.get_path(&iter).map(|path| path.get_indices()) {
+                    Some(indices) => match *indices {
+                        [artist, album, track] => (artist as usize, album as usize, track as usize),
+                        _ => {
+                            self.show_error("Select the track to present");
+                            return;
+                        }
+                    },
+                    None => return,
+                };
+                let title = model
+                    .get_value(&iter, 0)
+                    .get::<String>()
+                    .unwrap_or_default();
+                let lyrics = match self.model.db.lyrics(index) {
+                    Ok(lyrics) => lyrics.unwrap_or_default(),
+                    Err(e) => {
+                        self.show_error(&format!("Could not read lyrics: {}", e));
+                        return;
+                    }
+                };
+                let settings =
+                    match ask_presentation(&self.window, &self.model.presentation_settings) {
+                        Some(settings) => settings,
+                        None => return,
+                    };
+                self.model.presentation_settings = settings.clone();
+
+                let monitor = self.presentation_monitor();
+                let presentation = init::<PresentationWindow>((settings, monitor, title, lyrics))
+                    .expect("presentation window");
+                connect!(
+                    presentation@PresentationMsg::Quit,
+                    self.relm,
+                    Msg::PresentationClosed
+                );
+                self.presentation = Some(presentation);
+            }
+            Msg::PresentationClosed => self.presentation = None,
+            Msg::ToggleSetlists => {
+                if self.menu_setlists.get_active() {
+                    self.setlist_panel.show();
+                } else {
+                    self.setlist_panel.hide();
+                }
+            }
+            Msg::SetlistChosen => {
+                let active = self.setlist_combo.get_active();
+                let setlist = if active < 0 {
+                    None
+                } else {
+                    Some(active as usize)
+                };
+                //Refilling the combo changes it too
+                if setlist != self.model.setlist {
+                    self.model.setlist = setlist;
+                    self.update_setlist_entries();
+                }
+            }
+            Msg::NewSetlist => {
+                let name = match ask_setlist_name(&self.window, "New setlist", "") {
+                    Some(name) => name,
+                    None => return,
+                };
+                self.keep_base();
+                let mut setlist = Setlist::new();
+                setlist.name = name;
+                self.model.db.setlists.push(setlist);
+                self.model.setlist = Some(self.model.db.setlists.len() - 1);
+                self.changed();
+                self.update_setlists();
+            }
+            Msg::RenameSetlist => {
+                let i = match self.model.setlist {
+                    Some(i) => i,
+                    None => return,
+                };
+                let current = self.model.db.setlists[i].name.clone();
+                let name = match ask_setlist_name(&self.window, "Rename setlist", &current) {
+                    Some(name) => name,
+                    None => return,
+                };
+                if name == current {
+                    return;
+                }
+                self.keep_base();
+                self.model.db.setlists[i].name = name;
+                self.changed();
+                self.update_setlists();
+            }
+            Msg::DeleteSetlist => {
+                let i = match self.model.setlist {
+                    Some(i) => i,
+                    None => return,
+                };
+                let dialog = MessageDialog::new(
+                    Some(&self.window),
+                    DialogFlags::all(),
+                    MessageType::Question,
+                    ButtonsType::None,
+                    format!("Delete the setlist {}?", self.model.db.setlists[i].name).as_str(),
+                );
+                dialog.add_button("Delete", 0);
+                dialog.add_button("Keep", 1);
+                let delete = dialog.run() == 0;
+                dialog.destroy();
+                if !delete {
+                    return;
+                }
+                self.keep_base();
+                self.model.db.setlists.remove(i);
+                self.model.setlist = None;
+                self.changed();
+                self.update_setlists();
+            }
+            Msg::ExportSetlist => {
+                let setlist = match self.model.setlist {
+                    Some(i) => self.model.db.setlists[i].clone(),
+                    None => return,
+                };
+                let (path, format) = match ask_sheet_path(&self.window, &setlist.name) {
+                    Some(chosen) => chosen,
+                    None => return,
+                };
+                if let Err(e) = write_sheet(&path, &mut self.model.db, &setlist, format) {
+                    self.show_error(&format!("Could not write {}: {}", path, e));
+                }
+            }
+            Msg::AddToSetlist => {
+                let i = match self.model.setlist {
+                    Some(i) => i,
+                    None => return,
+                };
+                //A selected album adds all of its tracks
+                let selected = self
+                    .tree_view
+                    .get_selection()
+                    .get_selected()
+                    .and_then(|(model, iter)| model.get_path(&iter))
+                    .map(|path| path.get_indices());
+                let tracks = match selected.as_ref().map(|indices| &indices[..]) {
+                    Some(&[artist, album]) => {
+                        Selection::Album(artist as usize, album as usize).tracks(&self.model.db)
+                    }
+                    Some(&[artist, album, track]) => {
+                        vec![(artist as usize, album as usize, track as usize)]
+                    }
+                    _ => {
+                        self.show_error("Select the track or album to add");
+                        return;
+                    }
+                };
+                let entries: Vec<SetlistEntry> = tracks
+                    .into_iter()
+                    .filter_map(|index| self.model.db.track_ref(index))
+                    .map(|track| SetlistEntry::new(&track.artist, &track.album, &track.title))
+                    .collect();
+                if entries.is_empty() {
+                    return;
+                }
+                self.keep_base();
+
+                //They go after the selected entry, or at the end
+                let at = self
+                    .selected_setlist_row()
+                    .map_or(self.model.db.setlists[i].entries.len(), |row| row + 1);
+                let count = entries.len();
+                for (j, entry) in entries.into_iter().enumerate() {
+                    self.model.db.setlists[i].entries.insert(at + j, entry);
+                }
+                self.changed();
+                self.update_setlist_entries();
+                let path = TreePath::new_from_indicesv(&[(at + count - 1) as i32]);
+                self.setlist_view.get_selection().select_path(&path);
+            }
+            Msg::RemoveFromSetlist => {
+                let (i, row) = match (self.model.setlist, self.selected_setlist_row()) {
+                    (Some(i), Some(row)) => (i, row),
+                    _ => return,
+                };
+                self.keep_base();
+                self.model.db.setlists[i].entries.remove(row);
+                self.changed();
+                self.update_setlist_entries();
+            }
+            Msg::MoveInSetlist(up) => {
+                let (i, row) = match (self.model.setlist, self.selected_setlist_row()) {
+                    (Some(i), Some(row)) => (i, row),
+                    _ => return,
+                };
+                let other = if up {
+                    match row.checked_sub(1) {
+                        Some(other) => other,
+                        None => return,
+                    }
+                } else {
+                    row + 1
+                };
+                if other >= self.model.db.setlists[i].entries.len() {
+                    return;
+                }
+                self.keep_base();
+                self.model.db.setlists[i].entries.swap(row, other);
+                self.changed();
+                self.update_setlist_entries();
+                let path = TreePath::new_from_indicesv(&[other as i32]);
+                self.setlist_view.get_selection().select_path(&path);
+            }
+            Msg::SetlistEdited(row, column, text) => {
+                let i = match self.model.setlist {
+                    Some(i) => i,
+                    None => return,
+                };
+                //An empty tempo means it isn't known
+                let tempo = match text.trim() {
+                    "" => None,
+                    _ if column != 2 => None,
+                    tempo => match tempo.parse::<u16>() {
+                        Ok(tempo) => Some(tempo),
+                        Err(_) => {
+                            self.show_error(&format!(
+                                "{} is not a tempo in beats per minute",
+                                tempo
+                            ));
+                            return;
+                        }
+                    },
+                };
+                if row >= self.model.db.setlists[i].entries.len() {
+                    return;
+                }
+                self.keep_base();
+                {
+                    let entry = &mut self.model.db.setlists[i].entries[row];
+                    match column {
+                        1 => entry.key = text.trim().to_owned(),
+                        2 => entry.tempo = tempo,
+                        _ => entry.notes = text,
+                    }
+                }
+                self.changed();
+                self.update_setlist_entries();
+            }
+            Msg::SetlistSelected => {
+                let entry = match (self.model.setlist, self.selected_setlist_row()) {
+                    (Some(i), Some(row)) => self.model.db.setlists[i].entries.get(row).cloned(),
+                    _ => None,
+                };
+                let entry = match entry {
+                    Some(entry) => entry,
+                    None => return,
+                };
+                match self.model.db.setlist_track(&entry) {
+                    Some(index) => self.show_track(index),
+                    None => {
+                        self.tree_view.get_selection().unselect_all();
+                        self.text_viewer.set_text(&format!(
+                            "{} by {} is not in the database",
+                            entry.title, entry.artist
+                        ));
+                    }
+                }
             }
             Msg::Quit => gtk::main_quit(),
         }
     }
 }
 
+impl MainWindow {
+    fn changed(&mut self) {
+        self.model.needs_autosave = true;
+    }
+
+    //Remember what the file holds before the first unsaved edit is made
+    fn keep_base(&mut self) {
+        if self.model.base.is_none() && !self.model.db.file_path().is_empty() {
+            self.model.base = Some(self.model.db.snapshot());
+        }
+    }
+
+    //Ask about each conflict in turn, our version stays unless another one is picked
+    fn review_conflicts(&mut self, conflicts: &[Conflict]) {
+        for (i, conflict) in conflicts.iter().enumerate() {
+            let remaining = conflicts.len() - i;
+            if let Some(policy) = review_conflict(&self.window, conflict, remaining) {
+                self.model.db.resolve(conflict, policy);
+            }
+        }
+    }
+
+    //Get told when another program writes the open database file
+    fn watch_file(&mut self) {
+        self.watching = None;
+        let stream = self.relm.stream().clone();
+        let (channel, sender) = Channel::new(move |msg| stream.emit(msg));
+        let watcher = FileWatcher::new(self.model.db.file_path(), move || {
+            let _ = sender.send(Msg::FileChanged);
+        });
+        //Without a watcher changes just go unnoticed, not worth bothering the user about
+        if let Ok(watcher) = watcher {
+            self.watching = Some(Watching {
+                _watcher: watcher,
+                _channel: channel,
+            });
+        }
+    }
+
+    //The open file was written by another program, ask whether to read it again. Unsaved edits
+    //can be merged with what is in the file now instead.
+    fn offer_reload(&mut self) {
+        let path = self.model.db.file_path().to_owned();
+        let unsaved = self.model.base.is_some();
+        let message = if unsaved {
+            format!(
+                "{} was changed by another program. Merge those changes with your unsaved edits?",
+                file_name(&path)
+            )
+        } else {
+            format!(
+                "{} was changed by another program. Reload it?",
+                file_name(&path)
+            )
+        };
+        let dialog = MessageDialog::new(
+            Some(&self.window),
+            DialogFlags::all(),
+            MessageType::Question,
+            ButtonsType::None,
+            message.as_str(),
+        );
+        if unsaved {
+            dialog.add_button("Merge", 0);
+            dialog.add_button("Discard mine and reload", 1);
+        } else {
+            dialog.add_button("Reload", 1);
+        }
+        dialog.add_button("Keep mine", 2);
+        let response = dialog.run();
+        dialog.destroy();
+
+        match response {
+            0 => self.merge_from_disk(),
+            1 => {
+                let passphrase = self.model.db.passphrase().map(str::to_owned);
+                self.start_loading(path, passphrase);
+            }
+            _ => self.keep_mine(),
+        }
+    }
+
+    //Saving will overwrite the changes made by the other program
+    fn keep_mine(&mut self) {
+        let mut kept = self.model.db.keep_mine();
+        if let (Ok(()), Some(ref mut base)) = (&kept, self.model.base.as_mut()) {
+            kept = self.model.db.load_lyrics_of(&mut base.entries);
+        }
+        if let Err(e) = kept {
+            self.show_error(&format!(
+                "Could not keep your version, reload the file: {}",
+                e
+            ));
+        }
+    }
+
+    //Three-way merge of the unsaved edits with what another program wrote to the file
+    fn merge_from_disk(&mut self) {
+        let path = self.model.db.file_path().to_owned();
+        let theirs = match Database::from_with_progress(&path, self.model.db.passphrase(), |_| true)
+        {
+            Ok(theirs) => theirs,
+            Err(e) => {
+                self.show_error(&format!("Could not read {}: {}", path, e));
+                return;
+            }
+        };
+        let mut base = match self.model.base.take() {
+            Some(base) => base,
+            None => return,
+        };
+        let loaded = self.model.db.load_all_lyrics();
+        if let Err(e) = loaded.and_then(|()| self.model.db.load_lyrics_of(&mut base.entries)) {
+            self.model.base = Some(base);
+            self.show_error(&format!("Could not read lyrics: {}", e));
+            return;
+        }
+
+        //Edits from now on are made against what the file holds now
+        let theirs = Snapshot {
+            entries: theirs.entries,
+            setlists: theirs.setlists,
+        };
+        self.model.base = Some(theirs.clone());
+        let conflicts = self.model.db.merge3(&base, theirs);
+        self.review_conflicts(&conflicts);
+        self.model.db.accept_disk_state();
+
+        self.model.undo_stack.clear();
+        self.changed();
+        update_treestore(&self.model.db, &self.model.tree_store);
+        self.update_setlists();
+    }
+
+    //A newer autosave was found for the database that was just opened, ask what to do with it
+    fn offer_recovery(&mut self) {
+        let dialog = MessageDialog::new(
+            Some(&self.window),
+            DialogFlags::all(),
+            MessageType::Question,
+            ButtonsType::None,
+            "Unsaved changes to this database were recovered. Restore them?",
+        );
+        dialog.add_button("Restore", 0);
+        dialog.add_button("Discard", 1);
+        let restore = dialog.run() == 0;
+        dialog.destroy();
+
+        //Restored changes stay in the recovery file until the database is saved
+        let result = if restore {
+            self.keep_base();
+            self.model.db.restore_recovery()
+        } else {
+            self.model.db.discard_recovery()
+        };
+        if let Err(e) = result {
+            self.show_error(&format!("Could not recover the changes: {}", e));
+        }
+    }
+
+    fn ask_save_path(&self) -> Option<String> {
+        let dialog = FileChooserDialog::new(
+            Some("Save as..."),
+            Some(&self.window),
+            FileChooserAction::Save,
+        );
+        dialog.add_button("Save", 0);
+        dialog.add_button("Close", 1);
+        dialog.set_do_overwrite_confirmation(true);
+        let result = dialog.run();
+        let filename = dialog.get_filename();
+        dialog.destroy();
+        if result != 0 {
+            return None;
+        }
+        filename.map(|filename| filename.to_string_lossy().into_owned())
+    }
+
+    //Read another database file whole, asking for its passphrase if it is encrypted. Errors are
+    //shown and give None.
+    fn read_other(&self, path: &str) -> Option<Database> {
+        let mut retry = false;
+        loop {
+            let passphrase = match container::is_encrypted(path) {
+                Ok(false) => None,
+                Ok(true) => Some(ask_passphrase(&self.window, &file_name(path), retry)?),
+                Err(e) => {
+                    self.show_error(&format!("Could not read {}: {}", path, e));
+                    return None;
+                }
+            };
+            let passphrase = passphrase.as_ref().map(String::as_str);
+            match Database::from_with_progress(path, passphrase, |_| true) {
+                Ok(other) => return Some(other),
+                Err(DatabaseError::WrongPassphrase) => retry = true,
+                Err(e) => {
+                    self.show_error(&format!("Could not read {}: {}", path, e));
+                    return None;
+                }
+            }
+        }
+    }
+
+    //Ask for a passphrase if the file is encrypted and start loading it. `retry` is set when
+    //the last passphrase was wrong.
+    fn open_file(&mut self, path: String, retry: bool) {
+        let passphrase = match container::is_encrypted(&path) {
+            Ok(false) => None,
+            Ok(true) => match ask_passphrase(&self.window, &file_name(&path), retry) {
+                Some(passphrase) => Some(passphrase),
+                None => return,
+            },
+            Err(e) => {
+                self.show_error(&format!("Could not read {}: {}", path, e));
+                return;
+            }
+        };
+        self.start_loading(path, passphrase);
+    }
+
+    //Read the database at `path` on another thread, it replaces the open one once done
+    fn start_loading(&mut self, path: String, passphrase: Option<String>) {
+        //The editors belong to the database that is about to be replaced
+        if !self.albumwins.is_empty() {
+            self.show_error("Close the album editors before opening another database");
+            return;
+        }
+        let id = self.next_load_id;
+        self.next_load_id += 1;
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancelled = cancel.clone();
+
+        let stream = self.relm.stream().clone();
+        let (channel, sender) = Channel::new(move |msg| stream.emit(msg));
+        let thread_path = path.clone();
+        thread::spawn(move || {
+            //Sending fails once the window is gone, there is nobody left to tell then
+            let passphrase = passphrase.as_ref().map(String::as_str);
+            let result = Database::from_index_with_progress(&thread_path, passphrase, |progress| {
+                let _ = sender.send(Msg::LoadProgress(id, *progress));
+                !cancelled.load(Ordering::SeqCst)
+            });
+            let _ = sender.send(Msg::Loaded(id, result));
+        });
+
+        self.load_progress.set_fraction(0.0);
+        self.load_progress
+            .set_text(format!("Loading {}", file_name(&path)).as_str());
+        self.load_bar.show();
+
+        //A newer load replaces any earlier one
+        self.loading = Some(Loading {
+            id,
+            path,
+            cancel,
+            _channel: channel,
+            filling: None,
+        });
+    }
+
+    fn is_loading(&self, id: u32) -> bool {
+        self.loading
+            .as_ref()
+            .map_or(false, |loading| loading.id == id)
+    }
+
+    //The audience looks at another screen than the one this window is on, if there is one
+    fn presentation_monitor(&self) -> Option<i32> {
+        let screen = self.window.get_screen()?;
+        let count = screen.get_n_monitors();
+        if count < 2 {
+            return None;
+        }
+        let own = screen.get_monitor_at_window(&self.window.get_window()?);
+        Some((own + 1) % count)
+    }
+
+    fn show_error(&self, message: &str) {
+        let dialog = MessageDialog::new(
+            Some(&self.window),
+            DialogFlags::all(),
+            MessageType::Error,
+            ButtonsType::Close,
+            message,
+        );
+        dialog.run();
+        dialog.destroy();
+    }
+
+    //Lyrics are read on demand, get all of them before working on the whole database.
+    //Returns false if they couldn't be read.
+    fn load_all_lyrics(&mut self) -> bool {
+        match self.model.db.load_all_lyrics() {
+            Ok(()) => true,
+            Err(e) => {
+                self.show_error(&format!("Could not read lyrics: {}", e));
+                false
+            }
+        }
+    }
+
+    fn album_editor(&self, album: (usize, usize)) -> Option<&AlbumEditor> {
+        self.albumwins.values().find(|editor| editor.album == album)
+    }
+
+    //Whether open album editors keep the edit from being made
+    fn is_edit_blocked(&self, edit: &Edit) -> bool {
+        //Open editors would overwrite the moved tracks when saved
+        match edit.track_albums() {
+            Some((from, to)) => {
+                self.album_editor(from).is_some() || self.album_editor(to).is_some()
+            }
+            None => false,
+        }
+    }
+
+    //Select the track at (artist, album, track) in the tree. Moving the cursor shows the lyrics
+    //like a click on the track would.
+    fn show_track(&self, (artist, album, track): (usize, usize, usize)) {
+        let path = TreePath::new_from_indicesv(&[artist as i32, album as i32, track as i32]);
+        self.tree_view.expand_to_path(&path);
+        self.tree_view
+            .set_cursor(&path, None::<&TreeViewColumn>, false);
+        self.tree_view
+            .scroll_to_cell(Some(&path), None::<&TreeViewColumn>, false, 0.0, 0.0);
+    }
+
+    //Fill the setlist chooser from the database, keeping the shown setlist if it is still there
+    fn update_setlists(&mut self) {
+        let count = self.model.db.setlists.len();
+        if self.model.setlist.map_or(false, |i| i >= count) {
+            self.model.setlist = None;
+        }
+        if self.model.setlist.is_none() && count > 0 {
+            self.model.setlist = Some(0);
+        }
+        self.setlist_combo.remove_all();
+        for setlist in &self.model.db.setlists {
+            self.setlist_combo.append(None, &setlist.name);
+        }
+        self.setlist_combo
+            .set_active(self.model.setlist.map_or(-1, |i| i as i32));
+        self.update_setlist_entries();
+    }
+
+    fn update_setlist_entries(&self) {
+        update_setlist_store(
+            &self.model.db,
+            self.model.setlist,
+            &self.model.setlist_store,
+        );
+    }
+
+    fn selected_setlist_row(&self) -> Option<usize> {
+        let (model, iter) = self.setlist_view.get_selection().get_selected()?;
+        let path = model.get_path(&iter)?;
+        path.get_indices().first().map(|&row| row as usize)
+    }
+
+    fn apply_edit(&mut self, edit: &Edit) {
+        self.keep_base();
+        if !edit.apply(&mut self.model.db) {
+            return;
+        }
+        self.changed();
+
+        //Open editors follow their album to its new position
+        for editor in self.albumwins.values_mut() {
+            editor.album = edit.album_position(editor.album);
+        }
+
+        update_treestore(&self.model.db, &self.model.tree_store);
+        self.update_setlist_entries();
+        let path = edit.dest_path();
+        self.tree_view.expand_to_path(&path);
+        self.tree_view.get_selection().select_path(&path);
+    }
+}
+
 impl Widget for MainWindow {
     type Root = Window;
     fn root(&self) -> Self::Root {
@@ -166,11 +1890,46 @@ impl Widget for MainWindow {
         //Load glade items
         get_object!(window, Window, builder);
         get_object!(menu_open, MenuItem, builder);
+        get_object!(menu_save, MenuItem, builder);
+        get_object!(menu_merge, MenuItem, builder);
+        get_object!(menu_compare, MenuItem, builder);
+        get_object!(menu_passphrase, MenuItem, builder);
+        get_object!(menu_songbook, MenuItem, builder);
+        get_object!(menu_undo, MenuItem, builder);
+        get_object!(menu_duplicates, MenuItem, builder);
+        get_object!(menu_track_history, MenuItem, builder);
+        get_object!(menu_keep_history, MenuItem, builder);
+        get_object!(menu_follow_mpris, CheckMenuItem, builder);
+        get_object!(menu_follow_mpd, CheckMenuItem, builder);
+        get_object!(menu_present, MenuItem, builder);
+        get_object!(follow_panel, gtk::Box, builder);
+        get_object!(follow_title, Label, builder);
+        get_object!(follow_lyrics, Label, builder);
+        get_object!(button_stop_follow, Button, builder);
         get_object!(text_viewer, Label, builder);
         get_object!(tree_view, TreeView, builder);
         get_object!(button_add_artist, Button, builder);
         get_object!(view_column, TreeViewColumn, builder);
+        get_object!(load_bar, gtk::Box, builder);
+        get_object!(load_progress, ProgressBar, builder);
+        get_object!(button_cancel_load, Button, builder);
         get_object!(lyric_column, TreeViewColumn, builder);
+        get_object!(menu_setlists, CheckMenuItem, builder);
+        get_object!(setlist_panel, gtk::Box, builder);
+        get_object!(setlist_combo, ComboBoxText, builder);
+        get_object!(button_new_setlist, Button, builder);
+        get_object!(button_rename_setlist, Button, builder);
+        get_object!(button_delete_setlist, Button, builder);
+        get_object!(button_export_setlist, Button, builder);
+        get_object!(setlist_view, TreeView, builder);
+        get_object!(setlist_track_column, TreeViewColumn, builder);
+        get_object!(setlist_key_column, TreeViewColumn, builder);
+        get_object!(setlist_tempo_column, TreeViewColumn, builder);
+        get_object!(setlist_notes_column, TreeViewColumn, builder);
+        get_object!(button_setlist_add, Button, builder);
+        get_object!(button_setlist_remove, Button, builder);
+        get_object!(button_setlist_up, Button, builder);
+        get_object!(button_setlist_down, Button, builder);
 
         //Context menu
         get_object!(context_menu, Menu, builder);
@@ -190,6 +1949,31 @@ impl Widget for MainWindow {
             .expect("failed to set editable");
         tree_view.set_model(Some(&model.tree_store));
 
+        //Setup setlist view, all but the track can be edited in place
+        let setlist_columns = [
+            &setlist_track_column,
+            &setlist_key_column,
+            &setlist_tempo_column,
+            &setlist_notes_column,
+        ];
+        for (i, column) in setlist_columns.iter().enumerate() {
+            let cell = gtk::CellRendererText::new();
+            column.pack_start(&cell, true);
+            column.add_attribute(&cell, "text", i as i32);
+            if i == 0 {
+                continue;
+            }
+            cell.set_property("editable", &true)
+                .expect("failed to set editable");
+            let stream = relm.stream().clone();
+            cell.connect_edited(move |_, path, text| {
+                if let Some(&row) = path.get_indices().first() {
+                    stream.emit(Msg::SetlistEdited(row as usize, i as u32, text.to_owned()));
+                }
+            });
+        }
+        setlist_view.set_model(Some(&model.setlist_store));
+
         window.show_all();
 
         connect!(
@@ -205,8 +1989,113 @@ impl Widget for MainWindow {
             Msg::SelectedItem
         );
         connect!(relm, menu_open, connect_activate(_), Msg::MenuOpen);
+        connect!(relm, menu_save, connect_activate(_), Msg::MenuSave);
+        connect!(relm, menu_merge, connect_activate(_), Msg::MenuMerge);
+        connect!(relm, menu_compare, connect_activate(_), Msg::MenuCompare);
+        connect!(
+            relm,
+            menu_passphrase,
+            connect_activate(_),
+            Msg::MenuPassphrase
+        );
+        connect!(relm, menu_songbook, connect_activate(_), Msg::Songbook);
+        connect!(relm, menu_undo, connect_activate(_), Msg::Undo);
+        connect!(
+            relm,
+            menu_duplicates,
+            connect_activate(_),
+            Msg::FindDuplicates
+        );
+        connect!(
+            relm,
+            menu_track_history,
+            connect_activate(_),
+            Msg::TrackHistory
+        );
+        connect!(
+            relm,
+            menu_keep_history,
+            connect_activate(_),
+            Msg::KeepHistory
+        );
+        connect!(
+            relm,
+            menu_follow_mpris,
+            connect_toggled(_),
+            Msg::FollowMpris
+        );
+        connect!(relm, menu_follow_mpd, connect_toggled(_), Msg::FollowMpd);
+        connect!(relm, menu_present, connect_activate(_), Msg::Present);
+        connect!(
+            relm,
+            button_stop_follow,
+            connect_clicked(_),
+            Msg::StopFollowMpd
+        );
         connect!(relm, button_add_artist, connect_activate(_), Msg::AddArtist);
         connect!(relm, context_menu_edit, connect_activate(_), Msg::EditAlbum);
+        connect!(
+            relm,
+            button_cancel_load,
+            connect_clicked(_),
+            Msg::CancelLoad
+        );
+        connect!(relm, menu_setlists, connect_toggled(_), Msg::ToggleSetlists);
+        connect!(relm, setlist_combo, connect_changed(_), Msg::SetlistChosen);
+        connect!(
+            relm,
+            button_new_setlist,
+            connect_clicked(_),
+            Msg::NewSetlist
+        );
+        connect!(
+            relm,
+            button_rename_setlist,
+            connect_clicked(_),
+            Msg::RenameSetlist
+        );
+        connect!(
+            relm,
+            button_delete_setlist,
+            connect_clicked(_),
+            Msg::DeleteSetlist
+        );
+        connect!(
+            relm,
+            button_export_setlist,
+            connect_clicked(_),
+            Msg::ExportSetlist
+        );
+        connect!(
+            relm,
+            button_setlist_add,
+            connect_clicked(_),
+            Msg::AddToSetlist
+        );
+        connect!(
+            relm,
+            button_setlist_remove,
+            connect_clicked(_),
+            Msg::RemoveFromSetlist
+        );
+        connect!(
+            relm,
+            button_setlist_up,
+            connect_clicked(_),
+            Msg::MoveInSetlist(true)
+        );
+        connect!(
+            relm,
+            button_setlist_down,
+            connect_clicked(_),
+            Msg::MoveInSetlist(false)
+        );
+        connect!(
+            relm,
+            setlist_view,
+            connect_cursor_changed(_),
+            Msg::SetlistSelected
+        );
 
         //Connections that cant be done with relm
 
@@ -222,19 +2111,65 @@ impl Widget for MainWindow {
             }
             Inhibit(false)
         });
-        let model1 = model.tree_store.clone();
+        //Take over drops from the reorderable tree, the database decides where rows can go
+        let stream = relm.stream().clone();
+        tree_view.connect_drag_data_received(move |view, context, x, y, _, _, time| {
+            view.stop_signal_emission("drag-data-received");
+
+            let src = view
+                .get_selection()
+                .get_selected()
+                .and_then(|(model, iter)| model.get_path(&iter));
+            let dest = view.get_dest_row_at_pos(x, y);
+            let mut dropped = false;
+            if let (Some(src), Some((Some(dest), pos))) = (src, dest) {
+                let indices = |path: &TreePath| {
+                    path.get_indices()
+                        .into_iter()
+                        .map(|i| i as usize)
+                        .collect::<Vec<_>>()
+                };
+                stream.emit(Msg::DropRow(indices(&src), indices(&dest), pos));
+                dropped = true;
+            }
+            context.drag_finish(dropped, false, time);
+        });
+
+        //Renames go to the database, the tree row follows once it is renamed there
+        let stream = relm.stream().clone();
         cell_name.connect_edited(move |_, path, string| {
-            let iter = model1.get_iter(&path).unwrap();
-            model1.set(&iter, &[0], &[&string.to_owned()]);
+            let row = path.get_indices().into_iter().map(|i| i as usize).collect();
+            stream.emit(Msg::RenameRow(row, string.to_owned()));
         });
 
         MainWindow {
+            relm: relm.clone(),
             model,
             tree_view,
             window,
             text_viewer,
             context_menu,
-            albumwin: None,
+            albumwins: HashMap::new(),
+            next_albumwin_id: 0,
+            reload_pending: false,
+            load_bar,
+            load_progress,
+            loading: None,
+            reading_history: None,
+            next_load_id: 0,
+            watching: None,
+            menu_follow_mpris,
+            mpris: None,
+            menu_follow_mpd,
+            follow_panel,
+            follow_title,
+            follow_lyrics,
+            mpd: None,
+            presentation: None,
+            menu_setlists,
+            setlist_panel,
+            setlist_combo,
+            setlist_view,
         }
     }
 }
@@ -0,0 +1,58 @@
+use gtk::prelude::*;
+use gtk::{Dialog, DialogFlags, Label, ScrolledWindow, TextBuffer, TextView, Window};
+
+use database::diff::diff_lines;
+use database::{Conflict, MergePolicy};
+
+//Ask the user how to settle a merge conflict, showing a diff from our lyrics to theirs.
+//Returns None if the dialog was closed, which leaves our version in place.
+pub fn review_conflict(
+    parent: &Window,
+    conflict: &Conflict,
+    remaining: usize,
+) -> Option<MergePolicy> {
+    let dialog = Dialog::new_with_buttons(
+        Some("Merge conflict"),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Keep mine", 0), ("Keep theirs", 1), ("Keep both", 2)],
+    );
+    dialog.set_default_size(500, 400);
+
+    let heading = Label::new(Some(
+        format!(
+            "{} - {} - {} ({} left)",
+            conflict.artist, conflict.album, conflict.mine.title, remaining
+        )
+        .as_str(),
+    ));
+
+    let buffer = TextBuffer::new(None);
+    let diff = diff_lines(&conflict.mine.lyrics, &conflict.theirs.lyrics)
+        .iter()
+        .map(|change| change.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    buffer.set_text(&diff);
+
+    let text_view = TextView::new_with_buffer(&buffer);
+    text_view.set_editable(false);
+    text_view.set_monospace(true);
+
+    let scrolled = ScrolledWindow::new(None, None);
+    scrolled.add(&text_view);
+
+    let content = dialog.get_content_area();
+    content.pack_start(&heading, false, false, 5);
+    content.pack_start(&scrolled, true, true, 0);
+    dialog.show_all();
+
+    let policy = match dialog.run() {
+        0 => Some(MergePolicy::KeepMine),
+        1 => Some(MergePolicy::KeepTheirs),
+        2 => Some(MergePolicy::KeepBoth),
+        _ => None,
+    };
+    dialog.destroy();
+    policy
+}
@@ -11,3 +11,23 @@ pub use self::mainwindow::MainWindow;
 
 pub mod albumwindow;
 pub use self::albumwindow::AlbumWindow;
+
+pub mod mergedialog;
+
+pub mod diffdialog;
+
+pub mod duplicatesdialog;
+
+pub mod passphrasedialog;
+
+pub mod historydialog;
+
+pub mod candidatedialog;
+
+pub mod presentationwindow;
+
+pub mod presentationdialog;
+
+pub mod songbookdialog;
+
+pub mod setlistdialog;
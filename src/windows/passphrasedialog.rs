@@ -0,0 +1,84 @@
+use gtk::prelude::*;
+use gtk::{Dialog, DialogFlags, Entry, Label, Window};
+
+//Ask for the passphrase of an encrypted database file. Returns None if the user gave up.
+pub fn ask_passphrase(parent: &Window, file_name: &str, retry: bool) -> Option<String> {
+    let dialog = Dialog::new_with_buttons(
+        Some("Encrypted database"),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Open", 0), ("Cancel", 1)],
+    );
+    dialog.set_default_response(0);
+
+    let message = if retry {
+        format!("Wrong passphrase for {}, try again:", file_name)
+    } else {
+        format!("{} is encrypted, enter its passphrase:", file_name)
+    };
+    let label = Label::new(Some(message.as_str()));
+
+    let entry = Entry::new();
+    entry.set_visibility(false);
+    entry.set_activates_default(true);
+
+    let content = dialog.get_content_area();
+    content.pack_start(&label, false, false, 5);
+    content.pack_start(&entry, false, false, 5);
+    dialog.show_all();
+
+    let passphrase = match dialog.run() {
+        0 => entry.get_text(),
+        _ => None,
+    };
+    dialog.destroy();
+    passphrase
+}
+
+//Ask for a new passphrase to encrypt the database with, twice. Returns None if the user gave
+//up and Some(None) if both were left empty to save the database unencrypted.
+pub fn ask_new_passphrase(parent: &Window) -> Option<Option<String>> {
+    let dialog = Dialog::new_with_buttons(
+        Some("Set passphrase"),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Set", 0), ("Cancel", 1)],
+    );
+    dialog.set_default_response(0);
+
+    let label = Label::new(Some(
+        "Encrypt the database with this passphrase when it is saved, leave it empty to save it \
+         unencrypted:",
+    ));
+    let entry = Entry::new();
+    entry.set_visibility(false);
+    let repeat = Entry::new();
+    repeat.set_visibility(false);
+    repeat.set_activates_default(true);
+
+    let content = dialog.get_content_area();
+    content.pack_start(&label, false, false, 5);
+    content.pack_start(&entry, false, false, 5);
+    content.pack_start(&Label::new(Some("Repeat it:")), false, false, 5);
+    content.pack_start(&repeat, false, false, 5);
+    dialog.show_all();
+
+    let passphrase = loop {
+        if dialog.run() != 0 {
+            break None;
+        }
+        let passphrase = entry.get_text().unwrap_or_default();
+        if passphrase != repeat.get_text().unwrap_or_default() {
+            label.set_text("The passphrases don't match, enter them again:");
+            entry.set_text("");
+            repeat.set_text("");
+            entry.grab_focus();
+        } else if passphrase.is_empty() {
+            break Some(None);
+        } else {
+            break Some(Some(passphrase));
+        }
+    };
+    dialog.destroy();
+    passphrase
+}
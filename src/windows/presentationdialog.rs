@@ -0,0 +1,50 @@
+use gtk::prelude::*;
+use gtk::{ComboBoxText, Dialog, DialogFlags, FontButton, Grid, Label, Window};
+
+use export::presentation::{PresentationSettings, Unit};
+
+//Ask how lyrics should be presented, starting from `current`. Returns None if cancelled.
+pub fn ask_presentation(
+    parent: &Window,
+    current: &PresentationSettings,
+) -> Option<PresentationSettings> {
+    let dialog = Dialog::new_with_buttons(
+        Some("Present lyrics"),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Present", 0), ("Cancel", 1)],
+    );
+    dialog.set_default_response(0);
+
+    let font = FontButton::new_with_font(&current.font);
+    let unit = ComboBoxText::new();
+    unit.append(Some("verse"), "One verse at a time");
+    unit.append(Some("line"), "One line at a time");
+    unit.set_active_id(match current.unit {
+        Unit::Verse => "verse",
+        Unit::Line => "line",
+    });
+
+    let grid = Grid::new();
+    grid.set_row_spacing(6);
+    grid.set_column_spacing(12);
+    grid.attach(&Label::new(Some("Font")), 0, 0, 1, 1);
+    grid.attach(&font, 1, 0, 1, 1);
+    grid.attach(&Label::new(Some("Show")), 0, 1, 1, 1);
+    grid.attach(&unit, 1, 1, 1, 1);
+    dialog.get_content_area().pack_start(&grid, true, true, 5);
+    dialog.show_all();
+
+    let settings = match dialog.run() {
+        0 => Some(PresentationSettings {
+            font: font.get_font().unwrap_or_else(|| current.font.clone()),
+            unit: match unit.get_active_id().as_ref().map(String::as_str) {
+                Some("line") => Unit::Line,
+                _ => Unit::Verse,
+            },
+        }),
+        _ => None,
+    };
+    dialog.destroy();
+    settings
+}
@@ -0,0 +1,233 @@
+use gdk::enums::key::{self, Key};
+use gtk::prelude::*;
+use gtk::{Builder, CssProvider, EventBox, Label, Window, STYLE_PROVIDER_PRIORITY_APPLICATION};
+
+use relm::{Relm, Update, Widget};
+
+use export::presentation::{slides, split_font, PresentationSettings, Unit};
+
+//Sizes are in points
+const MIN_SIZE: f64 = 8.0;
+//How much bigger or smaller the text gets with each key press
+const SIZE_STEP: f64 = 1.1;
+
+//Lyrics are shown on a dark room's wall, anything but the text is black
+const STYLE: &str = "window { background-color: black; }";
+
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+//Presentation clickers send the page keys, and b or a period to black out the screen
+fn key_message(key: Key) -> Option<Msg> {
+    match key {
+        key::Right | key::Down | key::Page_Down | key::space | key::Return => Some(Msg::Next),
+        key::Left | key::Up | key::Page_Up | key::BackSpace => Some(Msg::Previous),
+        key::Home => Some(Msg::First),
+        key::End => Some(Msg::Last),
+        key::b | key::B | key::period => Some(Msg::Blackout),
+        key::plus | key::equal | key::KP_Add => Some(Msg::Bigger),
+        key::minus | key::KP_Subtract => Some(Msg::Smaller),
+        key::f | key::F11 => Some(Msg::Fullscreen),
+        key::Escape => Some(Msg::Close),
+        _ => None,
+    }
+}
+
+#[derive(Msg)]
+pub enum Msg {
+    //Title and lyrics of the track to present, starting over at its title
+    Show(String, String),
+    Next,
+    Previous,
+    First,
+    Last,
+    Blackout,
+    Bigger,
+    Smaller,
+    Fullscreen,
+    Close,
+    Quit,
+}
+
+pub struct Model {
+    slides: Vec<String>,
+    current: usize,
+    blackout: bool,
+    unit: Unit,
+    family: String,
+    size: f64,
+    //Monitor to fill, the window's own if None
+    monitor: Option<i32>,
+    fullscreen: bool,
+}
+
+pub struct PresentationWindow {
+    window: Window,
+    model: Model,
+    slide_label: Label,
+    position_label: Label,
+}
+
+impl PresentationWindow {
+    fn show_slide(&self) {
+        if self.model.blackout {
+            self.slide_label.set_text("");
+            self.position_label.set_text("");
+            return;
+        }
+        let slide = self
+            .model
+            .slides
+            .get(self.model.current)
+            .map_or("", String::as_str);
+        self.slide_label.set_markup(&format!(
+            "<span font_desc=\"{}\" size=\"{}\" foreground=\"white\">{}</span>",
+            escape_markup(&self.model.family),
+            (self.model.size * 1024.0) as i32,
+            escape_markup(slide)
+        ));
+        self.position_label.set_markup(&format!(
+            "<span foreground=\"gray\">{} / {}</span>",
+            self.model.current + 1,
+            self.model.slides.len()
+        ));
+    }
+}
+
+impl Update for PresentationWindow {
+    type Model = Model;
+    //How to present, the monitor to fill if not the window's own, and the title and lyrics of
+    //the first track
+    type ModelParam = (PresentationSettings, Option<i32>, String, String);
+    type Msg = Msg;
+
+    fn model(
+        _: &Relm<Self>,
+        (settings, monitor, title, lyrics): (PresentationSettings, Option<i32>, String, String),
+    ) -> Model {
+        let (family, size) = split_font(&settings.font);
+        Model {
+            slides: slides(&title, &lyrics, settings.unit),
+            current: 0,
+            blackout: false,
+            unit: settings.unit,
+            family,
+            size,
+            monitor,
+            fullscreen: true,
+        }
+    }
+
+    fn update(&mut self, event: Msg) {
+        match event {
+            Msg::Show(title, lyrics) => {
+                self.model.slides = slides(&title, &lyrics, self.model.unit);
+                self.model.current = 0;
+            }
+            Msg::Next => {
+                if self.model.current + 1 < self.model.slides.len() {
+                    self.model.current += 1;
+                }
+            }
+            Msg::Previous => {
+                if self.model.current > 0 {
+                    self.model.current -= 1;
+                }
+            }
+            Msg::First => self.model.current = 0,
+            Msg::Last => self.model.current = self.model.slides.len().saturating_sub(1),
+            Msg::Blackout => self.model.blackout = !self.model.blackout,
+            Msg::Bigger => self.model.size *= SIZE_STEP,
+            Msg::Smaller => self.model.size = (self.model.size / SIZE_STEP).max(MIN_SIZE),
+            Msg::Fullscreen => {
+                if self.model.fullscreen {
+                    self.window.unfullscreen();
+                } else {
+                    self.window.fullscreen();
+                }
+                self.model.fullscreen = !self.model.fullscreen;
+                return;
+            }
+            Msg::Close => {
+                self.window.close();
+                return;
+            }
+            Msg::Quit => {
+                self.window.destroy();
+                return;
+            }
+        }
+        self.show_slide();
+    }
+}
+
+impl Widget for PresentationWindow {
+    type Root = Window;
+    fn root(&self) -> Self::Root {
+        self.window.clone()
+    }
+
+    fn view(relm: &Relm<Self>, model: Self::Model) -> Self {
+        let glade_src = include_str!("presentationwindow.glade");
+        let builder = Builder::new_from_string(glade_src);
+
+        get_object!(window, Window, builder);
+        get_object!(event_box, EventBox, builder);
+        get_object!(slide_label, Label, builder);
+        get_object!(position_label, Label, builder);
+
+        let style = CssProvider::new();
+        style
+            .load_from_data(STYLE.as_bytes())
+            .expect("valid presentation style");
+        if let Some(context) = window.get_style_context() {
+            context.add_provider(&style, STYLE_PROVIDER_PRIORITY_APPLICATION);
+        }
+
+        window.show_all();
+        match (model.monitor, window.get_screen()) {
+            (Some(monitor), Some(screen)) => window.fullscreen_on_monitor(&screen, monitor),
+            _ => window.fullscreen(),
+        }
+
+        connect!(
+            relm,
+            window,
+            connect_delete_event(_, _),
+            return (Msg::Quit, Inhibit(false))
+        );
+        connect!(
+            relm,
+            window,
+            connect_key_press_event(_, event),
+            return (key_message(event.get_keyval()), Inhibit(true))
+        );
+        //A click moves on, a right click goes back
+        connect!(
+            relm,
+            event_box,
+            connect_button_press_event(_, event),
+            return (
+                match event.get_button() {
+                    1 => Some(Msg::Next),
+                    3 => Some(Msg::Previous),
+                    _ => None,
+                },
+                Inhibit(true)
+            )
+        );
+
+        let presentation = PresentationWindow {
+            window,
+            model,
+            slide_label,
+            position_label,
+        };
+        presentation.show_slide();
+        presentation
+    }
+}
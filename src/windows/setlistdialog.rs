@@ -0,0 +1,73 @@
+use gtk::prelude::*;
+use gtk::{
+    Dialog, DialogFlags, Entry, FileChooserAction, FileChooserDialog, FileFilter, Label, Window,
+};
+
+use export::setlist::SheetFormat;
+
+//Ask for the name of a setlist, starting with `current`. Returns None if cancelled.
+pub fn ask_setlist_name(parent: &Window, title: &str, current: &str) -> Option<String> {
+    let dialog = Dialog::new_with_buttons(
+        Some(title),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("OK", 0), ("Cancel", 1)],
+    );
+    dialog.set_default_response(0);
+
+    let entry = Entry::new();
+    entry.set_text(current);
+    entry.set_activates_default(true);
+
+    let content = dialog.get_content_area();
+    content.pack_start(&Label::new(Some("Name of the setlist:")), false, false, 5);
+    content.pack_start(&entry, false, false, 5);
+    dialog.show_all();
+
+    let name = match dialog.run() {
+        0 => entry.get_text().and_then(|name| {
+            if name.trim().is_empty() {
+                None
+            } else {
+                Some(name)
+            }
+        }),
+        _ => None,
+    };
+    dialog.destroy();
+    name
+}
+
+//Ask where to write the lyric sheet of the setlist `name`, the format goes by the file name
+pub fn ask_sheet_path(parent: &Window, name: &str) -> Option<(String, SheetFormat)> {
+    let dialog = FileChooserDialog::new(
+        Some("Export lyric sheet..."),
+        Some(parent),
+        FileChooserAction::Save,
+    );
+    dialog.add_button("Export", 0);
+    dialog.add_button("Close", 1);
+    dialog.set_do_overwrite_confirmation(true);
+    dialog.set_current_name(&format!("{}.{}", name, SheetFormat::Text.extension()));
+
+    let text = FileFilter::new();
+    FileFilterExt::set_name(&text, "Plain text (*.txt)");
+    text.add_pattern("*.txt");
+    dialog.add_filter(&text);
+    let markdown = FileFilter::new();
+    FileFilterExt::set_name(&markdown, "Markdown (*.md)");
+    markdown.add_pattern("*.md");
+    dialog.add_filter(&markdown);
+
+    let result = dialog.run();
+    let filename = dialog.get_filename();
+    dialog.destroy();
+    if result != 0 {
+        return None;
+    }
+    let path = filename?;
+    Some((
+        path.to_string_lossy().into_owned(),
+        SheetFormat::for_path(&path),
+    ))
+}
@@ -0,0 +1,307 @@
+use gtk::prelude::*;
+use gtk::{
+    ButtonsType, CellRendererText, CellRendererToggle, ComboBoxText, Dialog, DialogFlags, Entry,
+    FileChooserAction, FileChooserDialog, FontButton, Grid, Label, MessageDialog, MessageType,
+    PageSetup, PaperSize, PrintOperation, PrintOperationAction, ScrolledWindow, TreeIter, TreePath,
+    TreeStore, TreeView, TreeViewColumn, Unit, Window,
+};
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use database::Database;
+use export::songbook::{self, PageSize, Songbook, SongbookSettings};
+use export::{Selection, Song};
+
+//Answers of the dialog
+const PREVIEW: i32 = 0;
+const PRINT: i32 = 1;
+const SAVE: i32 = 2;
+
+fn show_error(parent: &Dialog, message: &str) {
+    let dialog = MessageDialog::new(
+        Some(parent),
+        DialogFlags::all(),
+        MessageType::Error,
+        ButtonsType::Close,
+        message,
+    );
+    dialog.run();
+    dialog.destroy();
+}
+
+//One row per artist, album and track, at the same paths as in the main window. Column 1 says
+//if the row goes into the songbook, albums and artists are in if all of their tracks are.
+fn fill_store(store: &TreeStore, db: &Database, selection: &Selection) {
+    let included: HashSet<_> = selection.tracks(db).into_iter().collect();
+    for (i, artist) in db.entries.iter().enumerate() {
+        let artist_iter = store.insert_with_values(None, None, &[0, 1], &[&artist.name, &false]);
+        let mut artist_in = !artist.albums.is_empty();
+        for (j, album) in artist.albums.iter().enumerate() {
+            let album_iter = store.insert_with_values(
+                Some(&artist_iter),
+                None,
+                &[0, 1],
+                &[&album.title, &false],
+            );
+            let mut album_in = !album.tracks.is_empty();
+            for (k, track) in album.tracks.iter().enumerate() {
+                let track_in = included.contains(&(i, j, k));
+                store.insert_with_values(
+                    Some(&album_iter),
+                    None,
+                    &[0, 1],
+                    &[&track.title, &track_in],
+                );
+                album_in &= track_in;
+            }
+            store.set_value(&album_iter, 1, &album_in.to_value());
+            artist_in &= album_in;
+        }
+        store.set_value(&artist_iter, 1, &artist_in.to_value());
+    }
+}
+
+fn is_included(store: &TreeStore, iter: &TreeIter) -> bool {
+    store.get_value(iter, 1).get::<bool>().unwrap_or(false)
+}
+
+//Include or leave out the row at `iter` with everything below it
+fn set_included(store: &TreeStore, iter: &TreeIter, included: bool) {
+    store.set_value(iter, 1, &included.to_value());
+    if let Some(child) = store.iter_children(iter) {
+        loop {
+            set_included(store, &child, included);
+            if !store.iter_next(&child) {
+                break;
+            }
+        }
+    }
+}
+
+//Toggle the row at `path`, albums and artists above it are in if all their rows are
+fn toggle(store: &TreeStore, path: &TreePath) {
+    let iter = match store.get_iter(path) {
+        Some(iter) => iter,
+        None => return,
+    };
+    let included = !is_included(store, &iter);
+    set_included(store, &iter, included);
+
+    let mut child = iter;
+    while let Some(parent) = store.iter_parent(&child) {
+        let mut all = true;
+        if let Some(row) = store.iter_children(&parent) {
+            loop {
+                all &= is_included(store, &row);
+                if !store.iter_next(&row) {
+                    break;
+                }
+            }
+        }
+        store.set_value(&parent, 1, &all.to_value());
+        child = parent;
+    }
+}
+
+//The tracks that are in, in the order of the database
+fn included_tracks(store: &TreeStore, db: &Database) -> Selection {
+    Selection::Tracks(
+        Selection::All
+            .tracks(db)
+            .into_iter()
+            .filter(|&(i, j, k)| {
+                let path = TreePath::new_from_indicesv(&[i as i32, j as i32, k as i32]);
+                store
+                    .get_iter(&path)
+                    .map_or(false, |iter| is_included(store, &iter))
+            })
+            .collect(),
+    )
+}
+
+//Lay out the songbook once the page it goes on is known, then draw the pages asked for
+fn print(
+    parent: &Dialog,
+    songs: Vec<Song>,
+    settings: &SongbookSettings,
+    action: PrintOperationAction,
+) -> Result<(), String> {
+    let operation = PrintOperation::new();
+    let setup = PageSetup::new();
+    setup.set_paper_size(&PaperSize::new(settings.page_size.paper_name()));
+    operation.set_default_page_setup(&setup);
+    operation.set_job_name(&settings.title);
+    //The songbook has margins of its own, and measures them in points like the PDF
+    operation.set_use_full_page(true);
+    operation.set_unit(Unit::Points);
+
+    let book: Rc<RefCell<Option<Songbook>>> = Rc::new(RefCell::new(None));
+    let laid_out = book.clone();
+    let settings = settings.clone();
+    operation.connect_begin_print(move |operation, context| {
+        if let Some(cr) = context.get_cairo_context() {
+            let book = Songbook::layout(
+                &cr,
+                context.get_width(),
+                context.get_height(),
+                &songs,
+                &settings,
+            );
+            operation.set_n_pages(book.page_count() as i32);
+            *laid_out.borrow_mut() = Some(book);
+        }
+    });
+    operation.connect_draw_page(move |_, context, page| {
+        if let (Some(cr), Some(book)) = (context.get_cairo_context(), book.borrow().as_ref()) {
+            book.draw_page(&cr, page as usize);
+        }
+    });
+    operation
+        .run(action, Some(parent))
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn ask_pdf_path(parent: &Dialog, title: &str) -> Option<String> {
+    let dialog = FileChooserDialog::new(Some("Save PDF..."), Some(parent), FileChooserAction::Save);
+    dialog.add_button("Save", 0);
+    dialog.add_button("Close", 1);
+    dialog.set_do_overwrite_confirmation(true);
+    dialog.set_current_name(&format!("{}.pdf", title));
+    let result = dialog.run();
+    let filename = dialog.get_filename();
+    dialog.destroy();
+    if result != 0 {
+        return None;
+    }
+    filename.map(|filename| filename.to_string_lossy().into_owned())
+}
+
+//Pick the tracks of a songbook, starting with `selection`, and how it looks. It is previewed,
+//printed or saved as PDF until the user closes the dialog, `settings` keeps how it looked.
+pub fn make_songbook(
+    parent: &Window,
+    db: &mut Database,
+    selection: &Selection,
+    settings: &mut SongbookSettings,
+) {
+    let dialog = Dialog::new_with_buttons(
+        Some("Songbook"),
+        Some(parent),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[
+            ("Preview", PREVIEW),
+            ("Print...", PRINT),
+            ("Save PDF...", SAVE),
+            ("Close", 3),
+        ],
+    );
+    dialog.set_default_size(500, 600);
+
+    let store = TreeStore::new(&[String::static_type(), bool::static_type()]);
+    fill_store(&store, db, selection);
+    let tree_view = TreeView::new_with_model(&store);
+    tree_view.set_headers_visible(false);
+    let toggle_cell = CellRendererToggle::new();
+    let name_cell = CellRendererText::new();
+    let column = TreeViewColumn::new();
+    column.pack_start(&toggle_cell, false);
+    column.add_attribute(&toggle_cell, "active", 1);
+    column.pack_start(&name_cell, true);
+    column.add_attribute(&name_cell, "text", 0);
+    tree_view.append_column(&column);
+    {
+        let store = store.clone();
+        toggle_cell.connect_toggled(move |_, path| toggle(&store, &path));
+    }
+    //Only what was selected is opened
+    match *selection {
+        Selection::Artist(i) => {
+            tree_view.expand_row(&TreePath::new_from_indicesv(&[i as i32]), true);
+        }
+        Selection::Album(i, j) => {
+            tree_view.expand_to_path(&TreePath::new_from_indicesv(&[i as i32, j as i32]));
+        }
+        Selection::Tracks(ref tracks) => {
+            for &(i, j, _) in tracks {
+                tree_view.expand_to_path(&TreePath::new_from_indicesv(&[i as i32, j as i32]));
+            }
+        }
+        Selection::All => (),
+    }
+    let scrolled = ScrolledWindow::new(None, None);
+    scrolled.add(&tree_view);
+
+    let title = Entry::new();
+    title.set_text(&settings.title);
+    let page_size = ComboBoxText::new();
+    for size in PageSize::ALL.iter() {
+        page_size.append(Some(size.id()), &size.id().to_uppercase());
+    }
+    page_size.set_active_id(settings.page_size.id());
+    let title_font = FontButton::new_with_font(&settings.title_font);
+    let body_font = FontButton::new_with_font(&settings.body_font);
+
+    let grid = Grid::new();
+    grid.set_row_spacing(6);
+    grid.set_column_spacing(12);
+    grid.attach(&Label::new(Some("Title")), 0, 0, 1, 1);
+    grid.attach(&title, 1, 0, 1, 1);
+    grid.attach(&Label::new(Some("Page size")), 0, 1, 1, 1);
+    grid.attach(&page_size, 1, 1, 1, 1);
+    grid.attach(&Label::new(Some("Heading font")), 0, 2, 1, 1);
+    grid.attach(&title_font, 1, 2, 1, 1);
+    grid.attach(&Label::new(Some("Lyrics font")), 0, 3, 1, 1);
+    grid.attach(&body_font, 1, 3, 1, 1);
+
+    let content = dialog.get_content_area();
+    content.pack_start(&scrolled, true, true, 5);
+    content.pack_start(&grid, false, false, 5);
+    dialog.show_all();
+
+    loop {
+        let response = dialog.run();
+        if response != PREVIEW && response != PRINT && response != SAVE {
+            break;
+        }
+        settings.title = title.get_text().unwrap_or_default();
+        settings.page_size = page_size
+            .get_active_id()
+            .and_then(|id| PageSize::from_id(&id))
+            .unwrap_or(settings.page_size);
+        settings.title_font = title_font
+            .get_font()
+            .unwrap_or_else(|| settings.title_font.clone());
+        settings.body_font = body_font
+            .get_font()
+            .unwrap_or_else(|| settings.body_font.clone());
+
+        let songs = match db.songs(&included_tracks(&store, db)) {
+            Ok(songs) => songs,
+            Err(e) => {
+                show_error(&dialog, &format!("Could not read lyrics: {}", e));
+                continue;
+            }
+        };
+        if songs.is_empty() {
+            show_error(&dialog, "Check the tracks to put in the songbook");
+            continue;
+        }
+        let result = match response {
+            PREVIEW => print(&dialog, songs, settings, PrintOperationAction::Preview),
+            PRINT => print(&dialog, songs, settings, PrintOperationAction::PrintDialog),
+            _ => match ask_pdf_path(&dialog, &settings.title) {
+                Some(path) => {
+                    songbook::write_pdf(&path, &songs, settings).map_err(|e| e.to_string())
+                }
+                None => Ok(()),
+            },
+        };
+        if let Err(e) = result {
+            show_error(&dialog, &format!("Could not make the songbook: {}", e));
+        }
+    }
+    dialog.destroy();
+}